@@ -0,0 +1,48 @@
+//! Benchmarks for the diff filtering pipeline.
+//!
+//! Run with `cargo bench --bench filter`.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use git_lines::diff::Diff;
+use std::fmt::Write;
+use std::hint::black_box;
+
+/// Number of changed lines in the synthetic diff
+const LINES: u32 = 100_000;
+
+/// Build a single-file diff replacing `LINES` lines in one hunk
+fn large_replacement_diff() -> String {
+    let mut text = String::from("diff --git a/big.txt b/big.txt\n--- a/big.txt\n+++ b/big.txt\n");
+    writeln!(text, "@@ -1,{LINES} +1,{LINES} @@").unwrap_or_default();
+    for i in 1..=LINES {
+        writeln!(text, "-old line {i}").unwrap_or_default();
+    }
+    for i in 1..=LINES {
+        writeln!(text, "+new line {i}").unwrap_or_default();
+    }
+    text
+}
+
+fn filter_benchmarks(c: &mut Criterion) {
+    let raw = large_replacement_diff();
+
+    c.bench_function("filter_100k_all", |b| {
+        b.iter_batched(
+            || Diff::parse(&raw),
+            |diff| black_box(diff.filter(|_, _| true, |_, _| true)),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+
+    // Every other deletion forces the pure-deletion path to split into many groups
+    c.bench_function("filter_100k_gapped_deletions", |b| {
+        b.iter_batched(
+            || Diff::parse(&raw),
+            |diff| black_box(diff.filter(|_, line| line % 2 == 0, |_, _| false)),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, filter_benchmarks);
+criterion_main!(benches);
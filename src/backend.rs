@@ -0,0 +1,734 @@
+//! Execution of the git commands that [`GitLines`](crate::GitLines) depends on.
+//!
+//! Staging needs only a handful of git operations: reading the unstaged diff
+//! and applying a patch to the index (plus committing it, for
+//! [`GitLines::commit_per_hunk`](crate::GitLines::commit_per_hunk)). They sit
+//! behind [`GitBackend`] so the selection logic can be exercised without
+//! spawning git, while [`SystemGit`] runs the real `git` binary.
+
+use crate::GitCommandError;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The git operations used by [`GitLines`](crate::GitLines).
+///
+/// Diffs must be produced with zero context lines (`-U0`) and without color,
+/// since hunk line numbers are taken verbatim from them.
+pub trait GitBackend {
+    /// Whether `repo_path` is inside a git work tree
+    /// (`git rev-parse --is-inside-work-tree`).
+    fn is_work_tree(&self, repo_path: &Path) -> Result<bool, GitCommandError>;
+
+    /// Unstaged changes for `files`, or for the whole working tree when empty
+    /// (`git diff -U0`).
+    fn diff(&self, repo_path: &Path, files: &[String]) -> Result<String, GitCommandError>;
+
+    /// Added and deleted line counts of unstaged changes for `files`, or the
+    /// whole working tree when empty (`git diff --numstat`).
+    fn diff_numstat(&self, repo_path: &Path, files: &[String]) -> Result<String, GitCommandError>;
+
+    /// Index entries for `files`, or the whole index when empty, each line
+    /// tagged with its status letter (`git ls-files -v`).
+    fn ls_files(&self, repo_path: &Path, files: &[String]) -> Result<String, GitCommandError>;
+
+    /// Paths of index entries missing from the working tree, each ended by
+    /// a NUL (`git ls-files --deleted -z`).
+    fn ls_deleted(&self, repo_path: &Path) -> Result<String, GitCommandError>;
+
+    /// Staged changes for the whole index (`git diff --cached -U0`).
+    fn diff_cached(&self, repo_path: &Path) -> Result<String, GitCommandError>;
+
+    /// Changes between two arbitrary paths (`git diff --no-index -U0`).
+    fn diff_no_index(
+        &self,
+        repo_path: &Path,
+        old: &Path,
+        new: &Path,
+    ) -> Result<String, GitCommandError>;
+
+    /// Changes to `files` (or all files if empty) from the tree object `tree`
+    /// to the index (`git diff -U0 --cached TREE`).
+    fn diff_cached_from(
+        &self,
+        repo_path: &Path,
+        tree: &str,
+        files: &[String],
+    ) -> Result<String, GitCommandError>;
+
+    /// Changes recorded in a stash entry (`git stash show -p -U0`).
+    fn diff_stash(&self, repo_path: &Path, stash: &str) -> Result<String, GitCommandError>;
+
+    /// Contents of a blob, by (possibly abbreviated) object id
+    /// (`git cat-file blob`).
+    fn cat_blob(&self, repo_path: &Path, blob: &str) -> Result<String, GitCommandError>;
+
+    /// Full object id of the commit `rev` names
+    /// (`git rev-parse --verify REV^{commit}`).
+    fn resolve_commit(&self, repo_path: &Path, rev: &str) -> Result<String, GitCommandError>;
+
+    /// Porcelain blame of `contents` as a version of `file`
+    /// (`git blame --porcelain --contents -`).
+    ///
+    /// Lines not in any commit are attributed to the all-zero object id.
+    fn blame(
+        &self,
+        repo_path: &Path,
+        file: &str,
+        contents: &str,
+    ) -> Result<String, GitCommandError>;
+
+    /// Apply a zero-context patch to the index (`git apply --cached`).
+    fn apply_cached(&self, repo_path: &Path, patch: &str) -> Result<(), GitCommandError>;
+
+    /// Apply a zero-context patch to the index by three-way merge against the
+    /// blobs named in its `index` headers (`git apply --cached --3way`).
+    ///
+    /// Git falls back to a plain apply for files whose blob it lacks. On
+    /// conflict it fails with [`GitCommandError::ApplyExitError`] and leaves
+    /// unmerged entries in the index.
+    fn apply_cached_3way(&self, repo_path: &Path, patch: &str) -> Result<(), GitCommandError>;
+
+    /// Apply a zero-context patch to the working tree, leaving the index
+    /// alone (`git apply`).
+    fn apply_worktree(&self, repo_path: &Path, patch: &str) -> Result<(), GitCommandError>;
+
+    /// Tree object id the index would commit as (`git write-tree`), computed
+    /// on a copy of the index so the real one is left untouched.
+    fn write_tree(&self, repo_path: &Path) -> Result<String, GitCommandError>;
+
+    /// Commit the index with `message` (`git commit --file -`).
+    fn commit(&self, repo_path: &Path, message: &str) -> Result<(), GitCommandError>;
+
+    /// Path of the index file this backend reads and writes.
+    fn index_path(&self, repo_path: &Path) -> Result<PathBuf, GitCommandError>;
+
+    /// A backend like this one that uses `index_file` in place of the index
+    /// (`GIT_INDEX_FILE`), so staging can be tried out without touching it.
+    fn with_index_file(&self, index_file: &Path) -> Box<dyn GitBackend + Send + Sync>;
+}
+
+/// Backend that runs the `git` binary found on `PATH`.
+///
+/// For sandboxes and CI, it can run another git executable and give every
+/// git command extra environment variables:
+///
+/// ```no_run
+/// # use git_lines::{GitLines, SystemGit};
+/// let git = SystemGit::default()
+///     .git_binary("/opt/git/bin/git")
+///     .env("GIT_CONFIG_NOSYSTEM", "1");
+/// let stager = GitLines::new(".").backend(git);
+/// ```
+///
+/// For a bare repository with an external work tree, or one whose `.git`
+/// lives elsewhere, it can name both explicitly; otherwise `GIT_DIR` and
+/// `GIT_WORK_TREE` are used when set:
+///
+/// ```no_run
+/// # use git_lines::{GitLines, SystemGit};
+/// let git = SystemGit::default()
+///     .git_dir("/srv/repos/dotfiles.git")
+///     .work_tree("/home/me");
+/// let stager = GitLines::new("/home/me").backend(git);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SystemGit {
+    /// Index file to use instead of the repository's own
+    index_file: Option<PathBuf>,
+    /// Git executable to run instead of `git` from `PATH`
+    git_binary: Option<PathBuf>,
+    /// Environment variables set on every git command
+    extra_env: Vec<(OsString, OsString)>,
+    /// Repository directory passed as `--git-dir`
+    git_dir: Option<PathBuf>,
+    /// Work tree passed as `--work-tree`
+    work_tree: Option<PathBuf>,
+}
+
+impl SystemGit {
+    /// Run `git_binary` instead of `git` from `PATH`
+    #[must_use]
+    pub fn git_binary(mut self, git_binary: impl Into<PathBuf>) -> Self {
+        self.git_binary = Some(git_binary.into());
+        self
+    }
+
+    /// Set the environment variable `key` to `value` for every git command
+    ///
+    /// [`GIT_INDEX_FILE`](GitBackend::with_index_file) is still overridden
+    /// where a scratch index is used.
+    #[must_use]
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.extra_env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Use the repository at `git_dir` (`git --git-dir`) instead of finding
+    /// it from the repository path
+    ///
+    /// A relative path is taken from the repository path. Without this,
+    /// `GIT_DIR` is used if set, from [`env`](Self::env) or the environment.
+    #[must_use]
+    pub fn git_dir(mut self, git_dir: impl Into<PathBuf>) -> Self {
+        self.git_dir = Some(git_dir.into());
+        self
+    }
+
+    /// Use `work_tree` as the working tree (`git --work-tree`)
+    ///
+    /// A relative path is taken from the repository path. Without this,
+    /// `GIT_WORK_TREE` is used if set, from [`env`](Self::env) or the
+    /// environment.
+    #[must_use]
+    pub fn work_tree(mut self, work_tree: impl Into<PathBuf>) -> Self {
+        self.work_tree = Some(work_tree.into());
+        self
+    }
+
+    /// Root of the working tree `repo_path` is in
+    /// (`git rev-parse --show-toplevel`)
+    ///
+    /// Follows [`git_dir`](Self::git_dir) and [`work_tree`](Self::work_tree),
+    /// or `GIT_DIR` and `GIT_WORK_TREE`, as every other command does. Fails
+    /// outside a working tree.
+    pub fn toplevel(&self, repo_path: &Path) -> Result<PathBuf, GitCommandError> {
+        let output = self
+            .git(repo_path)
+            .args(["rev-parse", "--show-toplevel"])
+            .output()
+            .map_err(|e| GitCommandError::RevParseFailed {
+                message: e.to_string(),
+            })?;
+        if !output.status.success() {
+            return Err(GitCommandError::RevParseFailed {
+                message: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+
+        Ok(PathBuf::from(
+            String::from_utf8_lossy(output.stdout.trim_ascii()).as_ref(),
+        ))
+    }
+
+    /// Start a git command run in `repo_path`
+    fn git(&self, repo_path: &Path) -> Command {
+        let mut command = Command::new(self.git_binary.as_deref().unwrap_or("git".as_ref()));
+        command.arg("-C").arg(repo_path);
+        // Variables given to `env` win over the process environment
+        let from_env = |key: &str| {
+            self.extra_env
+                .iter()
+                .rev()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+                .or_else(|| std::env::var_os(key))
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from)
+        };
+        if let Some(git_dir) = self.git_dir.clone().or_else(|| from_env("GIT_DIR")) {
+            command.arg("--git-dir").arg(git_dir);
+        }
+        if let Some(work_tree) = self.work_tree.clone().or_else(|| from_env("GIT_WORK_TREE")) {
+            command.arg("--work-tree").arg(work_tree);
+        }
+        command.envs(self.extra_env.iter().map(|(key, value)| (key, value)));
+        if let Some(index_file) = &self.index_file {
+            command.env("GIT_INDEX_FILE", index_file);
+        }
+        command
+    }
+
+    /// Run `git apply --unidiff-zero` with `flags` (`--cached` to apply to
+    /// the index), feeding `patch` on stdin
+    fn run_apply(
+        &self,
+        repo_path: &Path,
+        flags: &[&str],
+        patch: &str,
+    ) -> Result<(), GitCommandError> {
+        let mut child = self
+            .git(repo_path)
+            .arg("apply")
+            .args(flags)
+            .arg("--unidiff-zero")
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| GitCommandError::ApplySpawnFailed { source })?;
+
+        child
+            .stdin
+            .take()
+            .ok_or(GitCommandError::ApplyStdinFailed)?
+            .write_all(patch.as_bytes())
+            .map_err(|e| GitCommandError::ApplyWriteFailed {
+                message: e.to_string(),
+            })?;
+
+        let output = child
+            .wait_with_output()
+            .map_err(|e| GitCommandError::ApplyWaitFailed {
+                message: e.to_string(),
+            })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(GitCommandError::ApplyExitError {
+                stderr: stderr.into_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Run `git diff -U0` with `extra_args` before the `files` pathspec
+    fn run_diff(
+        &self,
+        repo_path: &Path,
+        extra_args: &[&str],
+        files: &[String],
+    ) -> Result<String, GitCommandError> {
+        let output = self
+            .git(repo_path)
+            .args(["diff", "--no-ext-diff", "-U0", "--no-color"])
+            .args(extra_args)
+            // Paths follow `--` so files missing from the worktree are not taken as revisions
+            .arg("--")
+            .args(files)
+            .output()
+            .map_err(|source| GitCommandError::DiffFailed { source })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(GitCommandError::DiffExitError {
+                stderr: stderr.into_owned(),
+            });
+        }
+
+        String::from_utf8(output.stdout).map_err(|e| GitCommandError::InvalidUtf8 {
+            message: e.to_string(),
+        })
+    }
+
+    /// Run `git ls-files` with `extra_args` before the `files` pathspec
+    fn run_ls_files(
+        &self,
+        repo_path: &Path,
+        extra_args: &[&str],
+        files: &[String],
+    ) -> Result<String, GitCommandError> {
+        let output = self
+            .git(repo_path)
+            .arg("ls-files")
+            .args(extra_args)
+            .arg("--")
+            .args(files)
+            .output()
+            .map_err(|e| GitCommandError::LsFilesFailed {
+                message: e.to_string(),
+            })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(GitCommandError::LsFilesExitError {
+                stderr: stderr.into_owned(),
+            });
+        }
+
+        String::from_utf8(output.stdout).map_err(|e| GitCommandError::InvalidUtf8 {
+            message: e.to_string(),
+        })
+    }
+}
+
+/// Scratch index files made so far by this process, numbering the next one
+static SCRATCH_INDEXES: AtomicU64 = AtomicU64::new(0);
+
+/// Copy `index` to a scratch index next to it, returning the copy's path
+///
+/// The copy is named `git-lines-<purpose>-<pid>-<n>.index`, `n` counting the
+/// copies this process made, so threads sharing a [`GitLines`](crate::GitLines)
+/// never share one. It is created with `create_new`, moving on to the next
+/// `n` when a name is taken, say by a file an earlier process left behind.
+/// Without an index there is nothing to copy, and the path returned does not
+/// exist yet: git reads a missing index as an empty one. The caller removes
+/// the copy.
+pub(crate) fn scratch_index(index: &Path, purpose: &str) -> io::Result<PathBuf> {
+    loop {
+        let n = SCRATCH_INDEXES.fetch_add(1, Ordering::Relaxed);
+        let scratch = index.with_file_name(format!(
+            "git-lines-{}-{}-{}.index",
+            purpose,
+            std::process::id(),
+            n
+        ));
+        if !index.exists() {
+            if scratch.exists() {
+                continue;
+            }
+            return Ok(scratch);
+        }
+        let mut copy = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&scratch)
+        {
+            Ok(copy) => copy,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = File::open(index).and_then(|mut index| io::copy(&mut index, &mut copy)) {
+            let _ = std::fs::remove_file(&scratch);
+            return Err(e);
+        }
+        return Ok(scratch);
+    }
+}
+
+impl GitBackend for SystemGit {
+    fn is_work_tree(&self, repo_path: &Path) -> Result<bool, GitCommandError> {
+        let output = self
+            .git(repo_path)
+            .args(["rev-parse", "--is-inside-work-tree"])
+            .output()
+            .map_err(|e| GitCommandError::RevParseFailed {
+                message: e.to_string(),
+            })?;
+
+        // Outside a repository (or a missing path) git exits non-zero; inside
+        // `.git` itself it prints "false"
+        Ok(output.status.success() && output.stdout.trim_ascii() == b"true")
+    }
+
+    fn diff(&self, repo_path: &Path, files: &[String]) -> Result<String, GitCommandError> {
+        self.run_diff(repo_path, &[], files)
+    }
+
+    fn diff_cached(&self, repo_path: &Path) -> Result<String, GitCommandError> {
+        self.run_diff(repo_path, &["--cached"], &[])
+    }
+
+    fn diff_cached_from(
+        &self,
+        repo_path: &Path,
+        tree: &str,
+        files: &[String],
+    ) -> Result<String, GitCommandError> {
+        self.run_diff(repo_path, &["--cached", tree], files)
+    }
+
+    fn diff_numstat(&self, repo_path: &Path, files: &[String]) -> Result<String, GitCommandError> {
+        self.run_diff(repo_path, &["--numstat"], files)
+    }
+
+    fn ls_files(&self, repo_path: &Path, files: &[String]) -> Result<String, GitCommandError> {
+        self.run_ls_files(repo_path, &["-v"], files)
+    }
+
+    fn ls_deleted(&self, repo_path: &Path) -> Result<String, GitCommandError> {
+        self.run_ls_files(repo_path, &["--deleted", "-z"], &[])
+    }
+
+    fn diff_stash(&self, repo_path: &Path, stash: &str) -> Result<String, GitCommandError> {
+        let output = self
+            .git(repo_path)
+            .args([
+                "stash",
+                "show",
+                "--patch",
+                "--no-ext-diff",
+                "-U0",
+                "--no-color",
+                stash,
+            ])
+            .output()
+            .map_err(|source| GitCommandError::DiffFailed { source })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(GitCommandError::DiffExitError {
+                stderr: stderr.into_owned(),
+            });
+        }
+
+        String::from_utf8(output.stdout).map_err(|e| GitCommandError::InvalidUtf8 {
+            message: e.to_string(),
+        })
+    }
+
+    fn cat_blob(&self, repo_path: &Path, blob: &str) -> Result<String, GitCommandError> {
+        let output = self
+            .git(repo_path)
+            .args(["cat-file", "blob", blob])
+            .output()
+            .map_err(|e| GitCommandError::CatFileFailed {
+                message: e.to_string(),
+            })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(GitCommandError::CatFileExitError {
+                stderr: stderr.into_owned(),
+            });
+        }
+
+        String::from_utf8(output.stdout).map_err(|e| GitCommandError::InvalidUtf8 {
+            message: e.to_string(),
+        })
+    }
+
+    fn resolve_commit(&self, repo_path: &Path, rev: &str) -> Result<String, GitCommandError> {
+        let output = self
+            .git(repo_path)
+            .args(["rev-parse", "--verify", "--quiet", "--end-of-options"])
+            .arg(format!("{}^{{commit}}", rev))
+            .output()
+            .map_err(|e| GitCommandError::RevParseFailed {
+                message: e.to_string(),
+            })?;
+
+        if !output.status.success() {
+            return Err(GitCommandError::UnknownCommit {
+                rev: rev.to_string(),
+            });
+        }
+
+        String::from_utf8(output.stdout)
+            .map(|sha| sha.trim_end().to_string())
+            .map_err(|e| GitCommandError::InvalidUtf8 {
+                message: e.to_string(),
+            })
+    }
+
+    fn blame(
+        &self,
+        repo_path: &Path,
+        file: &str,
+        contents: &str,
+    ) -> Result<String, GitCommandError> {
+        let mut child = self
+            .git(repo_path)
+            .args(["blame", "--porcelain", "--contents", "-", "--", file])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| GitCommandError::BlameFailed {
+                message: e.to_string(),
+            })?;
+
+        child
+            .stdin
+            .take()
+            .ok_or_else(|| GitCommandError::BlameFailed {
+                message: "no stdin handle".to_string(),
+            })?
+            .write_all(contents.as_bytes())
+            .map_err(|e| GitCommandError::BlameFailed {
+                message: e.to_string(),
+            })?;
+
+        let output = child
+            .wait_with_output()
+            .map_err(|e| GitCommandError::BlameFailed {
+                message: e.to_string(),
+            })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(GitCommandError::BlameExitError {
+                stderr: stderr.into_owned(),
+            });
+        }
+
+        String::from_utf8(output.stdout).map_err(|e| GitCommandError::InvalidUtf8 {
+            message: e.to_string(),
+        })
+    }
+
+    fn diff_no_index(
+        &self,
+        repo_path: &Path,
+        old: &Path,
+        new: &Path,
+    ) -> Result<String, GitCommandError> {
+        let output = self
+            .git(repo_path)
+            .args(["diff", "--no-index", "--no-ext-diff", "-U0", "--no-color"])
+            .arg(old)
+            .arg(new)
+            .output()
+            .map_err(|source| GitCommandError::DiffFailed { source })?;
+
+        // --no-index exits 1 when the files differ, whatever warnings it
+        // prints; an unreadable file also exits 1 but diffs nothing
+        let differs = output.status.code() == Some(1) && !output.stdout.is_empty();
+        if !output.status.success() && !differs {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(GitCommandError::DiffExitError {
+                stderr: stderr.into_owned(),
+            });
+        }
+
+        String::from_utf8(output.stdout).map_err(|e| GitCommandError::InvalidUtf8 {
+            message: e.to_string(),
+        })
+    }
+
+    fn apply_cached(&self, repo_path: &Path, patch: &str) -> Result<(), GitCommandError> {
+        self.run_apply(repo_path, &["--cached"], patch)
+    }
+
+    fn apply_cached_3way(&self, repo_path: &Path, patch: &str) -> Result<(), GitCommandError> {
+        self.run_apply(repo_path, &["--cached", "--3way"], patch)
+    }
+
+    fn apply_worktree(&self, repo_path: &Path, patch: &str) -> Result<(), GitCommandError> {
+        self.run_apply(repo_path, &[], patch)
+    }
+
+    fn write_tree(&self, repo_path: &Path) -> Result<String, GitCommandError> {
+        let write_tree_failed = |e: std::io::Error| GitCommandError::WriteTreeFailed {
+            message: e.to_string(),
+        };
+
+        // write-tree refreshes the index's cached trees, so run it on a copy
+        let index = self.index_path(repo_path)?;
+        let scratch = scratch_index(&index, "tree").map_err(write_tree_failed)?;
+        let output = self
+            .git(repo_path)
+            .arg("write-tree")
+            .env("GIT_INDEX_FILE", &scratch)
+            .output();
+        // Best effort: a leftover scratch file is harmless
+        let _ = std::fs::remove_file(&scratch);
+        let output = output.map_err(write_tree_failed)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(GitCommandError::WriteTreeExitError {
+                stderr: stderr.into_owned(),
+            });
+        }
+
+        String::from_utf8(output.stdout)
+            .map(|oid| oid.trim_end().to_string())
+            .map_err(|e| GitCommandError::InvalidUtf8 {
+                message: e.to_string(),
+            })
+    }
+
+    fn commit(&self, repo_path: &Path, message: &str) -> Result<(), GitCommandError> {
+        let commit_failed = |e: std::io::Error| GitCommandError::CommitFailed {
+            message: e.to_string(),
+        };
+        // The message goes over stdin so multi-paragraph bodies pass through intact
+        let mut child = self
+            .git(repo_path)
+            .args(["commit", "--quiet", "--file", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(commit_failed)?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(message.as_bytes()).map_err(commit_failed)?;
+        }
+        let output = child.wait_with_output().map_err(commit_failed)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(GitCommandError::CommitExitError {
+                stderr: stderr.into_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn index_path(&self, repo_path: &Path) -> Result<PathBuf, GitCommandError> {
+        if let Some(index_file) = &self.index_file {
+            return Ok(index_file.clone());
+        }
+
+        let output = self
+            .git(repo_path)
+            .args(["rev-parse", "--path-format=absolute", "--git-path", "index"])
+            .output()
+            .map_err(|e| GitCommandError::RevParseFailed {
+                message: e.to_string(),
+            })?;
+        if !output.status.success() {
+            return Err(GitCommandError::RevParseFailed {
+                message: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+
+        Ok(PathBuf::from(
+            String::from_utf8_lossy(output.stdout.trim_ascii()).as_ref(),
+        ))
+    }
+
+    fn with_index_file(&self, index_file: &Path) -> Box<dyn GitBackend + Send + Sync> {
+        Box::new(SystemGit {
+            index_file: Some(index_file.to_path_buf()),
+            ..self.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::GitLinesError;
+    use std::error::Error;
+
+    fn missing_git() -> SystemGit {
+        SystemGit::default().git_binary("/nonexistent/git")
+    }
+
+    fn io_kind(error: &dyn Error) -> Option<io::ErrorKind> {
+        error
+            .source()?
+            .downcast_ref::<io::Error>()
+            .map(io::Error::kind)
+    }
+
+    #[test]
+    fn missing_git_diff_source_is_not_found() {
+        let error = missing_git().diff(Path::new("."), &[]).unwrap_err();
+        assert!(matches!(error, GitCommandError::DiffFailed { .. }));
+        assert_eq!(io_kind(&error), Some(io::ErrorKind::NotFound));
+
+        let error = GitLinesError::from(error);
+        assert_eq!(io_kind(&error), Some(io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn scratch_indexes_are_unique_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir.path().join("index");
+        std::fs::write(&index, "entries").unwrap();
+
+        let first = scratch_index(&index, "test").unwrap();
+        let second = scratch_index(&index, "test").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "entries");
+        assert_eq!(std::fs::read_to_string(&second).unwrap(), "entries");
+    }
+
+    #[test]
+    fn missing_git_apply_source_is_not_found() {
+        let error = missing_git().apply_cached(Path::new("."), "").unwrap_err();
+        assert!(matches!(error, GitCommandError::ApplySpawnFailed { .. }));
+        assert_eq!(io_kind(&error), Some(io::ErrorKind::NotFound));
+    }
+}
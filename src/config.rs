@@ -0,0 +1,215 @@
+//! Defaults for CLI flags read from configuration files.
+//!
+//! Two files are read, each optional:
+//!
+//! - the user config, `$XDG_CONFIG_HOME/git-lines/config.toml` (falling back
+//!   to `~/.config/git-lines/config.toml`)
+//! - the repo config, `.git-lines.toml` in the repository root
+//!
+//! Flags given on the command line win over the repo config, which wins over
+//! the user config, which wins over the built-in defaults.
+//!
+//! ```toml
+//! color = "never"
+//! escape_control = "always"
+//! max_files = 20
+//! sort_files = true
+//! show_tabs = true
+//! tab_width = 8
+//! ```
+//!
+//! Only this `key = value` subset of TOML is read: one setting per line,
+//! strings in double quotes, and `#` comments on lines of their own.
+//!
+//! There is no `max_lines` setting, as there is no `--max-lines` flag (the
+//! cap is on files: `max_files`), and no excluded paths. Nor can the diff
+//! algorithm be set: line numbers come from git's default diff, and another
+//! algorithm would number the lines that stage tokens name differently.
+
+use crate::{ColorChoice, EscapeChoice};
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+
+/// Name of the repo config file, looked up in the repository root
+pub const REPO_CONFIG: &str = ".git-lines.toml";
+
+/// Flag defaults from one config file, or several layered together
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Default for `--color`
+    pub color: Option<ColorChoice>,
+    /// Default for `--escape-control`
+    pub escape_control: Option<EscapeChoice>,
+    /// Default for `stage --max-files`
+    pub max_files: Option<usize>,
+    /// Default for `stage --sort-files`/`--no-sort-files`
+    pub sort_files: Option<bool>,
+    /// Default for `diff --show-tabs`/`--no-show-tabs`
+    pub show_tabs: Option<bool>,
+    /// Default for `stage --tab-width`
+    pub tab_width: Option<usize>,
+}
+
+impl Config {
+    /// Parse a config file's contents
+    ///
+    /// Errors name the line number and what is wrong with it.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| format!("line {}: {}", index + 1, message);
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`"))?;
+            let value = value.trim();
+            match key.trim() {
+                "color" => config.color = Some(choice(value).map_err(|e| error(&e))?),
+                "escape_control" => {
+                    config.escape_control = Some(choice(value).map_err(|e| error(&e))?)
+                }
+                "max_files" => config.max_files = Some(number(value).map_err(|e| error(&e))?),
+                "sort_files" => config.sort_files = Some(boolean(value).map_err(|e| error(&e))?),
+                "show_tabs" => config.show_tabs = Some(boolean(value).map_err(|e| error(&e))?),
+                "tab_width" => config.tab_width = Some(number(value).map_err(|e| error(&e))?),
+                key => return Err(error(&format!("unknown key `{}`", key))),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Read the user config and the repo config of the working tree rooted
+    /// at `repo_root`, if any, layered by precedence
+    ///
+    /// Missing files count as empty; unreadable or malformed ones are errors.
+    pub fn load(repo_root: Option<&Path>) -> Result<Self, String> {
+        let user = match user_config_path() {
+            Some(path) => Self::read(&path)?,
+            None => Self::default(),
+        };
+        let repo = match repo_root {
+            Some(root) => Self::read(&root.join(REPO_CONFIG))?,
+            None => Self::default(),
+        };
+        Ok(user.overridden_by(repo))
+    }
+
+    /// Read one config file, or an empty config if it does not exist
+    fn read(path: &Path) -> Result<Self, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        Self::parse(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// This config with every setting `over` makes replacing ours
+    pub fn overridden_by(self, over: Self) -> Self {
+        Self {
+            color: over.color.or(self.color),
+            escape_control: over.escape_control.or(self.escape_control),
+            max_files: over.max_files.or(self.max_files),
+            sort_files: over.sort_files.or(self.sort_files),
+            show_tabs: over.show_tabs.or(self.show_tabs),
+            tab_width: over.tab_width.or(self.tab_width),
+        }
+    }
+}
+
+/// A quoted value naming one of a `--color`-style flag's choices
+fn choice<T: ValueEnum>(value: &str) -> Result<T, String> {
+    let name = value
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, found `{}`", value))?;
+    T::from_str(name, false).map_err(|_| format!("unknown value \"{}\"", name))
+}
+
+/// A non-negative integer value
+fn number(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected a number, found `{}`", value))
+}
+
+/// A `true` or `false` value
+fn boolean(value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("expected true or false, found `{}`", value)),
+    }
+}
+
+/// Location of the user config, if a config directory can be found
+fn user_config_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("git-lines").join("config.toml"))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_known_keys() {
+        let config =
+            Config::parse("# defaults\ncolor = \"never\"\nmax_files = 20\nsort_files = true\n")
+                .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                color: Some(ColorChoice::Never),
+                max_files: Some(20),
+                sort_files: Some(true),
+                ..Config::default()
+            }
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn parse_rejects_unknown_keys() {
+        let err = Config::parse("max_file = 3\n").unwrap_err();
+        assert_eq!(err, "line 1: unknown key `max_file`");
+    }
+
+    #[test]
+    fn parse_rejects_malformed_values() {
+        let err = |text| Config::parse(text).unwrap_err();
+        assert_eq!(
+            err("color = never\n"),
+            "line 1: expected a quoted string, found `never`"
+        );
+        assert_eq!(
+            err("\ncolor = \"sometimes\"\n"),
+            "line 2: unknown value \"sometimes\""
+        );
+        assert_eq!(
+            err("tab_width = -1\n"),
+            "line 1: expected a number, found `-1`"
+        );
+        assert_eq!(
+            err("show_tabs = yes\n"),
+            "line 1: expected true or false, found `yes`"
+        );
+        assert_eq!(err("show_tabs\n"), "line 1: expected `key = value`");
+    }
+
+    #[test]
+    fn repo_config_overrides_user_config() {
+        let user = Config::parse("max_files = 10\ntab_width = 8\n").unwrap();
+        let repo = Config::parse("max_files = 50\n").unwrap();
+        let merged = user.overridden_by(repo);
+        assert_eq!(merged.max_files, Some(50));
+        assert_eq!(merged.tab_width, Some(8));
+    }
+}
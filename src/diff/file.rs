@@ -1,56 +1,166 @@
-use super::hunk::Hunk;
+use super::full::{DisplayOptions, display_content, write_numbered_lines};
+use super::hunk::{Hunk, ModifiedLines};
 use std::fmt;
 
+/// What a diff does to its file as a whole, from the file-level headers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FileChange {
+    /// The file is new (`new file mode`, e.g. after `git add -N`)
+    Added,
+    /// The file's content, name or mode changed
+    #[default]
+    Modified,
+    /// The file was deleted (`deleted file mode`)
+    Deleted,
+}
+
 /// A complete diff for a single file.
 ///
 /// Contains all hunks (change blocks) for one file from a git diff.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FileDiff {
     /// File path (extracted from `+++ b/path` header)
     pub path: String,
     /// All hunks for this file
     pub hunks: Vec<Hunk>,
+    /// Source path when git detected this file as a copy (`copy from` header).
+    ///
+    /// Old line numbers in the hunks refer to this source file.
+    pub copied_from: Option<String>,
+    /// Previous path when git detected this file as a rename (`rename from` header).
+    ///
+    /// A rename without content changes has no hunks; it is staged whole with
+    /// the [`Rename`](crate::parse::LineRef::Rename) selector.
+    pub renamed_from: Option<String>,
+    /// Old-side path from the `--- a/` header, when it differs from
+    /// [`path`](Self::path) without a copy or rename, as in
+    /// `git diff --no-index` of two differently named files.
+    ///
+    /// Patches name it on their old side, so `a/` still points at it.
+    pub old_name: Option<String>,
+    /// Abbreviated id of the blob the hunks were taken against (the old side
+    /// of the `index` header).
+    ///
+    /// A three-way apply merges against this blob when the lines have moved
+    /// since the diff was taken. `None` when the old side is all zeros or the
+    /// header is missing.
+    pub old_blob: Option<String>,
+    /// Whether git reported the file as binary (`Binary files ... differ`).
+    ///
+    /// A binary file has no hunks, so none of its changes can be staged by line.
+    pub binary: bool,
+    /// File mode from the header (the `index` line, or `new file mode`), e.g.
+    /// `100644`, or `120000` for a symlink, whose one line is its target.
+    ///
+    /// Patches repeat it on their `index` line; `git apply --cached` keeps the
+    /// index entry's mode either way, so a staged symlink stays a symlink.
+    pub mode: Option<String>,
+    /// Whether the diff adds, modifies or deletes the file
+    pub change: FileChange,
 }
 
 impl FileDiff {
     /// Parse a single-file diff from git diff output.
     ///
     /// Expects input starting with `diff --git` and containing `+++ b/path` header.
+    /// For a file deleted from the worktree (`+++ /dev/null`), the path comes from
+    /// `--- a/path`; its deletions render as an ordinary modification, so a subset
+    /// of them stages a partial removal.
+    /// A `copy from` header (emitted under copy detection) is kept so the
+    /// rendered patch still creates the copy.
+    /// Likewise `rename from` is kept; a rename with 100% similarity has no
+    /// `---`/`+++` headers or hunks and takes its path from `rename to`.
+    /// A `Binary files` marker (e.g. a text file replaced by binary content)
+    /// yields a hunk-less diff flagged [`binary`](Self::binary), with the path
+    /// taken from the marker itself.
     ///
     /// Returns `None` if the file path cannot be extracted.
     #[must_use]
     pub fn parse(text: &str) -> Option<Self> {
-        // Extract path from +++ b/... header
-        let path = text
-            .lines()
-            .find_map(|line| line.strip_prefix("+++ b/"))
+        let header = || text.lines().take_while(|line| !line.starts_with("@@ "));
+        let change = if header().any(|line| line.starts_with("new file mode ")) {
+            FileChange::Added
+        } else if header().any(|line| line.starts_with("deleted file mode ")) {
+            FileChange::Deleted
+        } else {
+            FileChange::Modified
+        };
+
+        if let Some(path) = header().find_map(binary_marker_path) {
+            return Some(FileDiff {
+                path: path.to_string(),
+                hunks: Vec::new(),
+                copied_from: None,
+                renamed_from: None,
+                old_name: None,
+                old_blob: None,
+                binary: true,
+                mode: None,
+                change,
+            });
+        }
+
+        // Extract path from +++ b/... header, or --- a/... for deleted files
+        let path = header()
+            .find_map(|line| line.strip_prefix("+++ b/").map(header_path))
+            .or_else(|| header().find_map(|line| line.strip_prefix("--- a/").map(header_path)))
+            .or_else(|| header().find_map(|line| line.strip_prefix("rename to ")))
             .filter(|p| !p.is_empty())?
             .to_string();
 
-        // Find first hunk marker
-        let first_hunk_pos = text.find("\n@@ ").map(|i| i + 1)?;
+        let copied_from = header()
+            .find_map(|line| line.strip_prefix("copy from "))
+            .map(str::to_string);
+        let renamed_from = header()
+            .find_map(|line| line.strip_prefix("rename from "))
+            .map(str::to_string);
+        let old_name = header()
+            .find_map(|line| line.strip_prefix("--- a/").map(header_path))
+            .filter(|old| *old != path && copied_from.is_none() && renamed_from.is_none())
+            .map(str::to_string);
+        let old_blob = header()
+            .find_map(|line| line.strip_prefix("index "))
+            .and_then(|ids| ids.split_once(".."))
+            .map(|(old, _)| old)
+            .filter(|old| !old.bytes().all(|b| b == b'0'))
+            .map(str::to_string);
+        let mode = header()
+            .find_map(|line| line.strip_prefix("index ")?.split_once(' '))
+            .map(|(_, mode)| mode)
+            .or_else(|| header().find_map(|line| line.strip_prefix("new file mode ")))
+            .map(str::to_string);
 
-        // Find all subsequent hunk markers
-        let mut indices = vec![first_hunk_pos];
-        let mut search_start = first_hunk_pos + 1;
-
-        while let Some(pos) = text[search_start..].find("\n@@ ") {
-            let abs_pos = search_start + pos + 1; // +1 to skip the newline
-            indices.push(abs_pos);
-            search_start = abs_pos + 1;
+        // Only a rename may have no hunks
+        let sections = hunk_sections(text);
+        if sections.is_empty() {
+            return renamed_from.is_some().then(|| FileDiff {
+                path,
+                hunks: Vec::new(),
+                copied_from,
+                renamed_from,
+                old_name,
+                old_blob,
+                binary: false,
+                mode,
+                change,
+            });
         }
 
-        // Parse each hunk section
-        let hunks = indices
-            .iter()
-            .enumerate()
-            .filter_map(|(i, &start)| {
-                let end = indices.get(i + 1).copied().unwrap_or(text.len());
-                Hunk::parse(&text[start..end])
-            })
-            .collect();
+        let hunks = sections.into_iter().filter_map(Hunk::parse).collect();
 
-        Some(FileDiff { path, hunks })
+        Some(FileDiff {
+            path,
+            hunks,
+            copied_from,
+            renamed_from,
+            old_name,
+            old_blob,
+            binary: false,
+            mode,
+            change,
+        })
     }
 
     /// Filter lines across all hunks, returning a new FileDiff with only matching lines.
@@ -72,16 +182,36 @@ impl FileDiff {
     /// - `Some(FileDiff)` containing only hunks with matching lines
     /// - `None` if no lines matched in any hunk
     #[must_use]
-    pub fn filter<F, G>(self, mut keep_old: F, mut keep_new: G) -> Option<Self>
+    pub fn filter<F, G>(self, keep_old: F, keep_new: G) -> Option<Self>
+    where
+        F: FnMut(u32) -> bool,
+        G: FnMut(u32) -> bool,
+    {
+        self.filter_with_sources(keep_old, keep_new)
+            .map(|(file, _)| file)
+    }
+
+    /// Like [`filter`](Self::filter), also reporting where each output hunk came from.
+    ///
+    /// The returned vector holds, for each output hunk, the index of the input
+    /// hunk it was built from. An index that repeats marks a hunk split apart
+    /// by a gapped selection of deletions.
+    #[must_use]
+    pub fn filter_with_sources<F, G>(
+        self,
+        mut keep_old: F,
+        mut keep_new: G,
+    ) -> Option<(Self, Vec<usize>)>
     where
         F: FnMut(u32) -> bool,
         G: FnMut(u32) -> bool,
     {
         let mut output_hunks = Vec::new();
+        let mut sources = Vec::new();
         let mut cumulative_delta: i32 = 0; // additions - deletions from previous hunks
 
-        for hunk in self.hunks {
-            let Some(filtered) = hunk.filter(&mut keep_old, &mut keep_new) else {
+        for (index, hunk) in self.hunks.into_iter().enumerate() {
+            let Some(filtered) = hunk.filter_lines(&mut keep_old, &mut keep_new) else {
                 continue;
             };
 
@@ -90,29 +220,281 @@ impl FileDiff {
 
             // Update cumulative delta for subsequent hunks
             for h in &new_hunks {
-                cumulative_delta += h.new.lines.len() as i32;
-                cumulative_delta -= h.old.lines.len() as i32;
+                cumulative_delta += h.new.len() as i32;
+                cumulative_delta -= h.old.len() as i32;
             }
 
+            sources.extend(std::iter::repeat_n(index, new_hunks.len()));
             output_hunks.extend(new_hunks);
         }
 
         if output_hunks.is_empty() {
             None
         } else {
-            Some(FileDiff {
+            let file = FileDiff {
                 path: self.path,
                 hunks: output_hunks,
-            })
+                copied_from: self.copied_from,
+                renamed_from: self.renamed_from,
+                old_name: self.old_name,
+                old_blob: self.old_blob,
+                binary: self.binary,
+                mode: self.mode,
+                change: self.change,
+            };
+            Some((file, sources))
         }
     }
 }
 
-impl fmt::Display for FileDiff {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "diff --git a/{} b/{}", self.path, self.path)?;
-        writeln!(f, "--- a/{}", self.path)?;
-        writeln!(f, "+++ b/{}", self.path)?;
+impl FileDiff {
+    /// The hunk adding only the newline missing after the file's last line,
+    /// with the index of the hunk it comes from
+    ///
+    /// The old last line is deleted and re-added unchanged but for the
+    /// newline. `None` unless the old side lacks its final newline and the new
+    /// side has one.
+    #[must_use]
+    pub fn final_newline_hunk(&self) -> Option<(usize, Hunk)> {
+        let (index, hunk) = self
+            .hunks
+            .iter()
+            .enumerate()
+            .rfind(|(_, hunk)| hunk.old.missing_final_newline)?;
+        if hunk.new.missing_final_newline {
+            return None;
+        }
+        let last = hunk.old.lines.last()?;
+        let side = |missing_final_newline| ModifiedLines {
+            start: hunk.old.start + hunk.old.len() as u32 - 1,
+            lines: vec![last.clone()],
+            missing_final_newline,
+        };
+        Some((
+            index,
+            Hunk {
+                old: side(true),
+                new: side(false),
+            },
+        ))
+    }
+
+    /// Path of the file's old side: its copy or rename source or its
+    /// [`old_name`](Self::old_name), else its path
+    pub(crate) fn old_path(&self) -> &str {
+        self.copied_from
+            .as_deref()
+            .or(self.renamed_from.as_deref())
+            .or(self.old_name.as_deref())
+            .unwrap_or(&self.path)
+    }
+
+    /// Merge consecutive hunks that touch into one.
+    ///
+    /// Two hunks touch when the second's old lines start right where the
+    /// first's end (a hunk without deletions sits just after its `old.start`
+    /// line), as with adjacent lines split into separate hunks by filtering.
+    /// The merged hunk replaces both old sides with both new sides in order,
+    /// so it stages exactly what the pair did. Hunks stay apart when merging
+    /// would put lines after one that lacks its final newline.
+    pub fn coalesce_hunks(&mut self) {
+        let mut sources = vec![0; self.hunks.len()];
+        self.coalesce_hunks_with_sources(&mut sources);
+    }
+
+    /// [`coalesce_hunks`](Self::coalesce_hunks), keeping `sources` (one
+    /// entry per hunk) in step; a merged hunk keeps its first part's entry
+    pub(crate) fn coalesce_hunks_with_sources(&mut self, sources: &mut Vec<usize>) {
+        let mut hunks: Vec<Hunk> = Vec::with_capacity(self.hunks.len());
+        let mut kept_sources = Vec::with_capacity(sources.len());
+        for (hunk, source) in std::mem::take(&mut self.hunks)
+            .into_iter()
+            .zip(sources.drain(..))
+        {
+            match hunks.last_mut() {
+                Some(first) if touches(first, &hunk) => merge_hunks(first, hunk),
+                _ => {
+                    hunks.push(hunk);
+                    kept_sources.push(source);
+                }
+            }
+        }
+        self.hunks = hunks;
+        *sources = kept_sources;
+    }
+
+    /// Render the file as a unified diff for `patch(1)`.
+    ///
+    /// Unlike [`Display`](fmt::Display), there are no git headers (`diff --git`,
+    /// `copy from`, ...), only `--- a/` and `+++ b/`, and each change gets up to
+    /// `context` unchanged lines on either side, taken from `old`, the file's
+    /// old content. Hunks whose context would meet are merged, as `diff -u`
+    /// does. A file without hunks (binary, or a bare rename) renders as nothing.
+    /// The side a file is missing from is `/dev/null`: the old side of an added
+    /// file, and the new side of a deleted file whose lines all go.
+    ///
+    /// Returns `None` if `old` does not hold the lines the hunks delete.
+    #[must_use]
+    pub fn to_plain_patch(&self, old: &str, context: usize) -> Option<String> {
+        if self.hunks.is_empty() {
+            return Some(String::new());
+        }
+        let old_lines = content_lines(old);
+        let old_missing_newline = !old.is_empty() && !old.ends_with('\n');
+        let spans = self.old_spans(&old_lines)?;
+
+        let deletes_all = self.change == FileChange::Deleted
+            && self.hunks.iter().all(|h| h.new.is_empty())
+            && self.hunks.iter().map(|h| h.old.len()).sum::<usize>() == old_lines.len();
+        let old_header = match self.change {
+            FileChange::Added if old_lines.is_empty() => "/dev/null".to_string(),
+            _ => format!("a/{}", self.old_path()),
+        };
+        let new_header = if deletes_all {
+            "/dev/null".to_string()
+        } else {
+            format!("b/{}", self.path)
+        };
+        let mut out = format!("--- {}\n+++ {}\n", old_header, new_header);
+        let mut delta = 0isize;
+        for (first, last) in context_groups(&spans, context) {
+            let from = spans[first].0.saturating_sub(context);
+            let to = (spans[last].1 + context).min(old_lines.len());
+            let group = &self.hunks[first..=last];
+            let deleted: usize = group.iter().map(|h| h.old.len()).sum();
+            let added: usize = group.iter().map(|h| h.new.len()).sum();
+            let old_count = to - from;
+            let new_count = old_count - deleted + added;
+            let new_from = from.saturating_add_signed(delta);
+            out.push_str(&format!(
+                "@@ -{} +{} @@\n",
+                unified_range(from, old_count),
+                unified_range(new_from, new_count)
+            ));
+
+            let mut at = from;
+            for (hunk, &(start, end)) in group.iter().zip(&spans[first..=last]) {
+                for line in &old_lines[at..start] {
+                    out.push_str(&format!(" {}\n", line));
+                }
+                // Hunk renders its own lines and newline markers; drop its header
+                let body = hunk.to_string();
+                out.push_str(body.split_once('\n').map_or("", |(_, rest)| rest));
+                at = end;
+            }
+            for line in &old_lines[at..to] {
+                out.push_str(&format!(" {}\n", line));
+            }
+            if old_missing_newline && at < to && to == old_lines.len() {
+                out.push_str("\\ No newline at end of file\n");
+            }
+
+            delta += added as isize - deleted as isize;
+        }
+        Some(out)
+    }
+
+    /// Render the file in the numbered format with up to `context` unchanged
+    /// lines around each change, taken from `old`, the file's old content.
+    ///
+    /// Context lines carry their new line number, dimmed when `options.color`
+    /// is set, with a blank where the `-`/`+` sign would be:
+    ///
+    /// ```text
+    /// config.nix:
+    ///     9:    unchanged
+    ///   -10:    old
+    ///   +10:    new
+    ///    11:    unchanged
+    /// ```
+    ///
+    /// Changes whose context would meet share one block. Returns `None` if
+    /// `old` does not hold the lines the hunks delete.
+    #[must_use]
+    pub fn to_numbered_with_context(
+        &self,
+        old: &str,
+        context: usize,
+        options: DisplayOptions,
+    ) -> Option<String> {
+        let old_lines = content_lines(old);
+        let spans = self.old_spans(&old_lines)?;
+        let (dim, reset) = match options.color {
+            true => ("\x1b[2m", "\x1b[0m"),
+            false => ("", ""),
+        };
+        let write_context = |out: &mut String, from: usize, to: usize, delta: isize| {
+            for (i, line) in old_lines[from..to].iter().enumerate() {
+                let line_num = (from + i + 1).saturating_add_signed(delta);
+                let content = display_content(line, options);
+                out.push_str(&format!("   {dim}{line_num}:{reset} {content}\n"));
+            }
+        };
+
+        let mut out = String::new();
+        if let Some(from) = &self.renamed_from {
+            out.push_str(&format!("{} → {} (rename)\n", from, self.path));
+        }
+        out.push_str(&format!("{}:\n", self.path));
+        let mut delta = 0isize;
+        for (first, last) in context_groups(&spans, context) {
+            let mut at = spans[first].0.saturating_sub(context);
+            for (hunk, &(start, end)) in self.hunks[first..=last].iter().zip(&spans[first..=last]) {
+                write_context(&mut out, at, start, delta);
+                // Writing to a String cannot fail
+                let _ = write_numbered_lines(&mut out, hunk, options);
+                delta += hunk.new.len() as isize - hunk.old.len() as isize;
+                at = end;
+            }
+            write_context(&mut out, at, (at + context).min(old_lines.len()), delta);
+            out.push('\n');
+        }
+        Some(out)
+    }
+
+    /// Old-side span `[start, end)` of `old_lines` each hunk replaces, or
+    /// `None` if the lines there are not the ones the hunk deletes
+    ///
+    /// A pure addition is an empty span after its `old.start` line.
+    fn old_spans(&self, old_lines: &[&str]) -> Option<Vec<(usize, usize)>> {
+        self.hunks
+            .iter()
+            .map(|hunk| {
+                let start =
+                    (hunk.old.start as usize).checked_sub(usize::from(!hunk.old.is_empty()))?;
+                let end = start + hunk.old.len();
+                (old_lines.get(start..end)? == hunk.old.lines.as_slice()).then_some((start, end))
+            })
+            .collect()
+    }
+
+    /// Write the file's patch, with an `index` header naming
+    /// [`old_blob`](Self::old_blob) when `with_blob` is set
+    pub(crate) fn write_patch(&self, f: &mut impl fmt::Write, with_blob: bool) -> fmt::Result {
+        let old_path = self.old_path();
+        writeln!(f, "diff --git a/{} b/{}", old_path, self.path)?;
+        if let Some(source) = &self.copied_from {
+            writeln!(f, "copy from {}", source)?;
+            writeln!(f, "copy to {}", self.path)?;
+        }
+        if let Some(source) = &self.renamed_from {
+            writeln!(f, "rename from {}", source)?;
+            writeln!(f, "rename to {}", self.path)?;
+        }
+        // A content-free rename has no file headers, as git emits it
+        if !self.hunks.is_empty() {
+            if let Some(blob) = self.old_blob.as_ref().filter(|_| with_blob) {
+                // The new blob id no longer holds once lines are filtered out,
+                // and git apply only looks up the old one, so it is zeroed
+                write!(f, "index {}..{}", blob, "0".repeat(blob.len()))?;
+                match &self.mode {
+                    Some(mode) => writeln!(f, " {}", mode)?,
+                    None => writeln!(f)?,
+                }
+            }
+            writeln!(f, "--- a/{}", old_path)?;
+            writeln!(f, "+++ b/{}", self.path)?;
+        }
 
         for hunk in &self.hunks {
             write!(f, "{}", hunk)?;
@@ -122,11 +504,141 @@ impl fmt::Display for FileDiff {
     }
 }
 
+impl fmt::Display for FileDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_patch(f, false)
+    }
+}
+
+/// Text of each hunk in a single-file diff, from its `@@ ` header to the next
+pub(crate) fn hunk_sections(text: &str) -> Vec<&str> {
+    let Some(first_hunk_pos) = text.find("\n@@ ").map(|i| i + 1) else {
+        return Vec::new();
+    };
+
+    // Find all subsequent hunk markers
+    let mut indices = vec![first_hunk_pos];
+    let mut search_start = first_hunk_pos + 1;
+
+    while let Some(pos) = text[search_start..].find("\n@@ ") {
+        let abs_pos = search_start + pos + 1; // +1 to skip the newline
+        indices.push(abs_pos);
+        search_start = abs_pos + 1;
+    }
+
+    indices
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = indices.get(i + 1).copied().unwrap_or(text.len());
+            &text[start..end]
+        })
+        .collect()
+}
+
+/// Old-side span `[start, end)` of 0-based lines a hunk replaces
+fn old_span(hunk: &Hunk) -> (u32, u32) {
+    let start = if hunk.old.is_empty() {
+        hunk.old.start
+    } else {
+        hunk.old.start.saturating_sub(1)
+    };
+    (start, start + hunk.old.len() as u32)
+}
+
+/// Whether `second` can be merged onto the end of `first` without changing
+/// what the pair applies
+fn touches(first: &Hunk, second: &Hunk) -> bool {
+    old_span(first).1 == old_span(second).0
+        && !first.old.missing_final_newline
+        && (!first.new.missing_final_newline || second.new.is_empty())
+}
+
+/// Extend `first` with `second`, which must [touch](touches) it
+fn merge_hunks(first: &mut Hunk, second: Hunk) {
+    if first.old.is_empty() && !second.old.is_empty() {
+        first.old.start = second.old.start;
+    }
+    if first.new.is_empty() && !second.new.is_empty() {
+        first.new.start = second.new.start;
+    }
+    first.old.missing_final_newline = second.old.missing_final_newline;
+    if !second.new.is_empty() {
+        first.new.missing_final_newline = second.new.missing_final_newline;
+    }
+    first.old.lines.extend(second.old.lines);
+    first.new.lines.extend(second.new.lines);
+}
+
+/// A file's content split into lines, without their newlines
+fn content_lines(text: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    if text.ends_with('\n') || text.is_empty() {
+        lines.pop();
+    }
+    lines
+}
+
+/// First and last index of each run of hunks whose `context` lines would
+/// meet, given the old-side `spans` they replace
+fn context_groups(spans: &[(usize, usize)], context: usize) -> Vec<(usize, usize)> {
+    let mut groups = Vec::new();
+    let mut first = 0;
+    while first < spans.len() {
+        let mut last = first;
+        while last + 1 < spans.len()
+            && spans[last + 1].0.saturating_sub(spans[last].1) <= 2 * context
+        {
+            last += 1;
+        }
+        groups.push((first, last));
+        first = last + 1;
+    }
+    groups
+}
+
+/// A unified diff header range for `count` lines from 0-based line `from`
+///
+/// An empty range names the line before it, so its start is `from` itself.
+fn unified_range(from: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", from),
+        1 => format!("{}", from + 1),
+        n => format!("{},{}", from + 1, n),
+    }
+}
+
+/// Path from the rest of a `--- a/` or `+++ b/` line
+///
+/// Git ends the path with a tab when it contains a space, and other tools
+/// may follow that tab with a timestamp; everything from the tab on is
+/// dropped, keeping any spaces before it as part of the path. Without a tab,
+/// trailing whitespace such as a CR from CRLF line endings is trimmed.
+fn header_path(rest: &str) -> &str {
+    match rest.split_once('\t') {
+        Some((path, _)) => path,
+        None => rest.trim_end(),
+    }
+}
+
+/// Path named by a `Binary files a/x and b/x differ` line
+///
+/// Prefers the new side; a binary file deleted from the worktree only has the
+/// old side (`and /dev/null`).
+fn binary_marker_path(line: &str) -> Option<&str> {
+    let sides = line
+        .strip_prefix("Binary files ")?
+        .strip_suffix(" differ")?;
+    let (old, new) = sides.rsplit_once(" and ")?;
+    new.strip_prefix("b/")
+        .or_else(|| old.strip_prefix("a/"))
+        .filter(|p| !p.is_empty())
+}
+
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod tests {
     use super::*;
-    use crate::diff::hunk::ModifiedLines;
     use similar_asserts::assert_eq;
 
     #[test]
@@ -170,6 +682,326 @@ index fa2da6e..41114ff 100644
         assert_eq!(file_diff.hunks[1].new.lines, vec!["# SECOND INSERTION"]);
     }
 
+    #[test]
+    fn parse_worktree_deletion() {
+        let diff = r#"diff --git a/old.txt b/old.txt
+deleted file mode 100644
+index 3b18e51..0000000
+--- a/old.txt
++++ /dev/null
+@@ -1,2 +0,0 @@
+-one
+-two
+"#;
+        let file_diff = FileDiff::parse(diff).unwrap();
+        assert_eq!(file_diff.path, "old.txt");
+        assert_eq!(file_diff.hunks.len(), 1);
+        assert_eq!(file_diff.hunks[0].old.lines, vec!["one", "two"]);
+    }
+
+    #[test]
+    fn parse_text_to_binary() {
+        let diff = r#"diff --git a/config.nix b/config.nix
+index 3b18e51..8a1f2c4 100644
+Binary files a/config.nix and b/config.nix differ
+"#;
+        let file_diff = FileDiff::parse(diff).unwrap();
+        assert_eq!(file_diff.path, "config.nix");
+        assert!(file_diff.binary);
+        assert!(file_diff.hunks.is_empty());
+
+        let deleted = "diff --git a/blob.bin b/blob.bin\n\
+                       deleted file mode 100644\n\
+                       Binary files a/blob.bin and /dev/null differ\n";
+        assert_eq!(FileDiff::parse(deleted).unwrap().path, "blob.bin");
+    }
+
+    #[test]
+    fn parse_pure_rename() {
+        let diff = r#"diff --git a/old.nix b/new.nix
+similarity index 100%
+rename from old.nix
+rename to new.nix
+"#;
+        let file_diff = FileDiff::parse(diff).unwrap();
+        assert_eq!(file_diff.path, "new.nix");
+        assert_eq!(file_diff.renamed_from.as_deref(), Some("old.nix"));
+        assert!(file_diff.hunks.is_empty());
+        assert_eq!(
+            file_diff.to_string(),
+            "diff --git a/old.nix b/new.nix\nrename from old.nix\nrename to new.nix\n"
+        );
+    }
+
+    #[test]
+    fn parse_rename_with_edits() {
+        let diff = r#"diff --git a/old.nix b/new.nix
+similarity index 86%
+rename from old.nix
+rename to new.nix
+index 1111111..2222222 100644
+--- a/old.nix
++++ b/new.nix
+@@ -2,0 +3 @@ line 2
++new A
+"#;
+        let file_diff = FileDiff::parse(diff).unwrap();
+        assert_eq!(file_diff.path, "new.nix");
+        assert_eq!(file_diff.renamed_from.as_deref(), Some("old.nix"));
+        assert_eq!(file_diff.hunks.len(), 1);
+        assert!(
+            file_diff
+                .to_string()
+                .contains("rename from old.nix\nrename to new.nix\n"),
+            "{file_diff}"
+        );
+    }
+
+    #[test]
+    fn parse_copy_headers() {
+        let diff = r#"diff --git a/a.txt b/b.txt
+similarity index 85%
+copy from a.txt
+copy to b.txt
+index c4352f8..e16278d 100644
+--- a/a.txt
++++ b/b.txt
+@@ -5 +5 @@ line 4
+-line 5
++line five
+"#;
+        let file_diff = FileDiff::parse(diff).unwrap();
+        assert_eq!(file_diff.path, "b.txt");
+        assert_eq!(file_diff.copied_from.as_deref(), Some("a.txt"));
+
+        assert_eq!(
+            file_diff.to_string(),
+            "diff --git a/a.txt b/b.txt\ncopy from a.txt\ncopy to b.txt\n--- a/a.txt\n+++ b/b.txt\n@@ -5 +5 @@\n-line 5\n+line five\n"
+        );
+        // The plain patch drops the index header, and with it the old blob
+        let reparsed = FileDiff::parse(&file_diff.to_string()).unwrap();
+        assert_eq!(reparsed.old_blob, None);
+        assert_eq!(
+            reparsed,
+            FileDiff {
+                old_blob: None,
+                mode: None,
+                change: FileChange::Modified,
+                ..file_diff
+            }
+        );
+    }
+
+    #[test]
+    fn parse_keeps_differing_old_name() {
+        // git diff --no-index of two differently named files
+        let diff = r#"diff --git a/tmp/old.txt b/tmp/new.txt
+index 1111111..2222222 100644
+--- a/tmp/old.txt
++++ b/tmp/new.txt
+@@ -2 +2 @@ line 1
+-two
++TWO
+"#;
+        let file_diff = FileDiff::parse(diff).unwrap();
+        assert_eq!(file_diff.path, "tmp/new.txt");
+        assert_eq!(file_diff.old_name.as_deref(), Some("tmp/old.txt"));
+        assert_eq!(file_diff.renamed_from, None);
+        assert_eq!(
+            file_diff.to_string(),
+            "diff --git a/tmp/old.txt b/tmp/new.txt\n--- a/tmp/old.txt\n+++ b/tmp/new.txt\n@@ -2 +2 @@\n-two\n+TWO\n"
+        );
+    }
+
+    #[test]
+    fn plain_patch_regenerates_context() {
+        let diff = r#"diff --git a/f.txt b/f.txt
+index 1111111..2222222 100644
+--- a/f.txt
++++ b/f.txt
+@@ -2 +2 @@
+-line 2
++line two
+@@ -4,0 +5 @@
++line 4b
+@@ -11 +12 @@
+-line 11
++line eleven
+"#;
+        let old: String = (1..=12).map(|n| format!("line {}\n", n)).collect();
+        let file_diff = FileDiff::parse(diff).unwrap();
+
+        // The first two changes share context and merge; the third stands alone
+        assert_eq!(
+            file_diff.to_plain_patch(&old, 1).unwrap(),
+            "--- a/f.txt\n+++ b/f.txt\n\
+             @@ -1,5 +1,6 @@\n line 1\n-line 2\n+line two\n line 3\n line 4\n+line 4b\n line 5\n\
+             @@ -10,3 +11,3 @@\n line 10\n-line 11\n+line eleven\n line 12\n"
+        );
+
+        let stale = old.replace("line 11\n", "line 11 edited\n");
+        assert_eq!(file_diff.to_plain_patch(&stale, 1), None);
+    }
+
+    #[test]
+    fn plain_patch_names_missing_side_dev_null() {
+        let added = "diff --git a/n.txt b/n.txt\nnew file mode 100644\n--- /dev/null\n+++ b/n.txt\n\
+                     @@ -0,0 +1,2 @@\n+a\n+b\n";
+        assert_eq!(
+            FileDiff::parse(added)
+                .unwrap()
+                .to_plain_patch("", 3)
+                .unwrap(),
+            "--- /dev/null\n+++ b/n.txt\n@@ -0,0 +1,2 @@\n+a\n+b\n"
+        );
+
+        let deleted = "diff --git a/d.txt b/d.txt\ndeleted file mode 100644\n--- a/d.txt\n+++ /dev/null\n\
+                       @@ -1,2 +0,0 @@\n-a\n-b\n";
+        let file_diff = FileDiff::parse(deleted).unwrap();
+        assert_eq!(
+            file_diff.to_plain_patch("a\nb\n", 3).unwrap(),
+            "--- a/d.txt\n+++ /dev/null\n@@ -1,2 +0,0 @@\n-a\n-b\n"
+        );
+
+        // Deleting only some lines leaves the file in place
+        let mut partial = file_diff.clone();
+        partial.hunks[0].old.lines.pop();
+        assert!(
+            partial
+                .to_plain_patch("a\nb\n", 3)
+                .unwrap()
+                .starts_with("--- a/d.txt\n+++ b/d.txt\n")
+        );
+    }
+
+    #[test]
+    fn numbered_with_context_numbers_context_by_new_line() {
+        let diff = r#"diff --git a/f.txt b/f.txt
+--- a/f.txt
++++ b/f.txt
+@@ -2 +2 @@
+-line 2
++line two
+@@ -4,0 +5 @@
++line 4b
+@@ -11 +12 @@
+-line 11
++line eleven
+"#;
+        let old: String = (1..=12).map(|n| format!("line {}\n", n)).collect();
+        let file_diff = FileDiff::parse(diff).unwrap();
+
+        assert_eq!(
+            file_diff
+                .to_numbered_with_context(&old, 1, DisplayOptions::default())
+                .unwrap(),
+            "f.txt:\n   1: line 1\n  -2: line 2\n  +2: line two\n   3: line 3\n   4: line 4\n  \
+             +5: line 4b\n   6: line 5\n\n   11: line 10\n  -11: line 11\n  +12: line eleven\n   \
+             13: line 12\n\n"
+        );
+    }
+
+    #[test]
+    fn plain_patch_context_reaches_unterminated_last_line() {
+        let diff = "diff --git a/f.txt b/f.txt\n--- a/f.txt\n+++ b/f.txt\n@@ -1 +1 @@\n-a\n+A\n";
+        let file_diff = FileDiff::parse(diff).unwrap();
+        assert_eq!(
+            file_diff.to_plain_patch("a\nb\nc", 3).unwrap(),
+            "--- a/f.txt\n+++ b/f.txt\n@@ -1,3 +1,3 @@\n-a\n+A\n b\n c\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn change_read_from_file_headers() {
+        let change = |header: &str| {
+            let diff =
+                format!("diff --git a/f b/f\n{header}--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+b\n");
+            FileDiff::parse(&diff).unwrap().change
+        };
+        assert_eq!(change("new file mode 100644\n"), FileChange::Added);
+        assert_eq!(change("deleted file mode 100644\n"), FileChange::Deleted);
+        assert_eq!(
+            change("index 1111111..2222222 100644\n"),
+            FileChange::Modified
+        );
+        assert_eq!(
+            change("old mode 100644\nnew mode 100755\n"),
+            FileChange::Modified
+        );
+    }
+
+    #[test]
+    fn path_ends_at_header_tab_or_cr() {
+        let path = |header: &str| {
+            let diff = format!("diff --git a/f b/f\n{header}@@ -1 +1 @@\n-a\n+b\n");
+            FileDiff::parse(&diff).unwrap().path
+        };
+        assert_eq!(path("--- a/f.nix\t\n+++ b/f.nix\t\n"), "f.nix");
+        assert_eq!(
+            path("--- a/f.nix\t2024-01-01 00:00:00\n+++ b/f.nix\t2024-01-01 00:00:00\n"),
+            "f.nix"
+        );
+        assert_eq!(path("--- a/f.nix\r\n+++ b/f.nix\r\n"), "f.nix");
+        // Git's tab marks where a path with spaces ends
+        assert_eq!(path("--- a/my file \t\n+++ b/my file \t\n"), "my file ");
+        assert_eq!(path("--- a/gone.nix\t\n+++ /dev/null\n"), "gone.nix");
+    }
+
+    #[test]
+    fn symlink_mode_kept_in_patch() {
+        let diff = r#"diff --git a/link b/link
+index 3defea2..f63c08c 120000
+--- a/link
++++ b/link
+@@ -1 +1 @@
+-old-target
+\ No newline at end of file
++new-target
+\ No newline at end of file
+"#;
+        let file_diff = FileDiff::parse(diff).unwrap();
+        assert_eq!(file_diff.mode.as_deref(), Some("120000"));
+
+        let mut patch = String::new();
+        file_diff.write_patch(&mut patch, true).unwrap();
+        assert!(
+            patch.contains("\nindex 3defea2..0000000 120000\n"),
+            "{}",
+            patch
+        );
+        assert_eq!(FileDiff::parse(&patch), Some(file_diff));
+
+        let new_link = "diff --git a/link b/link\nnew file mode 120000\nindex 0000000..c7e58fc\n--- /dev/null\n+++ b/link\n@@ -0,0 +1 @@\n+tgt\n\\ No newline at end of file\n";
+        assert_eq!(
+            FileDiff::parse(new_link).unwrap().mode.as_deref(),
+            Some("120000")
+        );
+    }
+
+    #[test]
+    fn old_blob_kept_for_three_way_patch() {
+        let diff = r#"diff --git a/a.txt b/a.txt
+index c4352f8..e16278d 100644
+--- a/a.txt
++++ b/a.txt
+@@ -5,0 +6 @@ line 5
++line six
+"#;
+        let file_diff = FileDiff::parse(diff).unwrap();
+        assert_eq!(file_diff.old_blob.as_deref(), Some("c4352f8"));
+
+        let mut patch = String::new();
+        file_diff.write_patch(&mut patch, true).unwrap();
+        assert_eq!(
+            patch,
+            "diff --git a/a.txt b/a.txt\nindex c4352f8..0000000 100644\n--- a/a.txt\n+++ b/a.txt\n@@ -5,0 +6 @@\n+line six\n"
+        );
+        assert_eq!(FileDiff::parse(&patch), Some(file_diff));
+
+        let added = FileDiff::parse(&diff.replace("c4352f8", "0000000")).unwrap();
+        assert_eq!(added.old_blob, None);
+    }
+
     #[test]
     fn render_single_hunk() {
         let file_diff = FileDiff {
@@ -186,6 +1018,13 @@ index fa2da6e..41114ff 100644
                     missing_final_newline: false,
                 },
             }],
+            copied_from: None,
+            renamed_from: None,
+            old_name: None,
+            old_blob: None,
+            binary: false,
+            mode: None,
+            change: FileChange::Modified,
         };
 
         assert_eq!(
@@ -224,6 +1063,13 @@ index fa2da6e..41114ff 100644
                     },
                 },
             ],
+            copied_from: None,
+            renamed_from: None,
+            old_name: None,
+            old_blob: None,
+            binary: false,
+            mode: None,
+            change: FileChange::Modified,
         };
 
         assert_eq!(
@@ -248,6 +1094,13 @@ index fa2da6e..41114ff 100644
                     missing_final_newline: false,
                 },
             }],
+            copied_from: None,
+            renamed_from: None,
+            old_name: None,
+            old_blob: None,
+            binary: false,
+            mode: None,
+            change: FileChange::Modified,
         };
 
         let rendered = file_diff.to_string();
@@ -290,6 +1143,13 @@ index fa2da6e..41114ff 100644
                     },
                 },
             ],
+            copied_from: None,
+            renamed_from: None,
+            old_name: None,
+            old_blob: None,
+            binary: false,
+            mode: None,
+            change: FileChange::Modified,
         };
 
         let rendered = file_diff.to_string();
@@ -333,6 +1193,13 @@ index fa2da6e..41114ff 100644
                     },
                 },
             ],
+            copied_from: None,
+            renamed_from: None,
+            old_name: None,
+            old_blob: None,
+            binary: false,
+            mode: None,
+            change: FileChange::Modified,
         };
 
         let filtered = file_diff.filter(|_| false, |n| n == 10).unwrap();
@@ -351,6 +1218,66 @@ index fa2da6e..41114ff 100644
         );
     }
 
+    #[test]
+    fn coalesce_adjacent_deletions() {
+        let text = "diff --git a/list.txt b/list.txt
+--- a/list.txt
++++ b/list.txt
+@@ -5 +4,0 @@
+-five
+@@ -6 +4,0 @@
+-six
+@@ -9,0 +8 @@
++nine
+";
+        let mut file = FileDiff::parse(text).unwrap();
+        let mut sources = vec![0, 1, 2];
+        file.coalesce_hunks_with_sources(&mut sources);
+
+        assert_eq!(file.hunks.len(), 2);
+        assert_eq!(sources, vec![0, 2]);
+        assert_eq!(
+            file.to_string(),
+            "diff --git a/list.txt b/list.txt
+--- a/list.txt
++++ b/list.txt
+@@ -5,2 +4,0 @@
+-five
+-six
+@@ -9,0 +8 @@
++nine
+"
+        );
+    }
+
+    #[test]
+    fn coalesce_keeps_lines_after_missing_newline_apart() {
+        let text = "diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -2,0 +3 @@
++end
+\\ No newline at end of file
+@@ -3 +4 @@
+-three
++THREE
+";
+        let mut file = FileDiff::parse(text).unwrap();
+        let before = file.clone();
+        file.coalesce_hunks();
+        assert_eq!(file, before);
+
+        // Without the marker the addition and replacement merge
+        let mut file =
+            FileDiff::parse(&text.replace("\\ No newline at end of file\n", "")).unwrap();
+        file.coalesce_hunks();
+        assert_eq!(file.hunks.len(), 1);
+        assert_eq!(
+            file.hunks[0].to_string(),
+            "@@ -3 +3,2 @@\n-three\n+end\n+THREE\n"
+        );
+    }
+
     #[test]
     fn filter_from_multiple_hunks_adjusts_line_numbers() {
         // When filtering lines from multiple hunks, later hunks' new_start positions
@@ -392,6 +1319,13 @@ index fa2da6e..41114ff 100644
                     },
                 },
             ],
+            copied_from: None,
+            renamed_from: None,
+            old_name: None,
+            old_blob: None,
+            binary: false,
+            mode: None,
+            change: FileChange::Modified,
         };
 
         let filtered = file_diff.filter(|_| false, |n| n == 4 || n == 10).unwrap();
@@ -426,6 +1360,13 @@ index fa2da6e..41114ff 100644
                     },
                 },
             ],
+            copied_from: None,
+            renamed_from: None,
+            old_name: None,
+            old_blob: None,
+            binary: false,
+            mode: None,
+            change: FileChange::Modified,
         };
 
         assert_eq!(filtered, expected);
@@ -447,6 +1388,13 @@ index fa2da6e..41114ff 100644
                     missing_final_newline: false,
                 },
             }],
+            copied_from: None,
+            renamed_from: None,
+            old_name: None,
+            old_blob: None,
+            binary: false,
+            mode: None,
+            change: FileChange::Modified,
         };
 
         let filtered = file_diff.filter(|_| false, |_| false);
@@ -480,6 +1428,7 @@ index 79e51de..88ee0b1 100644
 }
 
 #[cfg(test)]
+#[allow(clippy::unwrap_used)]
 mod proptests {
     use super::*;
     use crate::diff::hunk::ModifiedLines;
@@ -521,6 +1470,13 @@ mod proptests {
             .prop_map(|(h1, h2, h3)| FileDiff {
                 path: "test.txt".to_string(),
                 hunks: vec![h1, h2, h3],
+                copied_from: None,
+                renamed_from: None,
+                old_name: None,
+                old_blob: None,
+                binary: false,
+                mode: None,
+                change: FileChange::Modified,
             })
     }
 
@@ -577,6 +1533,13 @@ mod proptests {
             .prop_map(|(h1, h2, h3)| FileDiff {
                 path: "mixed.txt".to_string(),
                 hunks: vec![h1, h2, h3],
+                copied_from: None,
+                renamed_from: None,
+                old_name: None,
+                old_blob: None,
+                binary: false,
+                mode: None,
+                change: FileChange::Modified,
             })
     }
 
@@ -617,6 +1580,11 @@ mod proptests {
                     "Failed to parse filtered FileDiff:\n{}\nOriginal: {}",
                     rendered, original_debug
                 );
+                prop_assert!(
+                    crate::diff::newline_markers_consistent(&rendered),
+                    "Misplaced no-newline marker:\n{}",
+                    rendered
+                );
             }
         }
 
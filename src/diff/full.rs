@@ -1,9 +1,60 @@
-use super::file::FileDiff;
+use super::file::{FileDiff, hunk_sections};
+use super::hunk::Hunk;
+use super::hunk::ModifiedLines;
+use super::hunk::group_contiguous_lines;
+use error_set::error_set;
+use std::borrow::Cow;
+
+/// How [`Diff::display_with`] renders line content in the numbered format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DisplayOptions {
+    /// Draw leading tabs as `→   ` (see [`Diff::display_show_tabs`])
+    pub show_tabs: bool,
+    /// Draw control characters as caret (`^[`, `^@`) or `\u{..}` escapes
+    pub escape_control: bool,
+    /// List each file's hunks in sections by kind: pure additions, pure
+    /// deletions, then replacements (see [`Diff::display_with`])
+    pub group_by_kind: bool,
+    /// Show at most this many hunks per file, then how many were left out
+    pub limit_hunks: Option<usize>,
+    /// Show at most this many files, then how many were left out
+    pub limit_files: Option<usize>,
+    /// Mark where each hunk starts and ends in the gutter: `┌` on its first
+    /// line, `│` on inner lines, `└` on its last and `─` on a one-line hunk
+    pub annotate_boundaries: bool,
+    /// Color deletion lines red and addition lines green with ANSI escapes
+    pub color: bool,
+}
+
+error_set! {
+    /// Errors from strictly parsing diff text with [`Diff::try_parse`]
+    DiffParseError := {
+        /// A hunk has no changed lines (e.g. `@@ -0,0 +0,0 @@`) or a malformed header
+        #[display("Hunk '{header}' in {file} changes no lines or is malformed")]
+        InvalidHunk { file: String, header: String },
+    }
+}
+
+/// A part of diff text that [`Diff::parse_checked`] could not use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseWarning {
+    /// The `diff --git` line of the file section it was found in
+    pub header: String,
+    /// Why the section, or a hunk of it, was skipped
+    pub reason: String,
+}
+
+impl std::fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "skipped '{}': {}", self.header, self.reason)
+    }
+}
 
 /// A complete git diff containing changes for multiple files.
 ///
 /// This is the top-level structure representing the full output of `git diff`.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Diff {
     /// All file diffs in this git diff
     pub files: Vec<FileDiff>,
@@ -15,29 +66,90 @@ impl Diff {
     /// Splits the input by `diff --git` markers and parses each section
     /// as a [`FileDiff`].
     ///
-    /// Files that fail to parse are silently skipped.
+    /// Files that fail to parse are silently skipped; see
+    /// [`parse_checked`](Self::parse_checked) to learn which.
     #[must_use]
     pub fn parse(text: &str) -> Self {
-        let marker = "diff --git ";
-
-        // Find all marker positions
-        let indices: Vec<usize> = text.match_indices(marker).map(|(i, _)| i).collect();
+        Self::parse_checked(text).0
+    }
 
-        if indices.is_empty() {
-            return Diff { files: Vec::new() };
+    /// Parse diff text like [`parse`](Self::parse), also listing what it skipped
+    ///
+    /// Each file section that yields no file, such as a mode-only change,
+    /// and each hunk that does not parse gets a [`ParseWarning`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use git_lines::diff::Diff;
+    ///
+    /// let text = "diff --git a/run.sh b/run.sh\nold mode 100644\nnew mode 100755\n";
+    /// let (diff, warnings) = Diff::parse_checked(text);
+    /// assert!(diff.files.is_empty());
+    /// assert_eq!(warnings[0].header, "diff --git a/run.sh b/run.sh");
+    /// ```
+    #[must_use]
+    pub fn parse_checked(text: &str) -> (Self, Vec<ParseWarning>) {
+        let mut files = Vec::new();
+        let mut warnings = Vec::new();
+        for section in file_sections(text) {
+            let warn = |reason: String| ParseWarning {
+                header: section.lines().next().unwrap_or_default().to_string(),
+                reason,
+            };
+            let hunks = hunk_sections(section);
+            let Some(file) = FileDiff::parse(section) else {
+                warnings.push(warn(if hunks.is_empty() {
+                    "no changed lines, e.g. only a mode change".to_string()
+                } else {
+                    "no file path in its headers".to_string()
+                }));
+                continue;
+            };
+            warnings.extend(
+                hunks
+                    .iter()
+                    .filter(|hunk| Hunk::parse(hunk).is_none())
+                    .map(|hunk| {
+                        warn(format!(
+                            "hunk '{}' changes no lines or is malformed",
+                            hunk.lines().next().unwrap_or_default()
+                        ))
+                    }),
+            );
+            files.push(file);
         }
 
-        // Parse each section between markers
-        let files = indices
-            .iter()
-            .enumerate()
-            .filter_map(|(i, &start)| {
-                let end = indices.get(i + 1).copied().unwrap_or(text.len());
-                FileDiff::parse(&text[start..end])
-            })
-            .collect();
+        (Diff { files }, warnings)
+    }
 
-        Diff { files }
+    /// Parse diff text like [`parse`](Self::parse), but fail on hunks it
+    /// would silently drop.
+    ///
+    /// Meant for diffs from outside `git diff`, where a degenerate hunk (both
+    /// sides empty) would otherwise vanish and leave an empty patch that
+    /// applies without complaint.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DiffParseError::InvalidHunk`] naming the first hunk that
+    /// changes no lines or whose header does not parse.
+    pub fn try_parse(text: &str) -> Result<Self, DiffParseError> {
+        let mut files = Vec::new();
+        for section in file_sections(text) {
+            let Some(file) = FileDiff::parse(section) else {
+                continue;
+            };
+            let hunks = hunk_sections(section);
+            if let Some(bad) = hunks.iter().find(|hunk| Hunk::parse(hunk).is_none()) {
+                return Err(DiffParseError::InvalidHunk {
+                    file: file.path,
+                    header: bad.lines().next().unwrap_or_default().to_string(),
+                });
+            }
+            files.push(file);
+        }
+        Ok(Diff { files })
     }
 
     /// Filter lines across all files, returning a new Diff with only matching lines.
@@ -73,23 +185,280 @@ impl Diff {
     /// );
     /// ```
     #[must_use]
-    pub fn filter<F, G>(self, mut keep_old: F, mut keep_new: G) -> Self
+    pub fn filter<F, G>(self, keep_old: F, keep_new: G) -> Self
+    where
+        F: FnMut(&str, u32) -> bool,
+        G: FnMut(&str, u32) -> bool,
+    {
+        self.filter_with_sources(keep_old, keep_new).0
+    }
+
+    /// Like [`filter`](Self::filter), also reporting where each output hunk came from.
+    ///
+    /// The second element has one entry per output file, as described by
+    /// [`FileDiff::filter_with_sources`].
+    #[must_use]
+    pub fn filter_with_sources<F, G>(
+        self,
+        mut keep_old: F,
+        mut keep_new: G,
+    ) -> (Self, Vec<Vec<usize>>)
     where
         F: FnMut(&str, u32) -> bool,
         G: FnMut(&str, u32) -> bool,
     {
-        let filtered_files: Vec<FileDiff> = self
+        let (files, sources) = self
             .files
             .into_iter()
             .filter_map(|file_diff| {
                 let path = file_diff.path.clone();
-                file_diff.filter(|old| keep_old(&path, old), |new| keep_new(&path, new))
+                file_diff
+                    .filter_with_sources(|old| keep_old(&path, old), |new| keep_new(&path, new))
+            })
+            .unzip();
+
+        (Diff { files }, sources)
+    }
+
+    /// Filter the lines of the file at `path`, returning just that file.
+    ///
+    /// Like [`filter`](Self::filter) scoped to one file, so the predicates take
+    /// only line numbers. Returns `None` if the diff has no such file or none
+    /// of its lines match.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use git_lines::diff::Diff;
+    ///
+    /// let diff = Diff::parse("...git diff output...");
+    ///
+    /// // Line 137 of flake.nix, if it was added
+    /// let flake = diff.retain_file("flake.nix", |_| false, |line| line == 137);
+    /// ```
+    #[must_use]
+    pub fn retain_file<F, G>(&self, path: &str, keep_old: F, keep_new: G) -> Option<FileDiff>
+    where
+        F: FnMut(u32) -> bool,
+        G: FnMut(u32) -> bool,
+    {
+        self.files
+            .iter()
+            .find(|file| file.path == path)?
+            .clone()
+            .filter(keep_old, keep_new)
+    }
+
+    /// Number of files with at least one hunk.
+    #[must_use]
+    pub fn files_changed_count(&self) -> usize {
+        self.files.iter().filter(|f| !f.hunks.is_empty()).count()
+    }
+
+    /// Total added lines across all files.
+    #[must_use]
+    pub fn total_additions(&self) -> usize {
+        self.hunks().map(|h| h.new.len()).sum()
+    }
+
+    /// Total deleted lines across all files.
+    #[must_use]
+    pub fn total_deletions(&self) -> usize {
+        self.hunks().map(|h| h.old.len()).sum()
+    }
+
+    /// All hunks across all files, in order
+    fn hunks(&self) -> impl Iterator<Item = &Hunk> {
+        self.files.iter().flat_map(|f| &f.hunks)
+    }
+
+    /// Order files by path.
+    ///
+    /// Git emits files in an order that depends on config such as
+    /// `diff.orderFile`; sorting makes rendered output stable across environments.
+    pub fn sort_files(&mut self) {
+        self.files.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+
+    /// Render a numbered menu of hunks for scripted selection.
+    ///
+    /// Each hunk becomes one tab-separated line: a 1-based index across all
+    /// files, the `file:refs` token that stages the whole hunk, and the first
+    /// changed line with its `+`/`-` marker. A rename without content
+    /// changes gets its own entry with the `rename` selector.
+    ///
+    /// ```text
+    /// 1    config.nix:-10,10..11    -    old_setting = true;
+    /// 2    config.nix:20    +    # SECOND
+    /// 3    new.nix:rename    old.nix → new.nix
+    /// ```
+    #[must_use]
+    pub fn plan(&self) -> String {
+        let entries = self.files.iter().flat_map(|file| {
+            let rename = file
+                .renamed_from
+                .as_ref()
+                .filter(|_| file.hunks.is_empty())
+                .map(|from| {
+                    let token = format!("{}:rename", file.path);
+                    (token, format!("{} → {}", from, file.path))
+                });
+            let hunks = file.hunks.iter().map(|hunk| {
+                let first_line = match (hunk.old.lines.first(), hunk.new.lines.first()) {
+                    (Some(line), _) => format!("-{line}"),
+                    (None, Some(line)) => format!("+{line}"),
+                    (None, None) => String::new(),
+                };
+                (format!("{}:{}", file.path, hunk.selector()), first_line)
+            });
+            rename.into_iter().chain(hunks)
+        });
+
+        entries
+            .enumerate()
+            .map(|(i, (token, description))| format!("{}\t{}\t{}\n", i + 1, token, description))
+            .collect()
+    }
+
+    /// Keep only the changed lines whose content passes `keep`, for display.
+    ///
+    /// Line numbers stay as in this diff, so the result's numbered display,
+    /// [`map`](Self::map) and [`tokens`](Self::tokens) name lines that can be
+    /// staged from the full diff. Each hunk is cut into runs of kept lines,
+    /// deletions before additions, so the result is no longer an applicable
+    /// patch. Files left without lines are dropped.
+    #[must_use]
+    pub fn grep(&self, mut keep: impl FnMut(&str) -> bool) -> Diff {
+        self.retain_lines(|_, _, _, line| keep(line))
+    }
+
+    /// Keep only the changed lines `keep` accepts, for display.
+    ///
+    /// `keep` is given each line's file path, whether it is a deletion, its
+    /// line number and its content. As with [`grep`](Self::grep), line
+    /// numbers stay as in this diff and the result is no longer an
+    /// applicable patch.
+    #[must_use]
+    pub fn retain_lines(&self, mut keep: impl FnMut(&str, bool, u32, &str) -> bool) -> Diff {
+        let side = |lines: &[(u32, &str)]| ModifiedLines {
+            start: lines.first().map_or(0, |(n, _)| *n),
+            lines: lines.iter().map(|(_, line)| (*line).to_owned()).collect(),
+            missing_final_newline: false,
+        };
+        let empty = |start: u32| ModifiedLines {
+            start,
+            lines: Vec::new(),
+            missing_final_newline: false,
+        };
+
+        let files = self
+            .files
+            .iter()
+            .filter_map(|file| {
+                let mut hunks = Vec::new();
+                for hunk in &file.hunks {
+                    for (lines, deleted) in [(&hunk.old, true), (&hunk.new, false)] {
+                        let kept: Vec<(u32, &str)> = lines
+                            .lines
+                            .iter()
+                            .enumerate()
+                            .map(|(i, line)| (lines.start + i as u32, line.as_str()))
+                            .filter(|&(n, line)| keep(&file.path, deleted, n, line))
+                            .collect();
+                        hunks.extend(group_contiguous_lines(&kept).into_iter().map(|run| {
+                            if deleted {
+                                Hunk {
+                                    old: side(run.lines),
+                                    new: empty(hunk.new.start),
+                                }
+                            } else {
+                                Hunk {
+                                    old: empty(hunk.old.start),
+                                    new: side(run.lines),
+                                }
+                            }
+                        }));
+                    }
+                }
+                (!hunks.is_empty()).then(|| FileDiff {
+                    path: file.path.clone(),
+                    hunks,
+                    copied_from: file.copied_from.clone(),
+                    renamed_from: file.renamed_from.clone(),
+                    old_name: file.old_name.clone(),
+                    old_blob: file.old_blob.clone(),
+                    binary: file.binary,
+                    mode: file.mode.clone(),
+                    change: file.change,
+                })
             })
             .collect();
+        Diff { files }
+    }
 
-        Diff {
-            files: filtered_files,
-        }
+    /// Render one `file:refs` stage token per file, selecting all its changes.
+    ///
+    /// Pure renames get the `rename` selector; binary files are left out.
+    /// Feed the tokens to `stage`, e.g. through `xargs`:
+    ///
+    /// ```text
+    /// flake.nix:-15,137,142..144
+    /// ```
+    #[must_use]
+    pub fn tokens(&self) -> String {
+        self.files
+            .iter()
+            .filter_map(|file| {
+                if file.hunks.is_empty() {
+                    return file
+                        .renamed_from
+                        .as_ref()
+                        .map(|_| format!("{}:rename\n", file.path));
+                }
+                let refs: Vec<String> = file.hunks.iter().map(Hunk::selector).collect();
+                Some(format!("{}:{}\n", file.path, refs.join(",")))
+            })
+            .collect()
+    }
+
+    /// Render a compact map of changed line ranges, one line per file.
+    ///
+    /// Ranges are listed per hunk in order, deletions before additions, with
+    /// deletion ranges written as they are selected (`-N..-M`):
+    ///
+    /// ```text
+    /// flake.nix: -15..-16, +137, +142..144
+    /// ```
+    #[must_use]
+    pub fn map(&self) -> String {
+        let range = |sign: &str, end_sign: &str, side: &ModifiedLines| match side.len() {
+            0 => None,
+            1 => Some(format!("{sign}{}", side.start)),
+            n => Some(format!(
+                "{sign}{}..{end_sign}{}",
+                side.start,
+                side.start + n as u32 - 1
+            )),
+        };
+
+        self.files
+            .iter()
+            .map(|file| {
+                if file.binary {
+                    return format!("{}: binary\n", file.path);
+                }
+                if let Some(from) = file.renamed_from.as_ref().filter(|_| file.hunks.is_empty()) {
+                    return format!("{}: rename from {}\n", file.path, from);
+                }
+                let ranges: Vec<String> = file
+                    .hunks
+                    .iter()
+                    .flat_map(|hunk| [range("-", "-", &hunk.old), range("+", "", &hunk.new)])
+                    .flatten()
+                    .collect();
+                format!("{}: {}\n", file.path, ranges.join(", "))
+            })
+            .collect()
     }
 
     /// Render the diff as a patch suitable for `git apply`.
@@ -97,15 +466,173 @@ impl Diff {
     /// This produces the standard unified diff format that git tooling expects.
     #[must_use]
     pub fn to_patch(&self) -> String {
-        use std::fmt::Write;
-        let mut result = String::new();
-        for file_diff in &self.files {
-            write!(result, "{}", file_diff).expect("writing to String never fails");
+        self.files.iter().map(FileDiff::to_string).collect()
+    }
+
+    /// Render the diff as a patch for `git apply --3way`.
+    ///
+    /// Like [`to_patch`](Self::to_patch), but each file keeps an `index`
+    /// header naming its [`old_blob`](FileDiff::old_blob), which git needs to
+    /// merge the hunks into a file whose lines have moved.
+    #[must_use]
+    pub fn to_three_way_patch(&self) -> String {
+        let mut patch = String::new();
+        for file in &self.files {
+            // Writing to a String cannot fail
+            let _ = file.write_patch(&mut patch, true);
+        }
+        patch
+    }
+
+    /// Render the diff as an HTML fragment for embedding in review pages.
+    ///
+    /// Each changed line is a `<span>` classed `add` or `del` whose
+    /// `data-token` holds the `file:refs` token that stages it. Content and
+    /// attributes are HTML-escaped; hunks are `<pre>` blocks so indentation
+    /// survives.
+    ///
+    /// ```text
+    /// <div class="git-lines-diff">
+    /// <section class="file" data-path="config.nix">
+    /// <h3>config.nix</h3>
+    /// <pre class="hunk"><span class="del" data-token="config.nix:-10">-10:    old</span>
+    /// <span class="add" data-token="config.nix:10">+10:    new</span>
+    /// </pre>
+    /// </section>
+    /// </div>
+    /// ```
+    #[must_use]
+    pub fn to_html(&self) -> String {
+        let mut out = String::from("<div class=\"git-lines-diff\">\n");
+        for file in &self.files {
+            let path = escape_html(&file.path);
+            out.push_str(&format!(
+                "<section class=\"file\" data-path=\"{path}\">\n<h3>{path}</h3>\n"
+            ));
+            for hunk in &file.hunks {
+                out.push_str("<pre class=\"hunk\">");
+                // (class, display marker, token sign, lines)
+                let sides = [("del", "-", "-", &hunk.old), ("add", "+", "", &hunk.new)];
+                for (class, marker, sign, side) in sides {
+                    for (i, line) in side.lines.iter().enumerate() {
+                        let line_num = side.start + i as u32;
+                        out.push_str(&format!(
+                            "<span class=\"{class}\" data-token=\"{path}:{sign}{line_num}\">{marker}{line_num}: {}</span>\n",
+                            escape_html(line)
+                        ));
+                    }
+                }
+                out.push_str("</pre>\n");
+            }
+            out.push_str("</section>\n");
+        }
+        out.push_str("</div>\n");
+        out
+    }
+
+    /// Render the diff as one line of JSON, for scripts.
+    ///
+    /// Each file lists its hunks; each hunk lists its changed lines,
+    /// deletions first, and whether either side lacks a final newline:
+    ///
+    /// ```text
+    /// {"files":[{"path":"config.nix","binary":false,"hunks":[{"lines":[
+    ///   {"kind":"delete","line":10,"content":"old"},
+    ///   {"kind":"add","line":10,"content":"new"}],
+    ///   "missing_final_newline":{"old":false,"new":false}}]}]}
+    /// ```
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"files\":[");
+        for (f, file) in self.files.iter().enumerate() {
+            if f > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"path\":");
+            out.push_str(&json_string(&file.path));
+            out.push_str(&format!(",\"binary\":{},\"hunks\":[", file.binary));
+            for (h, hunk) in file.hunks.iter().enumerate() {
+                if h > 0 {
+                    out.push(',');
+                }
+                out.push_str("{\"lines\":[");
+                let lines = [("delete", &hunk.old), ("add", &hunk.new)]
+                    .into_iter()
+                    .flat_map(|(kind, side)| {
+                        side.lines
+                            .iter()
+                            .enumerate()
+                            .map(move |(i, line)| (kind, side.start + i as u32, line))
+                    });
+                for (l, (kind, line, content)) in lines.enumerate() {
+                    if l > 0 {
+                        out.push(',');
+                    }
+                    out.push_str(&format!(
+                        "{{\"kind\":\"{}\",\"line\":{},\"content\":",
+                        kind, line
+                    ));
+                    out.push_str(&json_string(content));
+                    out.push('}');
+                }
+                out.push_str(&format!(
+                    "],\"missing_final_newline\":{{\"old\":{},\"new\":{}}}}}",
+                    hunk.old.missing_final_newline, hunk.new.missing_final_newline
+                ));
+            }
+            out.push_str("]}");
         }
-        result
+        out.push_str("]}\n");
+        out
     }
 }
 
+/// `text` as a quoted JSON string, escaped as [`Diff::to_json`] escapes
+/// line content
+///
+/// # Examples
+/// ```
+/// # use git_lines::diff::json_string;
+/// assert_eq!(json_string("say \"hi\"\n"), r#""say \"hi\"\n""#);
+/// ```
+#[must_use]
+pub fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if u32::from(c) < 0x20 => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Escape text for use in HTML content and double-quoted attributes
+fn escape_html(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
 impl std::fmt::Display for Diff {
     /// Formats the diff for human display with explicit line numbers.
     ///
@@ -113,41 +640,275 @@ impl std::fmt::Display for Diff {
     ///
     /// ```text
     /// file.nix:
-    ///   -10:    deleted line
-    ///   +10:    added line
-    ///   +11:    another addition
+    ///   -10: deleted line
+    ///   +10: added line
+    ///   +11: another addition
     /// ```
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write_numbered(f, DisplayOptions::default())
+    }
+}
+
+impl Diff {
+    /// Numbered display as for [`Display`](std::fmt::Display), with leading tabs
+    /// in line content drawn as `→   `.
+    ///
+    /// Content always follows the single space after `+N:` verbatim, but the
+    /// leading tabs of a tab-indented line (such as a Makefile recipe) are
+    /// easy to misread on screen.
+    ///
+    /// ```text
+    /// Makefile:
+    ///   +2: →   $(CC) -o app main.c
+    /// ```
+    #[must_use]
+    pub fn display_show_tabs(&self) -> String {
+        self.display_with(DisplayOptions {
+            show_tabs: true,
+            ..DisplayOptions::default()
+        })
+    }
+
+    /// Numbered display as for [`Display`](std::fmt::Display), rendered with
+    /// `options`.
+    ///
+    /// With `escape_control`, C0 control characters other than tab are drawn
+    /// in caret notation (ESC as `^[`, NUL as `^@`, DEL as `^?`) and C1
+    /// controls as `\u{..}`, so line content can't move the cursor or recolor
+    /// the terminal. Only the display changes; [`Diff::to_patch`] keeps the
+    /// original bytes.
+    ///
+    /// ```text
+    /// log.txt:
+    ///   +1:    ^[[31merror^[[0m
+    /// ```
+    ///
+    /// With `group_by_kind`, each file's hunks are listed under a heading per
+    /// kind, omitting kinds with no hunks; line numbers are unchanged:
+    ///
+    /// ```text
+    /// flake.nix:
+    ///  additions:
+    ///   +3:    pkgs.git
+    ///
+    ///  replacements:
+    ///   -9:    version = 1;
+    ///   +10:   version = 2;
+    /// ```
+    #[must_use]
+    pub fn display_with(&self, options: DisplayOptions) -> String {
+        let mut out = String::new();
+        // Writing to a String cannot fail
+        let _ = self.write_numbered(&mut out, options);
+        out
+    }
+
+    /// Write [`display_with`](Self::display_with)'s output to `out`, a hunk
+    /// at a time, without holding the whole display in memory
+    pub fn write_with(
+        &self,
+        out: &mut impl std::io::Write,
+        options: DisplayOptions,
+    ) -> std::io::Result<()> {
+        let mut adapter = IoAdapter { out, error: None };
+        match self.write_numbered(&mut adapter, options) {
+            Ok(()) => Ok(()),
+            Err(std::fmt::Error) => Err(adapter
+                .error
+                .unwrap_or_else(|| std::io::Error::other("formatting failed"))),
+        }
+    }
+
+    fn write_numbered(
+        &self,
+        f: &mut impl std::fmt::Write,
+        options: DisplayOptions,
+    ) -> std::fmt::Result {
         let mut first_file = true;
-        for file_diff in &self.files {
+        let limit_files = options.limit_files.unwrap_or(usize::MAX);
+        for file_diff in self.files.iter().take(limit_files) {
             if !first_file {
                 // Already have trailing newline from previous file
             }
             first_file = false;
 
+            if file_diff.binary {
+                writeln!(f, "{}: binary file, cannot stage by line\n", file_diff.path)?;
+                continue;
+            }
+            if let Some(from) = &file_diff.renamed_from {
+                writeln!(f, "{} → {} (rename)", from, file_diff.path)?;
+                if file_diff.hunks.is_empty() {
+                    writeln!(f)?;
+                    continue;
+                }
+            }
+
             writeln!(f, "{}:", file_diff.path)?;
 
-            for hunk in &file_diff.hunks {
-                // Show deletions
-                for (i, line) in hunk.old.lines.iter().enumerate() {
-                    let line_num = hunk.old.start + i as u32;
-                    writeln!(f, "  -{}:\t{}", line_num, line)?;
+            // Grouping reorders hunks; the limit applies in display order
+            let limit_hunks = options.limit_hunks.unwrap_or(usize::MAX);
+            if !options.group_by_kind {
+                for hunk in file_diff.hunks.iter().take(limit_hunks) {
+                    write_numbered_hunk(f, hunk, options)?;
                 }
-
-                // Show additions
-                for (i, line) in hunk.new.lines.iter().enumerate() {
-                    let line_num = hunk.new.start + i as u32;
-                    writeln!(f, "  +{}:\t{}", line_num, line)?;
+            } else {
+                let kinds = [
+                    ("additions", Hunk::is_pure_addition as fn(&Hunk) -> bool),
+                    ("deletions", Hunk::is_pure_deletion),
+                    ("replacements", Hunk::is_replacement),
+                ];
+                let mut shown = 0;
+                for (heading, is_kind) in kinds {
+                    let hunks: Vec<&Hunk> = file_diff
+                        .hunks
+                        .iter()
+                        .filter(|h| is_kind(h))
+                        .take(limit_hunks - shown)
+                        .collect();
+                    if !hunks.is_empty() {
+                        writeln!(f, " {}:", heading)?;
+                    }
+                    for hunk in &hunks {
+                        write_numbered_hunk(f, hunk, options)?;
+                    }
+                    shown += hunks.len();
                 }
-
-                writeln!(f)?;
             }
+            write_omitted(
+                f,
+                "  ",
+                file_diff.hunks.len().saturating_sub(limit_hunks),
+                "hunk",
+            )?;
         }
+        write_omitted(f, "", self.files.len().saturating_sub(limit_files), "file")?;
 
         Ok(())
     }
 }
 
+/// A `fmt::Write` over an `io::Write`, keeping the I/O error `fmt` drops
+struct IoAdapter<'a, W: std::io::Write> {
+    out: &'a mut W,
+    error: Option<std::io::Error>,
+}
+
+impl<W: std::io::Write> std::fmt::Write for IoAdapter<'_, W> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.out.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            std::fmt::Error
+        })
+    }
+}
+
+/// Text of each file in a diff, from its `diff --git ` line to the next
+fn file_sections(text: &str) -> Vec<&str> {
+    let indices: Vec<usize> = text.match_indices("diff --git ").map(|(i, _)| i).collect();
+    indices
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = indices.get(i + 1).copied().unwrap_or(text.len());
+            &text[start..end]
+        })
+        .collect()
+}
+
+/// Write a `... (K more hunks)` line and a blank line if `count` are left out
+fn write_omitted(
+    f: &mut impl std::fmt::Write,
+    indent: &str,
+    count: usize,
+    noun: &str,
+) -> std::fmt::Result {
+    match count {
+        0 => Ok(()),
+        1 => writeln!(f, "{}... (1 more {})\n", indent, noun),
+        n => writeln!(f, "{}... ({} more {}s)\n", indent, n, noun),
+    }
+}
+
+/// Write one hunk's numbered lines, deletions first, and a closing blank line
+fn write_numbered_hunk(
+    f: &mut impl std::fmt::Write,
+    hunk: &Hunk,
+    options: DisplayOptions,
+) -> std::fmt::Result {
+    write_numbered_lines(f, hunk, options)?;
+    writeln!(f)
+}
+
+/// Write one hunk's numbered lines, deletions first
+pub(super) fn write_numbered_lines(
+    f: &mut impl std::fmt::Write,
+    hunk: &Hunk,
+    options: DisplayOptions,
+) -> std::fmt::Result {
+    let last = (hunk.old.len() + hunk.new.len()).saturating_sub(1);
+    let gutter = |at: usize| match (options.annotate_boundaries, at) {
+        (false, _) => ' ',
+        (true, 0) if last == 0 => '─',
+        (true, 0) => '┌',
+        (true, at) if at == last => '└',
+        (true, _) => '│',
+    };
+    let (red, green, reset) = match options.color {
+        true => ("\x1b[31m", "\x1b[32m", "\x1b[0m"),
+        false => ("", "", ""),
+    };
+    for (i, line) in hunk.old.lines.iter().enumerate() {
+        let line_num = hunk.old.start + i as u32;
+        let content = display_content(line, options);
+        writeln!(f, "{red}{} -{}: {}{reset}", gutter(i), line_num, content)?;
+    }
+    for (i, line) in hunk.new.lines.iter().enumerate() {
+        let line_num = hunk.new.start + i as u32;
+        let content = display_content(line, options);
+        writeln!(
+            f,
+            "{green}{} +{}: {}{reset}",
+            gutter(hunk.old.len() + i),
+            line_num,
+            content
+        )?;
+    }
+    Ok(())
+}
+
+/// Line content for display, with each leading tab drawn as an arrow padded
+/// to four columns when `show_tabs` is set and control characters escaped
+/// when `escape_control` is set
+pub(super) fn display_content(line: &str, options: DisplayOptions) -> Cow<'_, str> {
+    let content = line.trim_start_matches('\t');
+    let line = match line.len() - content.len() {
+        tabs if options.show_tabs && tabs > 0 => Cow::Owned("→   ".repeat(tabs) + content),
+        _ => Cow::Borrowed(line),
+    };
+    if !options.escape_control || !line.chars().any(is_escaped_control) {
+        return line;
+    }
+    let mut escaped = String::with_capacity(line.len() + 8);
+    for c in line.chars() {
+        match c {
+            '\x7f' => escaped.push_str("^?"),
+            c if c < ' ' && c != '\t' => {
+                escaped.push('^');
+                escaped.push(char::from(c as u8 + b'@'));
+            }
+            c if is_escaped_control(c) => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Control characters that [`display_content`] escapes; tabs stay literal
+fn is_escaped_control(c: char) -> bool {
+    c.is_control() && c != '\t'
+}
+
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod tests {
@@ -175,6 +936,57 @@ index abc1234..def5678 100644
         assert_eq!(diff.files[0].hunks.len(), 1);
     }
 
+    #[test]
+    fn parse_checked_reports_skipped_sections() {
+        let text = "diff --git a/run.sh b/run.sh
+old mode 100644
+new mode 100755
+diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1,0 +2 @@
++two
+@@ -0,0 +0,0 @@
+";
+        let (diff, warnings) = Diff::parse_checked(text);
+        assert_eq!(diff, Diff::parse(text));
+        assert_eq!(diff.files.len(), 1);
+        let reasons: Vec<String> = warnings.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            reasons,
+            [
+                "skipped 'diff --git a/run.sh b/run.sh': no changed lines, e.g. only a mode change",
+                "skipped 'diff --git a/a.txt b/a.txt': hunk '@@ -0,0 +0,0 @@' changes no lines or is malformed",
+            ]
+        );
+    }
+
+    #[test]
+    fn try_parse_rejects_empty_hunk() {
+        let text = "diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1,0 +2 @@
++two
+diff --git a/b.txt b/b.txt
+--- a/b.txt
++++ b/b.txt
+@@ -0,0 +0,0 @@
+";
+        // The lenient parser drops the degenerate hunk, leaving b.txt empty
+        assert!(Diff::parse(text).files[1].hunks.is_empty());
+        let err = Diff::try_parse(text).unwrap_err();
+        assert!(
+            matches!(&err, DiffParseError::InvalidHunk { file, header }
+                if file == "b.txt" && header == "@@ -0,0 +0,0 @@"),
+            "{:?}",
+            err
+        );
+
+        let valid = text.split("diff --git a/b.txt").next().unwrap();
+        assert_eq!(Diff::try_parse(valid).unwrap(), Diff::parse(valid));
+    }
+
     #[test]
     fn parse_multiple_files() {
         let text = r#"diff --git a/flake.nix b/flake.nix
@@ -196,6 +1008,21 @@ index 111..222 100644
         assert_eq!(diff.files[1].path, "gtk.nix");
     }
 
+    #[test]
+    fn parsed_diffs_compare_equal() {
+        let text = r#"diff --git a/flake.nix b/flake.nix
+index abc1234..def5678 100644
+--- a/flake.nix
++++ b/flake.nix
+@@ -136,0 +137 @@
++      debug = true;
+"#;
+        let diff = Diff::parse(text);
+        assert_eq!(diff, Diff::parse(text));
+        assert_eq!(diff.clone(), diff);
+        assert_ne!(diff, Diff::parse(""));
+    }
+
     #[test]
     fn filter_single_file() {
         let text = r#"diff --git a/config.nix b/config.nix
@@ -250,6 +1077,45 @@ index 111..222 100644
         assert_eq!(filtered.files[1].path, "gtk.nix");
     }
 
+    #[test]
+    fn retain_file_scopes_to_one_file() {
+        let text = r#"diff --git a/flake.nix b/flake.nix
+index abc1234..def5678 100644
+--- a/flake.nix
++++ b/flake.nix
+@@ -136,0 +137,2 @@
++      debug = true;
++      verbose = true;
+diff --git a/gtk.nix b/gtk.nix
+index 111..222 100644
+--- a/gtk.nix
++++ b/gtk.nix
+@@ -11 +11 @@
+-    gtk.cursorTheme.size = 16;
++    gtk.cursorTheme.size = 24;
+"#;
+        let diff = Diff::parse(text);
+
+        let gtk = diff
+            .retain_file("gtk.nix", |line| line == 11, |_| false)
+            .unwrap();
+        assert_eq!(gtk.path, "gtk.nix");
+        assert_eq!(gtk.hunks.len(), 1);
+        assert_eq!(gtk.hunks[0].old.lines, ["    gtk.cursorTheme.size = 16;"]);
+        assert!(gtk.hunks[0].new.is_empty());
+
+        let flake = diff
+            .retain_file("flake.nix", |_| true, |line| line == 138)
+            .unwrap();
+        assert_eq!(flake.hunks[0].new.lines, ["      verbose = true;"]);
+
+        assert_eq!(
+            diff.retain_file("gtk.nix", |_| false, |line| line == 12),
+            None
+        );
+        assert_eq!(diff.retain_file("home.nix", |_| true, |_| true), None);
+    }
+
     #[test]
     fn filter_removes_empty_files() {
         let text = r#"diff --git a/flake.nix b/flake.nix
@@ -277,6 +1143,390 @@ index 111..222 100644
         assert_eq!(filtered.files[0].path, "flake.nix");
     }
 
+    #[test]
+    fn totals_over_two_files() {
+        let diff_text = r#"diff --git a/flake.nix b/flake.nix
+--- a/flake.nix
++++ b/flake.nix
+@@ -10,2 +10,3 @@
+-old one
+-old two
++new one
++new two
++new three
+@@ -20,0 +22 @@
++extra
+diff --git a/zsh.nix b/zsh.nix
+--- a/zsh.nix
++++ b/zsh.nix
+@@ -5 +4,0 @@
+-removed
+"#;
+        let diff = Diff::parse(diff_text);
+        assert_eq!(diff.files_changed_count(), 2);
+        assert_eq!(diff.total_additions(), 4);
+        assert_eq!(diff.total_deletions(), 3);
+
+        let empty = Diff::parse("");
+        assert_eq!(empty.files_changed_count(), 0);
+        assert_eq!(empty.total_additions(), 0);
+        assert_eq!(empty.total_deletions(), 0);
+    }
+
+    #[test]
+    fn sort_files_orders_by_path() {
+        let diff_text = r#"diff --git a/zsh.nix b/zsh.nix
+--- a/zsh.nix
++++ b/zsh.nix
+@@ -1,0 +2 @@
++zsh
+diff --git a/flake.nix b/flake.nix
+--- a/flake.nix
++++ b/flake.nix
+@@ -1,0 +2 @@
++flake
+diff --git a/gtk.nix b/gtk.nix
+--- a/gtk.nix
++++ b/gtk.nix
+@@ -1,0 +2 @@
++gtk
+"#;
+        let mut diff = Diff::parse(diff_text);
+        diff.sort_files();
+
+        let paths: Vec<&str> = diff.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["flake.nix", "gtk.nix", "zsh.nix"]);
+    }
+
+    #[test]
+    fn display_tab_indented_recipe() {
+        let diff_text = "diff --git a/Makefile b/Makefile
+--- a/Makefile
++++ b/Makefile
+@@ -1,0 +2 @@
++\t$(CC) -o app\tmain.c
+";
+        let diff = Diff::parse(diff_text);
+
+        // Content after the separator tab is verbatim
+        assert_eq!(
+            diff.to_string(),
+            "Makefile:\n  +2: \t$(CC) -o app\tmain.c\n\n"
+        );
+        // Only leading tabs are visualized
+        assert_eq!(
+            diff.display_show_tabs(),
+            "Makefile:\n  +2: →   $(CC) -o app\tmain.c\n\n"
+        );
+    }
+
+    #[test]
+    fn grep_keeps_matching_lines_and_numbers() {
+        let diff_text = "diff --git a/todo.nix b/todo.nix
+--- a/todo.nix
++++ b/todo.nix
+@@ -3,3 +3,4 @@
+-# TODO: drop
+-keep = 1;
+-# TODO: old
++keep = 2;
++# TODO: new
++# TODO: newer
++done = true;
+diff --git a/other.nix b/other.nix
+--- a/other.nix
++++ b/other.nix
+@@ -1 +1 @@
+-a
++b
+";
+        let diff = Diff::parse(diff_text);
+        let todos = diff.grep(|line| line.contains("TODO"));
+
+        assert_eq!(
+            todos.to_string(),
+            "todo.nix:\n  -3: # TODO: drop\n\n  -5: # TODO: old\n\n  +4: # TODO: new\n  +5: # TODO: newer\n\n"
+        );
+        assert_eq!(todos.tokens(), "todo.nix:-3,-5,4..5\n");
+        assert_eq!(diff.tokens(), "todo.nix:-3..-5,3..6\nother.nix:-1,1\n");
+        assert!(diff.grep(|_| false).files.is_empty());
+    }
+
+    #[test]
+    fn display_grouped_by_kind() {
+        let diff_text = "diff --git a/flake.nix b/flake.nix
+--- a/flake.nix
++++ b/flake.nix
+@@ -2 +2 @@
+-version = 1;
++version = 2;
+@@ -5,0 +6 @@
++pkgs.git
+@@ -9 +9,0 @@
+-pkgs.hg
+@@ -12,0 +13 @@
++pkgs.jj
+";
+        let diff = Diff::parse(diff_text);
+        let grouped = DisplayOptions {
+            group_by_kind: true,
+            ..DisplayOptions::default()
+        };
+
+        assert_eq!(
+            diff.display_with(grouped),
+            "flake.nix:
+ additions:
+  +6: pkgs.git
+
+  +13: pkgs.jj
+
+ deletions:
+  -9: pkgs.hg
+
+ replacements:
+  -2: version = 1;
+  +2: version = 2;
+
+"
+        );
+    }
+
+    #[test]
+    fn display_limits_hunks_and_files() {
+        let diff_text = "diff --git a/flake.nix b/flake.nix
+--- a/flake.nix
++++ b/flake.nix
+@@ -2 +2 @@
+-version = 1;
++version = 2;
+@@ -5,0 +6 @@
++pkgs.git
+@@ -9 +9,0 @@
+-pkgs.hg
+@@ -12,0 +13 @@
++pkgs.jj
+diff --git a/gtk.nix b/gtk.nix
+--- a/gtk.nix
++++ b/gtk.nix
+@@ -11,0 +12 @@
++    gtk.cursorTheme.size = 24;
+";
+        let diff = Diff::parse(diff_text);
+        let limited = DisplayOptions {
+            limit_hunks: Some(2),
+            limit_files: Some(1),
+            ..DisplayOptions::default()
+        };
+
+        assert_eq!(
+            diff.display_with(limited),
+            "flake.nix:
+  -2: version = 1;
+  +2: version = 2;
+
+  +6: pkgs.git
+
+  ... (2 more hunks)
+
+... (1 more file)
+
+"
+        );
+
+        let grouped = DisplayOptions {
+            group_by_kind: true,
+            limit_hunks: Some(3),
+            ..DisplayOptions::default()
+        };
+        assert!(
+            diff.display_with(grouped)
+                .contains(" deletions:\n  -9: pkgs.hg\n\n  ... (1 more hunk)\n\ngtk.nix:\n")
+        );
+    }
+
+    #[test]
+    fn display_annotates_hunk_boundaries() {
+        let diff_text = "diff --git a/flake.nix b/flake.nix
+--- a/flake.nix
++++ b/flake.nix
+@@ -2,2 +2 @@
+-version = 1;
+-pinned = true;
++version = 2;
+@@ -12,0 +12 @@
++pkgs.jj
+";
+        let diff = Diff::parse(diff_text);
+        let annotated = DisplayOptions {
+            annotate_boundaries: true,
+            ..DisplayOptions::default()
+        };
+
+        assert_eq!(
+            diff.display_with(annotated),
+            "flake.nix:
+┌ -2: version = 1;
+│ -3: pinned = true;
+└ +2: version = 2;
+
+─ +12: pkgs.jj
+
+"
+        );
+    }
+
+    #[test]
+    fn display_color_wraps_changed_lines() {
+        let diff_text = "diff --git a/a.nix b/a.nix
+--- a/a.nix
++++ b/a.nix
+@@ -2 +2,2 @@
+-x
++y
++z
+";
+        let diff = Diff::parse(diff_text);
+        let colored = DisplayOptions {
+            color: true,
+            annotate_boundaries: true,
+            ..DisplayOptions::default()
+        };
+
+        assert_eq!(
+            diff.display_with(colored),
+            "a.nix:\n\x1b[31m┌ -2: x\x1b[0m\n\x1b[32m│ +2: y\x1b[0m\n\x1b[32m└ +3: z\x1b[0m\n\n"
+        );
+    }
+
+    #[test]
+    fn display_escapes_control_characters() {
+        let diff_text = "diff --git a/log.txt b/log.txt
+--- a/log.txt
++++ b/log.txt
+@@ -1 +1 @@
+-\x1b[31merror\x1b[0m
++\terror\0code\x7f\u{9b}
+";
+        let diff = Diff::parse(diff_text);
+
+        // Plain display and patch keep the bytes untouched
+        assert_eq!(
+            diff.to_string(),
+            "log.txt:\n  -1: \x1b[31merror\x1b[0m\n  +1: \terror\0code\x7f\u{9b}\n\n"
+        );
+        assert!(diff.to_patch().contains("+\terror\0code\x7f\u{9b}\n"));
+
+        let escaped = DisplayOptions {
+            escape_control: true,
+            ..DisplayOptions::default()
+        };
+        assert_eq!(
+            diff.display_with(escaped),
+            "log.txt:\n  -1: ^[[31merror^[[0m\n  +1: \terror^@code^?\\u{9b}\n\n"
+        );
+        assert_eq!(
+            diff.display_with(DisplayOptions {
+                show_tabs: true,
+                ..escaped
+            }),
+            "log.txt:\n  -1: ^[[31merror^[[0m\n  +1: →   error^@code^?\\u{9b}\n\n"
+        );
+    }
+
+    #[test]
+    fn to_html_escapes_content() {
+        let diff_text = r#"diff --git a/a&b.html b/a&b.html
+--- a/a&b.html
++++ b/a&b.html
+@@ -3 +3 @@
+-<p>Tom & Jerry</p>
++<p class="x">Tom &amp; Jerry</p>
+"#;
+        let diff = Diff::parse(diff_text);
+
+        assert_eq!(
+            diff.to_html(),
+            r#"<div class="git-lines-diff">
+<section class="file" data-path="a&amp;b.html">
+<h3>a&amp;b.html</h3>
+<pre class="hunk"><span class="del" data-token="a&amp;b.html:-3">-3: &lt;p&gt;Tom &amp; Jerry&lt;/p&gt;</span>
+<span class="add" data-token="a&amp;b.html:3">+3: &lt;p class=&quot;x&quot;&gt;Tom &amp;amp; Jerry&lt;/p&gt;</span>
+</pre>
+</section>
+</div>
+"#
+        );
+    }
+
+    #[test]
+    fn to_json_lists_lines_per_hunk() {
+        let diff_text = r#"diff --git a/config.nix b/config.nix
+--- a/config.nix
++++ b/config.nix
+@@ -3 +3,2 @@
+-old "quoted"
++new
++last
+\ No newline at end of file
+"#;
+        let diff = Diff::parse(diff_text);
+
+        assert_eq!(
+            diff.to_json(),
+            concat!(
+                r#"{"files":[{"path":"config.nix","binary":false,"hunks":[{"lines":["#,
+                r#"{"kind":"delete","line":3,"content":"old \"quoted\""},"#,
+                r#"{"kind":"add","line":3,"content":"new"},"#,
+                r#"{"kind":"add","line":4,"content":"last"}],"#,
+                r#""missing_final_newline":{"old":false,"new":true}}]}]}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn to_json_escapes_control_characters() {
+        let diff = Diff::parse(
+            "diff --git a/a.txt b/a.txt\n--- a/a.txt\n+++ b/a.txt\n@@ -0,0 +1 @@\n+\tback\\slash\u{1b}\r\n",
+        );
+        assert!(
+            diff.to_json()
+                .contains(r#""content":"\tback\\slash\u001b\r""#),
+            "{}",
+            diff.to_json()
+        );
+    }
+
+    #[test]
+    fn map_multi_hunk_file() {
+        let diff_text = r#"diff --git a/flake.nix b/flake.nix
+--- a/flake.nix
++++ b/flake.nix
+@@ -15 +14,0 @@
+-removed
+@@ -137,0 +137 @@
++added
+@@ -141,2 +142,3 @@
+-old one
+-old two
++new one
++new two
++new three
+diff --git a/zsh.nix b/zsh.nix
+--- a/zsh.nix
++++ b/zsh.nix
+@@ -1,0 +2 @@
++zsh
+"#;
+        let diff = Diff::parse(diff_text);
+
+        assert_eq!(
+            diff.map(),
+            "flake.nix: -15, +137, -141..-142, +142..144\nzsh.nix: +2\n"
+        );
+    }
+
     #[test]
     fn to_patch_multiple_files() {
         let text = r#"diff --git a/flake.nix b/flake.nix
@@ -309,14 +1559,18 @@ index 111..222 100644
 #[cfg(test)]
 mod proptests {
     use super::*;
+    use crate::diff::file::FileChange;
     use crate::diff::hunk::{Hunk, ModifiedLines};
     use proptest::prelude::*;
     use std::collections::HashSet;
 
-    /// Generate line content
+    /// Generate line content, with the odd carriage return of a CRLF file
     fn arb_line_content() -> impl Strategy<Value = String> {
-        prop::collection::vec(prop::char::range(' ', '~'), 0..15)
-            .prop_map(|chars| chars.into_iter().collect())
+        prop::collection::vec(
+            prop_oneof![9 => prop::char::range(' ', '~'), 1 => Just('\r')],
+            0..15,
+        )
+        .prop_map(|chars| chars.into_iter().collect())
     }
 
     /// Generate a simple FileDiff with one hunk
@@ -335,6 +1589,13 @@ mod proptests {
                     missing_final_newline: false,
                 },
             }],
+            copied_from: None,
+            renamed_from: None,
+            old_name: None,
+            old_blob: None,
+            binary: false,
+            mode: None,
+            change: FileChange::Modified,
         })
     }
 
@@ -1,7 +1,7 @@
 use nom::{
     IResult, Parser,
-    bytes::complete::{tag, take_until},
-    character::complete::{digit1, line_ending, not_line_ending},
+    bytes::complete::{tag, take_till, take_until},
+    character::complete::{digit1, line_ending},
     combinator::{map_res, opt, value},
     multi::fold_many0,
     sequence::{delimited, pair, preceded, separated_pair, terminated},
@@ -11,20 +11,39 @@ use std::fmt;
 /// Lines modified in the old or new version of a file.
 ///
 /// Represents either deletions (old lines) or additions (new lines) within a hunk.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ModifiedLines {
     /// Starting line number (1-indexed)
     pub start: u32,
     /// The actual line content (without +/- prefix)
+    ///
+    /// Only the `\n` ending a line is stripped: the `\r` of a CRLF line
+    /// ending stays part of the content, so patches rendered from it still
+    /// match the file byte for byte.
     pub lines: Vec<String>,
     /// Whether the last line lacks a trailing newline
     pub missing_final_newline: bool,
 }
 
 impl ModifiedLines {
+    /// Number of lines on this side
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Whether this side has no lines (the hunk only changes the other side)
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
     /// Filter lines based on a predicate, returning which lines were kept
     /// along with boundary tracking information.
-    fn filter<F>(&self, mut keep: F) -> FilterResult
+    ///
+    /// Kept lines borrow from `self`; nothing is copied until output hunks are built.
+    fn filter<F>(&self, mut keep: F) -> FilterResult<'_>
     where
         F: FnMut(u32) -> bool,
     {
@@ -39,7 +58,7 @@ impl ModifiedLines {
         for (i, line) in self.lines.iter().enumerate() {
             let line_num = self.start + i as u32;
             if keep(line_num) {
-                result.lines.push((line_num, line.clone()));
+                result.lines.push((line_num, line.as_str()));
                 if i == 0 {
                     result.kept_first_boundary = true;
                 }
@@ -101,6 +120,54 @@ impl FilteredContent {
     /// The `cumulative_delta` parameter tracks the net line changes from
     /// previous hunks in the file, used to calculate correct `new_start` positions.
     pub fn into_hunks(self, cumulative_delta: i32) -> Vec<Hunk> {
+        FilteredLines {
+            insertion_point: self.insertion_point,
+            deletions: self
+                .deletions
+                .iter()
+                .map(|(n, c)| (*n, c.as_str()))
+                .collect(),
+            additions: self.additions.iter().map(String::as_str).collect(),
+            old_missing_newline: self.old_missing_newline,
+            new_missing_newline: self.new_missing_newline,
+        }
+        .into_hunks(cumulative_delta)
+    }
+}
+
+impl From<FilteredLines<'_>> for FilteredContent {
+    fn from(lines: FilteredLines<'_>) -> Self {
+        Self {
+            insertion_point: lines.insertion_point,
+            deletions: lines
+                .deletions
+                .into_iter()
+                .map(|(n, c)| (n, c.to_owned()))
+                .collect(),
+            additions: to_owned_lines(lines.additions),
+            old_missing_newline: lines.old_missing_newline,
+            new_missing_newline: lines.new_missing_newline,
+        }
+    }
+}
+
+/// [`FilteredContent`] with its line content borrowed from the source
+/// [`Hunk`], as the staging path uses it
+///
+/// Owned strings are only allocated when [`into_hunks`](Self::into_hunks)
+/// builds the output.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct FilteredLines<'a> {
+    pub insertion_point: u32,
+    pub deletions: Vec<(u32, &'a str)>,
+    pub additions: Vec<&'a str>,
+    pub old_missing_newline: bool,
+    pub new_missing_newline: bool,
+}
+
+impl FilteredLines<'_> {
+    /// Build output hunks, as [`FilteredContent::into_hunks`] does
+    pub(crate) fn into_hunks(self, cumulative_delta: i32) -> Vec<Hunk> {
         let has_deletions = !self.deletions.is_empty();
         let has_additions = !self.additions.is_empty();
 
@@ -118,7 +185,7 @@ impl FilteredContent {
                 },
                 new: ModifiedLines {
                     start: new_start,
-                    lines: self.additions,
+                    lines: to_owned_lines(self.additions),
                     missing_final_newline: self.new_missing_newline,
                 },
             }];
@@ -127,6 +194,7 @@ impl FilteredContent {
         // Case 2: Pure deletions (no additions)
         // Each contiguous group of deletions becomes a separate hunk
         if has_deletions && !has_additions {
+            let last_deletion = self.deletions.last().map(|(n, _)| *n);
             let groups = group_contiguous_lines(&self.deletions);
             let mut hunks = Vec::new();
             let mut local_delta = cumulative_delta;
@@ -138,16 +206,12 @@ impl FilteredContent {
 
                 // Check if this group has the last line (for no-newline tracking)
                 let group_has_last = self.old_missing_newline
-                    && group
-                        .lines
-                        .last()
-                        .map(|(num, _)| *num == self.deletions.last().map(|(n, _)| *n).unwrap_or(0))
-                        .unwrap_or(false);
+                    && group.lines.last().map(|(num, _)| *num) == last_deletion;
 
                 hunks.push(Hunk {
                     old: ModifiedLines {
                         start: old_start,
-                        lines: group.lines.into_iter().map(|(_, c)| c).collect(),
+                        lines: group.lines.iter().map(|(_, c)| (*c).to_owned()).collect(),
                         missing_final_newline: group_has_last,
                     },
                     new: ModifiedLines {
@@ -165,27 +229,43 @@ impl FilteredContent {
         }
 
         // Case 3: Mixed (both deletions and additions)
-        // For now, keep as single hunk - more complex splitting could be added later
+        // Deletions split into contiguous groups as in case 2. The additions
+        // replace the first group, where a single hunk would have put them
         if has_deletions && has_additions {
-            let old_start = self
-                .deletions
-                .first()
-                .map(|(n, _)| *n)
-                .unwrap_or(self.insertion_point);
-            let new_start = (old_start as i32 + cumulative_delta) as u32;
+            let last_deletion = self.deletions.last().map(|(n, _)| *n);
+            let groups = group_contiguous_lines(&self.deletions);
+            let mut additions = to_owned_lines(self.additions);
+            let mut hunks = Vec::new();
+            let mut local_delta = cumulative_delta;
 
-            return vec![Hunk {
-                old: ModifiedLines {
-                    start: old_start,
-                    lines: self.deletions.into_iter().map(|(_, c)| c).collect(),
-                    missing_final_newline: self.old_missing_newline,
-                },
-                new: ModifiedLines {
-                    start: new_start,
-                    lines: self.additions,
-                    missing_final_newline: self.new_missing_newline,
-                },
-            }];
+            for group in groups {
+                let old_start = group.first_line_num;
+                // Only the first group takes the additions
+                let new_lines = std::mem::take(&mut additions);
+                let new_start = if new_lines.is_empty() {
+                    old_start as i32 - 1 + local_delta
+                } else {
+                    old_start as i32 + local_delta
+                };
+                let group_has_last = self.old_missing_newline
+                    && group.lines.last().map(|(num, _)| *num) == last_deletion;
+                local_delta += new_lines.len() as i32 - group.lines.len() as i32;
+
+                hunks.push(Hunk {
+                    old: ModifiedLines {
+                        start: old_start,
+                        lines: group.lines.iter().map(|(_, c)| (*c).to_owned()).collect(),
+                        missing_final_newline: group_has_last,
+                    },
+                    new: ModifiedLines {
+                        start: new_start as u32,
+                        missing_final_newline: !new_lines.is_empty() && self.new_missing_newline,
+                        lines: new_lines,
+                    },
+                });
+            }
+
+            return hunks;
         }
 
         // Case 4: Empty (shouldn't happen - filter returns None for empty)
@@ -193,6 +273,11 @@ impl FilteredContent {
     }
 }
 
+/// Copy borrowed line content into the owned form stored by [`ModifiedLines`]
+fn to_owned_lines(lines: Vec<&str>) -> Vec<String> {
+    lines.into_iter().map(str::to_owned).collect()
+}
+
 /// A single hunk from a git diff.
 ///
 /// A hunk represents one contiguous block of changes in a file. With `-U0`
@@ -209,7 +294,8 @@ impl FilteredContent {
 /// - Pure addition: `old.lines` is empty
 /// - Pure deletion: `new.lines` is empty
 /// - Replacement: Both `old` and `new` have lines
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Hunk {
     /// Lines from the old version (deletions)
     pub old: ModifiedLines,
@@ -223,10 +309,76 @@ impl Hunk {
     /// Expects text starting with `@@ -old +new @@` header followed by
     /// content lines prefixed with `-`, `+`, or `\` (for no-newline marker).
     ///
-    /// Returns `None` if parsing fails.
+    /// Returns `None` if parsing fails, or if neither side has any lines (a
+    /// degenerate hunk such as `@@ -0,0 +0,0 @@` changes nothing).
     #[must_use]
     pub fn parse(text: &str) -> Option<Self> {
-        parse_hunk(text).ok().map(|(_, hunk)| hunk)
+        parse_hunk(text)
+            .ok()
+            .map(|(_, hunk)| hunk)
+            .filter(|hunk| !(hunk.old.is_empty() && hunk.new.is_empty()))
+    }
+
+    /// Whether the hunk only adds lines
+    #[must_use]
+    pub fn is_pure_addition(&self) -> bool {
+        self.old.is_empty() && !self.new.is_empty()
+    }
+
+    /// Whether the hunk only deletes lines
+    #[must_use]
+    pub fn is_pure_deletion(&self) -> bool {
+        !self.old.is_empty() && self.new.is_empty()
+    }
+
+    /// Whether the hunk replaces old lines with new ones
+    #[must_use]
+    pub fn is_replacement(&self) -> bool {
+        !self.old.is_empty() && !self.new.is_empty()
+    }
+
+    /// The hunk that undoes this one: additions become deletions and back
+    #[must_use]
+    pub fn reversed(&self) -> Hunk {
+        Hunk {
+            old: self.new.clone(),
+            new: self.old.clone(),
+        }
+    }
+
+    /// Line references selecting every line of this hunk, in `file:refs` syntax
+    /// without the file (e.g. `-10..-11,10..12`).
+    #[must_use]
+    pub fn selector(&self) -> String {
+        let range = |sign: &str, side: &ModifiedLines| match side.len() {
+            0 => None,
+            1 => Some(format!("{sign}{}", side.start)),
+            n => Some(format!(
+                "{sign}{}..{sign}{}",
+                side.start,
+                side.start + n as u32 - 1
+            )),
+        };
+
+        range("-", &self.old)
+            .into_iter()
+            .chain(range("", &self.new))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Old line number paired with `new_line` when this hunk is a replacement.
+    ///
+    /// Alignment is positional: the i-th deleted line pairs with the i-th added
+    /// line, which matches how git lays out line-for-line edits. Added lines
+    /// beyond the number of deletions (and hunks that only add or only delete)
+    /// have no aligned old line.
+    #[must_use]
+    pub fn aligned_old_line(&self, new_line: u32) -> Option<u32> {
+        let offset = new_line.checked_sub(self.new.start)?;
+        let in_new = (offset as usize) < self.new.len();
+        let in_old = (offset as usize) < self.old.len();
+        (in_new && in_old).then(|| self.old.start + offset)
     }
 
     /// Filter lines in the hunk, returning the filtered content.
@@ -252,6 +404,16 @@ impl Hunk {
     /// newline separator. This prevents corrupted git index state.
     #[must_use]
     pub fn filter<F, G>(&self, keep_old: F, keep_new: G) -> Option<FilteredContent>
+    where
+        F: FnMut(u32) -> bool,
+        G: FnMut(u32) -> bool,
+    {
+        self.filter_lines(keep_old, keep_new)
+            .map(FilteredContent::from)
+    }
+
+    /// [`filter`](Self::filter), borrowing the kept lines from the hunk
+    pub(crate) fn filter_lines<F, G>(&self, keep_old: F, keep_new: G) -> Option<FilteredLines<'_>>
     where
         F: FnMut(u32) -> bool,
         G: FnMut(u32) -> bool,
@@ -275,7 +437,7 @@ impl Hunk {
         let old_missing_newline = old_filtered.kept_last_boundary && self.old.missing_final_newline;
         let new_missing_newline = new_filtered.kept_last_boundary && self.new.missing_final_newline;
 
-        Some(FilteredContent {
+        Some(FilteredLines {
             insertion_point: self.old.start,
             deletions: old_filtered.lines,
             additions: new_filtered.lines.into_iter().map(|(_, c)| c).collect(),
@@ -286,14 +448,14 @@ impl Hunk {
 }
 
 /// Result of filtering lines, tracking boundary alignment with the original
-struct FilterResult {
+struct FilterResult<'a> {
     /// Each kept line with its original line number
-    lines: Vec<(u32, String)>,
+    lines: Vec<(u32, &'a str)>,
     kept_first_boundary: bool,
     kept_last_boundary: bool,
 }
 
-impl FilterResult {
+impl FilterResult<'_> {
     fn is_empty(&self) -> bool {
         self.lines.is_empty()
     }
@@ -315,10 +477,10 @@ fn requires_line_separator(old_source: &ModifiedLines, new_filtered: &FilterResu
 /// Forces inclusion of the last deletion (if not already kept) and
 /// synthesizes the first addition with the same content, providing
 /// the newline that separates subsequent additions.
-fn insert_line_separator(
-    old_source: &ModifiedLines,
-    old_filtered: &mut FilterResult,
-    new_filtered: &mut FilterResult,
+fn insert_line_separator<'a>(
+    old_source: &'a ModifiedLines,
+    old_filtered: &mut FilterResult<'a>,
+    new_filtered: &mut FilterResult<'a>,
 ) {
     let Some(last_old_line) = old_source.lines.last() else {
         return;
@@ -331,7 +493,7 @@ fn insert_line_separator(
 
         old_filtered
             .lines
-            .push((last_line_num, last_old_line.clone()));
+            .push((last_line_num, last_old_line.as_str()));
         old_filtered.kept_last_boundary = true;
     }
 
@@ -339,59 +501,29 @@ fn insert_line_separator(
     let synth_line_num = old_source.start + old_source.lines.len() as u32;
     new_filtered
         .lines
-        .insert(0, (synth_line_num, last_old_line.clone()));
+        .insert(0, (synth_line_num, last_old_line.as_str()));
     new_filtered.kept_first_boundary = true;
 }
 
 /// A contiguous group of lines
-pub(crate) struct ContiguousGroup {
+pub(crate) struct ContiguousGroup<'a> {
     pub first_line_num: u32,
-    pub lines: Vec<(u32, String)>,
+    pub lines: &'a [(u32, &'a str)],
 }
 
 /// Group lines into contiguous runs
 ///
 /// When there are gaps in line numbers (e.g., lines 3, 4, 6), this splits
-/// them into separate groups (e.g., [3, 4] and [6]).
-pub(crate) fn group_contiguous_lines(lines: &[(u32, String)]) -> Vec<ContiguousGroup> {
-    if lines.is_empty() {
-        return vec![];
-    }
-
-    let mut groups: Vec<ContiguousGroup> = Vec::new();
-    let mut current_group: Vec<(u32, String)> = Vec::new();
-
-    for (line_num, content) in lines {
-        if current_group.is_empty() {
-            // Start first group
-            current_group.push((*line_num, content.clone()));
-        } else {
-            let last_num = current_group.last().unwrap().0;
-            if *line_num == last_num + 1 {
-                // Contiguous - add to current group
-                current_group.push((*line_num, content.clone()));
-            } else {
-                // Gap detected - finalize current group and start new one
-                let first = current_group[0].0;
-                groups.push(ContiguousGroup {
-                    first_line_num: first,
-                    lines: current_group,
-                });
-                current_group = vec![(*line_num, content.clone())];
-            }
-        }
-    }
-
-    // Don't forget the last group
-    if !current_group.is_empty() {
-        let first = current_group[0].0;
-        groups.push(ContiguousGroup {
-            first_line_num: first,
-            lines: current_group,
-        });
-    }
-
-    groups
+/// them into separate groups (e.g., [3, 4] and [6]). Groups are sub-slices
+/// of the input, so no line content is copied.
+pub(crate) fn group_contiguous_lines<'a>(lines: &'a [(u32, &'a str)]) -> Vec<ContiguousGroup<'a>> {
+    lines
+        .chunk_by(|(prev, _), (next, _)| *next == prev + 1)
+        .map(|group| ContiguousGroup {
+            first_line_num: group[0].0,
+            lines: group,
+        })
+        .collect()
 }
 
 // Nom parser combinators for hunk parsing
@@ -419,12 +551,17 @@ fn hunk_header(input: &str) -> IResult<&str, (u32, u32)> {
     Ok((rest, (old_start, new_start)))
 }
 
+/// The rest of a line up to its `\n`, keeping any `\r` before it
+fn line_content(input: &str) -> IResult<&str, &str> {
+    take_till(|c| c == '\n').parse(input)
+}
+
 fn deletion_line(input: &str) -> IResult<&str, &str> {
-    preceded(tag("-"), terminated(not_line_ending, opt(line_ending))).parse(input)
+    preceded(tag("-"), terminated(line_content, opt(tag("\n")))).parse(input)
 }
 
 fn addition_line(input: &str) -> IResult<&str, &str> {
-    preceded(tag("+"), terminated(not_line_ending, opt(line_ending))).parse(input)
+    preceded(tag("+"), terminated(line_content, opt(tag("\n")))).parse(input)
 }
 
 fn no_newline_marker(input: &str) -> IResult<&str, bool> {
@@ -438,7 +575,7 @@ fn no_newline_marker(input: &str) -> IResult<&str, bool> {
 fn parse_hunk(input: &str) -> IResult<&str, Hunk> {
     // Parse header
     let (rest, (old_start, new_start)) =
-        terminated(hunk_header, pair(not_line_ending, line_ending)).parse(input)?;
+        terminated(hunk_header, pair(line_content, tag("\n"))).parse(input)?;
 
     // Collect deletions
     let (rest, old_lines) = fold_many0(deletion_line, Vec::new, |mut acc, line| {
@@ -478,13 +615,13 @@ fn parse_hunk(input: &str) -> IResult<&str, Hunk> {
 impl fmt::Display for Hunk {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Build header
-        let old_part = match self.old.lines.len() {
+        let old_part = match self.old.len() {
             0 => format!("-{},0", self.old.start),
             1 => format!("-{}", self.old.start),
             n => format!("-{},{}", self.old.start, n),
         };
 
-        let new_part = match self.new.lines.len() {
+        let new_part = match self.new.len() {
             0 => format!("+{},0", self.new.start),
             1 => format!("+{}", self.new.start),
             n => format!("+{},{}", self.new.start, n),
@@ -633,6 +770,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn classify_hunk_kinds() {
+        let addition = Hunk::parse("@@ -10,0 +11 @@\n+new\n").unwrap();
+        assert!(addition.is_pure_addition());
+        assert!(!addition.is_pure_deletion());
+        assert!(!addition.is_replacement());
+        assert_eq!((addition.old.len(), addition.new.len()), (0, 1));
+
+        let deletion = Hunk::parse("@@ -10,2 +9,0 @@\n-a\n-b\n").unwrap();
+        assert!(!deletion.is_pure_addition());
+        assert!(deletion.is_pure_deletion());
+        assert!(!deletion.is_replacement());
+        assert!(deletion.new.is_empty());
+
+        let replacement = Hunk::parse("@@ -10 +10,2 @@\n-a\n+b\n+c\n").unwrap();
+        assert!(!replacement.is_pure_addition());
+        assert!(!replacement.is_pure_deletion());
+        assert!(replacement.is_replacement());
+        assert_eq!((replacement.old.len(), replacement.new.len()), (1, 2));
+    }
+
+    #[test]
+    fn selector_covers_both_sides() {
+        let hunk = Hunk::parse("@@ -10,2 +10,3 @@\n-a\n-b\n+c\n+d\n+e\n").unwrap();
+        assert_eq!(hunk.selector(), "-10..-11,10..12");
+
+        let hunk = Hunk::parse("@@ -10,0 +11 @@\n+new\n").unwrap();
+        assert_eq!(hunk.selector(), "11");
+
+        let hunk = Hunk::parse("@@ -10 +9,0 @@\n-old\n").unwrap();
+        assert_eq!(hunk.selector(), "-10");
+    }
+
+    #[test]
+    fn aligned_old_line_pairs_by_position() {
+        // Three old lines replaced by four new ones, shifted by an earlier insertion
+        let hunk = Hunk::parse("@@ -10,3 +12,4 @@\n-a\n-b\n-c\n+A\n+B\n+C\n+D\n").unwrap();
+        assert_eq!(hunk.aligned_old_line(12), Some(10));
+        assert_eq!(hunk.aligned_old_line(14), Some(12));
+        // Extra addition has no old counterpart
+        assert_eq!(hunk.aligned_old_line(15), None);
+        // Outside the hunk
+        assert_eq!(hunk.aligned_old_line(11), None);
+        assert_eq!(hunk.aligned_old_line(16), None);
+
+        let hunk = Hunk::parse("@@ -10,0 +11 @@\n+new\n").unwrap();
+        assert_eq!(hunk.aligned_old_line(11), None);
+    }
+
     #[test]
     fn parse_pure_insertion() {
         let input = "@@ -10,0 +11 @@\n+new line here";
@@ -900,6 +1086,16 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn parse_keeps_carriage_returns() {
+        let input = "@@ -2 +2,2 @@\n-two\r\n+TWO\r\n+mid\rline\n";
+
+        let hunk = Hunk::parse(input).unwrap();
+        assert_eq!(hunk.old.lines, ["two\r"]);
+        assert_eq!(hunk.new.lines, ["TWO\r", "mid\rline"]);
+        assert_eq!(hunk.to_string(), input);
+    }
+
     #[test]
     fn render_empty_line_content() {
         let hunk = Hunk {
@@ -983,6 +1179,36 @@ mod tests {
         assert_eq!(filtered.insertion_point, 10);
     }
 
+    #[test]
+    fn mixed_gapped_deletions_split_into_hunks() {
+        let hunk = Hunk {
+            old: ModifiedLines {
+                start: 10,
+                lines: (10..=14).map(|n| format!("old {}", n)).collect(),
+                missing_final_newline: false,
+            },
+            new: ModifiedLines {
+                start: 10,
+                lines: vec!["new 10".to_string(), "new 11".to_string()],
+                missing_final_newline: false,
+            },
+        };
+
+        let hunks = hunk
+            .filter(|o| o == 10 || o == 13 || o == 14, |n| n == 10)
+            .unwrap()
+            .into_hunks(2);
+
+        let rendered: Vec<String> = hunks.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            rendered,
+            [
+                "@@ -10 +12 @@\n-old 10\n+new 10\n",
+                "@@ -13,2 +14,0 @@\n-old 13\n-old 14\n",
+            ]
+        );
+    }
+
     // =========================================================================
     // No newline at EOF tests
     // =========================================================================
@@ -1125,6 +1351,7 @@ mod tests {
 }
 
 #[cfg(test)]
+#[allow(clippy::unwrap_used)]
 mod proptests {
     use super::*;
     use proptest::prelude::*;
@@ -1132,9 +1359,13 @@ mod proptests {
 
     /// Generate line content without newlines (diff format handles those)
     fn arb_line_content() -> impl Strategy<Value = String> {
-        // Printable ASCII without newlines, reasonable length
-        prop::collection::vec(prop::char::range(' ', '~'), 0..30)
-            .prop_map(|chars| chars.into_iter().collect())
+        // Printable ASCII without newlines, plus carriage returns as in
+        // CRLF files, reasonable length
+        prop::collection::vec(
+            prop_oneof![9 => prop::char::range(' ', '~'), 1 => Just('\r')],
+            0..30,
+        )
+        .prop_map(|chars| chars.into_iter().collect())
     }
 
     /// Generate a ModifiedLines struct
@@ -1270,11 +1501,22 @@ mod proptests {
             let rendered = hunk.to_string();
             let parsed = Hunk::parse(&rendered);
 
+            // A hunk changing no lines renders, but is rejected as degenerate
+            if hunk.old.is_empty() && hunk.new.is_empty() {
+                prop_assert!(parsed.is_none(), "Parsed degenerate hunk:\n{}", rendered);
+                return Ok(());
+            }
+
             prop_assert!(
                 parsed.is_some(),
                 "Failed to parse rendered hunk:\n{}\nOriginal: {:?}",
                 rendered, hunk
             );
+            prop_assert!(
+                crate::diff::newline_markers_consistent(&rendered),
+                "Misplaced no-newline marker:\n{}",
+                rendered
+            );
 
             prop_assert_eq!(
                 parsed.unwrap(),
@@ -1381,7 +1623,7 @@ mod proptests {
                 // Every deletion must exist in hunk.old
                 for (_, line) in &filtered.deletions {
                     prop_assert!(
-                        hunk.old.lines.contains(line),
+                        hunk.old.lines.iter().any(|l| l == line),
                         "Filtered deletion {:?} not in original {:?}",
                         line, hunk.old.lines
                     );
@@ -1390,7 +1632,7 @@ mod proptests {
                 // Every addition must exist in hunk.new OR hunk.old (bridge synthesis)
                 for line in &filtered.additions {
                     prop_assert!(
-                        hunk.new.lines.contains(line) || hunk.old.lines.contains(line),
+                        hunk.new.lines.iter().chain(&hunk.old.lines).any(|l| l == line),
                         "Filtered addition {:?} not in original new {:?} or old {:?}",
                         line, hunk.new.lines, hunk.old.lines
                     );
@@ -1398,6 +1640,31 @@ mod proptests {
             }
         }
 
+        /// Marker placement: hunks built from any selection keep no-newline
+        /// markers after the last line of their side
+        #[test]
+        fn filtered_hunks_place_newline_markers(
+            hunk in arb_hunk(),
+            keep_old in arb_line_set(),
+            keep_new in arb_line_set()
+        ) {
+            if let Some(filtered) = hunk.filter(
+                |l| keep_old.contains(&l),
+                |l| keep_new.contains(&l)
+            ) {
+                let rendered: String = filtered
+                    .into_hunks(0)
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect();
+                prop_assert!(
+                    crate::diff::newline_markers_consistent(&rendered),
+                    "Misplaced no-newline marker:\n{}\nOriginal: {:?}",
+                    rendered, hunk
+                );
+            }
+        }
+
         /// Header consistency: rendered header line counts must match actual content
         #[test]
         fn hunk_header_matches_content(hunk in arb_hunk()) {
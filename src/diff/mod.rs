@@ -47,4 +47,88 @@ pub mod file;
 pub mod full;
 pub mod hunk;
 
-pub use full::Diff;
+pub use full::{Diff, DiffParseError, DisplayOptions, ParseWarning, json_string};
+
+/// Whether every `\ No newline at end of file` marker in a rendered patch is
+/// placed consistently.
+///
+/// A marker must directly follow a `-` or `+` line, and once a side of a file
+/// has been marked, no later line of that side may appear in the file.
+#[cfg(test)]
+pub(crate) fn newline_markers_consistent(patch: &str) -> bool {
+    const OLD: usize = 0;
+    const NEW: usize = 1;
+
+    let mut in_hunk = false;
+    let mut marked = [false; 2];
+    let mut previous = None;
+
+    for line in patch.lines() {
+        if line.starts_with("diff --git ") {
+            in_hunk = false;
+            marked = [false; 2];
+            previous = None;
+            continue;
+        }
+        if line.starts_with("@@ ") {
+            in_hunk = true;
+            previous = None;
+            continue;
+        }
+        if !in_hunk {
+            continue;
+        }
+
+        let side = match line.as_bytes().first() {
+            Some(b'-') => OLD,
+            Some(b'+') => NEW,
+            Some(b'\\') => {
+                let Some(side) = previous.take() else {
+                    return false;
+                };
+                marked[side] = true;
+                continue;
+            }
+            _ => {
+                previous = None;
+                continue;
+            }
+        };
+        if marked[side] {
+            return false;
+        }
+        previous = Some(side);
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newline_markers_rejects_misplaced_markers() {
+        let marker = "\\ No newline at end of file";
+        assert!(newline_markers_consistent(&format!(
+            "@@ -3 +3,2 @@\n-a\n{marker}\n+a\n+b\n{marker}\n"
+        )));
+        // Marker before the last addition
+        assert!(!newline_markers_consistent(&format!(
+            "@@ -3,0 +4,2 @@\n+a\n{marker}\n+b\n"
+        )));
+        // Marked side continues in a later hunk of the same file
+        assert!(!newline_markers_consistent(&format!(
+            "@@ -3 +2,0 @@\n-a\n{marker}\n@@ -5 +4,0 @@\n-b\n"
+        )));
+        // Marker with no changed line before it
+        assert!(!newline_markers_consistent(&format!(
+            "@@ -3 +3 @@\n{marker}\n-a\n+b\n"
+        )));
+        // A new file starts with fresh state
+        assert!(newline_markers_consistent(&format!(
+            "diff --git a/x b/x\n@@ -1 +0,0 @@\n-a\n{marker}\n\
+             diff --git a/y b/y\n@@ -1 +0,0 @@\n-b\n"
+        )));
+    }
+}
@@ -40,14 +40,23 @@
 //! - `-N` - Stage deletion of old line N
 //! - `N..M` - Stage range of additions (inclusive)
 //! - `-N..-M` - Stage range of deletions (inclusive)
+//! - `N..` / `-N..` - Stage additions / deletions from line N to the end of its hunk
+//! - `~-N` - Stage deletion of the old line aligned with new line N in a replacement
+//! - `rename` - Stage a rename that has no content changes
+//! - `all` - Stage every change to the file (`*` alone in the list does the same)
+//! - `eol` - Stage only the newline added after the file's last line
+//! - `!REF` - Leave out lines the other references select (`10..20,!15`)
 //! - `A,B,C` - Combine multiple line references
 //!
+//! The file may be omitted (`:137`) when exactly one file has changes.
+//!
 //! # Architecture
 //!
 //! The crate is organized into focused modules:
 //!
 //! - [`parse`] - Parse `file:refs` syntax into structured line references
 //! - [`diff`] - Parse and manipulate git diff output
+//! - [`backend`] - Run the git commands staging relies on
 //! - [`GitLines`] - Main API for staging operations
 //!
 //! # Use Cases
@@ -58,35 +67,111 @@
 //! - **Code review**: Stage reviewer suggestions line-by-line
 
 use error_set::error_set;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::num::NonZeroU32;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+pub mod backend;
 pub mod diff;
 pub mod parse;
 
+pub use backend::{GitBackend, SystemGit};
 pub use parse::ParseError;
 
 error_set! {
     /// Top-level error for git-lines operations
+    #[skip(Error)]
     GitLinesError := {
         /// No unstaged changes found in the specified file
         #[display("No changes found in {file}")]
         NoChanges { file: String },
+        /// No staged changes found in the specified file
+        #[display("No staged changes found in {file}")]
+        NoStagedChanges { file: String },
         /// No lines matched the specified line references
         #[display("No matching lines found for {file}")]
         NoMatchingLines { file: String },
+        /// A file-less selection was given but more than one file has changes
+        #[display("Multiple files have changes ({}); specify which file to stage", files.join(", "))]
+        AmbiguousFile { files: Vec<String> },
+        /// The file is hidden from `git diff` by its skip-worktree or
+        /// assume-unchanged bit, so its changes cannot be staged
+        #[display("{file} is marked skip-worktree or assume-unchanged, so git does not diff it (clear the bit with git update-index)")]
+        FileSkipWorktree { file: String },
+        /// With [`GitLines::stage_as`], the target's index content is not the
+        /// content the source file's diff was taken against
+        #[display("{target} in the index does not match the base of {file}'s diff")]
+        RemapBaseMismatch { file: String, target: String },
+        /// With [`GitLines::reject_conflict_markers`], a selected added line is a
+        /// merge conflict marker
+        #[display("Line {line} of {file} is a conflict marker; resolve the conflict before staging")]
+        ConflictMarkerStaged { file: String, line: u32 },
+        /// With [`GitLines::require_balanced`], the selected added lines leave a
+        /// bracket unmatched
+        #[display("Selected lines of {file} leave '{delimiter}' unmatched in `{text}`; select the whole block")]
+        UnbalancedSelection { file: String, delimiter: char, text: String },
+        /// The file is not tracked by git, so it has no diff to count
+        #[display("{file} is not tracked")]
+        FileNotTracked { file: String },
+        /// The path given to [`GitLines::open`] is not inside a git work tree
+        #[display("{path} is not a git repository")]
+        NotARepository { path: String },
+        /// The file's extension is not in the configured allowlist
+        #[display("Staging {file} is not allowed by the extension allowlist")]
+        DisallowedFile { file: String },
+        /// With [`GitLines::require_complete`], the selection missed some changed lines
+        #[display("Selection leaves changes to {file} unstaged: {}", missing.join(","))]
+        IncompleteSelection { file: String, missing: Vec<String> },
+        /// With [`GitLines::strict`], some line refs name no changed line
+        #[display("No changed lines of {file} match {}", refs.join(","))]
+        UnmatchedRefs { file: String, refs: Vec<String> },
+        /// With [`GitLines::strict`], part of the file's diff could not be parsed
+        #[display("Could not parse the diff of {file}: {reason}")]
+        UnparsedDiff { file: String, reason: String },
+        /// The file's diff is binary, so it has no lines to select
+        #[display("{file} is binary; its changes cannot be staged by line")]
+        BinaryFile { file: String },
+        /// With [`GitLines::three_way`], merging the selected lines into the index conflicted
+        #[display("Three-way apply conflicts in {}; the index was left unchanged", files.join(", "))]
+        MergeConflict { files: Vec<String> },
+        /// A file's old content does not hold the lines its diff deletes
+        #[display("The old content of {file} does not match its diff")]
+        StalePreimage { file: String },
+        /// With [`GitLines::verify_apply`], the index did not change by exactly the selected lines
+        #[display("Staging {file} would not stage exactly the selection: {detail}; the index was left unchanged")]
+        VerificationFailed { file: String, detail: String },
+        /// The [pre-apply hook](GitLines::pre_apply_hook) vetoed the patch
+        #[display("Pre-apply hook rejected the patch: {reason}")]
+        HookRejected { reason: String },
+        /// Lines selected from a stash did not apply to the current index
+        #[display("Lines from {stash} do not apply to the index (has it moved on from the stash's base?): {stderr}")]
+        StashConflict { stash: String, stderr: String },
+        /// With [`GitLines::commit_per_hunk`], committing a hunk left the file
+        /// with as many hunks as before
+        #[display("Committing a hunk of {file} did not shrink its diff; stopped after {committed} commit(s)")]
+        HunkNotCommitted { file: String, committed: usize },
+        /// Writing formatted output to the caller's writer failed
+        #[display("Failed to write diff output: {source}")]
+        OutputFailed(std::io::Error) {},
         /// Error parsing the file:refs syntax
         ParseError(ParseError),
     } || GitCommandError
 
     /// Errors from git command execution
+    #[skip(Error)]
     GitCommandError := {
         /// Repository path contains invalid UTF-8
         #[display("Repository path is not valid UTF-8")]
         InvalidRepoPath,
+        /// Failed to execute the git rev-parse command
+        #[display("Failed to run git rev-parse: {message}")]
+        RevParseFailed { message: String },
         /// Failed to execute the git diff command
-        #[display("Failed to run git diff: {message}")]
-        DiffFailed { message: String },
+        #[display("Failed to run git diff: {source}")]
+        DiffFailed(std::io::Error) {},
         /// Git diff command exited with non-zero status
         #[display("git diff failed: {stderr}")]
         DiffExitError { stderr: String },
@@ -94,8 +179,8 @@ error_set! {
         #[display("Invalid UTF-8 in git diff output: {message}")]
         InvalidUtf8 { message: String },
         /// Failed to spawn the git apply process
-        #[display("Failed to spawn git apply: {message}")]
-        ApplySpawnFailed { message: String },
+        #[display("Failed to spawn git apply: {source}")]
+        ApplySpawnFailed(std::io::Error) {},
         /// Failed to obtain stdin handle for git apply
         #[display("Failed to get stdin handle for git apply")]
         ApplyStdinFailed,
@@ -108,12 +193,382 @@ error_set! {
         /// Git apply command exited with non-zero status
         #[display("git apply failed: {stderr}")]
         ApplyExitError { stderr: String },
+        /// Failed to create a scratch copy of the index
+        #[display("Failed to create a scratch index: {message}")]
+        ScratchIndexFailed { message: String },
+        /// Failed to prepare or execute the git write-tree command
+        #[display("Failed to run git write-tree: {message}")]
+        WriteTreeFailed { message: String },
+        /// Git write-tree command exited with non-zero status
+        #[display("git write-tree failed: {stderr}")]
+        WriteTreeExitError { stderr: String },
+        /// Failed to execute the git commit command
+        #[display("Failed to run git commit: {message}")]
+        CommitFailed { message: String },
+        /// Git commit command exited with non-zero status
+        #[display("git commit failed: {stderr}")]
+        CommitExitError { stderr: String },
+        /// Failed to execute the git ls-files command
+        #[display("Failed to run git ls-files: {message}")]
+        LsFilesFailed { message: String },
+        /// Git ls-files command exited with non-zero status
+        #[display("git ls-files failed: {stderr}")]
+        LsFilesExitError { stderr: String },
+        /// Failed to execute the git cat-file command
+        #[display("Failed to run git cat-file: {message}")]
+        CatFileFailed { message: String },
+        /// Git cat-file command exited with non-zero status
+        #[display("git cat-file failed: {stderr}")]
+        CatFileExitError { stderr: String },
+        /// A revision does not name a commit
+        #[display("'{rev}' is not a commit")]
+        UnknownCommit { rev: String },
+        /// Failed to run or feed the git blame command
+        #[display("Failed to run git blame: {message}")]
+        BlameFailed { message: String },
+        /// Git blame command exited with non-zero status
+        #[display("git blame failed: {stderr}")]
+        BlameExitError { stderr: String },
+    }
+}
+
+// error_set's generated `source()` forwards to the wrapped error's own source,
+// which for an `io::Error` from running git is `None`; the `io::Error` itself is
+// what callers need to tell a missing git binary (`NotFound`) from other failures
+impl std::error::Error for GitCommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::DiffFailed { source } | Self::ApplySpawnFailed { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for GitLinesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::DiffFailed { source }
+            | Self::ApplySpawnFailed { source }
+            | Self::OutputFailed { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// What a staging operation staged, and how its hunks were laid out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageReport {
+    /// The staged lines
+    pub staged: diff::Diff,
+    /// Hunk layout of each staged file, in the same order as `staged`
+    pub files: Vec<HunkSplits>,
+    /// Where each selected line landed, in the order of the unstaged diff
+    pub lines: Vec<StagedLine>,
+    /// Line refs of the selection that named no changed line, as listed by
+    /// [`GitLines::unmatched_refs`]; the rest of the selection was staged
+    pub unmatched: Vec<String>,
+}
+
+impl Default for StageReport {
+    fn default() -> Self {
+        Self {
+            staged: diff::Diff { files: Vec::new() },
+            files: Vec::new(),
+            lines: Vec::new(),
+            unmatched: Vec::new(),
+        }
+    }
+}
+
+impl StageReport {
+    /// Build a report from a filtered diff and its hunk sources
+    /// (see [`diff::Diff::filter_with_sources`])
+    fn new(staged: diff::Diff, sources: Vec<Vec<usize>>) -> Self {
+        let files = staged
+            .files
+            .iter()
+            .zip(sources)
+            .map(|(file, sources)| HunkSplits {
+                path: file.path.clone(),
+                hunks: file.hunks.len(),
+                split: (0..sources.len())
+                    .filter(|&i| sources.iter().filter(|&&s| s == sources[i]).count() > 1)
+                    .collect(),
+            })
+            .collect();
+        Self {
+            staged,
+            files,
+            lines: Vec::new(),
+            unmatched: Vec::new(),
+        }
+    }
+
+    /// Add the report of another file's staging to this one
+    fn append(&mut self, other: StageReport) {
+        self.staged.files.extend(other.staged.files);
+        self.files.extend(other.files);
+        self.lines.extend(other.lines);
+        self.unmatched.extend(other.unmatched);
+    }
+
+    /// One line describing what was staged, for logs
+    ///
+    /// For example `staged 3 additions, 1 deletion in flake.nix (2 hunks)`.
+    #[must_use]
+    pub fn summary(&self) -> String {
+        let paths: Vec<&str> = self.files.iter().map(|f| f.path.as_str()).collect();
+        format!(
+            "staged {}, {} in {} ({})",
+            plural(self.staged.total_additions(), "addition"),
+            plural(self.staged.total_deletions(), "deletion"),
+            paths.join(", "),
+            plural(self.files.iter().map(|f| f.hunks).sum(), "hunk"),
+        )
+    }
+}
+
+/// `count` followed by `noun`, pluralized when count is not one
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {noun}"),
+        n => format!("{n} {noun}s"),
+    }
+}
+
+/// Where one selected line of the unstaged diff landed in the staged patch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StagedLine {
+    /// File path
+    pub path: String,
+    /// The selected line, as a [`parse::LineRef::Delete`] of its old line or
+    /// a [`parse::LineRef::Add`] of its new line in the unstaged diff
+    pub line: parse::LineRef,
+    /// Index of the staged hunk holding the line, among its file's hunks in
+    /// [`StageReport::staged`]
+    pub hunk: usize,
+    /// The line's number in that hunk: the same old line for a deletion, and
+    /// for an addition its line in the index once staged
+    pub staged_line: u32,
+}
+
+/// How one file's staged lines were laid out into hunks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HunkSplits {
+    /// File path
+    pub path: String,
+    /// Number of hunks in the staged patch for this file
+    pub hunks: usize,
+    /// Indices of the staged hunks that came from splitting one diff hunk
+    pub split: Vec<usize>,
+}
+
+/// Which lines [`GitLines::stage_by_file`] stages, and where
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StageMode<'a> {
+    /// The named lines, as [`GitLines::stage`] stages them
+    #[default]
+    Lines,
+    /// Every changed line but the named ones, as [`GitLines::stage_except`]
+    Except,
+    /// The named lines, into the given path, as [`GitLines::stage_as`]
+    As(&'a str),
+    /// The named lines of the given stash entry, as
+    /// [`GitLines::stage_from_stash`]
+    FromStash(&'a str),
+    /// The named lines of the given diff text, as
+    /// [`GitLines::stage_from_diff`]
+    FromDiff(&'a str),
+}
+
+/// What staging one file's selections did, from [`GitLines::stage_by_file`]
+#[derive(Debug)]
+pub struct FileStaging {
+    /// The selections staged together, as given
+    pub specs: Vec<String>,
+    /// What was staged, or why nothing was
+    pub result: Result<StageReport, GitLinesError>,
+}
+
+/// Number of changed lines a selection resolves to, or a file's unstaged totals
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LineCount {
+    /// Added lines that would be staged
+    pub additions: usize,
+    /// Deleted lines that would be staged
+    pub deletions: usize,
+}
+
+/// Which pair of trees a diff compares
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DiffSource {
+    /// Unstaged changes: the working tree against the index (`git diff`)
+    #[default]
+    WorkingTree,
+    /// Staged changes: the index against `HEAD` (`git diff --cached`)
+    Index,
+}
+
+impl fmt::Display for DiffSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DiffSource::WorkingTree => "working tree",
+            DiffSource::Index => "index",
+        })
+    }
+}
+
+/// What [`GitLines::diff_with_options`] diffs and how it renders the result
+///
+/// Only the source and the rendering are options: the diff itself is always
+/// git's zero-context diff with its default algorithm and whitespace handling
+/// and paths from the repository root, since those are the lines and paths
+/// that staging tokens address.
+///
+/// # Examples
+/// ```
+/// # use git_lines::DiffOptions;
+/// let options = DiffOptions::new().staged(true).sort_files(true);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffOptions {
+    display: diff::DisplayOptions,
+    context: Option<usize>,
+    sort_files: bool,
+    source: DiffSource,
+}
+
+impl DiffOptions {
+    /// Unstaged changes in git's file order, without display tweaks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Draw leading tabs in line content as `→   `
+    ///
+    /// See [`diff::Diff::display_show_tabs`].
+    #[must_use]
+    pub fn show_tabs(mut self, show: bool) -> Self {
+        self.display.show_tabs = show;
+        self
+    }
+
+    /// Escape control characters in line content
+    ///
+    /// See [`diff::Diff::display_with`].
+    #[must_use]
+    pub fn escape_control(mut self, escape: bool) -> Self {
+        self.display.escape_control = escape;
+        self
+    }
+
+    /// List each file's hunks in sections: additions, deletions, replacements
+    ///
+    /// See [`diff::Diff::display_with`].
+    #[must_use]
+    pub fn group_by_kind(mut self, group: bool) -> Self {
+        self.display.group_by_kind = group;
+        self
+    }
+
+    /// Color deletion lines red and addition lines green with ANSI escapes
+    ///
+    /// See [`diff::DisplayOptions::color`].
+    #[must_use]
+    pub fn color(mut self, color: bool) -> Self {
+        self.display.color = color;
+        self
+    }
+
+    /// Show up to `lines` unchanged lines around each change, like `git diff -U`
+    ///
+    /// See [`GitLines::display_with_context`].
+    #[must_use]
+    pub fn context(mut self, lines: usize) -> Self {
+        self.context = Some(lines);
+        self
+    }
+
+    /// Order files by path instead of git's diff order
+    #[must_use]
+    pub fn sort_files(mut self, sort: bool) -> Self {
+        self.sort_files = sort;
+        self
+    }
+
+    /// Diff the index against `HEAD` instead of the working tree against the index
+    ///
+    /// Files then select whole paths or directories of the staged diff.
+    #[must_use]
+    pub fn staged(mut self, staged: bool) -> Self {
+        self.source = if staged {
+            DiffSource::Index
+        } else {
+            DiffSource::WorkingTree
+        };
+        self
+    }
+
+    /// Diff `source`; [`staged`](Self::staged) with the source named
+    #[must_use]
+    pub fn source(mut self, source: DiffSource) -> Self {
+        self.source = source;
+        self
     }
 }
 
 /// Main interface for git-lines operations
 pub struct GitLines {
     repo_path: PathBuf,
+    sort_files: bool,
+    display: diff::DisplayOptions,
+    max_indent: Option<usize>,
+    tab_width: usize,
+    idempotent: bool,
+    require_complete: bool,
+    strict: bool,
+    reject_conflict_markers: bool,
+    require_balanced: bool,
+    three_way: bool,
+    ignore_case: bool,
+    coalesce_hunks: bool,
+    only_change: Option<diff::file::FileChange>,
+    verify_apply: bool,
+    introduced_by: Option<String>,
+    allowed_extensions: Option<Vec<String>>,
+    pre_apply_hook: Option<Arc<PreApplyHook>>,
+    profile_hook: Option<Arc<ProfileHook>>,
+    backend: Box<dyn GitBackend + Send + Sync>,
+}
+
+/// Callback given each patch before it is applied; see [`GitLines::pre_apply_hook`]
+pub type PreApplyHook = dyn Fn(&str) -> Result<(), String> + Send + Sync;
+
+/// Callback given the time each phase took; see [`GitLines::profile_hook`]
+pub type ProfileHook = dyn Fn(Phase, Duration) + Send + Sync;
+
+/// A step of reading or staging a diff, as timed by [`GitLines::profile_hook`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    /// Running `git diff` (or `git diff --cached`)
+    GitDiff,
+    /// Parsing its output
+    Parse,
+    /// Narrowing the parsed diff to the selected lines
+    Filter,
+    /// Running `git apply --cached`
+    GitApply,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::GitDiff => "git diff",
+            Phase::Parse => "parse",
+            Phase::Filter => "filter",
+            Phase::GitApply => "git apply",
+        })
+    }
 }
 
 impl GitLines {
@@ -121,172 +576,4393 @@ impl GitLines {
     pub fn new(repo_path: impl AsRef<Path>) -> Self {
         Self {
             repo_path: repo_path.as_ref().to_path_buf(),
+            sort_files: false,
+            display: diff::DisplayOptions::default(),
+            max_indent: None,
+            tab_width: 4,
+            idempotent: false,
+            require_complete: false,
+            strict: false,
+            reject_conflict_markers: false,
+            require_balanced: false,
+            three_way: false,
+            ignore_case: false,
+            coalesce_hunks: false,
+            only_change: None,
+            verify_apply: false,
+            introduced_by: None,
+            allowed_extensions: None,
+            pre_apply_hook: None,
+            profile_hook: None,
+            backend: Box::new(SystemGit::default()),
         }
     }
 
-    /// Stage specific lines from a file
+    /// Run git operations through `backend` instead of the `git` binary
     ///
-    /// Returns the staged diff for display/confirmation purposes.
+    /// The backend, like the hooks, must be `Send + Sync`, so the stager can
+    /// be shared with or moved to other threads.
     ///
     /// # Examples
     /// ```no_run
-    /// # use git_lines::GitLines;
-    /// let stager = GitLines::new(".");
-    /// let staged = stager.stage("flake.nix:137").unwrap();
-    /// println!("{}", staged); // Show what was staged
+    /// # use git_lines::{GitLines, SystemGit};
+    /// let stager = GitLines::new(".").backend(SystemGit::default());
     /// ```
-    pub fn stage(&self, file_ref: &str) -> Result<diff::Diff, GitLinesError> {
-        self.stage_lines(&parse::FileLineRefs::parse(file_ref)?)
+    #[must_use]
+    pub fn backend(mut self, backend: impl GitBackend + Send + Sync + 'static) -> Self {
+        self.backend = Box::new(backend);
+        self
     }
 
-    /// Get formatted diff output for specified files (or all files if empty)
+    /// Order staged diffs by file path instead of git's emission order
     ///
-    /// Returns diff output formatted with explicit line numbers for easy staging.
+    /// Staging is unaffected; this only makes the returned [`diff::Diff`] (and
+    /// anything rendered from it) stable regardless of `diff.orderFile` and
+    /// similar config.
     ///
     /// # Examples
     /// ```no_run
     /// # use git_lines::GitLines;
-    /// let stager = GitLines::new(".");
-    /// let diff = stager.diff(&[] as &[&str]).unwrap(); // all files
-    /// let diff = stager.diff(&["flake.nix"]).unwrap(); // specific file
+    /// let stager = GitLines::new(".").sort_files(true);
+    /// print!("{}", stager.stage_all(None).unwrap());
     /// ```
-    pub fn diff<I, S>(&self, files: I) -> Result<String, GitLinesError>
-    where
-        I: IntoIterator<Item = S>,
-        S: AsRef<str>,
-    {
-        let files: Vec<String> = files.into_iter().map(|s| s.as_ref().to_string()).collect();
-        let raw_diff = self.get_raw_diff(&files)?;
-        let parsed = diff::Diff::parse(&raw_diff);
-        Ok(parsed.to_string())
+    #[must_use]
+    pub fn sort_files(mut self, sort: bool) -> Self {
+        self.sort_files = sort;
+        self
     }
 
-    /// Get raw git diff output with zero context lines
-    fn get_raw_diff(&self, files: &[String]) -> Result<String, GitCommandError> {
-        let repo_path_str = self
-            .repo_path
-            .to_str()
-            .ok_or(GitCommandError::InvalidRepoPath)?;
-        let mut args = vec![
-            "-C",
-            repo_path_str,
-            "diff",
-            "--no-ext-diff",
-            "-U0",
-            "--no-color",
-        ];
-
-        args.extend(files.iter().map(|s| s.as_str()));
-
-        let output =
-            Command::new("git")
-                .args(&args)
-                .output()
-                .map_err(|e| GitCommandError::DiffFailed {
-                    message: e.to_string(),
-                })?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(GitCommandError::DiffExitError {
-                stderr: stderr.into_owned(),
+    /// Create a GitLines after checking that the path is inside a git work tree
+    ///
+    /// Unlike [`new`](Self::new), which defers any failure to the first
+    /// operation, this fails up front with [`GitLinesError::NotARepository`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::open(".").unwrap();
+    /// ```
+    pub fn open(repo_path: impl AsRef<Path>) -> Result<Self, GitLinesError> {
+        let stager = Self::new(repo_path);
+        if !stager.backend.is_work_tree(&stager.repo_path)? {
+            return Err(GitLinesError::NotARepository {
+                path: stager.repo_path.display().to_string(),
             });
         }
+        Ok(stager)
+    }
 
-        String::from_utf8(output.stdout).map_err(|e| GitCommandError::InvalidUtf8 {
-            message: e.to_string(),
-        })
+    /// Repository path this GitLines operates on
+    pub fn repo_path(&self) -> &Path {
+        &self.repo_path
     }
 
-    /// Stage specific lines from a file, returning the staged diff
-    fn stage_lines(&self, file_refs: &parse::FileLineRefs) -> Result<diff::Diff, GitLinesError> {
-        let diff_output = self.get_raw_diff(std::slice::from_ref(&file_refs.file))?;
+    /// Draw leading tabs in line content as `→   ` in formatted diff output
+    ///
+    /// See [`diff::Diff::display_show_tabs`].
+    #[must_use]
+    pub fn show_tabs(mut self, show: bool) -> Self {
+        self.display.show_tabs = show;
+        self
+    }
 
-        if diff_output.trim().is_empty() {
-            return Err(GitLinesError::NoChanges {
-                file: file_refs.file.clone(),
-            });
-        }
+    /// Escape control characters in line content in formatted diff output
+    ///
+    /// See [`diff::Diff::display_with`].
+    #[must_use]
+    pub fn escape_control(mut self, escape: bool) -> Self {
+        self.display.escape_control = escape;
+        self
+    }
 
-        let full_diff = diff::Diff::parse(&diff_output);
-        let filtered = full_diff.filter(
-            |_path, old_line| {
-                file_refs.refs.iter().any(|r| match r {
-                    parse::LineRef::Delete(n) => n.get() == old_line,
-                    parse::LineRef::DeleteRange(start, end) => {
-                        old_line >= start.get() && old_line <= end.get()
-                    }
-                    parse::LineRef::Add(_) | parse::LineRef::AddRange(_, _) => false,
-                })
-            },
-            |_path, new_line| {
-                file_refs.refs.iter().any(|r| match r {
-                    parse::LineRef::Add(n) => n.get() == new_line,
-                    parse::LineRef::AddRange(start, end) => {
-                        new_line >= start.get() && new_line <= end.get()
-                    }
-                    parse::LineRef::Delete(_) | parse::LineRef::DeleteRange(_, _) => false,
-                })
-            },
-        );
+    /// Only stage changed lines indented at most `columns` deep
+    ///
+    /// Applies on top of the line selection, so `file:1..100` with a limit of 0
+    /// stages just the top-level lines among 1-100. Indentation counts leading
+    /// spaces as one column and tabs up to the next multiple of
+    /// [`tab_width`](Self::tab_width).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").max_indent(0);
+    /// stager.stage("lib.rs:10..40").unwrap(); // only unindented lines
+    /// ```
+    #[must_use]
+    pub fn max_indent(mut self, columns: usize) -> Self {
+        self.max_indent = Some(columns);
+        self
+    }
 
-        if filtered.files.is_empty() {
-            return Err(GitLinesError::NoMatchingLines {
-                file: file_refs.file.clone(),
-            });
-        }
+    /// Columns a tab counts for in [`max_indent`](Self::max_indent) (default 4)
+    #[must_use]
+    pub fn tab_width(mut self, width: usize) -> Self {
+        self.tab_width = width;
+        self
+    }
 
-        self.apply_patch(&filtered.to_patch())?;
-        Ok(filtered)
+    /// Treat staging lines that are already staged as a successful no-op
+    ///
+    /// When a selection matches nothing unstaged, the staged (`--cached`) diff is
+    /// checked for every referenced line; if all are there, staging succeeds
+    /// with an empty [`diff::Diff`] instead of failing with
+    /// [`GitLinesError::NoMatchingLines`]. This makes re-running a staging
+    /// script safe.
+    ///
+    /// Additions are matched by mapping their worktree line number into the
+    /// index. Deletions are matched by old line number against `HEAD`, which
+    /// agrees with the numbers shown by `diff` as long as nothing else in the
+    /// file was staged before the first run. Aligned deletions (`~-N`) are
+    /// never considered already staged.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").idempotent(true);
+    /// stager.stage("flake.nix:137").unwrap();
+    /// stager.stage("flake.nix:137").unwrap(); // no-op
+    /// ```
+    #[must_use]
+    pub fn idempotent(mut self, idempotent: bool) -> Self {
+        self.idempotent = idempotent;
+        self
     }
 
-    /// Apply a patch to the git index
-    fn apply_patch(&self, patch: &str) -> Result<(), GitCommandError> {
-        use std::io::Write;
-
-        let repo_path_str = self
-            .repo_path
-            .to_str()
-            .ok_or(GitCommandError::InvalidRepoPath)?;
-        let mut child = Command::new("git")
-            .args([
-                "-C",
-                repo_path_str,
-                "apply",
-                "--cached",
-                "--unidiff-zero",
-                "-",
-            ])
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()
-            .map_err(|e| GitCommandError::ApplySpawnFailed {
-                message: e.to_string(),
-            })?;
+    /// Refuse selections that leave any changed line of the file unstaged
+    ///
+    /// For files that must be committed whole or not at all, such as generated
+    /// ones: a selection missing any change fails with
+    /// [`GitLinesError::IncompleteSelection`] listing the missing lines, while
+    /// `file:all` always passes.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").require_complete(true);
+    /// stager.stage("Cargo.lock:all").unwrap();
+    /// ```
+    #[must_use]
+    pub fn require_complete(mut self, require: bool) -> Self {
+        self.require_complete = require;
+        self
+    }
 
-        child
-            .stdin
-            .take()
-            .ok_or(GitCommandError::ApplyStdinFailed)?
-            .write_all(patch.as_bytes())
-            .map_err(|e| GitCommandError::ApplyWriteFailed {
-                message: e.to_string(),
-            })?;
+    /// Refuse selections with line refs that name no changed line
+    ///
+    /// Without this a mistyped ref such as `file.nix:999` is dropped and the
+    /// rest of the selection staged, with the dropped refs listed in
+    /// [`StageReport::unmatched`]; with it, staging fails with
+    /// [`GitLinesError::UnmatchedRefs`] listing every such ref. See
+    /// [`unmatched_refs`](Self::unmatched_refs) to check a selection up front.
+    ///
+    /// Likewise a file whose diff section or hunk could not be parsed fails
+    /// with [`GitLinesError::UnparsedDiff`] rather than staging what remains
+    /// (see [`parse_warnings`](Self::parse_warnings)).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").strict(true);
+    /// stager.stage("flake.nix:137,999").unwrap();
+    /// ```
+    #[must_use]
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
 
-        let output = child
-            .wait_with_output()
-            .map_err(|e| GitCommandError::ApplyWaitFailed {
-                message: e.to_string(),
-            })?;
+    /// Refuse to stage added lines that are merge conflict markers
+    ///
+    /// Guards against committing a half-resolved conflict: a selection adding
+    /// a `<<<<<<<`, `=======`, `|||||||` or `>>>>>>>` line fails with
+    /// [`GitLinesError::ConflictMarkerStaged`] before the index is modified.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").reject_conflict_markers(true);
+    /// stager.stage("flake.nix:10..20").unwrap();
+    /// ```
+    #[must_use]
+    pub fn reject_conflict_markers(mut self, reject: bool) -> Self {
+        self.reject_conflict_markers = reject;
+        self
+    }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(GitCommandError::ApplyExitError {
-                stderr: stderr.into_owned(),
-            });
-        }
+    /// Refuse to stage added lines whose brackets do not balance
+    ///
+    /// A heuristic guard against staging half a block: across each file's
+    /// selected added lines, every `(`, `[` and `{` must be closed by its
+    /// partner within the selection, and every closer must have an opener.
+    /// Otherwise staging fails with [`GitLinesError::UnbalancedSelection`]
+    /// naming the first unmatched delimiter. Brackets inside strings and
+    /// comments count too, and deleted lines are not checked.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").require_balanced(true);
+    /// stager.stage("src/main.rs:10..20").unwrap();
+    /// ```
+    #[must_use]
+    pub fn require_balanced(mut self, require: bool) -> Self {
+        self.require_balanced = require;
+        self
+    }
 
-        Ok(())
+    /// Apply selections by three-way merge (`git apply --3way`)
+    ///
+    /// Lines taken from an older base, such as a stash, are merged against the
+    /// blob the diff was made from, so they land in the right place even when
+    /// the surrounding lines have moved in the index. Files whose blob is
+    /// missing from the repository fall back to a plain apply.
+    ///
+    /// The merge is tried on a copy of the index first: if it conflicts,
+    /// staging fails with [`GitLinesError::MergeConflict`] naming the files,
+    /// and the index is left as it was.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").three_way(true);
+    /// stager.stage_from_stash("stash@{0}", "flake.nix:137").unwrap();
+    /// ```
+    #[must_use]
+    pub fn three_way(mut self, three_way: bool) -> Self {
+        self.three_way = three_way;
+        self
+    }
+
+    /// Match the file of a `file:refs` selection against changed paths
+    /// ignoring case
+    ///
+    /// On case-insensitive filesystems `File.nix` opens `file.nix`, but git
+    /// only knows the latter, so the selection would find no changes. With
+    /// this set, a path without an exact match is resolved to the changed
+    /// path equal to it ignoring case; several such paths fail with
+    /// [`GitLinesError::AmbiguousFile`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").ignore_case(true);
+    /// stager.stage("README.MD:3").unwrap(); // stages README.md
+    /// ```
+    #[must_use]
+    pub fn ignore_case(mut self, ignore: bool) -> Self {
+        self.ignore_case = ignore;
+        self
+    }
+
+    /// Merge staged hunks that touch into one hunk
+    ///
+    /// Selecting lines can leave adjacent changes in separate hunks; see
+    /// [`diff::file::FileDiff::coalesce_hunks`]. The same lines are staged
+    /// either way, but the staged patch and the returned diff read better.
+    #[must_use]
+    pub fn coalesce_hunks(mut self, coalesce: bool) -> Self {
+        self.coalesce_hunks = coalesce;
+        self
+    }
+
+    /// Only stage changes to files that the diff adds, modifies or deletes
+    /// as given by `change`
+    ///
+    /// Other files are left out as if they had no changes, so a selection
+    /// naming only them fails with [`GitLinesError::NoMatchingLines`] and
+    /// [`stage_all`](Self::stage_all) skips them.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// # use git_lines::diff::file::FileChange;
+    /// let stager = GitLines::new(".").only_change(FileChange::Modified);
+    /// stager.stage_all(None).unwrap();
+    /// ```
+    #[must_use]
+    pub fn only_change(mut self, change: diff::file::FileChange) -> Self {
+        self.only_change = Some(change);
+        self
+    }
+
+    /// Check that applying a selection changes the index by exactly the
+    /// selected lines before touching the index
+    ///
+    /// The patch is first applied to a copy of the index, which is then diffed
+    /// against its previous state: each file must lose the selected deletions
+    /// (by old line number and content) and gain the selected additions (in
+    /// order), including any line re-added to supply a missing final newline.
+    /// Anything else fails with [`GitLinesError::VerificationFailed`] and leaves
+    /// the index untouched. This guards against patch-building bugs at the cost
+    /// of a few extra git calls per staging.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").verify_apply(true);
+    /// stager.stage("flake.nix:137").unwrap();
+    /// ```
+    #[must_use]
+    pub fn verify_apply(mut self, verify: bool) -> Self {
+        self.verify_apply = verify;
+        self
+    }
+
+    /// Only stage deletions of lines that commit `rev` last changed
+    ///
+    /// Each file with selected deletions is blamed once per staging call, in
+    /// its index version (the side deletions are numbered against); deletions
+    /// attributed to another commit, and all additions, are left unstaged.
+    /// Useful for cleanups such as removing everything a bad commit added.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").introduced_by("a1b2c3d");
+    /// stager.stage("flake.nix:-1..-200").unwrap();
+    /// ```
+    #[must_use]
+    pub fn introduced_by(mut self, rev: impl Into<String>) -> Self {
+        self.introduced_by = Some(rev.into());
+        self
+    }
+
+    /// Only allow staging files with one of these extensions
+    ///
+    /// Any selection touching another file fails with
+    /// [`GitLinesError::DisallowedFile`] before the index is modified. Extensions
+    /// are given without the dot; `""` admits files with no extension (such as
+    /// `Makefile` or `.env`).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").allowed_extensions(["rs", "toml"]);
+    /// assert!(stager.stage("Cargo.lock:3").is_err());
+    /// ```
+    #[must_use]
+    pub fn allowed_extensions<I, S>(mut self, extensions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.allowed_extensions = Some(
+            extensions
+                .into_iter()
+                .map(|e| e.as_ref().trim_start_matches('.').to_string())
+                .collect(),
+        );
+        self
+    }
+
+    /// Call `hook` with every patch just before it is applied to the index
+    ///
+    /// The hook sees the exact patch text handed to `git apply` and can log
+    /// it, or veto it by returning `Err(reason)`: staging then fails with
+    /// [`GitLinesError::HookRejected`] and the index is left untouched.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").pre_apply_hook(|patch| {
+    ///     if patch.contains("b/secrets.nix") {
+    ///         return Err("secrets.nix is protected".to_string());
+    ///     }
+    ///     Ok(())
+    /// });
+    /// stager.stage("flake.nix:137").unwrap();
+    /// ```
+    #[must_use]
+    pub fn pre_apply_hook(
+        mut self,
+        hook: impl Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.pre_apply_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` with how long each [`Phase`] took, every time one runs
+    ///
+    /// A phase can run several times per operation (one `git diff` to
+    /// resolve the file, another to read it), so sum the durations per phase
+    /// for a breakdown.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".").profile_hook(|phase, elapsed| {
+    ///     eprintln!("{phase}: {elapsed:?}");
+    /// });
+    /// stager.stage("flake.nix:137").unwrap();
+    /// ```
+    #[must_use]
+    pub fn profile_hook(mut self, hook: impl Fn(Phase, Duration) + Send + Sync + 'static) -> Self {
+        self.profile_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Stage specific lines from a file
+    ///
+    /// Returns the staged diff for display/confirmation purposes. The file may be
+    /// omitted (`:137`) when exactly one file has unstaged changes.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// let staged = stager.stage("flake.nix:137").unwrap();
+    /// println!("{}", staged); // Show what was staged
+    /// ```
+    pub fn stage(&self, file_ref: &str) -> Result<diff::Diff, GitLinesError> {
+        self.stage_lines(&self.parse_file_ref(file_ref)?.into(), false)
+            .map(|report| report.staged)
+    }
+
+    /// Stage specific lines from a file, reporting how the hunks were laid out
+    ///
+    /// Stages exactly like [`stage`](Self::stage). The report additionally
+    /// lists, per file, which staged hunks came from splitting a single diff
+    /// hunk, which happens when selected deletions have a gap between them,
+    /// and maps each selected line to the staged hunk and line it landed on.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// let report = stager.stage_report("config.nix:-10,-12").unwrap();
+    /// for file in &report.files {
+    ///     println!("{}: {} hunks, {} split", file.path, file.hunks, file.split.len());
+    /// }
+    /// ```
+    pub fn stage_report(&self, file_ref: &str) -> Result<StageReport, GitLinesError> {
+        self.stage_lines(&self.parse_file_ref(file_ref)?.into(), false)
+    }
+
+    /// The patch [`stage`](Self::stage) would apply, without touching the index
+    ///
+    /// Selection and checks are the same as for staging, so this fails
+    /// exactly when staging would, short of `git apply` itself rejecting the
+    /// patch. With [`three_way`](Self::three_way) the patch carries the blob
+    /// ids a three-way apply needs. Empty when
+    /// [`idempotent`](Self::idempotent) finds the lines already staged.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// print!("{}", stager.stage_preview("flake.nix:137").unwrap());
+    /// ```
+    pub fn stage_preview(&self, file_ref: &str) -> Result<String, GitLinesError> {
+        let staged = self
+            .select_checked(&self.parse_file_ref(file_ref)?.into(), false)?
+            .staged;
+        Ok(if self.three_way {
+            staged.to_three_way_patch()
+        } else {
+            staged.to_patch()
+        })
+    }
+
+    /// Stage several selections, reading and applying each file's changes once
+    ///
+    /// Selections naming the same file are merged and staged from one diff,
+    /// so the line numbers of every selection refer to the same changes;
+    /// staging them one after another would read later ones against an
+    /// index the earlier ones already changed. A selection's `!REF`
+    /// exclusions leave out only lines that selection names, not lines
+    /// another one selects. Files are staged in turn, so a failure leaves
+    /// the files before it staged.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.stage_many(["file.nix:10", "file.nix:-20", "flake.nix:3"]).unwrap();
+    /// ```
+    pub fn stage_many<I, S>(&self, file_refs: I) -> Result<diff::Diff, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.stage_many_report(file_refs)
+            .map(|report| report.staged)
+    }
+
+    /// Stage several selections like [`stage_many`](Self::stage_many),
+    /// reporting on them together as [`stage_report`](Self::stage_report) does
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// let report = stager.stage_many_report(["flake.nix:137", "flake.nix:999"]).unwrap();
+    /// println!("unmatched: {}", report.unmatched.join(", "));
+    /// ```
+    pub fn stage_many_report<I, S>(&self, file_refs: I) -> Result<StageReport, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.stage_merged(file_refs, StageMode::Lines)
+    }
+
+    /// Stage selections file by file, yielding each file's outcome
+    ///
+    /// Selections naming the same file are merged and staged together, as
+    /// [`stage_many`](Self::stage_many) does, in any [`StageMode`]. Unlike
+    /// it, a file that fails does not stop the rest: each file is staged
+    /// only when the iterator reaches it, so a caller can stop at the first
+    /// error or go on and collect them all. A selection that fails to parse
+    /// is yielded alone with its error. For [`StageMode::FromStash`] the
+    /// stash is read once; if that fails, every selection is yielded
+    /// together with the error.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::{GitLines, StageMode};
+    /// let stager = GitLines::new(".");
+    /// for file in stager.stage_by_file(["flake.nix:3", "flake.nix:-10"], StageMode::Except) {
+    ///     if let Err(e) = file.result {
+    ///         eprintln!("{}: {}", file.specs.join(" "), e);
+    ///     }
+    /// }
+    /// ```
+    pub fn stage_by_file<'a, I, S>(
+        &'a self,
+        file_refs: I,
+        mode: StageMode<'a>,
+    ) -> impl Iterator<Item = FileStaging> + 'a
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let (groups, source) = self.file_groups(file_refs, mode);
+        groups.into_iter().map(move |group| FileStaging {
+            result: group
+                .selection
+                .and_then(|file_refs| self.stage_file(&file_refs, source.as_ref(), mode)),
+            specs: group.specs,
+        })
+    }
+
+    /// Stage selections file by file, stopping at the first failure
+    ///
+    /// Every selection is parsed before the index is touched.
+    fn stage_merged<I, S>(
+        &self,
+        file_refs: I,
+        mode: StageMode<'_>,
+    ) -> Result<StageReport, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let (groups, source) = self.file_groups(file_refs, mode);
+        let mut report = StageReport::default();
+        for file_refs in &groups_or_first_error(groups)? {
+            report.append(self.stage_file(file_refs, source.as_ref(), mode)?);
+        }
+        Ok(report)
+    }
+
+    /// Parse selections, merging those naming the same file
+    fn merge_file_refs<I, S>(&self, file_refs: I) -> Result<Vec<FileSpecs>, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        groups_or_first_error(self.file_groups(file_refs, StageMode::Lines).0)
+    }
+
+    /// Parse selections into per-file groups, along with the diff they
+    /// select from when that is not the working tree's
+    ///
+    /// The diff of a stash or given diff text is read and parsed once, and
+    /// selections are resolved against it.
+    fn file_groups<'m, I, S>(
+        &self,
+        file_refs: I,
+        mode: StageMode<'m>,
+    ) -> (Vec<FileGroup>, Option<(diff::Diff, &'m str)>)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let specs = file_refs.into_iter().map(|s| s.as_ref().to_string());
+        let source = match mode {
+            StageMode::FromStash(stash) => Some(
+                self.timed(Phase::GitDiff, || {
+                    self.backend.diff_stash(&self.repo_path, stash)
+                })
+                .map(|raw_diff| (self.parse_diff(&raw_diff), stash)),
+            ),
+            StageMode::FromDiff(raw_diff) => {
+                Some(Ok((self.parse_diff(raw_diff), "the given diff")))
+            }
+            _ => None,
+        };
+        let source = match source.transpose() {
+            Ok(source) => source,
+            Err(e) => {
+                let group = FileGroup {
+                    specs: specs.collect(),
+                    selection: Err(e.into()),
+                };
+                return (vec![group], None);
+            }
+        };
+
+        let mut groups: Vec<FileGroup> = Vec::new();
+        for spec in specs {
+            let parsed = match &source {
+                Some((diff, name)) => self
+                    .narrowed(diff.clone(), &spec, name)
+                    .map(|(file_refs, _)| file_refs),
+                None => self.parse_file_ref(&spec),
+            };
+            let parsed = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    groups.push(FileGroup {
+                        specs: vec![spec],
+                        selection: Err(e),
+                    });
+                    continue;
+                }
+            };
+            let existing = groups.iter().position(|group| {
+                group
+                    .selection
+                    .as_ref()
+                    .is_ok_and(|file_refs| file_refs.file == parsed.file)
+            });
+            match existing.map(|i| &mut groups[i]) {
+                Some(FileGroup {
+                    specs,
+                    selection: Ok(file_refs),
+                }) => match file_refs.push(&spec, parsed.refs) {
+                    Ok(()) => specs.push(spec),
+                    Err(e) => groups.push(FileGroup {
+                        specs: vec![spec],
+                        selection: Err(e.into()),
+                    }),
+                },
+                _ => groups.push(FileGroup {
+                    selection: Ok(FileSpecs::new(&spec, parsed)),
+                    specs: vec![spec],
+                }),
+            }
+        }
+        (groups, source)
+    }
+
+    /// Stage one file's merged selections in `mode`, selecting from
+    /// `source` when given rather than from the working tree
+    fn stage_file(
+        &self,
+        file_refs: &FileSpecs,
+        source: Option<&(diff::Diff, &str)>,
+        mode: StageMode<'_>,
+    ) -> Result<StageReport, GitLinesError> {
+        let Some((diff, _)) = source else {
+            return match mode {
+                StageMode::Except => self.stage_lines(file_refs, true),
+                StageMode::As(target) => self.stage_specs_as(file_refs, target),
+                _ => self.stage_lines(file_refs, false),
+            };
+        };
+        let mut diff = diff.clone();
+        diff.files.retain(|f| f.path == file_refs.file);
+        let report = self.select_from(diff, file_refs, false)?;
+        self.check_staged(&report.staged)?;
+        self.apply_patch(&report.staged)
+            .map_err(|e| match (mode, e) {
+                (StageMode::FromStash(stash), GitLinesError::ApplyExitError { stderr }) => {
+                    GitLinesError::StashConflict {
+                        stash: stash.to_string(),
+                        stderr,
+                    }
+                }
+                (_, e) => e,
+            })?;
+        Ok(report)
+    }
+
+    /// Stage several selections all or nothing, with a single `git apply`
+    ///
+    /// Every selection is resolved before the index is touched, so one that
+    /// fails to parse or match leaves everything unstaged. Selections naming
+    /// the same file are merged, and all files go into one patch, which git
+    /// applies whole or not at all.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.stage_atomic(["flake.nix:137", "config.nix:-10,10"]).unwrap();
+    /// ```
+    pub fn stage_atomic<I, S>(&self, file_refs: I) -> Result<diff::Diff, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut staged = diff::Diff { files: Vec::new() };
+        for file_refs in &self.merge_file_refs(file_refs)? {
+            match self.select_lines(file_refs, false) {
+                Err(GitLinesError::NoChanges { .. } | GitLinesError::NoMatchingLines { .. })
+                    if self.idempotent && self.already_staged(file_refs)? => {}
+                result => staged.files.extend(result?.staged.files),
+            }
+        }
+        if staged.files.is_empty() {
+            return Ok(staged);
+        }
+        if self.sort_files {
+            staged.sort_files();
+        }
+
+        self.check_staged(&staged)?;
+        self.apply_patch(&staged)?;
+        Ok(staged)
+    }
+
+    /// Stage lines selected from one file's changes into another file
+    ///
+    /// The lines are selected from `file_ref` as for [`stage`](Self::stage),
+    /// then applied to `target` in the index instead, for flows that generate
+    /// a change in one file (a template, say) and stage it as another. The
+    /// worktree and the source file's index entry are left alone.
+    ///
+    /// A zero-context patch applies wherever its line numbers point, so
+    /// `target` must hold in the index exactly the content the source's diff
+    /// was taken against; otherwise this fails with
+    /// [`GitLinesError::RemapBaseMismatch`] before touching the index. The
+    /// returned diff names `target`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.stage_as("template.txt:4..6", "instance.txt").unwrap();
+    /// ```
+    pub fn stage_as(&self, file_ref: &str, target: &str) -> Result<diff::Diff, GitLinesError> {
+        self.stage_as_report(file_ref, target)
+            .map(|report| report.staged)
+    }
+
+    /// Stage lines into another file like [`stage_as`](Self::stage_as),
+    /// reporting on them as [`stage_report`](Self::stage_report) does
+    ///
+    /// The report names `target`, as the staged diff does.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// let report = stager.stage_as_report("template.txt:4..6", "instance.txt").unwrap();
+    /// println!("{}", report.summary());
+    /// ```
+    pub fn stage_as_report(
+        &self,
+        file_ref: &str,
+        target: &str,
+    ) -> Result<StageReport, GitLinesError> {
+        self.stage_specs_as(&self.parse_file_ref(file_ref)?.into(), target)
+    }
+
+    /// Stage one file's selections into `target`
+    fn stage_specs_as(
+        &self,
+        file_refs: &FileSpecs,
+        target: &str,
+    ) -> Result<StageReport, GitLinesError> {
+        let mut report = self.select_lines(file_refs, false)?;
+        for file in &mut report.staged.files {
+            let base = match &file.old_blob {
+                Some(blob) => self.backend.cat_blob(&self.repo_path, blob)?,
+                None => String::new(),
+            };
+            // A target missing from the index cannot match either
+            let current = self
+                .backend
+                .cat_blob(&self.repo_path, &format!(":{}", target))
+                .ok();
+            if current.as_deref() != Some(base.as_str()) {
+                return Err(GitLinesError::RemapBaseMismatch {
+                    file: file.path.clone(),
+                    target: target.to_string(),
+                });
+            }
+            file.path = target.to_string();
+            file.renamed_from = None;
+            file.copied_from = None;
+        }
+        for file in &mut report.files {
+            file.path = target.to_string();
+        }
+        for line in &mut report.lines {
+            line.path = target.to_string();
+        }
+
+        self.check_staged(&report.staged)?;
+        self.apply_patch(&report.staged)?;
+        Ok(report)
+    }
+
+    /// Stage every changed line of a file except the referenced ones
+    ///
+    /// The inverse of [`stage`](Self::stage): `file.nix:12,15` stages all of
+    /// `file.nix`'s changes other than lines 12 and 15.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.stage_except("flake.nix:12,-15").unwrap(); // hold back two lines
+    /// ```
+    pub fn stage_except(&self, file_ref: &str) -> Result<diff::Diff, GitLinesError> {
+        self.stage_except_report(file_ref)
+            .map(|report| report.staged)
+    }
+
+    /// Stage every changed line except the referenced ones like
+    /// [`stage_except`](Self::stage_except), reporting on them as
+    /// [`stage_report`](Self::stage_report) does
+    ///
+    /// [`unmatched`](StageReport::unmatched) lists the held-back refs that
+    /// named no changed line.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// let report = stager.stage_except_report("flake.nix:12,-15").unwrap();
+    /// println!("{}", report.summary());
+    /// ```
+    pub fn stage_except_report(&self, file_ref: &str) -> Result<StageReport, GitLinesError> {
+        self.stage_lines(&self.parse_file_ref(file_ref)?.into(), true)
+    }
+
+    /// Stage the lines of a file marked keep in a per-line decision list
+    ///
+    /// Each entry pairs a line reference with whether to stage it, the shape a
+    /// review UI produces when every changed line gets a keep/skip toggle.
+    /// Only lines marked `true` are staged; an empty `file` means the only
+    /// changed file, as with `:137`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// # use git_lines::parse::LineRef;
+    /// # use std::num::NonZeroU32;
+    /// let stager = GitLines::new(".");
+    /// let line = |n| NonZeroU32::new(n).unwrap();
+    /// let decisions = [
+    ///     (LineRef::Delete(line(10)), true),
+    ///     (LineRef::Add(line(10)), true),
+    ///     (LineRef::Add(line(11)), false),
+    /// ];
+    /// stager.stage_decisions("config.nix", &decisions).unwrap();
+    /// ```
+    pub fn stage_decisions(
+        &self,
+        file: &str,
+        decisions: &[(parse::LineRef, bool)],
+    ) -> Result<diff::Diff, GitLinesError> {
+        let file = if file.is_empty() {
+            self.resolve_single_file()?
+        } else {
+            file.to_string()
+        };
+        let refs = decisions
+            .iter()
+            .filter(|(_, keep)| *keep)
+            .map(|(line_ref, _)| line_ref.clone())
+            .collect();
+
+        self.stage_lines(&parse::FileLineRefs { file, refs }.into(), false)
+            .map(|report| report.staged)
+    }
+
+    /// Stage specific lines of a stash entry's changes
+    ///
+    /// Line numbers refer to `git stash show -p <stash>`: deletions to the
+    /// commit the stash was made on, additions to the stashed worktree. The file
+    /// may be omitted (`:137`) when the stash touches exactly one file. Only the
+    /// worktree part of the stash is considered, not its saved index.
+    ///
+    /// If the index has moved on from the stash's base, the selected lines may
+    /// not apply; that is reported as [`GitLinesError::StashConflict`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.stage_from_stash("stash@{0}", "flake.nix:137").unwrap();
+    /// ```
+    pub fn stage_from_stash(
+        &self,
+        stash: &str,
+        file_ref: &str,
+    ) -> Result<diff::Diff, GitLinesError> {
+        self.stage_merged([file_ref], StageMode::FromStash(stash))
+            .map(|report| report.staged)
+    }
+
+    /// Stage specific lines of a diff given as text, without running `git diff`
+    ///
+    /// For a diff captured earlier, e.g. in CI after the working tree has
+    /// moved on. Line numbers refer to `raw_diff`, which must be made with
+    /// zero context lines (`git diff -U0`) for them to line up with the
+    /// numbers [`diff`](Self::diff) shows. The file may be omitted (`:137`)
+    /// when the diff touches exactly one file. The selected lines are checked
+    /// and applied to the index as with [`stage`](Self::stage), so they must
+    /// still apply to it.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let raw_diff = std::fs::read_to_string("changes.diff").unwrap();
+    /// let stager = GitLines::new(".");
+    /// stager.stage_from_diff(&raw_diff, "flake.nix:137").unwrap();
+    /// ```
+    pub fn stage_from_diff(
+        &self,
+        raw_diff: &str,
+        file_ref: &str,
+    ) -> Result<diff::Diff, GitLinesError> {
+        self.stage_many_from_diff(raw_diff, [file_ref])
+    }
+
+    /// Stage several selections of a diff given as text, applying each
+    /// file's lines once
+    ///
+    /// Selects as [`stage_from_diff`](Self::stage_from_diff) does, with
+    /// selections naming the same file merged as in
+    /// [`stage_many`](Self::stage_many). The given diff's line numbers do not
+    /// move as the index changes, so staging such selections one at a time
+    /// would apply later ones at the wrong lines. Every selection is parsed
+    /// before the index is touched; files are then staged in turn.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let raw_diff = std::fs::read_to_string("changes.diff").unwrap();
+    /// let stager = GitLines::new(".");
+    /// stager
+    ///     .stage_many_from_diff(&raw_diff, ["flake.nix:2", "flake.nix:5"])
+    ///     .unwrap();
+    /// ```
+    pub fn stage_many_from_diff<I, S>(
+        &self,
+        raw_diff: &str,
+        file_refs: I,
+    ) -> Result<diff::Diff, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.stage_merged(file_refs, StageMode::FromDiff(raw_diff))
+            .map(|report| report.staged)
+    }
+
+    /// Parse a `file:refs` string against a diff not read from the working
+    /// tree, narrowing the diff to the file it names
+    ///
+    /// `source` names the diff in errors, e.g. the stash it came from.
+    fn narrowed(
+        &self,
+        mut diff: diff::Diff,
+        file_ref: &str,
+        source: &str,
+    ) -> Result<(parse::FileLineRefs, diff::Diff), GitLinesError> {
+        let mut file_refs = parse::FileLineRefs::parse(file_ref)?;
+        if file_refs.file.is_empty() {
+            file_refs.file = single_file(&diff, source)?;
+        } else if self.ignore_case {
+            file_refs.file = path_ignoring_case(&diff, &file_refs.file)?;
+        }
+        diff.files.retain(|f| f.path == file_refs.file);
+        if diff.files.is_empty() {
+            return Err(GitLinesError::NoChanges {
+                file: format!("{} in {}", file_refs.file, source),
+            });
+        }
+        Ok((file_refs, diff))
+    }
+
+    /// Stage every changed line, optionally limited to files under a path prefix
+    ///
+    /// The prefix matches whole path components, so `packages/foo` selects
+    /// `packages/foo/lib.rs` but not `packages/foobar/lib.rs`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.stage_all(Some("packages/foo")).unwrap();
+    /// ```
+    pub fn stage_all(&self, path_prefix: Option<&str>) -> Result<diff::Diff, GitLinesError> {
+        let prefix = path_prefix.map(|p| p.trim_end_matches('/'));
+        let mut parsed = self.parse_diff(&self.get_raw_diff(&[])?);
+        self.retain_change(&mut parsed);
+        let mut selected = self.timed(Phase::Filter, || {
+            let renames = pure_renames(&parsed, |path| {
+                prefix.is_none_or(|p| is_under_path(path, p))
+            });
+            let mut selected = parsed.filter(
+                |path, _| prefix.is_none_or(|p| is_under_path(path, p)),
+                |path, _| prefix.is_none_or(|p| is_under_path(path, p)),
+            );
+            selected.files.extend(renames);
+            selected
+        });
+        if self.sort_files {
+            selected.sort_files();
+        }
+
+        if selected.files.is_empty() {
+            return Err(GitLinesError::NoChanges {
+                file: path_prefix.unwrap_or("working tree").to_string(),
+            });
+        }
+
+        self.check_staged(&selected)?;
+        self.apply_patch(&selected)?;
+        Ok(selected)
+    }
+
+    /// Unstage specific lines, returning the staged lines that were removed
+    /// (numbered as in the staged diff, so not an applicable patch)
+    ///
+    /// The inverse of [`stage`](Self::stage), reading the staged diff (`git
+    /// diff --cached`) instead of the unstaged one. Line numbers therefore
+    /// refer to the staged diff, the index against `HEAD`, as listed by
+    /// [`staged_diff`](Self::staged_diff), not to the working tree: `-N` puts
+    /// back old line N of `HEAD`, and `N` takes line N out of the index. The
+    /// working tree is untouched. The file may be omitted (`:3`) when exactly
+    /// one file has staged changes.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.stage("flake.nix:137..140").unwrap();
+    /// // Line numbers of the staged diff, not of the working tree
+    /// stager.unstage("flake.nix:140").unwrap();
+    /// ```
+    pub fn unstage(&self, file_ref: &str) -> Result<diff::Diff, GitLinesError> {
+        self.reverse_lines(
+            DiffSource::Index,
+            parse::FileLineRefs::parse(file_ref)?.into(),
+        )
+    }
+
+    /// Unstage every staged change to `file`, returning what was removed
+    ///
+    /// [`unstage`](Self::unstage) with `file:all`. A newly added file is left
+    /// empty in the index rather than removed, and a staged rename stays
+    /// staged.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.unstage_all_for_file("flake.nix").unwrap();
+    /// ```
+    pub fn unstage_all_for_file(&self, file: &str) -> Result<diff::Diff, GitLinesError> {
+        self.reverse_lines(
+            DiffSource::Index,
+            FileSpecs {
+                file: file.to_string(),
+                specs: vec![vec![parse::LineRef::All]],
+                star: false,
+            },
+        )
+    }
+
+    /// Throw away specific unstaged lines, returning the lines discarded
+    /// (numbered as in the unstaged diff, so not an applicable patch)
+    ///
+    /// A line-level `git checkout`: the selected lines, in the syntax and
+    /// numbering of [`stage`](Self::stage), are reverted in the working tree,
+    /// so added lines are removed and deleted lines restored. The index is
+    /// untouched. This cannot be undone, since the discarded lines were never
+    /// recorded by git.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// // Drop a debug print added at line 42, keep everything else
+    /// stager.discard("src/main.rs:42").unwrap();
+    /// ```
+    pub fn discard(&self, file_ref: &str) -> Result<diff::Diff, GitLinesError> {
+        self.reverse_lines(
+            DiffSource::WorkingTree,
+            self.parse_file_ref(file_ref)?.into(),
+        )
+    }
+
+    /// Throw away the lines of several selections, reverting each file once
+    ///
+    /// Selections naming the same file are merged and discarded from one
+    /// diff, as [`stage_many`](Self::stage_many) does for staging, so every
+    /// selection's line numbers refer to the same changes; discarding them
+    /// one after another would read later ones against a working tree the
+    /// earlier ones already rewrote. Every selection is parsed before any
+    /// file is touched; files are then reverted in turn, so a failure leaves
+    /// the files before it reverted.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.discard_many(["src/main.rs:2", "src/main.rs:5"]).unwrap();
+    /// ```
+    pub fn discard_many<I, S>(&self, file_refs: I) -> Result<diff::Diff, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut discarded = diff::Diff { files: Vec::new() };
+        for file_refs in self.merge_file_refs(file_refs)? {
+            discarded.files.extend(
+                self.reverse_lines(DiffSource::WorkingTree, file_refs)?
+                    .files,
+            );
+        }
+        Ok(discarded)
+    }
+
+    /// Revert the selected lines of `source`'s diff: take staged lines back
+    /// out of the index, or discard unstaged ones from the working tree
+    ///
+    /// The hunks are reversed, so their old side is the index (or working
+    /// tree), and filtered with the selection's sides swapped; applying that
+    /// is a reverse apply of the selected lines whose line numbers match the
+    /// side being changed.
+    fn reverse_lines(
+        &self,
+        source: DiffSource,
+        mut file_refs: FileSpecs,
+    ) -> Result<diff::Diff, GitLinesError> {
+        let mut changes = self.parsed_diff_from(source, &[] as &[&str])?;
+        let no_changes = |file: &str| match source {
+            DiffSource::Index => GitLinesError::NoStagedChanges {
+                file: file.to_string(),
+            },
+            DiffSource::WorkingTree => self.no_changes(file),
+        };
+        if file_refs.file.is_empty() {
+            if changes.files.is_empty() {
+                return Err(no_changes(&source.to_string()));
+            }
+            file_refs.file = single_file(&changes, &source.to_string())?;
+        }
+        changes.files.retain(|f| f.path == file_refs.file);
+        let Some(changed_file) = changes.files.first() else {
+            return Err(no_changes(&file_refs.file));
+        };
+        if changed_file.binary {
+            return Err(GitLinesError::BinaryFile {
+                file: file_refs.file.clone(),
+            });
+        }
+        if changed_file.hunks.is_empty() {
+            return Err(no_changes(&file_refs.file));
+        }
+
+        let excluded = ExcludedLines::default();
+        let selection = Selection::new(&changes, &file_refs.specs, false, &excluded);
+        let reversed = diff::Diff {
+            files: vec![diff::file::FileDiff {
+                hunks: changed_file
+                    .hunks
+                    .iter()
+                    .map(diff::hunk::Hunk::reversed)
+                    .collect(),
+                old_blob: None,
+                renamed_from: None,
+                old_name: None,
+                copied_from: None,
+                ..changed_file.clone()
+            }],
+        };
+        let reverse = self.timed(Phase::Filter, || {
+            reversed.filter(
+                |path, changed_line| selection.keeps_new(path, changed_line),
+                |path, base_line| selection.keeps_old(path, base_line),
+            )
+        });
+        if reverse.files.is_empty() {
+            return Err(GitLinesError::NoMatchingLines {
+                file: file_refs.file.clone(),
+            });
+        }
+        let reverted = changes.retain_lines(|path, deleted, line, _| {
+            if deleted {
+                selection.keeps_old(path, line)
+            } else {
+                selection.keeps_new(path, line)
+            }
+        });
+
+        match source {
+            DiffSource::Index => self.apply_patch(&reverse)?,
+            DiffSource::WorkingTree => {
+                let patch = reverse.to_patch();
+                self.timed(Phase::GitApply, || {
+                    self.backend.apply_worktree(&self.repo_path, &patch)
+                })?;
+            }
+        }
+        Ok(reverted)
+    }
+
+    /// Commit each hunk of a file separately, oldest line first
+    ///
+    /// Stages and commits one hunk at a time, re-reading the diff between
+    /// commits. Each message is `template` with `{first_line}` replaced by the
+    /// hunk's first changed line (collapsed onto one line and shortened) and
+    /// `{file}` by the path. Anything already staged lands in the first commit.
+    /// Each commit must leave the file with fewer hunks; otherwise this stops
+    /// with [`GitLinesError::HunkNotCommitted`] rather than commit forever.
+    ///
+    /// Returns the commit messages in the order they were made.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.commit_per_hunk("flake.nix", "update {first_line}").unwrap();
+    /// ```
+    pub fn commit_per_hunk(
+        &self,
+        file: &str,
+        template: &str,
+    ) -> Result<Vec<String>, GitLinesError> {
+        let mut messages = Vec::new();
+        let mut remaining = usize::MAX;
+
+        loop {
+            let current = self.parse_diff(&self.get_raw_diff(&[file.to_string()])?);
+            let Some(mut file_diff) = current.files.into_iter().next() else {
+                break;
+            };
+            if file_diff.binary {
+                return Err(GitLinesError::BinaryFile {
+                    file: file_diff.path,
+                });
+            }
+            if file_diff.hunks.is_empty() {
+                break;
+            }
+            if file_diff.hunks.len() >= remaining {
+                return Err(GitLinesError::HunkNotCommitted {
+                    file: file_diff.path,
+                    committed: messages.len(),
+                });
+            }
+            remaining = file_diff.hunks.len();
+            file_diff.hunks.truncate(1);
+            let hunk = &file_diff.hunks[0];
+            let first_line = hunk
+                .new
+                .lines
+                .first()
+                .or(hunk.old.lines.first())
+                .map_or("", String::as_str);
+            let message = commit_message(template, &file_diff.path, first_line);
+
+            let selected = diff::Diff {
+                files: vec![file_diff],
+            };
+            self.check_staged(&selected)?;
+            self.apply_patch(&selected)?;
+            self.backend.commit(&self.repo_path, &message)?;
+            messages.push(message);
+        }
+
+        if messages.is_empty() {
+            return Err(self.no_changes(file));
+        }
+        Ok(messages)
+    }
+
+    /// Count the lines a selection would stage, without touching the index
+    ///
+    /// Fails with the same errors as [`stage`](Self::stage) would, so a
+    /// successful count means the selection is stageable.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// let count = stager.count("config.nix:-10,10..12").unwrap();
+    /// println!("+{} -{}", count.additions, count.deletions);
+    /// ```
+    pub fn count(&self, file_ref: &str) -> Result<LineCount, GitLinesError> {
+        let selected = self
+            .select_lines(&self.parse_file_ref(file_ref)?.into(), false)?
+            .staged;
+
+        Ok(LineCount {
+            additions: selected.total_additions(),
+            deletions: selected.total_deletions(),
+        })
+    }
+
+    /// Count a file's unstaged added and deleted lines
+    ///
+    /// A cheap size check: this reads `git diff --numstat` rather than parsing
+    /// the full diff. Fails with [`GitLinesError::NoChanges`] for an unchanged
+    /// file, [`GitLinesError::FileNotTracked`] for one git does not track, and
+    /// [`GitLinesError::BinaryFile`] when git counts no lines.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// let count = stager.changed_line_count("flake.nix").unwrap();
+    /// println!("+{} -{}", count.additions, count.deletions);
+    /// ```
+    pub fn changed_line_count(&self, file: &str) -> Result<LineCount, GitLinesError> {
+        let numstat = self
+            .backend
+            .diff_numstat(&self.repo_path, &[file.to_string()])?;
+        let Some(line) = numstat.lines().next() else {
+            // Only look further on the error path
+            if self
+                .backend
+                .ls_files(&self.repo_path, &[file.to_string()])?
+                .is_empty()
+            {
+                return Err(GitLinesError::FileNotTracked {
+                    file: file.to_string(),
+                });
+            }
+            return Err(self.no_changes(file));
+        };
+
+        // `added<TAB>deleted<TAB>path`, with `-` for both counts on binary files
+        let mut fields = line.split('\t');
+        match (
+            fields.next().and_then(|n| n.parse().ok()),
+            fields.next().and_then(|n| n.parse().ok()),
+        ) {
+            (Some(additions), Some(deletions)) => Ok(LineCount {
+                additions,
+                deletions,
+            }),
+            _ => Err(GitLinesError::BinaryFile {
+                file: file.to_string(),
+            }),
+        }
+    }
+
+    /// List tracked files whose changes `git diff` does not show
+    ///
+    /// These are files marked skip-worktree (as sparse checkouts do) or
+    /// assume-unchanged; selections from them fail with
+    /// [`GitLinesError::FileSkipWorktree`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// for file in stager.hidden_files().unwrap() {
+    ///     println!("{file}");
+    /// }
+    /// ```
+    pub fn hidden_files(&self) -> Result<Vec<String>, GitLinesError> {
+        Ok(self
+            .backend
+            .ls_files(&self.repo_path, &[])?
+            .lines()
+            .filter_map(hidden_entry)
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Tree object id the index would be committed as, without committing
+    ///
+    /// Two staging plans that leave the index with the same content give the
+    /// same id, so duplicate attempts can be detected before committing. The
+    /// index itself is not modified.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.stage("flake.nix:137").unwrap();
+    /// println!("{}", stager.index_tree_hash().unwrap());
+    /// ```
+    pub fn index_tree_hash(&self) -> Result<String, GitLinesError> {
+        Ok(self.backend.write_tree(&self.repo_path)?)
+    }
+
+    /// Preview what a sequence of selections would stage, without staging
+    ///
+    /// Each `file:refs` selection is staged in order into a scratch copy of the
+    /// index, which is then diffed against `HEAD` and discarded. The result is
+    /// what committing would record, including anything already staged; the
+    /// real index is left untouched.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// let preview = stager.preview(["flake.nix:137", "config.nix:-10,10"]).unwrap();
+    /// println!("{}", preview);
+    /// ```
+    pub fn preview<I, S>(&self, file_refs: I) -> Result<diff::Diff, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let staged = self.with_scratch_index("preview", |scratch| {
+            file_refs
+                .into_iter()
+                .try_for_each(|file_ref| scratch.stage(file_ref.as_ref()).map(drop))?;
+            Ok(scratch.get_raw_diff_from(DiffSource::Index, &[])?)
+        })?;
+
+        Ok(self.parse_diff(&staged))
+    }
+
+    /// Get formatted diff output for specified files (or all files if empty)
+    ///
+    /// Returns diff output formatted with explicit line numbers for easy staging.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// let diff = stager.diff(&[] as &[&str]).unwrap(); // all files
+    /// let diff = stager.diff(&["flake.nix"]).unwrap(); // specific file
+    /// ```
+    pub fn diff<I, S>(&self, files: I) -> Result<String, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut out = Vec::new();
+        self.write_diff(files, &mut out)?;
+        // The formatted diff is built from `String`s, so it is valid UTF-8
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+
+    /// Write the formatted diff for specified files (or all files if empty)
+    /// to `out`
+    ///
+    /// Produces exactly what [`diff`](Self::diff) returns, but hands each
+    /// hunk to `out` as it is formatted instead of building the whole diff
+    /// in memory. `out` is written to often; wrap unbuffered writers in a
+    /// [`BufWriter`](std::io::BufWriter).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.write_diff(&[] as &[&str], std::io::stdout().lock()).unwrap();
+    /// ```
+    pub fn write_diff<I, S, W>(&self, files: I, mut out: W) -> Result<(), GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+        W: std::io::Write,
+    {
+        self.parsed_diff(files)?
+            .write_with(&mut out, self.display)
+            .map_err(|source| GitLinesError::OutputFailed { source })
+    }
+
+    /// Get formatted diff output of `source` for specified files (or all files
+    /// if empty)
+    ///
+    /// With [`DiffSource::Index`] the line numbers are those of the staged
+    /// diff, which [`unstage`](Self::unstage) takes, and which can split an
+    /// already staged hunk back apart. Its files follow
+    /// [`sort_files`](Self::sort_files).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::{DiffSource, GitLines};
+    /// let stager = GitLines::new(".");
+    /// print!("{}", stager.diff_source(DiffSource::Index, &["flake.nix"]).unwrap());
+    /// ```
+    pub fn diff_source<I, S>(&self, source: DiffSource, files: I) -> Result<String, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let options = DiffOptions {
+            display: self.display,
+            sort_files: self.sort_files && source == DiffSource::Index,
+            source,
+            ..DiffOptions::default()
+        };
+        self.diff_with_options(files, &options)
+    }
+
+    /// Get formatted diff output for specified files (or all files if empty),
+    /// as selected and rendered by `options`
+    ///
+    /// [`diff`](Self::diff) is this with default options plus the stager's own
+    /// [`show_tabs`](Self::show_tabs) and [`escape_control`](Self::escape_control).
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::{DiffOptions, GitLines};
+    /// let stager = GitLines::new(".");
+    /// let options = DiffOptions::new().staged(true).show_tabs(true);
+    /// print!("{}", stager.diff_with_options(&["Makefile"], &options).unwrap());
+    /// ```
+    pub fn diff_with_options<I, S>(
+        &self,
+        files: I,
+        options: &DiffOptions,
+    ) -> Result<String, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut parsed = self.parsed_diff_from(options.source, files)?;
+        if options.sort_files {
+            parsed.sort_files();
+        }
+        match options.context {
+            Some(context) => self.display_with_context(&parsed, context, options.display),
+            None => Ok(parsed.display_with(options.display)),
+        }
+    }
+
+    /// Get the parsed unstaged diff for the specified files (or all files if empty)
+    ///
+    /// The structure [`diff`](Self::diff) renders, for walking files, hunks
+    /// and lines directly instead of re-parsing the numbered text, or for
+    /// rendering in other formats, e.g. [`diff::Diff::to_html`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// for file in stager.parsed_diff(&["flake.nix"]).unwrap().files {
+    ///     for hunk in &file.hunks {
+    ///         println!("{}: -{} +{}", file.path, hunk.old.len(), hunk.new.len());
+    ///     }
+    /// }
+    /// print!("{}", stager.parsed_diff(&["flake.nix"]).unwrap().to_html());
+    /// ```
+    pub fn parsed_diff<I, S>(&self, files: I) -> Result<diff::Diff, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.parsed_diff_from(DiffSource::WorkingTree, files)
+    }
+
+    /// Get the parsed diff of `source` for the specified files (or all files
+    /// if empty)
+    ///
+    /// For the index, files select whole paths or directories of the staged
+    /// diff.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::{DiffSource, GitLines};
+    /// let stager = GitLines::new(".");
+    /// let staged = stager.parsed_diff_from(DiffSource::Index, &["flake.nix"]).unwrap();
+    /// println!("{} lines staged", staged.total_additions());
+    /// ```
+    pub fn parsed_diff_from<I, S>(
+        &self,
+        source: DiffSource,
+        files: I,
+    ) -> Result<diff::Diff, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let files: Vec<String> = files.into_iter().map(|s| s.as_ref().to_string()).collect();
+        let raw_diff = self.get_raw_diff_from(source, &files)?;
+        let mut parsed = self.parse_diff(&raw_diff);
+        if source == DiffSource::Index && !files.is_empty() {
+            parsed
+                .files
+                .retain(|file| files.iter().any(|f| in_path(&file.path, f)));
+        }
+        Ok(parsed)
+    }
+
+    /// Render a diff of unstaged changes as a patch for `patch(1)`
+    ///
+    /// Each file is rendered by [`diff::file::FileDiff::to_plain_patch`] with three
+    /// lines of context, read from the blob the diff was taken against (the
+    /// index version of the file). Fails with [`GitLinesError::StalePreimage`]
+    /// if that blob does not match the diff.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// let diff = stager.parsed_diff(&["flake.nix"]).unwrap();
+    /// print!("{}", stager.plain_patch(&diff).unwrap());
+    /// ```
+    pub fn plain_patch(&self, diff: &diff::Diff) -> Result<String, GitLinesError> {
+        let mut patch = String::new();
+        for file in &diff.files {
+            let old = self.old_content(file)?;
+            patch.push_str(
+                &file
+                    .to_plain_patch(&old, PLAIN_PATCH_CONTEXT)
+                    .ok_or_else(|| GitLinesError::StalePreimage {
+                        file: file.path.clone(),
+                    })?,
+            );
+        }
+        Ok(patch)
+    }
+
+    /// Render a diff in the numbered format with up to `context` unchanged
+    /// lines around each change
+    ///
+    /// Each file is rendered by
+    /// [`diff::file::FileDiff::to_numbered_with_context`], with context read
+    /// from the blob the diff was taken against, as for
+    /// [`plain_patch`](Self::plain_patch). Only the rendering has context;
+    /// the diff, and anything staged from it, stays zero-context. Files
+    /// without hunks (binary files, bare renames) render as in
+    /// [`diff::Diff::display_with`]. Fails with
+    /// [`GitLinesError::StalePreimage`] if the blob does not match the diff.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// # use git_lines::diff::DisplayOptions;
+    /// let stager = GitLines::new(".");
+    /// let diff = stager.parsed_diff(&["flake.nix"]).unwrap();
+    /// print!("{}", stager.display_with_context(&diff, 3, DisplayOptions::default()).unwrap());
+    /// ```
+    pub fn display_with_context(
+        &self,
+        diff: &diff::Diff,
+        context: usize,
+        options: diff::DisplayOptions,
+    ) -> Result<String, GitLinesError> {
+        let mut out = String::new();
+        for file in &diff.files {
+            if file.binary || file.hunks.is_empty() {
+                let alone = diff::Diff {
+                    files: vec![file.clone()],
+                };
+                out.push_str(&alone.display_with(options));
+                continue;
+            }
+            let old = self.old_content(file)?;
+            out.push_str(
+                &file
+                    .to_numbered_with_context(&old, context, options)
+                    .ok_or_else(|| GitLinesError::StalePreimage {
+                        file: file.path.clone(),
+                    })?,
+            );
+        }
+        Ok(out)
+    }
+
+    /// Content of the blob a file's diff was taken against, empty for a new file
+    fn old_content(&self, file: &diff::file::FileDiff) -> Result<String, GitLinesError> {
+        Ok(match &file.old_blob {
+            Some(blob) => self.backend.cat_blob(&self.repo_path, blob)?,
+            None => String::new(),
+        })
+    }
+
+    /// Get formatted output for everything currently staged in the index
+    ///
+    /// Unlike the diff returned by [`stage`](Self::stage), which covers only that
+    /// call, this shows the cumulative staged state in the same numbered format
+    /// as [`diff`](Self::diff), with files ordered as
+    /// [`sort_files`](Self::sort_files) says.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// stager.stage("flake.nix:137").unwrap();
+    /// print!("{}", stager.staged_diff().unwrap());
+    /// ```
+    pub fn staged_diff(&self) -> Result<String, GitLinesError> {
+        let raw_diff = self.get_raw_diff_from(DiffSource::Index, &[])?;
+        let mut staged = self.parse_diff(&raw_diff);
+        if self.sort_files {
+            staged.sort_files();
+        }
+        Ok(self.format(&staged))
+    }
+
+    /// Get a numbered menu of hunks for the specified files (or all files if empty)
+    ///
+    /// Each line holds an index, the token that stages that hunk, and its first
+    /// changed line; see [`diff::Diff::plan`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// print!("{}", stager.plan(&["flake.nix"]).unwrap());
+    /// ```
+    pub fn plan<I, S>(&self, files: I) -> Result<String, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let files: Vec<String> = files.into_iter().map(|s| s.as_ref().to_string()).collect();
+        let raw_diff = self.get_raw_diff(&files)?;
+        Ok(self.parse_diff(&raw_diff).plan())
+    }
+
+    /// Selections that would stage exactly what `patch` changes, one per file
+    ///
+    /// Each hunk becomes a deletion and an addition range as in
+    /// [`diff::Diff::tokens`]; content-free renames select `rename`, and
+    /// binary files are left out. The line numbers are the patch's own, so
+    /// they match `git lines diff` when the patch was taken against the index
+    /// and the worktree holds its result.
+    ///
+    /// # Examples
+    /// ```
+    /// # use git_lines::GitLines;
+    /// let patch = "diff --git a/flake.nix b/flake.nix
+    /// --- a/flake.nix
+    /// +++ b/flake.nix
+    /// @@ -15 +15 @@
+    /// -old
+    /// +new
+    /// ";
+    /// let tokens = GitLines::tokens_for_patch(patch);
+    /// assert_eq!(tokens[0].to_string(), "flake.nix:-15,15");
+    /// ```
+    #[must_use]
+    pub fn tokens_for_patch(patch: &str) -> Vec<parse::FileLineRefs> {
+        diff::Diff::parse(patch)
+            .files
+            .into_iter()
+            .filter(|file| !file.binary)
+            .filter_map(|file| {
+                let refs: Vec<parse::LineRef> = if file.hunks.is_empty() {
+                    file.renamed_from.as_ref()?;
+                    vec![parse::LineRef::Rename]
+                } else {
+                    file.hunks
+                        .iter()
+                        .flat_map(|hunk| [side_ref(&hunk.old, true), side_ref(&hunk.new, false)])
+                        .flatten()
+                        .collect()
+                };
+                Some(parse::FileLineRefs {
+                    file: file.path,
+                    refs,
+                })
+            })
+            .collect()
+    }
+
+    /// Diff two arbitrary files outside the index (`git diff --no-index`)
+    ///
+    /// Relative paths are resolved against the repository path. When `refs` is
+    /// given (`REFS` syntax without a file, e.g. `-10,12`), only those lines are
+    /// kept, so the result renders as a partial patch via [`diff::Diff::to_patch`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// print!("{}", stager.diff_no_index("a.txt", "b.txt", None).unwrap());
+    /// print!("{}", stager.diff_no_index("a.txt", "b.txt", Some("2")).unwrap().to_patch());
+    /// ```
+    pub fn diff_no_index(
+        &self,
+        old: impl AsRef<Path>,
+        new: impl AsRef<Path>,
+        refs: Option<&str>,
+    ) -> Result<diff::Diff, GitLinesError> {
+        let raw_diff = self.get_raw_diff_no_index(old.as_ref(), new.as_ref())?;
+        let parsed = self.parse_diff(&raw_diff);
+
+        let Some(refs) = refs else {
+            return Ok(parsed);
+        };
+        let (filtered, _) = filter_by_refs(
+            parsed,
+            &[parse::parse_line_refs(refs)?],
+            false,
+            &ExcludedLines::default(),
+        );
+        if filtered.files.is_empty() {
+            return Err(GitLinesError::NoMatchingLines {
+                file: new.as_ref().display().to_string(),
+            });
+        }
+        Ok(filtered)
+    }
+
+    /// Render a diff in the numbered format, honoring display options
+    fn format(&self, diff: &diff::Diff) -> String {
+        diff.display_with(self.display)
+    }
+
+    /// A copy of this stager's settings running on `backend`
+    fn with_backend(&self, backend: Box<dyn GitBackend + Send + Sync>) -> Self {
+        GitLines {
+            repo_path: self.repo_path.clone(),
+            sort_files: self.sort_files,
+            display: self.display,
+            max_indent: self.max_indent,
+            tab_width: self.tab_width,
+            idempotent: self.idempotent,
+            require_complete: self.require_complete,
+            strict: self.strict,
+            reject_conflict_markers: self.reject_conflict_markers,
+            require_balanced: self.require_balanced,
+            three_way: self.three_way,
+            ignore_case: self.ignore_case,
+            coalesce_hunks: self.coalesce_hunks,
+            only_change: self.only_change,
+            verify_apply: self.verify_apply,
+            introduced_by: self.introduced_by.clone(),
+            allowed_extensions: self.allowed_extensions.clone(),
+            pre_apply_hook: self.pre_apply_hook.clone(),
+            profile_hook: self.profile_hook.clone(),
+            backend,
+        }
+    }
+
+    /// Run `f` with a GitLines working on a scratch copy of the index
+    ///
+    /// The copy sits next to the index under a name unique to this call (see
+    /// [`backend::scratch_index`]), so concurrent callers never share one, and
+    /// is removed afterwards.
+    fn with_scratch_index<T>(
+        &self,
+        purpose: &str,
+        f: impl FnOnce(&GitLines) -> Result<T, GitLinesError>,
+    ) -> Result<T, GitLinesError> {
+        let index = self.backend.index_path(&self.repo_path)?;
+        let scratch = backend::scratch_index(&index, purpose).map_err(|e| {
+            GitCommandError::ScratchIndexFailed {
+                message: e.to_string(),
+            }
+        })?;
+
+        let result = f(&self.with_backend(self.backend.with_index_file(&scratch)));
+        // Best effort: a leftover scratch file is harmless
+        let _ = std::fs::remove_file(&scratch);
+        result
+    }
+
+    /// The error for a file with no unstaged changes
+    ///
+    /// A file git is told not to diff gets [`GitLinesError::FileSkipWorktree`]
+    /// rather than a baffling [`GitLinesError::NoChanges`]. Failing to check
+    /// falls back to the latter.
+    fn no_changes(&self, file: &str) -> GitLinesError {
+        let hidden = self
+            .backend
+            .ls_files(&self.repo_path, &[file.to_string()])
+            .is_ok_and(|entries| entries.lines().filter_map(hidden_entry).any(|p| p == file));
+        if hidden {
+            GitLinesError::FileSkipWorktree {
+                file: file.to_string(),
+            }
+        } else {
+            GitLinesError::NoChanges {
+                file: file.to_string(),
+            }
+        }
+    }
+
+    /// Get raw git diff output with zero context lines
+    fn get_raw_diff(&self, files: &[String]) -> Result<String, GitCommandError> {
+        self.get_raw_diff_from(DiffSource::WorkingTree, files)
+    }
+
+    /// Get raw git diff output of `source` with zero context lines
+    ///
+    /// The index is diffed whole; `files` only narrows the working tree diff.
+    fn get_raw_diff_from(
+        &self,
+        source: DiffSource,
+        files: &[String],
+    ) -> Result<String, GitCommandError> {
+        self.timed(Phase::GitDiff, || match source {
+            DiffSource::WorkingTree => self.backend.diff(&self.repo_path, files),
+            DiffSource::Index => self.backend.diff_cached(&self.repo_path),
+        })
+    }
+
+    /// Parse raw diff output, timed as [`Phase::Parse`]
+    fn parse_diff(&self, text: &str) -> diff::Diff {
+        self.timed(Phase::Parse, || diff::Diff::parse(text))
+    }
+
+    /// Run `f`, reporting how long it took to the profile hook if one is set
+    fn timed<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let Some(hook) = &self.profile_hook else {
+            return f();
+        };
+        let start = Instant::now();
+        let result = f();
+        hook(phase, start.elapsed());
+        result
+    }
+
+    /// Get raw `git diff --no-index` output with zero context lines
+    fn get_raw_diff_no_index(&self, old: &Path, new: &Path) -> Result<String, GitCommandError> {
+        self.timed(Phase::GitDiff, || {
+            self.backend.diff_no_index(&self.repo_path, old, new)
+        })
+    }
+
+    /// Parse a `file:refs` string, resolving an omitted file name
+    fn parse_file_ref(&self, file_ref: &str) -> Result<parse::FileLineRefs, GitLinesError> {
+        let mut file_refs = parse::FileLineRefs::parse(file_ref)?;
+        if file_refs.file.is_empty() {
+            file_refs.file = self.resolve_single_file()?;
+        } else if self.ignore_case {
+            let changed = self.parse_diff(&self.get_raw_diff(&[])?);
+            file_refs.file = path_ignoring_case(&changed, &file_refs.file)?;
+        }
+        Ok(file_refs)
+    }
+
+    /// The line refs of a selection that name no unstaged changed line
+    ///
+    /// Each ref is listed as written, in selection order. Exclusions
+    /// (`!REF`) are never listed: leaving out nothing is harmless.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// for r in stager.unmatched_refs("flake.nix:137,999").unwrap() {
+    ///     eprintln!("warning: {} matches nothing", r);
+    /// }
+    /// ```
+    pub fn unmatched_refs(&self, file_ref: &str) -> Result<Vec<String>, GitLinesError> {
+        let file_refs = self.parse_file_ref(file_ref)?.into();
+        let diff = self.unstaged_file_diff(&file_refs)?;
+        Ok(unmatched_refs(&diff, file_refs.refs()))
+    }
+
+    /// The parts of the unstaged diff of `files` that could not be parsed
+    ///
+    /// These are left out of every diff read, so a file whose whole section
+    /// was skipped, such as one with only a mode change, has nothing to
+    /// stage. An empty `files` checks the whole diff.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use git_lines::GitLines;
+    /// let stager = GitLines::new(".");
+    /// for warning in stager.parse_warnings(&["run.sh"]).unwrap() {
+    ///     eprintln!("warning: {}", warning);
+    /// }
+    /// ```
+    pub fn parse_warnings<I, S>(&self, files: I) -> Result<Vec<diff::ParseWarning>, GitLinesError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let files: Vec<String> = files.into_iter().map(|f| f.as_ref().to_string()).collect();
+        let raw = self.get_raw_diff(&files)?;
+        Ok(self
+            .timed(Phase::Parse, || diff::Diff::parse_checked(&raw))
+            .1)
+    }
+
+    /// Resolve a file-less selection to the only file with unstaged changes
+    fn resolve_single_file(&self) -> Result<String, GitLinesError> {
+        single_file(&self.parse_diff(&self.get_raw_diff(&[])?), "working tree")
+    }
+
+    /// Stage specific lines from a file, returning what was staged
+    fn stage_lines(
+        &self,
+        file_refs: &FileSpecs,
+        except: bool,
+    ) -> Result<StageReport, GitLinesError> {
+        let report = self.select_checked(file_refs, except)?;
+        if !report.staged.files.is_empty() {
+            self.apply_patch(&report.staged)?;
+        }
+        Ok(report)
+    }
+
+    /// Select lines as [`stage_lines`](Self::stage_lines) would, running
+    /// every check short of applying them
+    fn select_checked(
+        &self,
+        file_refs: &FileSpecs,
+        except: bool,
+    ) -> Result<StageReport, GitLinesError> {
+        let report = match self.select_lines(file_refs, except) {
+            Err(
+                GitLinesError::NoChanges { .. }
+                | GitLinesError::NoMatchingLines { .. }
+                | GitLinesError::UnmatchedRefs { .. },
+            ) if self.idempotent && !except && self.already_staged(file_refs)? => {
+                return Ok(StageReport::default());
+            }
+            result => result?,
+        };
+        self.check_staged(&report.staged)?;
+        Ok(report)
+    }
+
+    /// Filter the unstaged diff of a file down to the selected lines
+    fn select_lines(
+        &self,
+        file_refs: &FileSpecs,
+        except: bool,
+    ) -> Result<StageReport, GitLinesError> {
+        let full_diff = self.unstaged_file_diff(file_refs)?;
+        self.select_from(full_diff, file_refs, except)
+    }
+
+    /// The unstaged diff of the file a selection names
+    fn unstaged_file_diff(&self, file_refs: &FileSpecs) -> Result<diff::Diff, GitLinesError> {
+        // A pathspec naming only the new path hides the rename, so renames are
+        // looked up in the whole diff
+        let rename = file_refs.refs().any(|r| *r == parse::LineRef::Rename);
+        let pathspec = if rename {
+            &[]
+        } else {
+            std::slice::from_ref(&file_refs.file)
+        };
+        let diff_output = self.get_raw_diff(pathspec)?;
+
+        if diff_output.trim().is_empty() {
+            return Err(self.no_changes(&file_refs.file));
+        }
+
+        let (mut full_diff, warnings) =
+            self.timed(Phase::Parse, || diff::Diff::parse_checked(&diff_output));
+        let section = format!(" b/{}", file_refs.file);
+        if self.strict
+            && let Some(warning) = warnings.iter().find(|w| w.header.ends_with(&section))
+        {
+            return Err(GitLinesError::UnparsedDiff {
+                file: file_refs.file.clone(),
+                reason: warning.reason.clone(),
+            });
+        }
+        if rename {
+            full_diff.files.retain(|f| f.path == file_refs.file);
+        } else if let Some(file) = full_diff
+            .files
+            .iter_mut()
+            .find(|f| f.path == file_refs.file && f.change == diff::file::FileChange::Added)
+        {
+            // Likewise an edited rename reads as an added file; diff it again
+            // with the deleted files, so git can pair it with its old path and
+            // its patch moves the old file
+            let deleted = self.backend.ls_deleted(&self.repo_path)?;
+            let mut paths = vec![file_refs.file.clone()];
+            paths.extend(
+                deleted
+                    .split('\0')
+                    .filter(|p| !p.is_empty())
+                    .map(String::from),
+            );
+            if paths.len() > 1
+                && let Some(renamed) = self
+                    .parse_diff(&self.get_raw_diff(&paths)?)
+                    .files
+                    .into_iter()
+                    .find(|f| f.path == file_refs.file && f.renamed_from.is_some())
+            {
+                *file = renamed;
+            }
+        }
+        Ok(full_diff)
+    }
+
+    /// Drop files whose change is not the [`only_change`](Self::only_change) one
+    fn retain_change(&self, diff: &mut diff::Diff) {
+        if let Some(change) = self.only_change {
+            diff.files.retain(|file| file.change == change);
+        }
+    }
+
+    /// Filter an already-read diff down to the selected lines
+    fn select_from(
+        &self,
+        mut full_diff: diff::Diff,
+        file_refs: &FileSpecs,
+        except: bool,
+    ) -> Result<StageReport, GitLinesError> {
+        self.retain_change(&mut full_diff);
+        if full_diff
+            .files
+            .iter()
+            .any(|f| f.binary && f.path == file_refs.file)
+        {
+            return Err(GitLinesError::BinaryFile {
+                file: file_refs.file.clone(),
+            });
+        }
+        let mut excluded = self
+            .max_indent
+            .map(|max| ExcludedLines::deeper_than(&full_diff, max, self.tab_width))
+            .unwrap_or_default();
+        if let Some(rev) = &self.introduced_by {
+            excluded.extend(self.not_introduced_by(&full_diff, rev)?);
+        }
+        let unmatched = unmatched_refs(&full_diff, file_refs.refs());
+        if self.strict && !unmatched.is_empty() {
+            return Err(GitLinesError::UnmatchedRefs {
+                file: file_refs.file.clone(),
+                refs: unmatched,
+            });
+        }
+        if self.require_complete {
+            let missing = unselected_lines(&full_diff, &file_refs.specs, except, &excluded);
+            if !missing.is_empty() {
+                return Err(GitLinesError::IncompleteSelection {
+                    file: file_refs.file.clone(),
+                    missing,
+                });
+            }
+        }
+        let selected = selected_lines(&full_diff, &file_refs.specs, except, &excluded);
+        let (mut filtered, mut sources) = self.timed(Phase::Filter, || {
+            filter_by_refs(full_diff, &file_refs.specs, except, &excluded)
+        });
+        if self.coalesce_hunks {
+            for (file, sources) in filtered.files.iter_mut().zip(&mut sources) {
+                file.coalesce_hunks_with_sources(sources);
+            }
+        }
+        let mut report = StageReport::new(filtered, sources);
+        report.lines = locate_lines(&report.staged, selected);
+        report.unmatched = unmatched;
+        if self.sort_files {
+            report.staged.sort_files();
+            report.files.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+
+        if report.staged.files.is_empty() {
+            return Err(GitLinesError::NoMatchingLines {
+                file: file_refs.file.clone(),
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Changed lines that [`introduced_by`](Self::introduced_by) rules out:
+    /// every addition, and deletions last changed by a commit other than `rev`
+    fn not_introduced_by(
+        &self,
+        diff: &diff::Diff,
+        rev: &str,
+    ) -> Result<ExcludedLines, GitLinesError> {
+        let commit = self.backend.resolve_commit(&self.repo_path, rev)?;
+        let mut excluded = ExcludedLines::default();
+        for file in &diff.files {
+            let changed = |side: fn(&diff::hunk::Hunk) -> &diff::hunk::ModifiedLines| {
+                file.hunks
+                    .iter()
+                    .map(side)
+                    .flat_map(|lines| (0..lines.len() as u32).map(move |i| lines.start + i))
+                    .collect::<Vec<u32>>()
+            };
+            excluded
+                .new
+                .entry(file.path.clone())
+                .or_default()
+                .extend(changed(|hunk| &hunk.new));
+            let deleted = changed(|hunk| &hunk.old);
+            if deleted.is_empty() {
+                continue;
+            }
+            let old = match &file.old_blob {
+                Some(blob) => self.backend.cat_blob(&self.repo_path, blob)?,
+                None => String::new(),
+            };
+            let blame = self.backend.blame(&self.repo_path, file.old_path(), &old)?;
+            let commits = blame_commits(&blame);
+            excluded.old.entry(file.path.clone()).or_default().extend(
+                deleted
+                    .into_iter()
+                    .filter(|line| commits.get(line) != Some(&commit.as_str())),
+            );
+        }
+        Ok(excluded)
+    }
+
+    /// Whether every line referenced by `file_refs` is already in the index
+    fn already_staged(&self, file_refs: &FileSpecs) -> Result<bool, GitLinesError> {
+        let path = file_refs.file.as_str();
+        let unstaged = self.parse_diff(&self.get_raw_diff(std::slice::from_ref(&file_refs.file))?);
+        let staged = self.parse_diff(&self.get_raw_diff_from(DiffSource::Index, &[])?);
+        let staged_hunks: Vec<&diff::hunk::Hunk> = staged
+            .files
+            .iter()
+            .filter(|f| f.path == path)
+            .flat_map(|f| &f.hunks)
+            .collect();
+
+        let staged_addition = |line: u32| {
+            let index_line = to_index_line(&unstaged, path, line);
+            staged_hunks
+                .iter()
+                .any(|h| side_contains(&h.new, index_line))
+        };
+        let staged_deletion = |line: u32| staged_hunks.iter().any(|h| side_contains(&h.old, line));
+
+        Ok(file_refs.refs().all(|r| match *r {
+            parse::LineRef::Add(n) => staged_addition(n.get()),
+            parse::LineRef::AddRange(start, end) => (start.get()..=end.get()).all(staged_addition),
+            parse::LineRef::Delete(n) => staged_deletion(n.get()),
+            parse::LineRef::DeleteRange(start, end) => {
+                (start.get()..=end.get()).all(staged_deletion)
+            }
+            parse::LineRef::DeleteAligned(_)
+            | parse::LineRef::AddRangeFrom(_)
+            | parse::LineRef::DeleteRangeFrom(_)
+            | parse::LineRef::Rename
+            | parse::LineRef::All
+            | parse::LineRef::FinalNewline => false,
+            // Exclusions ask for nothing to be staged
+            parse::LineRef::Exclude(_) => true,
+        }))
+    }
+
+    /// Checks run on a diff before it is applied to the index
+    fn check_staged(&self, diff: &diff::Diff) -> Result<(), GitLinesError> {
+        self.check_allowed(diff)?;
+        if self.reject_conflict_markers {
+            check_conflict_markers(diff)?;
+        }
+        if self.require_balanced {
+            check_balanced(diff)?;
+        }
+        Ok(())
+    }
+
+    /// Reject diffs touching files outside the extension allowlist
+    fn check_allowed(&self, diff: &diff::Diff) -> Result<(), GitLinesError> {
+        let Some(allowed) = &self.allowed_extensions else {
+            return Ok(());
+        };
+
+        for file in &diff.files {
+            let extension = Path::new(&file.path)
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or("");
+            if !allowed.iter().any(|a| a == extension) {
+                return Err(GitLinesError::DisallowedFile {
+                    file: file.path.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a diff to the git index
+    fn apply_patch(&self, diff: &diff::Diff) -> Result<(), GitLinesError> {
+        if let Some(hook) = &self.pre_apply_hook {
+            let patch = if self.three_way {
+                diff.to_three_way_patch()
+            } else {
+                diff.to_patch()
+            };
+            hook(&patch).map_err(|reason| GitLinesError::HookRejected { reason })?;
+        }
+        if self.verify_apply {
+            self.with_scratch_index("verify", |scratch| {
+                let before = scratch.backend.write_tree(&self.repo_path)?;
+                scratch.apply_unverified(diff)?;
+                scratch.verify_applied(diff, &before)
+            })?;
+        }
+        self.apply_unverified(diff)
+    }
+
+    /// Check that the index changed from `tree` by exactly `diff`'s lines
+    fn verify_applied(&self, diff: &diff::Diff, tree: &str) -> Result<(), GitLinesError> {
+        let paths: Vec<String> = diff
+            .files
+            .iter()
+            .flat_map(|file| [file.path.clone(), file.old_path().to_string()])
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let raw_diff = self.timed(Phase::GitDiff, || {
+            self.backend.diff_cached_from(&self.repo_path, tree, &paths)
+        })?;
+        let staged = self.parse_diff(&raw_diff);
+
+        for file in &diff.files {
+            let actual = staged.files.iter().find(|f| f.path == file.path);
+            let detail = match actual {
+                None if file.hunks.is_empty() => continue,
+                None => Some("nothing was staged".to_string()),
+                Some(actual) => staged_mismatch(file, actual),
+            };
+            if let Some(detail) = detail {
+                return Err(GitLinesError::VerificationFailed {
+                    file: file.path.clone(),
+                    detail,
+                });
+            }
+        }
+        if let Some(extra) = staged
+            .files
+            .iter()
+            .find(|f| !diff.files.iter().any(|file| file.path == f.path))
+        {
+            return Err(GitLinesError::VerificationFailed {
+                file: extra.path.clone(),
+                detail: "the file was not selected".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Apply a diff to the index, as three-way merge when configured
+    fn apply_unverified(&self, diff: &diff::Diff) -> Result<(), GitLinesError> {
+        if !self.three_way {
+            let patch = diff.to_patch();
+            return Ok(self.timed(Phase::GitApply, || {
+                self.backend.apply_cached(&self.repo_path, &patch)
+            })?);
+        }
+
+        // A conflicted three-way apply leaves unmerged entries in the index, so
+        // the merge is tried on a copy before the real index is touched
+        let patch = diff.to_three_way_patch();
+        self.with_scratch_index("apply", |scratch| {
+            Ok(scratch.timed(Phase::GitApply, || {
+                scratch.backend.apply_cached_3way(&self.repo_path, &patch)
+            })?)
+        })
+        .map_err(|e| match e {
+            GitLinesError::ApplyExitError { stderr } => {
+                // git lists each conflicted path as `U <path>`
+                let files: Vec<String> = stderr
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("U "))
+                    .map(str::to_string)
+                    .collect();
+                if files.is_empty() {
+                    GitLinesError::ApplyExitError { stderr }
+                } else {
+                    GitLinesError::MergeConflict { files }
+                }
+            }
+            e => e,
+        })?;
+        Ok(self.backend.apply_cached_3way(&self.repo_path, &patch)?)
+    }
+}
+
+/// Context lines around each change in [`GitLines::plain_patch`], as `diff -u`
+pub const PLAIN_PATCH_CONTEXT: usize = 3;
+
+/// How `actual`'s changed lines differ from `expected`'s, if at all
+///
+/// Deletions are compared by old line number and content, which both diffs
+/// share; additions by content in order, since the selection numbers them in
+/// the working tree and the index diff in the index.
+fn staged_mismatch(
+    expected: &diff::file::FileDiff,
+    actual: &diff::file::FileDiff,
+) -> Option<String> {
+    let deletions = |file: &diff::file::FileDiff| -> Vec<(u32, String)> {
+        file.hunks
+            .iter()
+            .flat_map(|h| (h.old.start..).zip(h.old.lines.iter().cloned()))
+            .collect()
+    };
+    let additions = |file: &diff::file::FileDiff| -> Vec<String> {
+        file.hunks
+            .iter()
+            .flat_map(|h| h.new.lines.iter().cloned())
+            .collect()
+    };
+
+    let (want, got) = (deletions(expected), deletions(actual));
+    if want != got {
+        let lines = |lines: &[(u32, String)]| {
+            lines
+                .iter()
+                .map(|(n, _)| format!("-{}", n))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        return Some(format!(
+            "expected deletions [{}], index deleted [{}]",
+            lines(&want),
+            lines(&got)
+        ));
+    }
+    let (want, got) = (additions(expected), additions(actual));
+    if want != got {
+        return Some(format!(
+            "added lines differ: expected {}, index has {}",
+            want.len(),
+            got.len()
+        ));
+    }
+    None
+}
+
+/// Reject diffs that add a merge conflict marker line
+fn check_conflict_markers(diff: &diff::Diff) -> Result<(), GitLinesError> {
+    for file in &diff.files {
+        for hunk in &file.hunks {
+            if let Some(offset) = hunk.new.lines.iter().position(|l| is_conflict_marker(l)) {
+                return Err(GitLinesError::ConflictMarkerStaged {
+                    file: file.path.clone(),
+                    line: hunk.new.start + offset as u32,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fail on the first bracket left unmatched by a file's added lines
+fn check_balanced(diff: &diff::Diff) -> Result<(), GitLinesError> {
+    for file in &diff.files {
+        let added = file
+            .hunks
+            .iter()
+            .flat_map(|hunk| &hunk.new.lines)
+            .flat_map(|text| text.chars().map(move |c| (text, c)));
+        if let Some((text, delimiter)) = unmatched_bracket(added) {
+            return Err(GitLinesError::UnbalancedSelection {
+                file: file.path.clone(),
+                delimiter,
+                text: text.trim().to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The first closer without a matching opener, or else the first opener
+/// left unclosed, from characters tagged with the line they came from
+fn unmatched_bracket<L: Copy>(chars: impl IntoIterator<Item = (L, char)>) -> Option<(L, char)> {
+    let mut open: Vec<(L, char)> = Vec::new();
+    for (line, c) in chars {
+        let opener = match c {
+            '(' | '[' | '{' => {
+                open.push((line, c));
+                continue;
+            }
+            ')' => '(',
+            ']' => '[',
+            '}' => '{',
+            _ => continue,
+        };
+        match open.last() {
+            Some(&(_, o)) if o == opener => {
+                open.pop();
+            }
+            _ => return Some((line, c)),
+        }
+    }
+    open.first().copied()
+}
+
+/// Whether a line is one git writes around conflicting sides of a merge
+///
+/// `<<<<<<<`, `|||||||` (diff3 base) and `>>>>>>>` may carry a label after a
+/// space; the `=======` separator stands alone.
+fn is_conflict_marker(line: &str) -> bool {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    ["<<<<<<<", "|||||||", ">>>>>>>"].iter().any(|marker| {
+        line.strip_prefix(marker)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+    }) || line == "======="
+}
+
+/// Whether `path` is `selector` itself or lies in the directory it names
+fn in_path(path: &str, selector: &str) -> bool {
+    let selector = selector.trim_end_matches('/');
+    path.strip_prefix(selector)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Path of a `git ls-files -v` entry that `git diff` skips
+///
+/// The tag is `S` for skip-worktree and lowercase for assume-unchanged.
+fn hidden_entry(entry: &str) -> Option<&str> {
+    let (tag, path) = entry.split_once(' ')?;
+    (tag == "S" || tag.chars().all(|c| c.is_ascii_lowercase())).then_some(path)
+}
+
+/// Longest `{first_line}` substitution, in characters, before it is cut short
+const FIRST_LINE_MAX: usize = 60;
+
+/// Fill a commit message template for one hunk
+///
+/// The changed line is collapsed onto a single line: surrounding whitespace is
+/// dropped, inner runs of whitespace and control characters become one space,
+/// and it is cut to [`FIRST_LINE_MAX`] characters with a trailing `...`.
+fn commit_message(template: &str, path: &str, first_line: &str) -> String {
+    let words: Vec<&str> = first_line
+        .split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|w| !w.is_empty())
+        .collect();
+    let mut line = words.join(" ");
+    if line.chars().count() > FIRST_LINE_MAX {
+        line = line.chars().take(FIRST_LINE_MAX - 3).collect::<String>() + "...";
+    }
+
+    // Substitute piecewise so braces inside the changed line are left alone
+    template
+        .split("{first_line}")
+        .map(|part| part.replace("{file}", path))
+        .collect::<Vec<_>>()
+        .join(&line)
+        .trim()
+        .to_string()
+}
+
+/// The path in `diff` that `file` names, comparing case-insensitively unless
+/// it matches exactly
+///
+/// A path matching nothing is returned as is, to fail as unchanged later.
+fn path_ignoring_case(diff: &diff::Diff, file: &str) -> Result<String, GitLinesError> {
+    if diff.files.iter().any(|f| f.path == file) {
+        return Ok(file.to_string());
+    }
+    let lower = file.to_lowercase();
+    let matches: Vec<&str> = diff
+        .files
+        .iter()
+        .map(|f| f.path.as_str())
+        .filter(|path| path.to_lowercase() == lower)
+        .collect();
+    match matches.as_slice() {
+        [] => Ok(file.to_string()),
+        [path] => Ok(path.to_string()),
+        paths => Err(GitLinesError::AmbiguousFile {
+            files: paths.iter().map(|p| p.to_string()).collect(),
+        }),
+    }
+}
+
+/// The only file in `diff`, which is described as `source` when empty
+fn single_file(diff: &diff::Diff, source: &str) -> Result<String, GitLinesError> {
+    match diff.files.as_slice() {
+        [] => Err(GitLinesError::NoChanges {
+            file: source.to_string(),
+        }),
+        [file] => Ok(file.path.clone()),
+        files => Err(GitLinesError::AmbiguousFile {
+            files: files.iter().map(|f| f.path.clone()).collect(),
+        }),
+    }
+}
+
+/// Whether `path` is `prefix` itself or lies beneath it as a directory
+fn is_under_path(path: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Map a worktree line outside any unstaged hunk to its line number in the index
+fn to_index_line(unstaged: &diff::Diff, path: &str, line: u32) -> u32 {
+    let shift: i64 = unstaged
+        .files
+        .iter()
+        .filter(|f| f.path == path)
+        .flat_map(|f| &f.hunks)
+        .filter(|h| match h.new.len() {
+            // Pure deletions sit after new line `start`
+            0 => h.new.start < line,
+            len => h.new.start + len as u32 <= line,
+        })
+        .map(|h| h.new.len() as i64 - h.old.len() as i64)
+        .sum();
+    (i64::from(line) - shift).max(0) as u32
+}
+
+/// Whether a hunk side covers `line`
+fn side_contains(side: &diff::hunk::ModifiedLines, line: u32) -> bool {
+    (side.start..side.start + side.len() as u32).contains(&line)
+}
+
+/// Whether `line` falls in any of the inclusive `spans`
+fn in_spans(spans: &[(u32, u32)], line: u32) -> bool {
+    spans
+        .iter()
+        .any(|&(start, end)| (start..=end).contains(&line))
+}
+
+/// Changed lines ruled out regardless of the selection, by path and line number
+#[derive(Default)]
+struct ExcludedLines {
+    old: HashMap<String, HashSet<u32>>,
+    new: HashMap<String, HashSet<u32>>,
+}
+
+impl ExcludedLines {
+    /// Changed lines indented deeper than `max_indent` columns
+    fn deeper_than(diff: &diff::Diff, max_indent: usize, tab_width: usize) -> Self {
+        let mut excluded = Self::default();
+        for file in &diff.files {
+            for hunk in &file.hunks {
+                for (side, lines) in [
+                    (&mut excluded.old, &hunk.old),
+                    (&mut excluded.new, &hunk.new),
+                ] {
+                    let deep = lines
+                        .lines
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, line)| indentation(line, tab_width) > max_indent)
+                        .map(|(i, _)| lines.start + i as u32);
+                    side.entry(file.path.clone()).or_default().extend(deep);
+                }
+            }
+        }
+        excluded
+    }
+
+    /// Rule out `other`'s lines as well
+    fn extend(&mut self, other: Self) {
+        for (mine, theirs) in [(&mut self.old, other.old), (&mut self.new, other.new)] {
+            for (path, lines) in theirs {
+                mine.entry(path).or_default().extend(lines);
+            }
+        }
+    }
+
+    fn contains(lines: &HashMap<String, HashSet<u32>>, path: &str, line: u32) -> bool {
+        lines.get(path).is_some_and(|set| set.contains(&line))
+    }
+}
+
+/// Commit each final line is attributed to in `git blame --porcelain` output
+///
+/// Every group of lines starts with a header `<sha> <orig> <final> [<count>]`;
+/// the line's content follows, tab-prefixed, after any commit metadata.
+fn blame_commits(porcelain: &str) -> HashMap<u32, &str> {
+    porcelain
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let sha = fields.next()?;
+            let _orig = fields.next()?;
+            let line_number = fields.next()?.parse().ok()?;
+            (sha.len() >= 40 && sha.bytes().all(|b| b.is_ascii_hexdigit()))
+                .then_some((line_number, sha))
+        })
+        .collect()
+}
+
+/// Width of a line's leading whitespace, with tabs advancing to the next tab stop
+fn indentation(line: &str, tab_width: usize) -> usize {
+    let tab_width = tab_width.max(1);
+    let mut column = 0;
+    for c in line.chars() {
+        match c {
+            ' ' => column += 1,
+            '\t' => column += tab_width - column % tab_width,
+            _ => break,
+        }
+    }
+    column
+}
+
+/// Content-free renames in `diff` whose path passes `keep`
+///
+/// These have no lines for a filter to select, so callers add them back.
+fn pure_renames(diff: &diff::Diff, keep: impl Fn(&str) -> bool) -> Vec<diff::file::FileDiff> {
+    diff.files
+        .iter()
+        .filter(|f| f.renamed_from.is_some() && f.hunks.is_empty() && keep(&f.path))
+        .cloned()
+        .collect()
+}
+
+/// The line refs selecting changes of one file, from one spec or several
+///
+/// Each spec keeps its own refs, so its `!REF` exclusions only leave out
+/// lines it would select itself: several specs select the union of what
+/// each selects alone.
+struct FileSpecs {
+    file: String,
+    specs: Vec<Vec<parse::LineRef>>,
+    /// Whether the specs are a single bare `*`, which takes no others
+    star: bool,
+}
+
+impl FileSpecs {
+    /// The refs of one `file:refs` spec, as written in `spec`
+    fn new(spec: &str, file_refs: parse::FileLineRefs) -> Self {
+        Self {
+            star: parse::is_star(spec),
+            ..file_refs.into()
+        }
+    }
+
+    /// Add the refs of another spec for the same file
+    ///
+    /// A bare `*` stays alone among the specs for a file, as it does
+    /// within one spec.
+    fn push(&mut self, spec: &str, refs: Vec<parse::LineRef>) -> Result<(), parse::ParseError> {
+        if self.star || parse::is_star(spec) {
+            let other = if self.star { &refs } else { &self.specs[0] };
+            return Err(parse::ParseError::InvalidStar {
+                value: other
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            });
+        }
+        self.specs.push(refs);
+        Ok(())
+    }
+
+    /// Every spec's refs, in order
+    fn refs(&self) -> impl Iterator<Item = &parse::LineRef> {
+        self.specs.iter().flatten()
+    }
+}
+
+impl From<parse::FileLineRefs> for FileSpecs {
+    fn from(file_refs: parse::FileLineRefs) -> Self {
+        Self {
+            file: file_refs.file,
+            specs: vec![file_refs.refs],
+            star: false,
+        }
+    }
+}
+
+/// Selections grouped by the file they name, as given and as parsed
+struct FileGroup {
+    specs: Vec<String>,
+    selection: Result<FileSpecs, GitLinesError>,
+}
+
+/// The parsed selections of every group, or the first group's error
+fn groups_or_first_error(groups: Vec<FileGroup>) -> Result<Vec<FileSpecs>, GitLinesError> {
+    groups.into_iter().map(|group| group.selection).collect()
+}
+
+/// The lines of a diff picked by one or more specs' line refs
+struct Selection<'a> {
+    /// Per spec, the lines its refs name and the lines its exclusions
+    /// (`!REF`) leave out again
+    specs: Vec<(Picks<'a>, Picks<'a>)>,
+    except: bool,
+    excluded: &'a ExcludedLines,
+}
+
+impl<'a> Selection<'a> {
+    /// Select the lines any of `specs` names (or, with `except`, every line
+    /// none names), minus any `excluded` lines
+    fn new(
+        diff: &diff::Diff,
+        specs: &'a [Vec<parse::LineRef>],
+        except: bool,
+        excluded: &'a ExcludedLines,
+    ) -> Self {
+        let specs = specs
+            .iter()
+            .map(|refs| {
+                let mut included = Vec::new();
+                let mut left_out = Vec::new();
+                for r in refs {
+                    match r {
+                        parse::LineRef::Exclude(inner) => left_out.push(inner.as_ref()),
+                        _ => included.push(r),
+                    }
+                }
+                (Picks::new(diff, included), Picks::new(diff, left_out))
+            })
+            .collect();
+
+        Self {
+            specs,
+            except,
+            excluded,
+        }
+    }
+
+    /// Whether the deletion of `old_line` in `path` is selected
+    fn keeps_old(&self, path: &str, old_line: u32) -> bool {
+        let selected = self.specs.iter().any(|(included, left_out)| {
+            included.names_old(old_line) && !left_out.names_old(old_line)
+        });
+        selected != self.except && !ExcludedLines::contains(&self.excluded.old, path, old_line)
+    }
+
+    /// Whether the addition of `new_line` in `path` is selected
+    fn keeps_new(&self, path: &str, new_line: u32) -> bool {
+        let selected = self.specs.iter().any(|(included, left_out)| {
+            included.names_new(new_line) && !left_out.names_new(new_line)
+        });
+        selected != self.except && !ExcludedLines::contains(&self.excluded.new, path, new_line)
+    }
+
+    /// Whether content-free renames are selected
+    fn keeps_renames(&self) -> bool {
+        let selected = self.specs.iter().any(|(included, _)| {
+            included
+                .refs
+                .iter()
+                .any(|r| matches!(r, parse::LineRef::Rename | parse::LineRef::All))
+        });
+        selected != self.except
+    }
+}
+
+/// The changed lines a list of line refs name, resolved against a diff
+struct Picks<'a> {
+    refs: Vec<&'a parse::LineRef>,
+    /// Old lines named by aligned deletions, resolved against the diff
+    aligned_old_lines: Vec<u32>,
+    /// Old line spans named by open deletion ranges, closed at their hunk's end
+    open_old_spans: Vec<(u32, u32)>,
+    /// New line spans named by open addition ranges, closed at their hunk's end
+    open_new_spans: Vec<(u32, u32)>,
+}
+
+impl<'a> Picks<'a> {
+    fn new(diff: &diff::Diff, refs: Vec<&'a parse::LineRef>) -> Self {
+        // Aligned deletions name a new line; resolve them to old lines up front
+        let aligned_old_lines = refs
+            .iter()
+            .filter_map(|r| match r {
+                parse::LineRef::DeleteAligned(n) => diff
+                    .files
+                    .iter()
+                    .flat_map(|f| &f.hunks)
+                    .find_map(|h| h.aligned_old_line(n.get())),
+                _ => None,
+            })
+            .collect();
+
+        // Open ranges run to the end of the hunk side holding their start
+        let close = |side: fn(&diff::hunk::Hunk) -> &diff::hunk::ModifiedLines, start: u32| {
+            diff.files
+                .iter()
+                .flat_map(|f| &f.hunks)
+                .map(side)
+                .find(|lines| side_contains(lines, start))
+                .map(|lines| (start, lines.start + lines.len() as u32 - 1))
+        };
+        let open_old_spans = refs
+            .iter()
+            .filter_map(|r| match r {
+                parse::LineRef::DeleteRangeFrom(n) => close(|h| &h.old, n.get()),
+                _ => None,
+            })
+            .collect();
+        let open_new_spans = refs
+            .iter()
+            .filter_map(|r| match r {
+                parse::LineRef::AddRangeFrom(n) => close(|h| &h.new, n.get()),
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            refs,
+            aligned_old_lines,
+            open_old_spans,
+            open_new_spans,
+        }
+    }
+
+    /// Whether the deletion of `old_line` is named
+    fn names_old(&self, old_line: u32) -> bool {
+        self.aligned_old_lines.contains(&old_line)
+            || in_spans(&self.open_old_spans, old_line)
+            || self.refs.iter().any(|r| match r {
+                parse::LineRef::Delete(n) => n.get() == old_line,
+                parse::LineRef::DeleteRange(start, end) => {
+                    old_line >= start.get() && old_line <= end.get()
+                }
+                parse::LineRef::All => true,
+                parse::LineRef::Add(_)
+                | parse::LineRef::AddRange(_, _)
+                | parse::LineRef::AddRangeFrom(_)
+                | parse::LineRef::DeleteRangeFrom(_)
+                | parse::LineRef::DeleteAligned(_)
+                | parse::LineRef::Rename
+                | parse::LineRef::FinalNewline
+                | parse::LineRef::Exclude(_) => false,
+            })
+    }
+
+    /// Whether the addition of `new_line` is named
+    fn names_new(&self, new_line: u32) -> bool {
+        in_spans(&self.open_new_spans, new_line)
+            || self.refs.iter().any(|r| match r {
+                parse::LineRef::Add(n) => n.get() == new_line,
+                parse::LineRef::AddRange(start, end) => {
+                    new_line >= start.get() && new_line <= end.get()
+                }
+                parse::LineRef::All => true,
+                parse::LineRef::Delete(_)
+                | parse::LineRef::DeleteRange(_, _)
+                | parse::LineRef::AddRangeFrom(_)
+                | parse::LineRef::DeleteRangeFrom(_)
+                | parse::LineRef::DeleteAligned(_)
+                | parse::LineRef::Rename
+                | parse::LineRef::FinalNewline
+                | parse::LineRef::Exclude(_) => false,
+            })
+    }
+}
+
+/// Keep only the diff lines selected by `specs` (or, with `except`, every
+/// line not selected by them), minus any `excluded` lines
+fn filter_by_refs(
+    diff: diff::Diff,
+    specs: &[Vec<parse::LineRef>],
+    except: bool,
+    excluded: &ExcludedLines,
+) -> (diff::Diff, Vec<Vec<usize>>) {
+    let selection = Selection::new(&diff, specs, except, excluded);
+    let renames = if selection.keeps_renames() {
+        pure_renames(&diff, |_| true)
+    } else {
+        Vec::new()
+    };
+    let eol = specs
+        .iter()
+        .flatten()
+        .any(|r| *r == parse::LineRef::FinalNewline);
+    let final_newlines: Vec<_> = if !except && eol {
+        diff.files
+            .iter()
+            .filter_map(|file| {
+                let (index, hunk) = file.final_newline_hunk()?;
+                let file = diff::file::FileDiff {
+                    hunks: Vec::new(),
+                    ..file.clone()
+                };
+                Some((file, index, hunk))
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let (mut filtered, mut sources) = diff.filter_with_sources(
+        |path, old_line| selection.keeps_old(path, old_line),
+        |path, new_line| selection.keeps_new(path, new_line),
+    );
+
+    for rename in renames {
+        filtered.files.push(rename);
+        sources.push(Vec::new());
+    }
+    for (file, index, hunk) in final_newlines {
+        add_final_newline(&mut filtered, &mut sources, file, index, hunk);
+    }
+    (filtered, sources)
+}
+
+/// Add the [`FinalNewline`](parse::LineRef::FinalNewline) hunk taken from
+/// hunk `index` of `file` to the filtered diff
+///
+/// Skipped when lines of that hunk were selected as well: they already
+/// decide how the file ends.
+fn add_final_newline(
+    filtered: &mut diff::Diff,
+    sources: &mut Vec<Vec<usize>>,
+    mut file: diff::file::FileDiff,
+    index: usize,
+    mut hunk: diff::hunk::Hunk,
+) {
+    let Some(i) = filtered.files.iter().position(|f| f.path == file.path) else {
+        file.hunks.push(hunk);
+        filtered.files.push(file);
+        sources.push(vec![index]);
+        return;
+    };
+    if sources[i].contains(&index) {
+        return;
+    }
+    // Earlier staged hunks shift where the line lands in the index
+    let delta: i64 = filtered.files[i]
+        .hunks
+        .iter()
+        .map(|h| h.new.len() as i64 - h.old.len() as i64)
+        .sum();
+    hunk.new.start = (i64::from(hunk.new.start) + delta) as u32;
+    filtered.files[i].hunks.push(hunk);
+    sources[i].push(index);
+}
+
+/// The line ref selecting every line of one hunk side, if it has any
+fn side_ref(side: &diff::hunk::ModifiedLines, deletion: bool) -> Option<parse::LineRef> {
+    let start = NonZeroU32::new(side.start)?;
+    let end = NonZeroU32::new(side.start + side.len().saturating_sub(1) as u32)?;
+    match (side.len(), deletion) {
+        (0, _) => None,
+        (1, true) => Some(parse::LineRef::Delete(start)),
+        (1, false) => Some(parse::LineRef::Add(start)),
+        (_, true) => Some(parse::LineRef::DeleteRange(start, end)),
+        (_, false) => Some(parse::LineRef::AddRange(start, end)),
+    }
+}
+
+/// Changed lines of `diff` that [`filter_by_refs`] keeps, with their content,
+/// as single-line `Delete` and `Add` refs by path
+fn selected_lines(
+    diff: &diff::Diff,
+    specs: &[Vec<parse::LineRef>],
+    except: bool,
+    excluded: &ExcludedLines,
+) -> Vec<(String, parse::LineRef, String)> {
+    let selection = Selection::new(diff, specs, except, excluded);
+    let mut selected = Vec::new();
+
+    for file in &diff.files {
+        for hunk in &file.hunks {
+            for (i, line) in hunk.old.lines.iter().enumerate() {
+                let n = hunk.old.start + i as u32;
+                if let Some(n) = NonZeroU32::new(n)
+                    && selection.keeps_old(&file.path, n.get())
+                {
+                    selected.push((file.path.clone(), parse::LineRef::Delete(n), line.clone()));
+                }
+            }
+            for (i, line) in hunk.new.lines.iter().enumerate() {
+                let n = hunk.new.start + i as u32;
+                if let Some(n) = NonZeroU32::new(n)
+                    && selection.keeps_new(&file.path, n.get())
+                {
+                    selected.push((file.path.clone(), parse::LineRef::Add(n), line.clone()));
+                }
+            }
+        }
+    }
+    selected
+}
+
+/// Find where each of the [`selected_lines`] landed in `staged`
+///
+/// Deletions keep their old line numbers. Additions keep their order, but a
+/// staged hunk may also re-add an unselected line to supply a missing final
+/// newline, so each is matched to the next staged addition with its content.
+fn locate_lines(
+    staged: &diff::Diff,
+    selected: Vec<(String, parse::LineRef, String)>,
+) -> Vec<StagedLine> {
+    let mut additions: HashMap<&str, Vec<(usize, u32, &str)>> = HashMap::new();
+    for file in &staged.files {
+        additions.insert(
+            &file.path,
+            file.hunks
+                .iter()
+                .enumerate()
+                .flat_map(|(i, hunk)| {
+                    let start = hunk.new.start;
+                    hunk.new
+                        .lines
+                        .iter()
+                        .enumerate()
+                        .map(move |(j, line)| (i, start + j as u32, line.as_str()))
+                })
+                .collect(),
+        );
+    }
+    let mut next_addition: HashMap<String, usize> = HashMap::new();
+
+    let mut lines = Vec::new();
+    for (path, line, content) in selected {
+        let Some(file) = staged.files.iter().find(|f| f.path == path) else {
+            continue;
+        };
+        let found = match line {
+            parse::LineRef::Delete(n) => file
+                .hunks
+                .iter()
+                .position(|h| side_contains(&h.old, n.get()))
+                .map(|hunk| (hunk, n.get())),
+            _ => {
+                let candidates = additions.get(path.as_str()).map_or(&[][..], Vec::as_slice);
+                let next = next_addition.entry(path.clone()).or_default();
+                candidates[*next..]
+                    .iter()
+                    .position(|&(_, _, text)| text == content)
+                    .map(|offset| {
+                        *next += offset + 1;
+                        let (hunk, staged_line, _) = candidates[*next - 1];
+                        (hunk, staged_line)
+                    })
+            }
+        };
+        if let Some((hunk, staged_line)) = found {
+            lines.push(StagedLine {
+                path,
+                line,
+                hunk,
+                staged_line,
+            });
+        }
+    }
+    lines
+}
+
+/// Changed lines of `diff` that [`filter_by_refs`] would leave out, as line refs
+fn unselected_lines(
+    diff: &diff::Diff,
+    specs: &[Vec<parse::LineRef>],
+    except: bool,
+    excluded: &ExcludedLines,
+) -> Vec<String> {
+    let selection = Selection::new(diff, specs, except, excluded);
+    let mut missing = Vec::new();
+
+    for file in &diff.files {
+        for hunk in &file.hunks {
+            let old_lines = hunk.old.start..hunk.old.start + hunk.old.len() as u32;
+            let new_lines = hunk.new.start..hunk.new.start + hunk.new.len() as u32;
+            missing.extend(
+                old_lines
+                    .filter(|&n| !selection.keeps_old(&file.path, n))
+                    .map(|n| format!("-{n}")),
+            );
+            missing.extend(
+                new_lines
+                    .filter(|&n| !selection.keeps_new(&file.path, n))
+                    .map(|n| n.to_string()),
+            );
+        }
+        if file.hunks.is_empty() && file.renamed_from.is_some() && !selection.keeps_renames() {
+            missing.push("rename".to_string());
+        }
+    }
+    missing
+}
+
+/// The refs, as written, that name no changed line of `diff`
+///
+/// Exclusions are skipped, as leaving out nothing is harmless.
+fn unmatched_refs<'a>(
+    diff: &diff::Diff,
+    refs: impl IntoIterator<Item = &'a parse::LineRef>,
+) -> Vec<String> {
+    let none = ExcludedLines::default();
+    refs.into_iter()
+        .filter(|r| !matches!(r, parse::LineRef::Exclude(_)))
+        .filter(|r| {
+            let spec = [vec![(*r).clone()]];
+            let selection = Selection::new(diff, &spec, false, &none);
+            let matched = match r {
+                parse::LineRef::All => !diff.files.is_empty(),
+                parse::LineRef::Rename => diff.files.iter().any(|f| f.renamed_from.is_some()),
+                parse::LineRef::FinalNewline => {
+                    diff.files.iter().any(|f| f.final_newline_hunk().is_some())
+                }
+                _ => diff.files.iter().any(|file| {
+                    file.hunks.iter().any(|hunk| {
+                        let old = hunk.old.start..hunk.old.start + hunk.old.len() as u32;
+                        let new = hunk.new.start..hunk.new.start + hunk.new.len() as u32;
+                        old.into_iter().any(|n| selection.keeps_old(&file.path, n))
+                            || new.into_iter().any(|n| selection.keeps_new(&file.path, n))
+                    })
+                }),
+            };
+            !matched
+        })
+        .map(ToString::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use similar_asserts::assert_eq;
+    use std::sync::Mutex;
+
+    /// Backend serving a canned diff and recording applied patches
+    #[derive(Clone, Default)]
+    struct MockGit {
+        diff: String,
+        apply_error: Option<String>,
+        blame: String,
+        /// Index changes reported after applying, in place of the last patch
+        index_change: Option<String>,
+        applied: Arc<Mutex<Vec<String>>>,
+        /// Content of every blob
+        blob: String,
+    }
+
+    impl GitBackend for MockGit {
+        fn diff(&self, _repo_path: &Path, files: &[String]) -> Result<String, GitCommandError> {
+            // Mimic git's pathspec filtering on whole file sections, kept
+            // as written so headers such as `new file mode` survive
+            Ok(self
+                .diff
+                .split_inclusive('\n')
+                .fold(Vec::<String>::new(), |mut sections, line| {
+                    match sections.last_mut() {
+                        Some(section) if !line.starts_with("diff --git ") => section.push_str(line),
+                        _ => sections.push(line.to_string()),
+                    }
+                    sections
+                })
+                .into_iter()
+                .filter(|section| {
+                    files.is_empty()
+                        || diff::file::FileDiff::parse(section)
+                            .is_some_and(|f| files.contains(&f.path))
+                })
+                .collect())
+        }
+
+        fn is_work_tree(&self, _repo_path: &Path) -> Result<bool, GitCommandError> {
+            Ok(true)
+        }
+
+        fn diff_cached(&self, _repo_path: &Path) -> Result<String, GitCommandError> {
+            Ok(self.applied.lock().unwrap().concat())
+        }
+
+        fn diff_no_index(
+            &self,
+            _repo_path: &Path,
+            _old: &Path,
+            _new: &Path,
+        ) -> Result<String, GitCommandError> {
+            Ok(self.diff.clone())
+        }
+
+        fn diff_cached_from(
+            &self,
+            _repo_path: &Path,
+            _tree: &str,
+            _files: &[String],
+        ) -> Result<String, GitCommandError> {
+            Ok(match &self.index_change {
+                Some(change) => change.clone(),
+                None => self
+                    .applied
+                    .lock()
+                    .unwrap()
+                    .last()
+                    .cloned()
+                    .unwrap_or_default(),
+            })
+        }
+
+        fn apply_cached(&self, _repo_path: &Path, patch: &str) -> Result<(), GitCommandError> {
+            if let Some(stderr) = &self.apply_error {
+                return Err(GitCommandError::ApplyExitError {
+                    stderr: stderr.clone(),
+                });
+            }
+            self.applied.lock().unwrap().push(patch.to_string());
+            Ok(())
+        }
+
+        fn apply_cached_3way(&self, repo_path: &Path, patch: &str) -> Result<(), GitCommandError> {
+            self.apply_cached(repo_path, patch)
+        }
+
+        fn apply_worktree(&self, repo_path: &Path, patch: &str) -> Result<(), GitCommandError> {
+            self.apply_cached(repo_path, patch)
+        }
+
+        fn diff_numstat(
+            &self,
+            _repo_path: &Path,
+            files: &[String],
+        ) -> Result<String, GitCommandError> {
+            let parsed = diff::Diff::parse(&self.diff);
+            Ok(parsed
+                .files
+                .iter()
+                .filter(|f| files.is_empty() || files.contains(&f.path))
+                .map(|f| {
+                    let additions: usize = f.hunks.iter().map(|h| h.new.len()).sum();
+                    let deletions: usize = f.hunks.iter().map(|h| h.old.len()).sum();
+                    format!("{}\t{}\t{}\n", additions, deletions, f.path)
+                })
+                .collect())
+        }
+
+        fn ls_deleted(&self, _repo_path: &Path) -> Result<String, GitCommandError> {
+            let parsed = diff::Diff::parse(&self.diff);
+            Ok(parsed
+                .files
+                .iter()
+                .filter(|f| f.change == diff::file::FileChange::Deleted)
+                .map(|f| format!("{}\0", f.path))
+                .collect())
+        }
+
+        fn ls_files(&self, _repo_path: &Path, files: &[String]) -> Result<String, GitCommandError> {
+            // Every file in the canned diff counts as tracked
+            let parsed = diff::Diff::parse(&self.diff);
+            Ok(parsed
+                .files
+                .iter()
+                .filter(|f| files.is_empty() || files.contains(&f.path))
+                .map(|f| format!("H {}\n", f.path))
+                .collect())
+        }
+
+        fn cat_blob(&self, _repo_path: &Path, _blob: &str) -> Result<String, GitCommandError> {
+            Ok(self.blob.clone())
+        }
+
+        fn diff_stash(&self, _repo_path: &Path, _stash: &str) -> Result<String, GitCommandError> {
+            Ok(self.diff.clone())
+        }
+
+        fn resolve_commit(&self, _repo_path: &Path, rev: &str) -> Result<String, GitCommandError> {
+            Ok(rev.to_string())
+        }
+
+        fn blame(
+            &self,
+            _repo_path: &Path,
+            _file: &str,
+            _contents: &str,
+        ) -> Result<String, GitCommandError> {
+            Ok(self.blame.clone())
+        }
+
+        fn write_tree(&self, _repo_path: &Path) -> Result<String, GitCommandError> {
+            Ok("4b825dc642cb6eb9a060e54bf8d69288fbee4904".to_string())
+        }
+
+        fn commit(&self, _repo_path: &Path, _message: &str) -> Result<(), GitCommandError> {
+            Ok(())
+        }
+
+        fn index_path(&self, _repo_path: &Path) -> Result<PathBuf, GitCommandError> {
+            Ok(PathBuf::from("/nonexistent/index"))
+        }
+
+        fn with_index_file(&self, _index_file: &Path) -> Box<dyn GitBackend + Send + Sync> {
+            Box::new(self.clone())
+        }
+    }
+
+    const REPLACEMENT: &str = "diff --git a/config.nix b/config.nix
+--- a/config.nix
++++ b/config.nix
+@@ -10 +10,2 @@
+-    old_setting = true;
++    new_setting = false;
++    extra_setting = true;
+";
+
+    fn stager(mock: MockGit) -> GitLines {
+        GitLines::new("/nonexistent").backend(mock)
+    }
+
+    const TWO_FILES: &str = "diff --git a/zsh.nix b/zsh.nix
+--- a/zsh.nix
++++ b/zsh.nix
+@@ -0,0 +1 @@
++\tzsh
+diff --git a/lib/flake.nix b/lib/flake.nix
+--- a/lib/flake.nix
++++ b/lib/flake.nix
+@@ -0,0 +1 @@
++\x1b[1mflake
+";
+
+    #[test]
+    fn parsed_diff_matches_rendered_diff() {
+        let stager = stager(MockGit {
+            diff: TWO_FILES.to_string(),
+            ..MockGit::default()
+        });
+        let parsed = stager.parsed_diff(&[] as &[&str]).unwrap();
+        let paths: Vec<&str> = parsed.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["zsh.nix", "lib/flake.nix"]);
+        assert_eq!(parsed.files[0].hunks[0].new.lines, ["\tzsh"]);
+        assert_eq!(
+            stager.diff(&[] as &[&str]).unwrap(),
+            parsed.display_with(diff::DisplayOptions::default())
+        );
+    }
+
+    #[test]
+    fn write_diff_streams_the_rendered_diff() {
+        let stager = stager(MockGit {
+            diff: TWO_FILES.to_string(),
+            ..MockGit::default()
+        })
+        .show_tabs(true);
+        let mut out = Vec::new();
+        stager.write_diff(&[] as &[&str], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            stager.diff(&[] as &[&str]).unwrap()
+        );
+
+        // The writer's own error comes back, not a formatting error
+        struct Closed;
+        impl std::io::Write for Closed {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let err = stager.write_diff(&[] as &[&str], Closed).unwrap_err();
+        assert!(
+            matches!(&err, GitLinesError::OutputFailed { source } if source.kind() == std::io::ErrorKind::BrokenPipe),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn diff_with_options_sorts_and_renders() {
+        let stager = stager(MockGit {
+            diff: TWO_FILES.to_string(),
+            ..MockGit::default()
+        });
+
+        assert_eq!(
+            stager
+                .diff_with_options(&[] as &[&str], &DiffOptions::new())
+                .unwrap(),
+            stager.diff(&[] as &[&str]).unwrap()
+        );
+        let options = DiffOptions::new()
+            .sort_files(true)
+            .show_tabs(true)
+            .escape_control(true);
+        assert_eq!(
+            stager.diff_with_options(&[] as &[&str], &options).unwrap(),
+            "lib/flake.nix:\n  +1: ^[[1mflake\n\nzsh.nix:\n  +1: →   zsh\n\n"
+        );
+    }
+
+    #[test]
+    fn diff_with_options_staged_selects_paths() {
+        let stager = stager(MockGit {
+            diff: TWO_FILES.to_string(),
+            ..MockGit::default()
+        });
+        stager.stage("zsh.nix:1").unwrap();
+        stager.stage("lib/flake.nix:1").unwrap();
+
+        let staged = DiffOptions::new().staged(true);
+        assert_eq!(
+            stager.diff_with_options(["lib/"], &staged).unwrap(),
+            "lib/flake.nix:\n  +1: \x1b[1mflake\n\n"
+        );
+        assert_eq!(
+            stager
+                .diff_with_options(["zsh.nix"], &staged.show_tabs(true))
+                .unwrap(),
+            "zsh.nix:\n  +1: →   zsh\n\n"
+        );
+        assert_eq!(stager.diff_with_options(["li"], &staged).unwrap(), "");
+    }
+
+    #[test]
+    fn diff_with_options_renders_context_and_color() {
+        let stager = stager(MockGit {
+            diff: "diff --git a/a.nix b/a.nix
+index 1111111..2222222 100644
+--- a/a.nix
++++ b/a.nix
+@@ -3 +3 @@ two
+-three
++THREE
+"
+            .to_string(),
+            blob: "one\ntwo\nthree\nfour\nfive\n".to_string(),
+            ..MockGit::default()
+        });
+
+        let options = DiffOptions::new().context(1);
+        assert_eq!(
+            stager.diff_with_options(["a.nix"], &options).unwrap(),
+            "a.nix:\n   2: two\n  -3: three\n  +3: THREE\n   4: four\n\n"
+        );
+        assert_eq!(
+            stager
+                .diff_with_options(["a.nix"], &options.color(true))
+                .unwrap(),
+            "a.nix:\n   \x1b[2m2:\x1b[0m two\n\x1b[31m  -3: three\x1b[0m\n\x1b[32m  +3: THREE\x1b[0m\n   \x1b[2m4:\x1b[0m four\n\n"
+        );
+    }
+
+    #[test]
+    fn staged_diffs_follow_sort_files() {
+        let stager = stager(MockGit {
+            diff: TWO_FILES.to_string(),
+            ..MockGit::default()
+        })
+        .sort_files(true);
+        stager.stage("zsh.nix:1").unwrap();
+        stager.stage("lib/flake.nix:1").unwrap();
+
+        let sorted = "lib/flake.nix:\n  +1: \x1b[1mflake\n\nzsh.nix:\n  +1: \tzsh\n\n";
+        assert_eq!(stager.staged_diff().unwrap(), sorted);
+        assert_eq!(
+            stager
+                .diff_source(DiffSource::Index, &[] as &[&str])
+                .unwrap(),
+            sorted
+        );
+        // The working tree diff keeps git's order
+        assert!(stager.diff(&[] as &[&str]).unwrap().starts_with("zsh.nix:"));
+    }
+
+    #[test]
+    fn unstage_reports_empty_index() {
+        let stager = stager(MockGit::default());
+        assert_eq!(
+            stager
+                .diff_source(DiffSource::Index, &[] as &[&str])
+                .unwrap(),
+            ""
+        );
+        let err = stager.unstage("config.nix:1").unwrap_err();
+        assert!(matches!(err, GitLinesError::NoStagedChanges { ref file } if file == "config.nix"));
+        assert_eq!(err.to_string(), "No staged changes found in config.nix");
+    }
+
+    const BLAME: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa 1 1 1
+author A
+filename notes.txt
+\tfirst
+bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb 1 2 1
+author B
+filename notes.txt
+\tsecond
+";
+
+    #[test]
+    fn blame_commits_reads_final_lines() {
+        let commits = blame_commits(BLAME);
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[&1], "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        assert_eq!(commits[&2], "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+    }
+
+    #[test]
+    fn introduced_by_keeps_that_commits_deletions() {
+        let mock = MockGit {
+            diff: "diff --git a/notes.txt b/notes.txt
+--- a/notes.txt
++++ b/notes.txt
+@@ -1,2 +1 @@
+-first
+-second
++replacement
+"
+            .to_string(),
+            blame: BLAME.to_string(),
+            ..MockGit::default()
+        };
+        let stager = stager(mock.clone()).introduced_by("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+
+        let staged = stager.stage("notes.txt:-1,-2,1").unwrap();
+        assert_eq!(staged.to_string(), "notes.txt:\n  -2: second\n\n");
+        assert!(matches!(
+            stager.stage("notes.txt:-1"),
+            Err(GitLinesError::NoMatchingLines { .. })
+        ));
+    }
+
+    #[test]
+    fn verify_apply_accepts_exact_selection() {
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            ..MockGit::default()
+        };
+        let stager = stager(mock.clone()).verify_apply(true);
+
+        stager.stage("config.nix:-10,10").unwrap();
+        // Applied to the scratch index, then for real
+        assert_eq!(mock.applied.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn verify_apply_detects_mismatch() {
+        // Fault: the index gains both additions though only one was selected
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            index_change: Some(REPLACEMENT.to_string()),
+            ..MockGit::default()
+        };
+        let err = stager(mock.clone())
+            .verify_apply(true)
+            .stage("config.nix:-10,10")
+            .unwrap_err();
+        assert!(
+            matches!(&err, GitLinesError::VerificationFailed { file, .. } if file == "config.nix"),
+            "{}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            "Staging config.nix would not stage exactly the selection: added lines \
+             differ: expected 1, index has 2; the index was left unchanged"
+        );
+        // Only the scratch index saw the patch
+        assert_eq!(mock.applied.lock().unwrap().len(), 1);
+
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            index_change: Some(String::new()),
+            ..MockGit::default()
+        };
+        assert!(matches!(
+            stager(mock).verify_apply(true).stage("config.nix:10"),
+            Err(GitLinesError::VerificationFailed { detail, .. }) if detail == "nothing was staged"
+        ));
+    }
+
+    #[test]
+    fn indentation_counts_tab_stops() {
+        assert_eq!(indentation("fn main() {", 4), 0);
+        assert_eq!(indentation("    let x = 1;", 4), 4);
+        assert_eq!(indentation("\t\tx", 4), 8);
+        assert_eq!(indentation("  \tx", 4), 4);
+        assert_eq!(indentation("\tx", 8), 8);
+        assert_eq!(indentation("   ", 4), 3);
+    }
+
+    #[test]
+    fn commit_per_hunk_stops_when_diff_does_not_shrink() {
+        // The canned diff never changes, as if every commit left it in place
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            ..MockGit::default()
+        };
+
+        assert!(matches!(
+            stager(mock).commit_per_hunk("config.nix", "update {first_line}"),
+            Err(GitLinesError::HunkNotCommitted { ref file, committed: 1 }) if file == "config.nix"
+        ));
+    }
+
+    #[test]
+    fn commit_message_collapses_first_line() {
+        assert_eq!(
+            commit_message("update {first_line}", "a.nix", "  \tpkgs.git   # vcs\r"),
+            "update pkgs.git # vcs"
+        );
+        assert_eq!(
+            commit_message("{file}: {first_line}", "a.nix", "x = \"{file}\";"),
+            "a.nix: x = \"{file}\";"
+        );
+        assert_eq!(commit_message("update {first_line}", "a.nix", ""), "update");
+
+        let long = commit_message("{first_line}", "a.nix", &"x".repeat(100));
+        assert_eq!(long.chars().count(), FIRST_LINE_MAX);
+        assert!(long.ends_with("..."));
+    }
+
+    #[test]
+    fn stage_applies_only_selected_lines() {
+        let applied = Arc::default();
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+
+        let staged = stager(mock).stage("config.nix:-10,10").unwrap();
+
+        assert_eq!(staged.total_additions(), 1);
+        assert_eq!(
+            applied.lock().unwrap().as_slice(),
+            ["diff --git a/config.nix b/config.nix
+--- a/config.nix
++++ b/config.nix
+@@ -10 +10 @@
+-    old_setting = true;
++    new_setting = false;
+"]
+        );
+    }
+
+    #[test]
+    fn stage_many_keeps_exclusions_to_their_spec() {
+        let applied = Arc::default();
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+
+        // The first spec leaves out line 11, but the second selects it
+        let staged = stager(mock)
+            .stage_many(["config.nix:10..11,!11", "config.nix:11"])
+            .unwrap();
+
+        assert_eq!(staged.total_additions(), 2);
+        assert_eq!(applied.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn added_file_is_diffed_again_only_with_deleted_files() {
+        let added = "diff --git a/new.nix b/new.nix\nnew file mode 100644\n--- /dev/null\n\
+                     +++ b/new.nix\n@@ -0,0 +1,2 @@\n+a\n+b\n";
+        let diffs = |diff: String| {
+            let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let counted = Arc::clone(&runs);
+            let mock = MockGit {
+                diff,
+                ..MockGit::default()
+            };
+            stager(mock)
+                .profile_hook(move |phase, _| {
+                    if phase == Phase::GitDiff {
+                        counted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    }
+                })
+                .stage("new.nix:1")
+                .unwrap();
+            runs.load(std::sync::atomic::Ordering::Relaxed)
+        };
+
+        assert_eq!(diffs(added.to_string()), 1);
+        let deleted = "diff --git a/old.nix b/old.nix\ndeleted file mode 100644\n--- a/old.nix\n\
+                       +++ /dev/null\n@@ -1 +0,0 @@\n-a\n";
+        assert_eq!(diffs(format!("{added}{deleted}")), 2);
+    }
+
+    #[test]
+    fn profile_times_every_diff_read_and_parse() {
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&phases);
+        let stager = stager(MockGit {
+            diff: REPLACEMENT.to_string(),
+            ..MockGit::default()
+        })
+        .profile_hook(move |phase, _| recorded.lock().unwrap().push(phase));
+
+        stager.plan(&[] as &[&str]).unwrap();
+        stager.staged_diff().unwrap();
+        stager.stage(":-10,10").unwrap();
+        let phases = phases.lock().unwrap();
+        let count = |phase| phases.iter().filter(|&&p| p == phase).count();
+        // plan, staged_diff, and stage both resolving the file and selecting
+        assert_eq!(count(Phase::GitDiff), 4);
+        assert_eq!(count(Phase::Parse), 4);
+    }
+
+    #[test]
+    fn stage_many_keeps_star_alone_among_specs() {
+        let applied = Arc::default();
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+        let stager = stager(mock);
+
+        for specs in [
+            ["config.nix:*", "config.nix:10"],
+            ["config.nix:10", "config.nix:*"],
+        ] {
+            assert!(matches!(
+                stager.stage_atomic(specs),
+                Err(GitLinesError::ParseError(parse::ParseError::InvalidStar { ref value }))
+                    if value == "10"
+            ));
+        }
+        assert!(applied.lock().unwrap().is_empty());
+
+        // `all` may go with other refs, so its specs still merge
+        stager
+            .stage_many(["config.nix:all", "config.nix:10"])
+            .unwrap();
+        assert_eq!(applied.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn stage_decisions_toggles_lines_of_one_hunk() {
+        let line = |n| std::num::NonZeroU32::new(n).unwrap();
+        let decisions = |keep: [bool; 3]| {
+            [
+                (parse::LineRef::Delete(line(10)), keep[0]),
+                (parse::LineRef::Add(line(10)), keep[1]),
+                (parse::LineRef::Add(line(11)), keep[2]),
+            ]
+        };
+        let staged_with = |keep| {
+            let applied = Arc::default();
+            let mock = MockGit {
+                diff: REPLACEMENT.to_string(),
+                applied: Arc::clone(&applied),
+                ..MockGit::default()
+            };
+            let result = stager(mock).stage_decisions("config.nix", &decisions(keep));
+            (result, std::mem::take(&mut *applied.lock().unwrap()))
+        };
+
+        let (_, applied) = staged_with([false, false, true]);
+        assert_eq!(
+            applied,
+            ["diff --git a/config.nix b/config.nix
+--- a/config.nix
++++ b/config.nix
+@@ -10,0 +11 @@
++    extra_setting = true;
+"]
+        );
+
+        let (staged, _) = staged_with([true, true, false]);
+        let staged = staged.unwrap();
+        assert_eq!(staged.total_deletions(), 1);
+        assert_eq!(staged.total_additions(), 1);
+
+        let (result, applied) = staged_with([false, false, false]);
+        assert!(matches!(result, Err(GitLinesError::NoMatchingLines { .. })));
+        assert!(applied.is_empty());
+    }
+
+    #[test]
+    fn stage_report_counts_split_hunks() {
+        let mock = MockGit {
+            diff: "diff --git a/config.nix b/config.nix
+--- a/config.nix
++++ b/config.nix
+@@ -10,3 +9,0 @@
+-a
+-b
+-c
+@@ -20,0 +18 @@
++d
+"
+            .to_string(),
+            ..MockGit::default()
+        };
+
+        let report = stager(mock).stage_report("config.nix:-10,-12,18").unwrap();
+
+        assert_eq!(report.staged.files[0].hunks.len(), 3);
+        assert_eq!(
+            report.files,
+            [HunkSplits {
+                path: "config.nix".to_string(),
+                hunks: 3,
+                split: vec![0, 1],
+            }]
+        );
+    }
+
+    #[test]
+    fn stage_report_locates_selected_lines() {
+        let mock = MockGit {
+            diff: "diff --git a/config.nix b/config.nix
+--- a/config.nix
++++ b/config.nix
+@@ -10,3 +9,0 @@
+-a
+-b
+-c
+@@ -20,0 +18,2 @@
++d
++e
+"
+            .to_string(),
+            ..MockGit::default()
+        };
+
+        let report = stager(mock).stage_report("config.nix:-10,-12,19").unwrap();
+
+        let line = |n| std::num::NonZeroU32::new(n).unwrap();
+        let located = |line, hunk, staged_line| StagedLine {
+            path: "config.nix".to_string(),
+            line,
+            hunk,
+            staged_line,
+        };
+        assert_eq!(
+            report.lines,
+            [
+                located(parse::LineRef::Delete(line(10)), 0, 10),
+                located(parse::LineRef::Delete(line(12)), 1, 12),
+                // Two of the three deletions before it are staged
+                located(parse::LineRef::Add(line(19)), 2, 19),
+            ]
+        );
+    }
+
+    #[test]
+    fn stage_report_summary_line() {
+        let mock = MockGit {
+            diff: "diff --git a/flake.nix b/flake.nix
+--- a/flake.nix
++++ b/flake.nix
+@@ -3 +3,2 @@
+-a
++b
++c
+@@ -9,0 +11 @@
++d
+"
+            .to_string(),
+            ..MockGit::default()
+        };
+
+        let report = stager(mock).stage_report("flake.nix:all").unwrap();
+
+        assert_eq!(
+            report.summary(),
+            "staged 3 additions, 1 deletion in flake.nix (2 hunks)"
+        );
+    }
+
+    #[test]
+    fn tokens_for_patch_covers_each_hunk() {
+        let patch = "diff --git a/config.nix b/config.nix
+index 1111111..2222222 100644
+--- a/config.nix
++++ b/config.nix
+@@ -10,3 +9,0 @@
+-a
+-b
+-c
+@@ -20 +17,2 @@
+-d
++D
++e
+diff --git a/old.nix b/new.nix
+similarity index 100%
+rename from old.nix
+rename to new.nix
+";
+        let tokens: Vec<String> = GitLines::tokens_for_patch(patch)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(tokens, ["config.nix:-10..-12,-20,17..18", "new.nix:rename"]);
+    }
+
+    #[test]
+    fn reject_conflict_markers_blocks_marker_lines() {
+        let applied = Arc::default();
+        let mock = MockGit {
+            diff: "diff --git a/config.nix b/config.nix
+--- a/config.nix
++++ b/config.nix
+@@ -4,0 +5,3 @@
++<<<<<<< HEAD
++    setting = true;
++=======
+"
+            .to_string(),
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+
+        let result = stager(mock.clone())
+            .reject_conflict_markers(true)
+            .stage("config.nix:5..7");
+        assert!(matches!(
+            result,
+            Err(GitLinesError::ConflictMarkerStaged { ref file, line: 5 }) if file == "config.nix"
+        ));
+        assert!(applied.lock().unwrap().is_empty());
+
+        // Unflagged, or with only ordinary lines selected, it stages
+        stager(mock.clone())
+            .reject_conflict_markers(true)
+            .stage("config.nix:6")
+            .unwrap();
+        stager(mock).stage("config.nix:5").unwrap();
+        assert_eq!(applied.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn require_balanced_checks_selected_additions() {
+        let applied = Arc::default();
+        let mock = MockGit {
+            diff: "diff --git a/main.rs b/main.rs
+--- a/main.rs
++++ b/main.rs
+@@ -4,0 +5,4 @@
++fn run() {
++    if ready {
++    }
++}
+"
+            .to_string(),
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+
+        let result = stager(mock.clone())
+            .require_balanced(true)
+            .stage("main.rs:5..7");
+        assert!(matches!(
+            result,
+            Err(GitLinesError::UnbalancedSelection { ref file, delimiter: '{', ref text })
+                if file == "main.rs" && text == "fn run() {"
+        ));
+        let result = stager(mock.clone())
+            .require_balanced(true)
+            .stage("main.rs:7..8");
+        assert!(matches!(
+            result,
+            Err(GitLinesError::UnbalancedSelection {
+                delimiter: '}',
+                ref text,
+                ..
+            }) if text == "}"
+        ));
+        assert!(applied.lock().unwrap().is_empty());
+
+        stager(mock.clone())
+            .require_balanced(true)
+            .stage("main.rs:5..8")
+            .unwrap();
+        stager(mock)
+            .require_balanced(true)
+            .stage("main.rs:6..7")
+            .unwrap();
+        assert_eq!(applied.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        let tagged = |text: &str| text.chars().map(|c| (1, c)).collect::<Vec<_>>();
+        assert_eq!(unmatched_bracket(tagged("f(a[0], {b})")), None);
+        assert_eq!(unmatched_bracket(tagged("f(a]")), Some((1, ']')));
+        assert_eq!(unmatched_bracket(tagged("(()")), Some((1, '(')));
+        assert_eq!(unmatched_bracket(tagged("x)")), Some((1, ')')));
+    }
+
+    #[test]
+    fn conflict_marker_lines() {
+        assert!(is_conflict_marker("<<<<<<< HEAD"));
+        assert!(is_conflict_marker("<<<<<<<"));
+        assert!(is_conflict_marker("||||||| base"));
+        assert!(is_conflict_marker("======="));
+        assert!(is_conflict_marker(">>>>>>> feature\r"));
+        assert!(!is_conflict_marker("<<<<<<<< eight"));
+        assert!(!is_conflict_marker("======= trailing"));
+        assert!(!is_conflict_marker("  <<<<<<< indented"));
+        assert!(!is_conflict_marker("a <<<<<<< b"));
+    }
+
+    #[test]
+    fn require_complete_rejects_partial_selection() {
+        let applied = Arc::default();
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+        let stager = stager(mock).require_complete(true);
+
+        let result = stager.stage("config.nix:-10,10");
+        assert!(matches!(
+            result,
+            Err(GitLinesError::IncompleteSelection { ref missing, .. }) if missing == &["11"]
+        ));
+        assert!(applied.lock().unwrap().is_empty());
+
+        let staged = stager.stage("config.nix:all").unwrap();
+        assert_eq!(staged.total_deletions(), 1);
+        assert_eq!(staged.total_additions(), 2);
+        assert_eq!(applied.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn strict_rejects_unmatched_refs() {
+        let applied = Arc::default();
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+        let stager = stager(mock).strict(true);
+
+        assert_eq!(
+            stager
+                .unmatched_refs("config.nix:-10,12..14,999,!11")
+                .unwrap(),
+            ["12..14", "999"]
+        );
+        let result = stager.stage("config.nix:10,999");
+        assert!(matches!(
+            result,
+            Err(GitLinesError::UnmatchedRefs { ref refs, .. }) if refs == &["999"]
+        ));
+        assert!(applied.lock().unwrap().is_empty());
+
+        stager.stage("config.nix:10").unwrap();
+        assert_eq!(applied.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn stager_can_be_shared_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<GitLines>();
+    }
+
+    #[test]
+    fn stage_report_lists_unmatched_refs() {
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            ..MockGit::default()
+        };
+        let stager = stager(mock);
+
+        let report = stager.stage_report("config.nix:10,999").unwrap();
+        assert_eq!(report.unmatched, ["999"]);
+        let report = stager
+            .stage_many_report(["config.nix:10", "config.nix:12..14"])
+            .unwrap();
+        assert_eq!(report.unmatched, ["12..14"]);
+    }
+
+    #[test]
+    fn stage_from_diff_uses_the_given_diff() {
+        let applied = Arc::default();
+        // The backend has no changes to diff
+        let mock = MockGit {
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+        let stager = stager(mock);
+
+        let staged = stager.stage_from_diff(REPLACEMENT, ":-10,10").unwrap();
+        assert_eq!(staged.total_deletions(), 1);
+        assert_eq!(staged.total_additions(), 1);
+        assert_eq!(applied.lock().unwrap().len(), 1);
+
+        let err = stager
+            .stage_from_diff(REPLACEMENT, "other.nix:1")
+            .unwrap_err();
+        assert!(
+            matches!(err, GitLinesError::NoChanges { file } if file == "other.nix in the given diff")
+        );
+    }
+
+    #[test]
+    fn stage_many_from_diff_applies_each_file_once() {
+        let applied = Arc::default();
+        let mock = MockGit {
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+        let raw_diff = "diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1,0 +2 @@
++X
+@@ -3,0 +5,2 @@
++Y
++Z
+";
+
+        stager(mock)
+            .stage_many_from_diff(raw_diff, ["a.txt:2", "a.txt:5"])
+            .unwrap();
+
+        // Line 5 is placed against the index before line 2 was added
+        assert_eq!(
+            applied.lock().unwrap().as_slice(),
+            ["diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1,0 +2 @@
++X
+@@ -3,0 +5 @@
++Y
+"]
+        );
+    }
+
+    #[test]
+    fn stage_by_file_groups_specs_and_keeps_going() {
+        let applied = Arc::default();
+        let mock = MockGit {
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+        let raw_diff = "diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1,0 +2 @@
++X
+@@ -3,0 +5 @@
++Y
+diff --git a/b.txt b/b.txt
+--- a/b.txt
++++ b/b.txt
+@@ -1 +1 @@
+-old
++new
+";
+        let stager = stager(mock);
+
+        let staged: Vec<(Vec<String>, bool)> = stager
+            .stage_by_file(
+                ["a.txt:x", "a.txt:2", "b.txt:1", "a.txt:5"],
+                StageMode::FromDiff(raw_diff),
+            )
+            .map(|file| (file.specs, file.result.is_ok()))
+            .collect();
+
+        let specs = |specs: &[&str]| specs.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            staged,
+            vec![
+                (specs(&["a.txt:x"]), false),
+                (specs(&["a.txt:2", "a.txt:5"]), true),
+                (specs(&["b.txt:1"]), true),
+            ]
+        );
+        assert_eq!(applied.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn stage_without_changes_is_no_changes() {
+        let err = stager(MockGit::default())
+            .stage("config.nix:10")
+            .unwrap_err();
+        assert!(matches!(err, GitLinesError::NoChanges { file } if file == "config.nix"));
+    }
+
+    #[test]
+    fn stage_unmatched_lines_applies_nothing() {
+        let applied = Arc::default();
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            applied: Arc::clone(&applied),
+            ..MockGit::default()
+        };
+
+        let err = stager(mock).stage("config.nix:99").unwrap_err();
+        assert!(matches!(err, GitLinesError::NoMatchingLines { file } if file == "config.nix"));
+        assert!(applied.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn apply_failure_surfaces_git_stderr() {
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            apply_error: Some("error: patch failed".to_string()),
+            ..MockGit::default()
+        };
+
+        let err = stager(mock).stage("config.nix:10").unwrap_err();
+        assert!(
+            matches!(err, GitLinesError::ApplyExitError { stderr } if stderr == "error: patch failed")
+        );
+    }
+
+    #[test]
+    fn file_less_selection_with_two_files_is_ambiguous() {
+        let mock = MockGit {
+            diff: format!(
+                "{REPLACEMENT}diff --git a/zsh.nix b/zsh.nix
+--- a/zsh.nix
++++ b/zsh.nix
+@@ -1,0 +2 @@
++zsh
+"
+            ),
+            ..MockGit::default()
+        };
+
+        let err = stager(mock).stage(":10").unwrap_err();
+        assert!(
+            matches!(err, GitLinesError::AmbiguousFile { files } if files == ["config.nix", "zsh.nix"])
+        );
+    }
+
+    #[test]
+    fn ignore_case_resolves_or_reports_ambiguity() {
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            ..MockGit::default()
+        };
+        assert!(matches!(
+            stager(mock.clone()).stage("Config.NIX:10"),
+            Err(GitLinesError::NoChanges { .. })
+        ));
+        let staged = stager(mock)
+            .ignore_case(true)
+            .stage("Config.NIX:10")
+            .unwrap();
+        assert_eq!(staged.files[0].path, "config.nix");
+
+        let mock = MockGit {
+            diff: format!(
+                "{REPLACEMENT}{}",
+                REPLACEMENT.replace("config.nix", "CONFIG.nix")
+            ),
+            ..MockGit::default()
+        };
+        let err = stager(mock.clone())
+            .ignore_case(true)
+            .stage("Config.nix:10")
+            .unwrap_err();
+        assert!(
+            matches!(&err, GitLinesError::AmbiguousFile { files } if files == &["config.nix", "CONFIG.nix"]),
+            "{}",
+            err
+        );
+        // An exact match is never ambiguous
+        let staged = stager(mock)
+            .ignore_case(true)
+            .stage("CONFIG.nix:10")
+            .unwrap();
+        assert_eq!(staged.files[0].path, "CONFIG.nix");
+    }
+
+    #[test]
+    fn pre_apply_hook_sees_and_vetoes_patch() {
+        let mock = MockGit {
+            diff: REPLACEMENT.to_string(),
+            ..MockGit::default()
+        };
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let stager = stager(mock.clone()).pre_apply_hook(move |patch| {
+            log.lock().unwrap().push(patch.to_string());
+            if patch.contains("extra_setting") {
+                return Err("extra_setting is frozen".to_string());
+            }
+            Ok(())
+        });
+
+        let err = stager.stage("config.nix:11").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Pre-apply hook rejected the patch: extra_setting is frozen"
+        );
+        assert!(mock.applied.lock().unwrap().is_empty());
+
+        stager.stage("config.nix:-10,10").unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 2);
+        assert_eq!(*mock.applied.lock().unwrap(), seen.lock().unwrap()[1..]);
     }
 }
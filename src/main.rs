@@ -1,8 +1,22 @@
-use clap::{CommandFactory, Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use clap_complete::{Shell, generate};
 use clap_mangen::Man;
-use git_lines::GitLines;
-use std::io;
+use config::Config;
+use git_lines::diff::file::FileChange;
+use git_lines::diff::{DisplayOptions, json_string};
+use git_lines::parse::{FileLineRefs, LineRef};
+use git_lines::{
+    DiffSource, GitLines, GitLinesError, PLAIN_PATCH_CONTEXT, Phase, StageMode, SystemGit,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+mod config;
 
 #[derive(Parser)]
 #[command(name = "git-lines")]
@@ -19,6 +33,31 @@ struct Cli {
     #[arg(short = 'C', global = true)]
     path: Option<String>,
 
+    /// Repository directory, for a bare repository or a relocated .git
+    /// [default: GIT_DIR, or found from the path]
+    #[arg(long, value_name = "DIR", global = true)]
+    git_dir: Option<String>,
+
+    /// Working tree of the --git-dir repository [default: GIT_WORK_TREE]
+    #[arg(long, value_name = "DIR", global = true)]
+    work_tree: Option<String>,
+
+    /// When to color output; `always` overrides --no-color and NO_COLOR [default: auto]
+    #[arg(long, value_enum, value_name = "WHEN", global = true)]
+    color: Option<ColorChoice>,
+
+    /// Never color output (same as setting NO_COLOR)
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// When to draw control characters in line content as ^[-style escapes [default: auto]
+    #[arg(long, value_enum, value_name = "WHEN", global = true)]
+    escape_control: Option<EscapeChoice>,
+
+    /// How to report a failure on stderr
+    #[arg(long, value_enum, value_name = "FORMAT", global = true, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -35,28 +74,212 @@ enum Commands {
     ///   -N        stage deletion of old line N
     ///   N..M      stage range of additions
     ///   -N..-M    stage range of deletions
+    ///   N.., -N.. stage from line N to the end of its hunk
+    ///   ~-N       stage deletion of the old line replaced by new line N
+    ///   rename    stage the file's rename (renames without content changes)
+    ///   all       stage every change to the file (or * on its own)
+    ///   eol       stage only the newline added at the end of the file
+    ///   !REF      leave out lines the other refs select (10..20,!15)
     ///   A,B,C     combine any of the above
     ///
+    /// FILE may be omitted (:REFS) when only one file has changes.
+    ///
     /// Basic:
     ///   file:137           single added line
     ///   file:-15           single deleted line
     ///   file:10..15        range of additions
+    ///   :137               line 137 of the only changed file
     ///
     /// Advanced - skip lines within contiguous changes:
     ///   file:40..45,48     lines 40-45 and 48, skip 46-47
     ///   file:10,15,20      only specific lines, not 11-14 or 16-19
     ///   file:-10..-12,-15  delete 10-12 and 15, skip 13-14
+    ///   file:~-10,10       swap in new line 10 for the old line it replaces
     ///
     /// Multiple files:
     ///   a.nix:10 b.nix:20  stage from multiple files
+    ///
+    /// Whole subtree:
+    ///   --all --only-path packages/foo
+    ///
+    /// Everything but a few lines (arguments are then plain files):
+    ///   file --except 12,-15
+    ///
+    /// Dry run of the whole set against a scratch index:
+    ///   a.nix:10 b.nix:-3 --preview
+    ///
+    /// Lines of a generated file staged into a copy with the same base:
+    ///   template.txt:4..6 --to instance.txt
+    ///
+    /// Lines of a diff captured earlier with `git diff -U0`:
+    ///   file:12 --stdin < changes.diff
+    ///
+    /// Lines out of a stash (numbers from `git stash show -p -U0`):
+    ///   file:12 --from-stash stash@{0}
+    ///   file:12 --from-stash stash@{0} --3way   merge if the lines have moved
     #[command(verbatim_doc_comment)]
     Stage {
         /// One or more FILE:REFS specifications
+        #[arg(required_unless_present = "all")]
         file_refs: Vec<String>,
 
+        /// Stage every changed line instead of a selection
+        #[arg(long, conflicts_with = "file_refs")]
+        all: bool,
+
+        /// Stage every changed line of each FILE except these REFS
+        #[arg(long, value_name = "REFS", conflicts_with_all = ["all", "count_only"], allow_hyphen_values = true)]
+        except: Option<String>,
+
+        /// Take the lines from a diff read from stdin instead of running git
+        /// diff; it must be made with -U0 for the line numbers to line up
+        #[arg(long, conflicts_with_all = ["all", "except", "from_stash", "to", "count_only", "preview", "dry_run", "report", "summary", "atomic"])]
+        stdin: bool,
+
+        /// Take the lines from this stash entry (e.g. stash@{0}) instead of the worktree
+        #[arg(long, value_name = "STASH", conflicts_with_all = ["all", "except", "count_only"])]
+        from_stash: Option<String>,
+
+        /// Stage the selected lines into PATH instead, whose index content must
+        /// match FILE's
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["all", "except", "from_stash", "count_only", "preview", "report"])]
+        to: Option<String>,
+
+        /// With --all, only stage files under this directory
+        #[arg(long, value_name = "PREFIX", requires = "all")]
+        only_path: Option<String>,
+
         /// Suppress output showing what was staged
         #[arg(short, long)]
         quiet: bool,
+
+        /// Also write each staged file's patch to DIR/<path>.patch
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["preview", "count_only"])]
+        output_dir: Option<String>,
+
+        /// Stage the remaining specs when one fails, then report every failure
+        #[arg(long)]
+        keep_going: bool,
+
+        /// Refuse, before staging anything, to touch more than N files
+        #[arg(long, value_name = "N")]
+        max_files: Option<usize>,
+
+        /// Show what the selections would stage, leaving the index untouched
+        #[arg(long, conflicts_with_all = ["all", "except", "from_stash", "count_only", "report", "show"])]
+        preview: bool,
+
+        /// List each file's staged hunk count and which hunks were split apart
+        #[arg(long, conflicts_with_all = ["all", "except", "from_stash", "count_only"])]
+        report: bool,
+
+        /// Print one line per spec (e.g. `staged 3 additions, 1 deletion in
+        /// flake.nix (2 hunks)`) instead of the staged patch
+        #[arg(long, conflicts_with_all = ["all", "except", "from_stash", "to", "count_only", "preview", "report"])]
+        summary: bool,
+
+        /// With --summary, also print the staged patch
+        #[arg(long, requires = "summary")]
+        show_patch: bool,
+
+        /// Print the patch each spec would apply, leaving the index untouched
+        #[arg(long, conflicts_with_all = ["all", "except", "from_stash", "to", "preview", "report", "summary", "count_only", "output_dir", "show"])]
+        dry_run: bool,
+
+        /// Stage every spec in one patch, or nothing if any spec fails
+        #[arg(long, conflicts_with_all = ["all", "except", "from_stash", "to", "keep_going", "preview", "report", "summary", "count_only"])]
+        atomic: bool,
+
+        /// Only stage changed lines indented at most N columns (0 = top level)
+        #[arg(long, value_name = "N")]
+        indent: Option<usize>,
+
+        /// Columns a tab counts for with --indent [default: 4]
+        #[arg(long, value_name = "WIDTH", requires = "indent")]
+        tab_width: Option<usize>,
+
+        /// Merge the lines into the index with a three-way apply, so they land
+        /// correctly after the surrounding lines have moved
+        #[arg(long = "3way")]
+        three_way: bool,
+
+        /// Only stage deletions of lines that commit REV last changed (per git blame)
+        #[arg(long, value_name = "REV")]
+        introduced_by: Option<String>,
+
+        /// Merge staged hunks that touch into one hunk
+        #[arg(long)]
+        coalesce: bool,
+
+        /// Match FILE against changed paths ignoring case
+        #[arg(long)]
+        ignore_case: bool,
+
+        /// Check on a copy of the index that exactly the selected lines get
+        /// staged before staging them
+        #[arg(long)]
+        verify_apply: bool,
+
+        /// Fail if a selected added line is a merge conflict marker
+        #[arg(long)]
+        reject_conflict_markers: bool,
+
+        /// Fail if the selected added lines leave a bracket unmatched
+        #[arg(long)]
+        require_balanced: bool,
+
+        /// Fail unless the selection covers every changed line of the file
+        #[arg(long)]
+        require_complete: bool,
+
+        /// Fail when a line ref matches no changed line, or part of a file's
+        /// diff cannot be parsed, instead of warning
+        #[arg(long)]
+        strict: bool,
+
+        /// Warn when a FILE:REFS spec lists the same line more than once
+        #[arg(long, conflicts_with = "error_duplicates")]
+        warn_duplicates: bool,
+
+        /// Fail, before staging anything, when a spec lists a line more than once
+        #[arg(long)]
+        error_duplicates: bool,
+
+        /// Succeed without changes when the lines are already staged
+        #[arg(long)]
+        idempotent: bool,
+
+        /// After staging, show everything now staged in the index
+        #[arg(long, conflicts_with = "count_only")]
+        show: bool,
+
+        /// Report staged files sorted by path rather than in git's order
+        #[arg(long, overrides_with = "no_sort_files")]
+        sort_files: bool,
+
+        /// Report staged files in git's order, overriding the config
+        #[arg(long, overrides_with = "sort_files")]
+        no_sort_files: bool,
+
+        /// Print added and deleted line counts (numstat-style) without staging
+        #[arg(long)]
+        count_only: bool,
+
+        /// Only include files the diff adds (e.g. after `git add -N`)
+        #[arg(long, conflicts_with_all = ["only_modified", "only_deleted"])]
+        only_new: bool,
+
+        /// Only include files whose content, name or mode changed
+        #[arg(long, conflicts_with = "only_deleted")]
+        only_modified: bool,
+
+        /// Only include files deleted from the worktree
+        #[arg(long)]
+        only_deleted: bool,
+
+        /// Print how long each phase (git diff, parse, ...) took to stderr
+        #[arg(long)]
+        profile: bool,
     },
     /// Show unstaged changes with line numbers for staging
     ///
@@ -72,10 +295,222 @@ enum Commands {
     ///
     /// To stage only the replacement (skip +11):
     ///   git lines stage config.nix:-10,10
+    ///
+    /// Staged changes, numbered for unstage:
+    ///   git lines diff --cached
+    ///
+    /// Compare two files outside the index:
+    ///   git lines diff --no-index old.nix new.nix
+    ///   git lines diff --no-index old.nix new.nix --select -10,10 --format patch
+    ///
+    /// For patch(1) or systems without git (adds context, drops git headers):
+    ///   git lines diff config.nix --format plain-patch > change.patch
+    ///
+    /// Overview of where changes are:
+    ///   git lines diff --map
+    ///   flake.nix: -15..-16, +137, +142..144
+    ///
+    /// Embed in a review page (each line's data-token is its stage token):
+    ///   git lines diff --format html
+    ///
+    /// Stage only the changed lines containing a fixed string (no regex):
+    ///   git lines diff --grep TODO --format tokens | xargs git lines stage
     #[command(verbatim_doc_comment)]
     Diff {
         /// Files to show diff for (defaults to all changed files)
         files: Vec<String>,
+
+        /// Draw leading tabs in line content as →
+        #[arg(long, overrides_with = "no_show_tabs")]
+        show_tabs: bool,
+
+        /// Print leading tabs as they are, overriding the config
+        #[arg(long, overrides_with = "show_tabs")]
+        no_show_tabs: bool,
+
+        /// Compare the two given paths instead of the working tree and index
+        #[arg(long)]
+        no_index: bool,
+
+        /// Show staged changes (index against HEAD), numbered for `unstage`
+        #[arg(long, visible_alias = "staged", conflicts_with = "no_index")]
+        cached: bool,
+
+        /// Show a diff read from stdin instead of running git diff; it must
+        /// be made with -U0 for the line numbers to match `stage --stdin`
+        #[arg(long, conflicts_with_all = ["no_index", "cached", "unified"])]
+        stdin: bool,
+
+        /// With --no-index, keep only these lines (REFS syntax, e.g. -10,10)
+        #[arg(
+            long,
+            value_name = "REFS",
+            requires = "no_index",
+            allow_hyphen_values = true
+        )]
+        select: Option<String>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DiffFormat::Numbered)]
+        format: DiffFormat,
+
+        /// Shorthand for --format json
+        #[arg(long, conflicts_with_all = ["format", "map", "dump_ast"])]
+        json: bool,
+
+        /// Show N unchanged lines around each change, like `git diff -U<N>`
+        /// (display only; line numbers and staging are unaffected)
+        #[arg(
+            short = 'U',
+            long,
+            value_name = "N",
+            conflicts_with_all = ["format", "json", "map", "group_by_kind", "limit_hunks", "limit_files", "no_index"]
+        )]
+        unified: Option<usize>,
+
+        /// Show only changed lines whose content contains TEXT, keeping their
+        /// line numbers (not with patch formats). TEXT is a fixed string, as
+        /// with `grep -F`: regular expressions are not supported
+        #[arg(long, value_name = "TEXT")]
+        grep: Option<String>,
+
+        /// List each file's hunks in sections: additions, deletions, then
+        /// replacements
+        #[arg(long, conflicts_with_all = ["format", "map"])]
+        group_by_kind: bool,
+
+        /// Print only each file's changed line ranges, one line per file
+        #[arg(long, conflicts_with_all = ["format", "show_tabs"])]
+        map: bool,
+
+        /// Mark where each hunk starts (┌) and ends (└) in the gutter
+        #[arg(long, conflicts_with_all = ["format", "map"])]
+        annotate_boundaries: bool,
+
+        /// Show at most N hunks per file, noting how many more there are
+        #[arg(long, value_name = "N", conflicts_with_all = ["format", "map"])]
+        limit_hunks: Option<usize>,
+
+        /// Show at most N files, noting how many more there are
+        #[arg(long, value_name = "N", conflicts_with_all = ["format", "map"])]
+        limit_files: Option<usize>,
+
+        /// Print the parsed diff's structure with Debug, for parser bug reports
+        #[arg(long, hide = true, conflicts_with_all = ["format", "show_tabs", "grep", "group_by_kind", "map"])]
+        dump_ast: bool,
+
+        /// Only include files the diff adds (e.g. after `git add -N`)
+        #[arg(long, conflicts_with_all = ["only_modified", "only_deleted"])]
+        only_new: bool,
+
+        /// Only include files whose content, name or mode changed
+        #[arg(long, conflicts_with = "only_deleted")]
+        only_modified: bool,
+
+        /// Only include files deleted from the worktree
+        #[arg(long)]
+        only_deleted: bool,
+
+        /// Print how long each phase (git diff, parse, ...) took to stderr
+        #[arg(long)]
+        profile: bool,
+    },
+    /// List hunks as a numbered menu of stage tokens
+    ///
+    /// Each line is tab-separated: index, FILE:REFS token staging the
+    /// whole hunk, and the hunk's first changed line.
+    ///
+    /// Example output:
+    ///   1    config.nix:-10,10..11    -    old_setting = true;
+    ///   2    config.nix:20    +    # SECOND
+    ///
+    /// Pass chosen tokens back to stage:
+    ///   git lines stage config.nix:20
+    #[command(verbatim_doc_comment)]
+    Plan {
+        /// Files to list hunks for (defaults to all changed files)
+        files: Vec<String>,
+    },
+    /// Take staged lines back out of the index
+    ///
+    /// Takes FILE:REFS like stage, but line numbers refer to the staged
+    /// diff (index against HEAD, as listed by `stage --show`), not to the
+    /// working tree: -N puts back old line N of HEAD, N takes line N out of
+    /// the index. A bare FILE unstages all of it. The working tree is left
+    /// as it is.
+    ///
+    /// Examples:
+    ///   git lines unstage flake.nix:140
+    ///   git lines unstage flake.nix
+    #[command(verbatim_doc_comment)]
+    Unstage {
+        /// FILE:REFS specifications, or files to unstage entirely
+        #[arg(required = true)]
+        file_refs: Vec<String>,
+
+        /// Suppress output showing what was unstaged
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Throw away changed lines from the working tree
+    ///
+    /// Takes FILE:REFS like stage, with the same line numbers, and reverts
+    /// just those lines in the working tree: added lines are removed and
+    /// deleted lines restored. The index is left as it is. Discarded lines
+    /// cannot be recovered, so --force is required.
+    ///
+    /// Example:
+    ///   git lines discard --force src/main.rs:42
+    #[command(verbatim_doc_comment)]
+    Discard {
+        /// FILE:REFS specifications
+        #[arg(required = true)]
+        file_refs: Vec<String>,
+
+        /// Confirm that the lines should be thrown away
+        #[arg(long, required = true)]
+        force: bool,
+
+        /// Suppress output showing what was discarded
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Commit each hunk of a file as its own commit
+    ///
+    /// Hunks are staged and committed one at a time, top to bottom. The
+    /// message template may use {first_line} (the hunk's first changed line)
+    /// and {file}.
+    ///
+    /// Example:
+    ///   git lines commit --per-hunk flake.nix --message-template "update {first_line}"
+    ///
+    /// A subject and body from a file (- reads stdin):
+    ///   git lines commit --per-hunk flake.nix -F message.txt
+    #[command(verbatim_doc_comment)]
+    Commit {
+        /// File whose hunks to commit
+        file: String,
+
+        /// Make one commit per hunk (currently the only mode)
+        #[arg(long, required = true)]
+        per_hunk: bool,
+
+        /// Commit message, with {first_line} and {file} placeholders
+        #[arg(long, value_name = "TEMPLATE", default_value = "update {first_line}")]
+        message_template: String,
+
+        /// Read the message template from a file instead (- for stdin)
+        #[arg(
+            short = 'F',
+            long,
+            value_name = "PATH",
+            conflicts_with = "message_template"
+        )]
+        message_file: Option<String>,
+
+        /// Suppress output listing the commits made
+        #[arg(short, long)]
+        quiet: bool,
     },
     /// Generate shell completion scripts
     ///
@@ -112,40 +547,1052 @@ enum Commands {
     Man,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// Output format for `diff`
+#[derive(Clone, Copy, ValueEnum)]
+enum DiffFormat {
+    /// Numbered lines, as used for choosing selections
+    Numbered,
+    /// Zero-context unified diff for `git apply --unidiff-zero`
+    Patch,
+    /// Unified diff with context and no git headers, for `patch -p1`
+    PlainPatch,
+    /// HTML fragment whose lines carry their stage tokens
+    Html,
+    /// One FILE:REFS token per file selecting its lines, for `xargs git lines stage`
+    Tokens,
+    /// Files, hunks and numbered lines as JSON, for scripts
+    Json,
+}
+
+/// Writes each staged file's patch to its own file for `stage --output-dir`
+struct PatchExport {
+    /// Directory the patches go in
+    dir: PathBuf,
+    /// Patch file written so far for each repository path; later patches
+    /// for the same path append
+    written: HashMap<String, PathBuf>,
+}
+
+impl PatchExport {
+    /// Start an export into `dir`, creating it if needed
+    fn new(dir: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            written: HashMap::new(),
+        })
+    }
+
+    /// Write the patch of each file in `staged`
+    ///
+    /// A file staged again in the same run (say, by a second spec) gets the
+    /// new patch appended, so the file still replays in order with `git apply`.
+    /// Paths whose names flatten alike (`d/b.txt`, `d_b.txt`) get numbered
+    /// patch files (`d_b.txt.patch`, `d_b.txt.2.patch`) rather than sharing one.
+    fn write(&mut self, staged: &git_lines::diff::Diff) -> io::Result<()> {
+        for file in &staged.files {
+            let mut out = match self.written.get(&file.path) {
+                Some(path) => std::fs::OpenOptions::new().append(true).open(path)?,
+                None => {
+                    let path = self.unused_path(&file.path);
+                    self.written.insert(file.path.clone(), path.clone());
+                    std::fs::File::create(&path)?
+                }
+            };
+            write!(out, "{}", file)?;
+        }
+        Ok(())
+    }
+
+    /// Patch file for a path not exported yet, numbered past any patch file
+    /// already written for another path
+    fn unused_path(&self, path: &str) -> PathBuf {
+        let name = patch_file_name(path);
+        let taken = |candidate: &PathBuf| self.written.values().any(|p| p == candidate);
+        let stem = name.trim_end_matches(".patch");
+        let mut candidate = self.dir.join(&name);
+        let mut n = 1;
+        while taken(&candidate) {
+            n += 1;
+            candidate = self.dir.join(format!("{}.{}.patch", stem, n));
+        }
+        candidate
+    }
+}
+
+/// Warn about the refs of `specs` that named no changed line
+///
+/// Specs whose refs all matched nothing failed to stage and say so
+/// themselves, so they are not warned about.
+fn warn_unmatched(specs: &[String], unmatched: &[String]) {
+    let named: usize = specs
+        .iter()
+        .filter_map(|spec| FileLineRefs::parse(spec).ok())
+        .map(|parsed| {
+            parsed
+                .refs
+                .iter()
+                .filter(|r| !matches!(r, LineRef::Exclude(_)))
+                .count()
+        })
+        .sum();
+    if !unmatched.is_empty() && unmatched.len() < named {
+        eprintln!(
+            "warning: '{}' has refs matching no changed line: {}",
+            specs.join(" "),
+            unmatched.join(", ")
+        );
+    }
+}
+
+/// Name of the patch file for a repository path: directory separators and
+/// anything unusual become `_`, so `src/lib.rs` is `src_lib.rs.patch`
+fn patch_file_name(path: &str) -> String {
+    let name: String = path
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}.patch", name)
+}
+
+/// Time spent per phase of a command, printed to stderr when dropped
+///
+/// Backs `--profile`. Dropping rather than an explicit print means the
+/// breakdown also shows for commands that fail or return early.
+struct Profile {
+    start: Instant,
+    /// Total time per phase, in the order each phase first ran
+    phases: Arc<Mutex<Vec<(String, Duration)>>>,
+}
+
+impl Profile {
+    fn start() -> Self {
+        Self {
+            start: Instant::now(),
+            phases: Arc::default(),
+        }
+    }
+
+    /// Hook for [`GitLines::profile_hook`] recording the library's phases
+    fn hook(&self) -> impl Fn(Phase, Duration) + Send + Sync + 'static {
+        let phases = Arc::clone(&self.phases);
+        move |phase, elapsed| record(&phases, &phase.to_string(), elapsed)
+    }
+
+    /// Run `f`, recording its time under `label`
+    fn time<T>(&self, label: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        record(&self.phases, label, start.elapsed());
+        result
+    }
+}
+
+impl Drop for Profile {
+    fn drop(&mut self) {
+        let total = self.start.elapsed();
+        eprintln!("profile:");
+        let phases = self.phases.lock().unwrap_or_else(PoisonError::into_inner);
+        for (label, elapsed) in phases.iter() {
+            eprintln!(
+                "  {:<10} {:>10.3} ms",
+                label,
+                elapsed.as_secs_f64() * 1000.0
+            );
+        }
+        eprintln!(
+            "  {:<10} {:>10.3} ms",
+            "total",
+            total.as_secs_f64() * 1000.0
+        );
+    }
+}
+
+/// Add `elapsed` to `label`'s total
+fn record(phases: &Mutex<Vec<(String, Duration)>>, label: &str, elapsed: Duration) {
+    let mut phases = phases.lock().unwrap_or_else(PoisonError::into_inner);
+    match phases.iter_mut().find(|(l, _)| l == label) {
+        Some((_, total)) => *total += elapsed,
+        None => phases.push((label.to_string(), elapsed)),
+    }
+}
+
+/// Specs that failed under `stage --keep-going`, reported together at the end
+struct StageFailures {
+    /// Number of specs attempted
+    attempted: usize,
+    /// Number of specs that failed, counting each spec of a failed batch
+    failed: usize,
+    /// One message per failed spec or batch, in order
+    errors: Vec<String>,
+}
+
+impl fmt::Display for StageFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} specs failed:", self.failed, self.attempted)?;
+        for error in &self.errors {
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+// main prints returned errors with Debug; keep the list readable
+impl fmt::Debug for StageFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for StageFailures {}
+
+/// A library error, with what the command was doing when it happened
+struct CommandError {
+    /// The failed step, e.g. "Failed to stage 'a.nix:9'"
+    context: String,
+    /// File named by the spec being handled, for errors that do not name one
+    spec_file: Option<String>,
+    error: GitLinesError,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.error)
+    }
+}
+
+impl fmt::Debug for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Wrap a library error with the step that failed, for use with `map_err`
+fn failed(context: impl Into<String>) -> impl FnOnce(GitLinesError) -> CommandError {
+    let context = context.into();
+    move |error| CommandError {
+        context,
+        spec_file: None,
+        error,
+    }
+}
+
+/// Like [`failed`], for a step handling the `file:refs` spec `spec`
+fn failed_on(context: impl Into<String>, spec: &str) -> impl FnOnce(GitLinesError) -> CommandError {
+    let context = context.into();
+    // The file comes before the last colon, even when the refs do not parse
+    let spec_file = spec
+        .rsplit_once(':')
+        .map(|(file, _)| file)
+        .filter(|file| !file.is_empty())
+        .map(str::to_string);
+    move |error| CommandError {
+        context,
+        spec_file,
+        error,
+    }
+}
+
+/// How a failure is reported on stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ErrorFormat {
+    /// `Error: ` and a message for people
+    Text,
+    /// One JSON object: `{"error": KIND, "file": FILE or null, "detail": MESSAGE}`
+    Json,
+}
+
+/// The `--error-format json` report of a failure
+#[derive(Debug, PartialEq, Eq)]
+struct ErrorReport<'a> {
+    /// Error variant name, e.g. `NoMatchingLines`; `Other` for failures that
+    /// did not come from the library
+    error: &'static str,
+    /// File the error is about, if it names one
+    file: Option<&'a str>,
+    /// The full human-readable message
+    detail: String,
+}
+
+impl<'a> ErrorReport<'a> {
+    fn new(error: &'a (dyn std::error::Error + 'static)) -> Self {
+        let (kind, file) = match error.downcast_ref::<CommandError>() {
+            Some(command) => match error_kind(&command.error) {
+                (kind, None) => (kind, command.spec_file.as_deref()),
+                named => named,
+            },
+            None => ("Other", None),
+        };
+        Self {
+            error: kind,
+            file,
+            detail: error.to_string(),
+        }
+    }
+
+    /// Exit status for the JSON report: 2 for malformed selections, like
+    /// clap's usage errors, and 1 for everything else
+    fn exit_code(&self) -> u8 {
+        match self.error {
+            "ParseError" => 2,
+            _ => 1,
+        }
+    }
+
+    /// The report as one line of JSON
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"error\":{},\"file\":{},\"detail\":{}}}",
+            json_string(self.error),
+            self.file.map_or_else(|| "null".to_string(), json_string),
+            json_string(&self.detail)
+        )
+    }
+}
+
+/// Stable name of a library error's variant, and the file it names if any
+fn error_kind(error: &GitLinesError) -> (&'static str, Option<&str>) {
+    use GitLinesError as E;
+    match error {
+        E::NoChanges { file } => ("NoChanges", Some(file)),
+        E::NoStagedChanges { file } => ("NoStagedChanges", Some(file)),
+        E::NoMatchingLines { file } => ("NoMatchingLines", Some(file)),
+        E::AmbiguousFile { .. } => ("AmbiguousFile", None),
+        E::FileSkipWorktree { file } => ("FileSkipWorktree", Some(file)),
+        E::RemapBaseMismatch { file, .. } => ("RemapBaseMismatch", Some(file)),
+        E::ConflictMarkerStaged { file, .. } => ("ConflictMarkerStaged", Some(file)),
+        E::UnbalancedSelection { file, .. } => ("UnbalancedSelection", Some(file)),
+        E::FileNotTracked { file } => ("FileNotTracked", Some(file)),
+        E::NotARepository { .. } => ("NotARepository", None),
+        E::DisallowedFile { file } => ("DisallowedFile", Some(file)),
+        E::IncompleteSelection { file, .. } => ("IncompleteSelection", Some(file)),
+        E::UnmatchedRefs { file, .. } => ("UnmatchedRefs", Some(file)),
+        E::UnparsedDiff { file, .. } => ("UnparsedDiff", Some(file)),
+        E::BinaryFile { file } => ("BinaryFile", Some(file)),
+        E::MergeConflict { .. } => ("MergeConflict", None),
+        E::StalePreimage { file } => ("StalePreimage", Some(file)),
+        E::VerificationFailed { file, .. } => ("VerificationFailed", Some(file)),
+        E::HookRejected { .. } => ("HookRejected", None),
+        E::StashConflict { .. } => ("StashConflict", None),
+        E::HunkNotCommitted { file, .. } => ("HunkNotCommitted", Some(file)),
+        E::OutputFailed { .. } => ("OutputFailed", None),
+        E::ParseError(_) => ("ParseError", None),
+        E::InvalidRepoPath => ("InvalidRepoPath", None),
+        E::RevParseFailed { .. } => ("RevParseFailed", None),
+        E::DiffFailed { .. } => ("DiffFailed", None),
+        E::DiffExitError { .. } => ("DiffExitError", None),
+        E::InvalidUtf8 { .. } => ("InvalidUtf8", None),
+        E::ApplySpawnFailed { .. } => ("ApplySpawnFailed", None),
+        E::ApplyStdinFailed => ("ApplyStdinFailed", None),
+        E::ApplyWriteFailed { .. } => ("ApplyWriteFailed", None),
+        E::ApplyWaitFailed { .. } => ("ApplyWaitFailed", None),
+        E::ApplyExitError { .. } => ("ApplyExitError", None),
+        E::ScratchIndexFailed { .. } => ("ScratchIndexFailed", None),
+        E::WriteTreeFailed { .. } => ("WriteTreeFailed", None),
+        E::WriteTreeExitError { .. } => ("WriteTreeExitError", None),
+        E::CommitFailed { .. } => ("CommitFailed", None),
+        E::CommitExitError { .. } => ("CommitExitError", None),
+        E::LsFilesFailed { .. } => ("LsFilesFailed", None),
+        E::LsFilesExitError { .. } => ("LsFilesExitError", None),
+        E::CatFileFailed { .. } => ("CatFileFailed", None),
+        E::CatFileExitError { .. } => ("CatFileExitError", None),
+        E::UnknownCommit { .. } => ("UnknownCommit", None),
+        E::BlameFailed { .. } => ("BlameFailed", None),
+        E::BlameExitError { .. } => ("BlameExitError", None),
+    }
+}
+
+/// When to color output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    /// Color when stdout is a terminal, unless --no-color or NO_COLOR is set
+    Auto,
+    /// Always color
+    Always,
+    /// Never color
+    Never,
+}
+
+/// When to escape control characters in the numbered display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EscapeChoice {
+    /// Escape when stdout is a terminal
+    Auto,
+    /// Always escape
+    Always,
+    /// Never escape; print line content byte for byte
+    Never,
+}
+
+/// A `--flag`/`--no-flag` pair as a setting, or `None` if neither was given
+fn switch(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// The file change picked by `--only-new`, `--only-modified` or `--only-deleted`
+fn only_change(new: bool, modified: bool, deleted: bool) -> Option<FileChange> {
+    match (new, modified, deleted) {
+        (true, _, _) => Some(FileChange::Added),
+        (_, true, _) => Some(FileChange::Modified),
+        (_, _, true) => Some(FileChange::Deleted),
+        _ => None,
+    }
+}
+
+/// Resolve whether to color output
+///
+/// `--color=always` wins over `--no-color` and a non-empty `NO_COLOR`, which
+/// in turn win over terminal detection.
+fn color_enabled(
+    choice: ColorChoice,
+    no_color: bool,
+    no_color_env: Option<&str>,
+    tty: bool,
+) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => !no_color && no_color_env.is_none_or(str::is_empty) && tty,
+    }
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    let error_format = cli.error_format;
+    let Err(error) = run(cli) else {
+        return ExitCode::SUCCESS;
+    };
+    match error_format {
+        ErrorFormat::Text => {
+            eprintln!("Error: {:?}", error);
+            ExitCode::FAILURE
+        }
+        ErrorFormat::Json => {
+            let report = ErrorReport::new(error.as_ref());
+            eprintln!("{}", report.to_json());
+            ExitCode::from(report.exit_code())
+        }
+    }
+}
 
+fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    // Completions and man pages read no repository or config, so a broken
+    // config cannot get in the way of them
     match cli.command {
         Commands::Completions { shell } => {
             let mut cmd = Cli::command();
             generate(shell, &mut cmd, "git-lines", &mut io::stdout());
+            return Ok(());
         }
         Commands::Man => {
             let cmd = Cli::command();
             let man = Man::new(cmd);
             man.render(&mut io::stdout())?;
+            return Ok(());
         }
-        Commands::Stage { file_refs, quiet } => {
+        _ => {}
+    }
+    let mut git = SystemGit::default();
+    if let Some(git_dir) = &cli.git_dir {
+        git = git.git_dir(git_dir);
+    }
+    if let Some(work_tree) = &cli.work_tree {
+        git = git.work_tree(work_tree);
+    }
+    // Outside a working tree only the user config applies
+    let repo_root = git
+        .toplevel(std::path::Path::new(cli.path.as_deref().unwrap_or(".")))
+        .ok();
+    let config = Config::load(repo_root.as_deref())?;
+    // --no-color is an explicit choice, so it also outranks the config's color
+    let color_choice = match (cli.color, cli.no_color) {
+        (Some(choice), _) => choice,
+        (None, true) => ColorChoice::Auto,
+        (None, false) => config.color.unwrap_or(ColorChoice::Auto),
+    };
+    let no_color_env = std::env::var("NO_COLOR").ok();
+    let color = color_enabled(
+        color_choice,
+        cli.no_color,
+        no_color_env.as_deref(),
+        io::stdout().is_terminal(),
+    );
+    let escape_control = match cli
+        .escape_control
+        .or(config.escape_control)
+        .unwrap_or(EscapeChoice::Auto)
+    {
+        EscapeChoice::Auto => io::stdout().is_terminal(),
+        EscapeChoice::Always => true,
+        EscapeChoice::Never => false,
+    };
+    let display = DisplayOptions {
+        escape_control,
+        ..DisplayOptions::default()
+    };
+    let new_stager = |repo_path: &str| GitLines::new(repo_path).backend(git.clone());
+
+    match cli.command {
+        // Handled above
+        Commands::Completions { .. } | Commands::Man => {}
+        Commands::Stage {
+            file_refs,
+            all,
+            only_path,
+            except,
+            stdin,
+            from_stash,
+            to,
+            quiet,
+            output_dir,
+            keep_going,
+            max_files,
+            preview,
+            report,
+            summary,
+            show_patch,
+            dry_run,
+            atomic,
+            indent,
+            tab_width,
+            idempotent,
+            three_way,
+            introduced_by,
+            coalesce,
+            ignore_case,
+            verify_apply,
+            reject_conflict_markers,
+            require_balanced,
+            require_complete,
+            strict,
+            warn_duplicates,
+            error_duplicates,
+            show,
+            sort_files,
+            no_sort_files,
+            count_only,
+            only_new,
+            only_modified,
+            only_deleted,
+            profile,
+        } => {
+            let profile = profile.then(Profile::start);
+            if warn_duplicates || error_duplicates {
+                for file_ref in &file_refs {
+                    let spec = match &except {
+                        Some(refs) => format!("{}:{}", file_ref, refs),
+                        None => file_ref.clone(),
+                    };
+                    // Malformed specs are reported by the staging itself
+                    let Ok(parsed) = FileLineRefs::parse(&spec) else {
+                        continue;
+                    };
+                    let duplicates = parsed.duplicate_lines();
+                    if duplicates.is_empty() {
+                        continue;
+                    }
+                    let message = format!(
+                        "'{}' lists lines more than once: {}",
+                        spec,
+                        duplicates.join(", ")
+                    );
+                    if error_duplicates {
+                        return Err(message.into());
+                    }
+                    eprintln!("warning: {}", message);
+                }
+            }
             let repo_path = cli.path.as_deref().unwrap_or(".");
-            let stager = GitLines::new(repo_path);
-            for file_ref in &file_refs {
+            let mut stager = new_stager(repo_path)
+                .sort_files(
+                    switch(sort_files, no_sort_files)
+                        .or(config.sort_files)
+                        .unwrap_or(false),
+                )
+                .tab_width(tab_width.or(config.tab_width).unwrap_or(4))
+                .idempotent(idempotent)
+                .require_complete(require_complete)
+                .strict(strict)
+                .reject_conflict_markers(reject_conflict_markers)
+                .require_balanced(require_balanced)
+                .three_way(three_way)
+                .coalesce_hunks(coalesce)
+                .ignore_case(ignore_case)
+                .verify_apply(verify_apply)
+                .escape_control(escape_control);
+            if let Some(profile) = &profile {
+                stager = stager.profile_hook(profile.hook());
+            }
+            if let Some(indent) = indent {
+                stager = stager.max_indent(indent);
+            }
+            if let Some(rev) = introduced_by {
+                stager = stager.introduced_by(rev);
+            }
+            let change = only_change(only_new, only_modified, only_deleted);
+            if let Some(change) = change {
+                stager = stager.only_change(change);
+            }
+            if preview {
+                let preview = stager
+                    .preview(&file_refs)
+                    .map_err(failed("Failed to preview"))?;
+                print!("Would stage:\n{}", preview);
+                return Ok(());
+            }
+            if dry_run {
+                for file_ref in &file_refs {
+                    let patch = stager.stage_preview(file_ref).map_err(failed_on(
+                        format!("Failed to stage '{}'", file_ref),
+                        file_ref,
+                    ))?;
+                    print!("{}", patch);
+                }
+                return Ok(());
+            }
+            if count_only {
+                for file_ref in &file_refs {
+                    let count = stager.count(file_ref).map_err(failed_on(
+                        format!("Failed to count '{}'", file_ref),
+                        file_ref,
+                    ))?;
+                    println!("{}\t{}\t{}", count.additions, count.deletions, file_ref);
+                }
+                return Ok(());
+            }
+            if let Some(max_files) = max_files.or(config.max_files) {
+                let files: BTreeSet<String> = if all {
+                    stager
+                        .parsed_diff(only_path.as_slice())
+                        .map_err(failed("Failed to get diff"))?
+                        .files
+                        .into_iter()
+                        .filter(|file| change.is_none_or(|change| file.change == change))
+                        .map(|file| file.path)
+                        .collect()
+                } else {
+                    file_refs
+                        .iter()
+                        .map(|spec| match (&except, FileLineRefs::parse(spec)) {
+                            (None, Ok(parsed)) => parsed.file,
+                            // Plain files with --except; malformed specs fail later
+                            _ => spec.clone(),
+                        })
+                        .collect()
+                };
+                if files.len() > max_files {
+                    return Err(format!(
+                        "Refusing to stage {} files (more than --max-files {})",
+                        files.len(),
+                        max_files
+                    )
+                    .into());
+                }
+            }
+            let mut export = output_dir
+                .map(|dir| PatchExport::new(std::path::Path::new(repo_path).join(dir)))
+                .transpose()
+                .map_err(|e| format!("Failed to create output directory: {}", e))?;
+            let mut export_patches = |staged: &git_lines::diff::Diff| match &mut export {
+                Some(export) => export
+                    .write(staged)
+                    .map_err(|e| format!("Failed to write patch: {}", e)),
+                None => Ok(()),
+            };
+            // Under --strict staging fails instead; stash and stdin selections
+            // are not made against the working tree
+            if !strict && from_stash.is_none() && !stdin && !file_refs.is_empty() {
+                // File-less and malformed specs name no file to check
+                let files: Vec<String> = file_refs
+                    .iter()
+                    .filter_map(|spec| match (&except, FileLineRefs::parse(spec)) {
+                        (None, Ok(parsed)) => Some(parsed.file),
+                        (None, Err(_)) => None,
+                        (Some(_), _) => Some(spec.clone()),
+                    })
+                    .filter(|file| !file.is_empty())
+                    .collect();
+                if !files.is_empty() {
+                    for warning in stager.parse_warnings(&files).unwrap_or_default() {
+                        eprintln!("warning: {}", warning);
+                    }
+                }
+            }
+            if all {
                 let staged = stager
-                    .stage(file_ref)
-                    .map_err(|e| format!("Failed to stage '{}': {}", file_ref, e))?;
+                    .stage_all(only_path.as_deref())
+                    .map_err(failed("Failed to stage all changes"))?;
+                export_patches(&staged)?;
                 if !quiet {
-                    print!("Staged:\n{}", staged);
+                    print!("Staged:\n{}", staged.display_with(display));
                 }
             }
+            let raw_diff = if stdin {
+                Some(io::read_to_string(io::stdin())?)
+            } else {
+                None
+            };
+            let mut failures = StageFailures {
+                attempted: file_refs.len(),
+                failed: 0,
+                errors: Vec::new(),
+            };
+            if atomic {
+                let staged = stager
+                    .stage_atomic(&file_refs)
+                    .map_err(failed("Failed to stage atomically; nothing was staged"))?;
+                export_patches(&staged)?;
+                if !quiet {
+                    print!("Staged:\n{}", staged.display_with(display));
+                }
+            } else {
+                let specs: Vec<String> = match &except {
+                    Some(refs) => file_refs
+                        .iter()
+                        .map(|file| format!("{}:{}", file, refs))
+                        .collect(),
+                    None => file_refs.clone(),
+                };
+                let mode = match (&raw_diff, &except, &from_stash, &to) {
+                    (Some(raw), ..) => StageMode::FromDiff(raw),
+                    (None, Some(_), _, _) => StageMode::Except,
+                    (None, None, Some(stash), _) => StageMode::FromStash(stash),
+                    (None, None, None, Some(target)) => StageMode::As(target),
+                    (None, None, None, None) => StageMode::Lines,
+                };
+                for file in stager.stage_by_file(&specs, mode) {
+                    let staged = match file.result {
+                        Ok(staged) => staged,
+                        Err(e) => {
+                            let e = failed_on(
+                                format!("Failed to stage '{}'", file.specs.join(" ")),
+                                &file.specs[0],
+                            )(e);
+                            if !keep_going {
+                                return Err(e.into());
+                            }
+                            failures.failed += file.specs.len();
+                            failures.errors.push(e.to_string());
+                            continue;
+                        }
+                    };
+                    // Under --strict staging fails instead, and already staged
+                    // lines match nothing under --idempotent
+                    if !idempotent {
+                        warn_unmatched(&file.specs, &staged.unmatched);
+                    }
+                    if !quiet {
+                        if report {
+                            print!("Staged:\n{}", staged.staged.display_with(display));
+                            println!("Hunks:");
+                            for file in &staged.files {
+                                print!("  {}: {} hunks", file.path, file.hunks);
+                                // Unsplit files have nothing to list
+                                if !file.split.is_empty() {
+                                    let split: Vec<String> =
+                                        file.split.iter().map(|i| (i + 1).to_string()).collect();
+                                    print!(", split: {}", split.join(" "));
+                                }
+                                println!();
+                            }
+                        } else if summary {
+                            println!("{}", staged.summary());
+                            if show_patch {
+                                print!("{}", staged.staged.display_with(display));
+                            }
+                        } else {
+                            print!("Staged:\n{}", staged.staged.display_with(display));
+                        }
+                    }
+                    export_patches(&staged.staged)?;
+                }
+            }
+            if show {
+                let index = stager
+                    .staged_diff()
+                    .map_err(failed("Failed to get staged diff"))?;
+                print!("Index:\n{}", index);
+            }
+            if !failures.errors.is_empty() {
+                return Err(failures.into());
+            }
         }
-        Commands::Diff { files } => {
+        Commands::Diff {
+            files,
+            show_tabs,
+            no_show_tabs,
+            no_index,
+            cached,
+            stdin,
+            select,
+            format,
+            json,
+            unified,
+            grep,
+            group_by_kind,
+            map,
+            annotate_boundaries,
+            limit_hunks,
+            limit_files,
+            dump_ast,
+            only_new,
+            only_modified,
+            only_deleted,
+            profile,
+        } => {
+            let profile = profile.then(Profile::start);
+            let format = if json { DiffFormat::Json } else { format };
             let repo_path = cli.path.as_deref().unwrap_or(".");
-            let stager = GitLines::new(repo_path);
-            let output = stager
-                .diff(&files)
-                .map_err(|e| format!("Failed to get diff: {}", e))?;
+            let mut stager = new_stager(repo_path);
+            if let Some(profile) = &profile {
+                stager = stager.profile_hook(profile.hook());
+            }
+            let diff = if no_index {
+                let [old, new] = files.as_slice() else {
+                    return Err("--no-index takes exactly two paths".into());
+                };
+                stager.diff_no_index(old, new, select.as_deref())
+            } else if stdin {
+                let raw_diff = io::read_to_string(io::stdin())?;
+                let parse = || git_lines::diff::Diff::parse(&raw_diff);
+                let mut diff = match &profile {
+                    Some(profile) => profile.time(&Phase::Parse.to_string(), parse),
+                    None => parse(),
+                };
+                if !files.is_empty() {
+                    diff.files.retain(|file| files.contains(&file.path));
+                }
+                Ok(diff)
+            } else if cached {
+                stager.parsed_diff_from(DiffSource::Index, &files)
+            } else {
+                stager.parsed_diff(&files)
+            }
+            .map_err(failed("Failed to get diff"))?;
+            if dump_ast {
+                println!("{:#?}", diff);
+                return Ok(());
+            }
+            let mut diff = match &grep {
+                Some(_) if matches!(format, DiffFormat::Patch | DiffFormat::PlainPatch) => {
+                    return Err("--grep leaves no applicable patch; use another --format".into());
+                }
+                Some(text) => diff.grep(|line| line.contains(text.as_str())),
+                None => diff,
+            };
+            if let Some(change) = only_change(only_new, only_modified, only_deleted) {
+                diff.files.retain(|file| file.change == change);
+            }
+            if map {
+                print!("{}", diff.map());
+                return Ok(());
+            }
+            match format {
+                DiffFormat::Numbered => {
+                    let options = DisplayOptions {
+                        show_tabs: switch(show_tabs, no_show_tabs)
+                            .or(config.show_tabs)
+                            .unwrap_or(false),
+                        group_by_kind,
+                        limit_hunks,
+                        limit_files,
+                        annotate_boundaries,
+                        color,
+                        ..display
+                    };
+                    // Without context the display streams to stdout a hunk at a time
+                    let render = || match unified {
+                        Some(context) => stager
+                            .display_with_context(&diff, context, options)
+                            .map(|numbered| print!("{}", numbered))
+                            .map_err(failed("Failed to read context")),
+                        None => diff
+                            .write_with(&mut io::stdout().lock(), options)
+                            .map_err(|source| GitLinesError::OutputFailed { source })
+                            .map_err(failed("Failed to write diff")),
+                    };
+                    match &profile {
+                        Some(profile) => profile.time("render", render),
+                        None => render(),
+                    }?;
+                }
+                DiffFormat::Patch => print!("{}", diff.to_patch()),
+                DiffFormat::PlainPatch => {
+                    let patch = if let [old, _] = files.as_slice()
+                        && no_index
+                    {
+                        // The old side of --no-index is a file, not a blob
+                        let old_path = std::path::Path::new(repo_path).join(old);
+                        let content = std::fs::read_to_string(&old_path)
+                            .map_err(|e| format!("Failed to read {}: {}", old_path.display(), e))?;
+                        diff.files
+                            .iter()
+                            .map(|file| file.to_plain_patch(&content, PLAIN_PATCH_CONTEXT))
+                            .collect::<Option<String>>()
+                            .ok_or_else(|| {
+                                format!("{} changed while diffing", old_path.display())
+                            })?
+                    } else {
+                        stager
+                            .plain_patch(&diff)
+                            .map_err(|e| format!("Failed to render patch: {}", e))?
+                    };
+                    print!("{}", patch);
+                }
+                DiffFormat::Html => print!("{}", diff.to_html()),
+                DiffFormat::Tokens => print!("{}", diff.tokens()),
+                DiffFormat::Json => print!("{}", diff.to_json()),
+            }
+        }
+        Commands::Commit {
+            file,
+            per_hunk: _,
+            message_template,
+            message_file,
+            quiet,
+        } => {
+            let repo_path = cli.path.as_deref().unwrap_or(".");
+            let stager = new_stager(repo_path);
+            let message_template = match message_file.as_deref() {
+                Some("-") => io::read_to_string(io::stdin())?,
+                // Relative to -C, as if started there
+                Some(path) => std::fs::read_to_string(std::path::Path::new(repo_path).join(path))
+                    .map_err(|e| format!("Failed to read '{}': {}", path, e))?,
+                None => message_template,
+            };
+            let messages = stager
+                .commit_per_hunk(&file, &message_template)
+                .map_err(failed(format!("Failed to commit '{}'", file)))?;
+            if !quiet {
+                for message in &messages {
+                    println!("Committed: {}", message.lines().next().unwrap_or(""));
+                }
+            }
+        }
+        Commands::Plan { files } => {
+            let repo_path = cli.path.as_deref().unwrap_or(".");
+            let stager = new_stager(repo_path);
+            let output = stager.plan(&files).map_err(failed("Failed to get plan"))?;
             print!("{}", output);
         }
+        Commands::Unstage { file_refs, quiet } => {
+            let repo_path = cli.path.as_deref().unwrap_or(".");
+            let stager = new_stager(repo_path).escape_control(escape_control);
+            for file_ref in &file_refs {
+                let unstaged = if file_ref.contains(':') {
+                    stager.unstage(file_ref)
+                } else {
+                    stager.unstage_all_for_file(file_ref)
+                }
+                .map_err(failed_on(
+                    format!("Failed to unstage '{}'", file_ref),
+                    file_ref,
+                ))?;
+                if !quiet {
+                    print!("Unstaged:\n{}", unstaged.display_with(display));
+                }
+            }
+        }
+        Commands::Discard {
+            file_refs,
+            force: _,
+            quiet,
+        } => {
+            let repo_path = cli.path.as_deref().unwrap_or(".");
+            let stager = new_stager(repo_path).escape_control(escape_control);
+            // Every spec is read against the same working tree, so they are
+            // discarded together; name the file of a spec that fails to parse
+            let spec = file_refs
+                .iter()
+                .find(|spec| FileLineRefs::parse(spec).is_err())
+                .unwrap_or(&file_refs[0]);
+            let discarded = stager.discard_many(&file_refs).map_err(failed_on(
+                format!("Failed to discard '{}'", file_refs.join(" ")),
+                spec,
+            ))?;
+            if !quiet {
+                print!("Discarded:\n{}", discarded.display_with(display));
+            }
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_color_env_suppresses_color() {
+        assert!(color_enabled(ColorChoice::Auto, false, None, true));
+        assert!(!color_enabled(ColorChoice::Auto, false, Some("1"), true));
+        // An empty NO_COLOR is treated as unset
+        assert!(color_enabled(ColorChoice::Auto, false, Some(""), true));
+    }
+
+    #[test]
+    fn stage_failures_list_each_spec() {
+        let failures = StageFailures {
+            attempted: 3,
+            failed: 2,
+            errors: vec![
+                "Failed to stage 'a.nix:9': No matching lines found for a.nix".to_string(),
+                "Failed to stage 'b.nix:x': Invalid line number 'x'".to_string(),
+            ],
+        };
+        assert_eq!(
+            failures.to_string(),
+            "2 of 3 specs failed:\n  Failed to stage 'a.nix:9': No matching lines found for a.nix\n  Failed to stage 'b.nix:x': Invalid line number 'x'"
+        );
+    }
+
+    #[test]
+    fn patch_file_names_are_flat() {
+        assert_eq!(patch_file_name("flake.nix"), "flake.nix.patch");
+        assert_eq!(patch_file_name("src/diff/mod.rs"), "src_diff_mod.rs.patch");
+        assert_eq!(patch_file_name("my file:v2.txt"), "my_file_v2.txt.patch");
+    }
+
+    #[test]
+    fn error_report_json_escapes_strings() {
+        let report = ErrorReport {
+            error: "ParseError",
+            file: Some("dir/\"q\".txt"),
+            detail: "bad\tref\\\n\u{1b}".to_string(),
+        };
+        assert_eq!(
+            report.to_json(),
+            r#"{"error":"ParseError","file":"dir/\"q\".txt","detail":"bad\tref\\\n\u001b"}"#
+        );
+        let report = ErrorReport {
+            file: None,
+            ..report
+        };
+        assert!(report.to_json().contains(r#""file":null"#));
+    }
+
+    #[test]
+    fn color_precedence() {
+        assert!(color_enabled(ColorChoice::Always, true, Some("1"), false));
+        assert!(!color_enabled(ColorChoice::Auto, true, None, true));
+        assert!(!color_enabled(ColorChoice::Auto, false, None, false));
+        assert!(!color_enabled(ColorChoice::Never, false, None, true));
+    }
+}
@@ -6,7 +6,7 @@
 //! # Syntax
 //!
 //! The expected format is `FILE:REFS` where:
-//! - `FILE` is a file path (cannot be empty)
+//! - `FILE` is a file path, or empty to select the only changed file
 //! - `REFS` is a comma-separated list of line references
 //!
 //! # Line Reference Types
@@ -15,6 +15,13 @@
 //! - `-N` - Deletion at old line N
 //! - `N..M` - Range of additions (inclusive)
 //! - `-N..-M` - Range of deletions (inclusive)
+//! - `~-N` - Deletion of the old line aligned with new line N in a replacement
+//! - `rename` - The file's rename, for renames with no content change
+//! - `all` - Every change to the file
+//! - `*` - The same as `all`, but only on its own
+//!
+//! The same list is available at runtime from [`grammar`], for help text and
+//! completion.
 //!
 //! # Examples
 //!
@@ -43,6 +50,7 @@
 //! ```
 
 use error_set::error_set;
+use std::fmt;
 use std::num::NonZeroU32;
 
 error_set! {
@@ -51,9 +59,6 @@ error_set! {
         /// Input string does not contain a colon separator
         #[display("Invalid format '{input}': expected 'file:refs'")]
         InvalidFormat { input: String },
-        /// File name portion before the colon is empty or whitespace
-        #[display("Invalid format '{input}': file name cannot be empty")]
-        EmptyFileName { input: String },
         /// No line references provided after the colon
         #[display("No line references provided")]
         EmptyRefs,
@@ -63,6 +68,12 @@ error_set! {
         /// Range has start greater than end
         #[display("Invalid range {start}..{end}: start must be <= end")]
         InvalidRange { start: u32, end: u32 },
+        /// Exclusion of something other than changed lines, or of nothing else
+        #[display("Cannot exclude '{value}': only line references can be excluded from others")]
+        InvalidExclusion { value: String },
+        /// `*` listed together with other references
+        #[display("Cannot combine '*' with '{value}': it already selects every change")]
+        InvalidStar { value: String },
         /// Deletion reference does not start with '-' prefix
         #[display("Delete reference must start with '-', got '{value}'")]
         InvalidDeleteRef { value: String },
@@ -73,7 +84,8 @@ error_set! {
 ///
 /// Line references specify which lines from a git diff should be staged.
 /// Additions reference new line numbers, deletions reference old line numbers.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum LineRef {
     /// Addition at new line number
     Add(NonZeroU32),
@@ -83,14 +95,44 @@ pub enum LineRef {
     Delete(NonZeroU32),
     /// Deletion range (inclusive start and end)
     DeleteRange(NonZeroU32, NonZeroU32),
+    /// Additions from new line number to the end of the hunk holding it (`N..`)
+    ///
+    /// The end is found in the diff being staged, so lines of later hunks
+    /// are never included; a start outside every hunk selects nothing.
+    AddRangeFrom(NonZeroU32),
+    /// Deletions from old line number to the end of the hunk holding it (`-N..`)
+    DeleteRangeFrom(NonZeroU32),
+    /// Deletion of the old line aligned with new line number in a replacement
+    ///
+    /// See [`Hunk::aligned_old_line`](crate::diff::hunk::Hunk::aligned_old_line) for
+    /// how old and new lines are paired.
+    DeleteAligned(NonZeroU32),
+    /// The file's rename itself (`rename`), for renames with no content change
+    Rename,
+    /// Every changed line of the file, and its rename if any (`all` or `*`)
+    ///
+    /// Written as `*`, it must be the only reference, in its spec and among
+    /// the specs for the file.
+    All,
+    /// Only the newline added after the file's last line (`eol`)
+    ///
+    /// Stages the old last line as deleted and re-added with a newline,
+    /// whatever else its hunk changes.
+    FinalNewline,
+    /// Lines the inner reference names, left out of the others (`!REF`)
+    ///
+    /// Only line forms can be excluded, and at least one reference in the
+    /// list must select lines: `10..20,!15` stages 10 to 14 and 16 to 20.
+    Exclude(Box<LineRef>),
 }
 
 /// Parsed file reference with line selections.
 ///
 /// Represents the structured form of a `file:refs` string after parsing.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FileLineRefs {
-    /// The file path
+    /// The file path, empty when the selection names no file (`:137`)
     pub file: String,
     /// The line references to stage from this file
     pub refs: Vec<LineRef>,
@@ -106,6 +148,18 @@ impl FileLineRefs {
     /// - `-N` - Deletion of line N
     /// - `N..M` - Addition range
     /// - `-N..-M` - Deletion range
+    /// - `N..`, `-N..` - Addition or deletion range to the end of the hunk
+    /// - `~-N` - Deletion of the old line aligned with new line N
+    /// - `rename` - The rename of `FILE` from its old path
+    /// - `all` - Every change to `FILE`
+    /// - `*` - The same as `all`; no other reference may go with it
+    /// - `eol` - The newline added after the last line of `FILE`
+    /// - `!REF` - Lines of any of the line forms above, left out of the rest
+    ///
+    /// `FILE` may be left empty (`:137`), in which case [`file`](Self::file) is
+    /// empty and the caller resolves it to the only changed file. `FILE` may
+    /// itself contain colons (`C:/repo/a.rs:10`): it ends at the last colon
+    /// that is followed by valid references.
     ///
     /// # Examples
     ///
@@ -135,51 +189,316 @@ impl FileLineRefs {
     ///
     /// Returns [`ParseError`] if:
     /// - Input doesn't contain `:` separator
-    /// - File name is empty or whitespace
     /// - No line references provided
     /// - Line numbers are invalid
     pub fn parse(input: &str) -> Result<Self, ParseError> {
+        // Colons may belong to the path, so try the last one first
+        if let Some((file, refs_str)) = input.rsplit_once(':') {
+            if let Ok(refs) = parse_line_refs(refs_str) {
+                return Ok(Self {
+                    file: file.trim().to_string(),
+                    refs,
+                });
+            }
+        }
+
         let (file, refs_str) = input
             .split_once(':')
             .ok_or_else(|| ParseError::InvalidFormat {
                 input: input.to_string(),
             })?;
 
-        let file = file.trim();
-        if file.is_empty() {
-            return Err(ParseError::EmptyFileName {
-                input: input.to_string(),
-            });
-        }
-
         Ok(Self {
-            file: file.to_string(),
+            file: file.trim().to_string(),
             refs: parse_line_refs(refs_str)?,
         })
     }
+
+    /// Lines selected more than once, in the same syntax as the refs.
+    ///
+    /// Exact repeats (`10,10`) and overlapping ranges (`10,10..12`) are both
+    /// reported; overlapping spans are merged, so `10..12,11..13` yields
+    /// `11..12`. Additions, deletions and aligned deletions are checked
+    /// separately, since `10` and `-10` name different lines.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use git_lines::parse::FileLineRefs;
+    ///
+    /// let refs = FileLineRefs::parse("file.nix:10,10..12,-3,-3").unwrap();
+    /// assert_eq!(refs.duplicate_lines(), vec!["10", "-3"]);
+    /// ```
+    pub fn duplicate_lines(&self) -> Vec<String> {
+        let mut adds = Vec::new();
+        let mut deletes = Vec::new();
+        let mut aligned = Vec::new();
+        let mut renames = 0;
+        let mut alls = 0;
+        let mut final_newlines = 0;
+        for line_ref in &self.refs {
+            match *line_ref {
+                LineRef::Add(n) => adds.push((n.get(), n.get())),
+                LineRef::AddRange(start, end) => adds.push((start.get(), end.get())),
+                LineRef::Delete(n) => deletes.push((n.get(), n.get())),
+                LineRef::DeleteRange(start, end) => deletes.push((start.get(), end.get())),
+                // Open to the end of a hunk not known here
+                LineRef::AddRangeFrom(start) => adds.push((start.get(), u32::MAX)),
+                LineRef::DeleteRangeFrom(start) => deletes.push((start.get(), u32::MAX)),
+                LineRef::DeleteAligned(n) => aligned.push((n.get(), n.get())),
+                LineRef::Rename => renames += 1,
+                LineRef::All => alls += 1,
+                LineRef::FinalNewline => final_newlines += 1,
+                // Naming a line twice to leave it out is harmless
+                LineRef::Exclude(_) => {}
+            }
+        }
+
+        let mut duplicates = Vec::new();
+        for (spans, prefix) in [(adds, ""), (deletes, "-"), (aligned, "~-")] {
+            duplicates.extend(overlaps(spans).into_iter().map(|(start, end)| {
+                if start == end {
+                    format!("{}{}", prefix, start)
+                } else if end == u32::MAX {
+                    format!("{}{}..", prefix, start)
+                } else {
+                    format!("{}{}..{}{}", prefix, start, prefix, end)
+                }
+            }));
+        }
+        if renames > 1 {
+            duplicates.push("rename".to_string());
+        }
+        if alls > 1 {
+            duplicates.push("all".to_string());
+        }
+        if final_newlines > 1 {
+            duplicates.push("eol".to_string());
+        }
+        duplicates
+    }
+}
+
+/// Renders the `file:refs` syntax that [`FileLineRefs::parse`] reads back
+impl fmt::Display for FileLineRefs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.file)?;
+        for (i, line_ref) in self.refs.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", line_ref)?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders one reference in the `refs` syntax, e.g. `-10..-12`
+impl fmt::Display for LineRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineRef::Add(n) => write!(f, "{}", n),
+            LineRef::AddRange(start, end) => write!(f, "{}..{}", start, end),
+            LineRef::Delete(n) => write!(f, "-{}", n),
+            LineRef::DeleteRange(start, end) => write!(f, "-{}..-{}", start, end),
+            LineRef::AddRangeFrom(start) => write!(f, "{}..", start),
+            LineRef::DeleteRangeFrom(start) => write!(f, "-{}..", start),
+            LineRef::DeleteAligned(n) => write!(f, "~-{}", n),
+            LineRef::Rename => f.write_str("rename"),
+            LineRef::All => f.write_str("all"),
+            LineRef::FinalNewline => f.write_str("eol"),
+            LineRef::Exclude(inner) => write!(f, "!{}", inner),
+        }
+    }
+}
+
+/// Merged spans covered by more than one of the given inclusive spans
+fn overlaps(mut spans: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    spans.sort_unstable();
+    let mut covered: Option<u32> = None;
+    let mut result: Vec<(u32, u32)> = Vec::new();
+    for (start, end) in spans {
+        if let Some(covered_end) = covered.filter(|&c| start <= c) {
+            let span_end = end.min(covered_end);
+            match result.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(span_end),
+                _ => result.push((start, span_end)),
+            }
+        }
+        covered = Some(covered.map_or(end, |c| c.max(end)));
+    }
+    result
+}
+
+/// One supported form of line reference, as listed by [`grammar`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefForm {
+    /// The form's syntax, with `N` and `M` standing for line numbers
+    pub syntax: &'static str,
+    /// What the form selects
+    pub description: &'static str,
+    /// A concrete reference of this form
+    pub example: &'static str,
+}
+
+const GRAMMAR: &[RefForm] = &[
+    RefForm {
+        syntax: "N",
+        description: "addition at new line N",
+        example: "137",
+    },
+    RefForm {
+        syntax: "-N",
+        description: "deletion of old line N",
+        example: "-20",
+    },
+    RefForm {
+        syntax: "N..M",
+        description: "additions at new lines N through M",
+        example: "10..15",
+    },
+    RefForm {
+        syntax: "-N..-M",
+        description: "deletions of old lines N through M",
+        example: "-10..-12",
+    },
+    RefForm {
+        syntax: "N..",
+        description: "additions from new line N to the end of its hunk",
+        example: "40..",
+    },
+    RefForm {
+        syntax: "-N..",
+        description: "deletions from old line N to the end of its hunk",
+        example: "-40..",
+    },
+    RefForm {
+        syntax: "~-N",
+        description: "deletion of the old line that new line N replaces",
+        example: "~-12",
+    },
+    RefForm {
+        syntax: "!REF",
+        description: "leave out lines another reference selects",
+        example: "10..20,!15",
+    },
+    RefForm {
+        syntax: "rename",
+        description: "the file's rename, for renames with no content change",
+        example: "rename",
+    },
+    RefForm {
+        syntax: "all",
+        description: "every change to the file (also `*` on its own)",
+        example: "all",
+    },
+    RefForm {
+        syntax: "eol",
+        description: "only the newline added at the end of the file",
+        example: "eol",
+    },
+];
+
+/// The line reference forms accepted after `FILE:`, one per [`LineRef`] variant.
+///
+/// References are combined with commas (`-10,10..12`).
+///
+/// # Examples
+///
+/// ```
+/// use git_lines::parse::{FileLineRefs, grammar};
+///
+/// for form in grammar() {
+///     let spec = format!("file.nix:{}", form.example);
+///     assert!(FileLineRefs::parse(&spec).is_ok(), "{}", form.syntax);
+/// }
+/// ```
+#[must_use]
+pub fn grammar() -> &'static [RefForm] {
+    GRAMMAR
 }
 
 /// Parse the line references part (after the colon)
 /// Examples: "137", "10..15", "10,15,-20"
-fn parse_line_refs(input: &str) -> Result<Vec<LineRef>, ParseError> {
-    let refs: Vec<LineRef> = input
+pub(crate) fn parse_line_refs(input: &str) -> Result<Vec<LineRef>, ParseError> {
+    let parts: Vec<&str> = input
         .split(',')
         .map(|part| part.trim())
         .filter(|part| !part.is_empty())
-        .map(parse_single_ref)
+        .collect();
+    let refs: Vec<LineRef> = parts
+        .iter()
+        .map(|part| parse_single_ref(part))
         .collect::<Result<Vec<_>, _>>()?;
 
     if refs.is_empty() {
         return Err(ParseError::EmptyRefs);
     }
+    if let Some(exclusion) = refs
+        .iter()
+        .find(|r| matches!(r, LineRef::Exclude(_)))
+        .filter(|_| refs.iter().all(|r| matches!(r, LineRef::Exclude(_))))
+    {
+        return Err(ParseError::InvalidExclusion {
+            value: exclusion.to_string(),
+        });
+    }
+    if parts.len() > 1
+        && let Some(star) = parts.iter().position(|part| *part == "*")
+    {
+        return Err(ParseError::InvalidStar {
+            value: parts[usize::from(star == 0)].to_string(),
+        });
+    }
 
     Ok(refs)
 }
 
+/// Whether a `file:refs` string selects with a bare `*`
+///
+/// `*` parses to [`LineRef::All`] like `all` does, so callers merging specs
+/// check the written form to keep it alone among the specs for a file.
+pub(crate) fn is_star(input: &str) -> bool {
+    input
+        .rsplit_once(':')
+        .is_some_and(|(_, refs)| refs.trim() == "*")
+}
+
 /// Parse a single line reference (could be single number, range, or deletion)
 fn parse_single_ref(input: &str) -> Result<LineRef, ParseError> {
+    match input {
+        "rename" => return Ok(LineRef::Rename),
+        "all" | "*" => return Ok(LineRef::All),
+        "eol" => return Ok(LineRef::FinalNewline),
+        _ => {}
+    }
+
+    if let Some(excluded) = input.strip_prefix('!') {
+        return match parse_single_ref(excluded)? {
+            LineRef::Exclude(_) | LineRef::Rename | LineRef::All | LineRef::FinalNewline => {
+                Err(ParseError::InvalidExclusion {
+                    value: input.to_string(),
+                })
+            }
+            inner => Ok(LineRef::Exclude(Box::new(inner))),
+        };
+    }
+
+    // Aligned deletion (~-N) names a new line, so only the single form exists
+    if let Some(aligned) = input.strip_prefix('~') {
+        return Ok(LineRef::DeleteAligned(parse_delete_number(aligned)?));
+    }
+
     // Check for range syntax (N..M or -N..-M)
     if let Some((start_str, end_str)) = input.split_once("..") {
+        // An open range (N.., -N..) runs to the end of its hunk
+        if end_str.is_empty() {
+            return Ok(if start_str.starts_with('-') {
+                LineRef::DeleteRangeFrom(parse_delete_number(start_str)?)
+            } else {
+                LineRef::AddRangeFrom(parse_add_number(start_str)?)
+            });
+        }
         // Determine if it's a deletion range
         if start_str.starts_with('-') {
             let start = parse_delete_number(start_str)?;
@@ -238,10 +557,33 @@ mod tests {
     use super::*;
     use similar_asserts::assert_eq;
 
+    #[test]
+    fn display_round_trips() {
+        for spec in [
+            "flake.nix:137",
+            "a.nix:-10..-12,~-4,10..11,-3",
+            "c.nix:40..,-7..",
+            "d.nix:10..20,!15,!-3..-4",
+            "b.nix:rename,all",
+            "e.nix:all,!10",
+        ] {
+            let refs = FileLineRefs::parse(spec).unwrap();
+            assert_eq!(refs.to_string(), spec);
+        }
+    }
+
     fn nz(n: u32) -> NonZeroU32 {
         NonZeroU32::new(n).unwrap()
     }
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn file_line_refs_json_round_trips() {
+        let refs = FileLineRefs::parse("flake.nix:-10..-12,137,!11,all").unwrap();
+        let json = serde_json::to_string(&refs).unwrap();
+        assert_eq!(serde_json::from_str::<FileLineRefs>(&json).unwrap(), refs);
+    }
+
     #[test]
     fn parse_single_addition() {
         let result = FileLineRefs::parse("flake.nix:137").unwrap();
@@ -256,6 +598,63 @@ mod tests {
         assert_eq!(result.refs, vec![LineRef::AddRange(nz(39), nz(43))]);
     }
 
+    #[test]
+    fn parse_paths_with_colons() {
+        let result = FileLineRefs::parse("C:/repo/a.rs:10").unwrap();
+        assert_eq!(result.file, "C:/repo/a.rs");
+        assert_eq!(result.refs, vec![LineRef::Add(nz(10))]);
+
+        let result = FileLineRefs::parse("weird:name.txt:5").unwrap();
+        assert_eq!(result.file, "weird:name.txt");
+        assert_eq!(result.refs, vec![LineRef::Add(nz(5))]);
+
+        let result = FileLineRefs::parse("file.nix:10").unwrap();
+        assert_eq!(result.file, "file.nix");
+        assert_eq!(result.refs, vec![LineRef::Add(nz(10))]);
+
+        // Errors still point at the refs after the first colon
+        assert!(matches!(
+            FileLineRefs::parse("file.nix:abc"),
+            Err(ParseError::InvalidLineNumber { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_open_ranges() {
+        let result = FileLineRefs::parse("flake.nix:40..,-12..").unwrap();
+        assert_eq!(
+            result.refs,
+            vec![
+                LineRef::AddRangeFrom(nz(40)),
+                LineRef::DeleteRangeFrom(nz(12))
+            ]
+        );
+        assert!(FileLineRefs::parse("flake.nix:..").is_err());
+        assert!(FileLineRefs::parse("flake.nix:-0..").is_err());
+    }
+
+    #[test]
+    fn parse_exclusions() {
+        let result = FileLineRefs::parse("flake.nix:10..20,!15,!-45").unwrap();
+        assert_eq!(
+            result.refs,
+            vec![
+                LineRef::AddRange(nz(10), nz(20)),
+                LineRef::Exclude(Box::new(LineRef::Add(nz(15)))),
+                LineRef::Exclude(Box::new(LineRef::Delete(nz(45)))),
+            ]
+        );
+        assert!(matches!(
+            FileLineRefs::parse("flake.nix:!15"),
+            Err(ParseError::InvalidExclusion { .. })
+        ));
+        assert!(matches!(
+            FileLineRefs::parse("flake.nix:10,!all"),
+            Err(ParseError::InvalidExclusion { .. })
+        ));
+        assert!(FileLineRefs::parse("flake.nix:10,!!15").is_err());
+    }
+
     #[test]
     fn parse_multiple_additions() {
         let result = FileLineRefs::parse("default.nix:40,41").unwrap();
@@ -304,6 +703,125 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_aligned_deletion() {
+        let result = FileLineRefs::parse("file.nix:~-10,12").unwrap();
+        assert_eq!(
+            result.refs,
+            vec![LineRef::DeleteAligned(nz(10)), LineRef::Add(nz(12))]
+        );
+    }
+
+    #[test]
+    fn parse_rename_selector() {
+        let result = FileLineRefs::parse("new.nix:rename").unwrap();
+        assert_eq!(result.refs, vec![LineRef::Rename]);
+    }
+
+    #[test]
+    fn parse_all_selector() {
+        let result = FileLineRefs::parse("gen.nix:all").unwrap();
+        assert_eq!(result.refs, vec![LineRef::All]);
+    }
+
+    #[test]
+    fn parse_star_selects_all() {
+        let result = FileLineRefs::parse("gen.nix:*").unwrap();
+        assert_eq!(result.refs, vec![LineRef::All]);
+        assert_eq!(result.to_string(), "gen.nix:all");
+    }
+
+    #[test]
+    fn parse_rejects_star_with_other_refs() {
+        for (refs, other) in [
+            ("*,10", "10"),
+            ("-3,*", "-3"),
+            ("all,*", "all"),
+            ("*,!10", "!10"),
+        ] {
+            assert!(
+                matches!(
+                    parse_line_refs(refs),
+                    Err(ParseError::InvalidStar { ref value }) if value == other
+                ),
+                "{}",
+                refs
+            );
+        }
+        assert!(FileLineRefs::parse("gen.nix:all,!10").is_ok());
+        assert!(FileLineRefs::parse("gen.nix:rename,all").is_ok());
+    }
+
+    #[test]
+    fn parse_final_newline_selector() {
+        let result = FileLineRefs::parse("gen.nix:eol").unwrap();
+        assert_eq!(result.refs, vec![LineRef::FinalNewline]);
+    }
+
+    /// The grammar form a reference is written in; exhaustive, so a new
+    /// variant cannot be added without deciding its grammar entry
+    fn form_of(line_ref: &LineRef) -> &'static str {
+        match line_ref {
+            LineRef::Add(_) => "N",
+            LineRef::Delete(_) => "-N",
+            LineRef::AddRange(..) => "N..M",
+            LineRef::DeleteRange(..) => "-N..-M",
+            LineRef::AddRangeFrom(_) => "N..",
+            LineRef::DeleteRangeFrom(_) => "-N..",
+            LineRef::DeleteAligned(_) => "~-N",
+            LineRef::Rename => "rename",
+            LineRef::All => "all",
+            LineRef::FinalNewline => "eol",
+            LineRef::Exclude(_) => "!REF",
+        }
+    }
+
+    #[test]
+    fn grammar_examples_parse_as_their_form() {
+        for form in grammar() {
+            let refs = parse_line_refs(form.example).unwrap();
+            // An exclusion needs a reference to exclude from ahead of it
+            let expected = if form.syntax == "!REF" { 2 } else { 1 };
+            assert_eq!(refs.len(), expected, "{}", form.example);
+            assert_eq!(form_of(&refs[expected - 1]), form.syntax);
+        }
+
+        let mut syntaxes: Vec<&str> = grammar().iter().map(|f| f.syntax).collect();
+        syntaxes.sort_unstable();
+        syntaxes.dedup();
+        assert_eq!(syntaxes.len(), 11, "one entry per LineRef variant");
+    }
+
+    #[test]
+    fn duplicate_lines_exact_repeats() {
+        let refs = FileLineRefs::parse("file.nix:10,-4,10,~-7,-4,~-7").unwrap();
+        assert_eq!(refs.duplicate_lines(), vec!["10", "-4", "~-7"]);
+    }
+
+    #[test]
+    fn duplicate_lines_overlapping_ranges() {
+        let refs = FileLineRefs::parse("file.nix:10,10..12,11..14,20..25,-3..-6,-5..-8").unwrap();
+        assert_eq!(refs.duplicate_lines(), vec!["10..12", "-5..-6"]);
+    }
+
+    #[test]
+    fn duplicate_lines_distinct_refs() {
+        let refs = FileLineRefs::parse("file.nix:10,-10,~-10,11..12,13,rename").unwrap();
+        assert!(refs.duplicate_lines().is_empty());
+    }
+
+    #[test]
+    fn parse_aligned_deletion_requires_minus() {
+        let result = FileLineRefs::parse("file.nix:~10");
+        assert!(matches!(result, Err(ParseError::InvalidDeleteRef { .. })));
+    }
+
+    #[test]
+    fn parse_aligned_deletion_range_rejected() {
+        let result = FileLineRefs::parse("file.nix:~-10..-12");
+        assert!(matches!(result, Err(ParseError::InvalidLineNumber { .. })));
+    }
+
     #[test]
     fn parse_invalid_format() {
         assert!(FileLineRefs::parse("no_colon").is_err());
@@ -316,20 +834,22 @@ mod tests {
 
     #[test]
     fn parse_empty_file_name() {
-        let result = FileLineRefs::parse(":10");
-        assert!(matches!(result, Err(ParseError::EmptyFileName { .. })));
+        let result = FileLineRefs::parse(":10").unwrap();
+        assert_eq!(result.file, "");
+        assert_eq!(result.refs, vec![LineRef::Add(nz(10))]);
     }
 
     #[test]
     fn parse_empty_file_with_range() {
-        let result = FileLineRefs::parse(":10..15");
-        assert!(matches!(result, Err(ParseError::EmptyFileName { .. })));
+        let result = FileLineRefs::parse(":10..15").unwrap();
+        assert_eq!(result.file, "");
+        assert_eq!(result.refs, vec![LineRef::AddRange(nz(10), nz(15))]);
     }
 
     #[test]
     fn parse_whitespace_file_name() {
-        let result = FileLineRefs::parse("  :10");
-        assert!(matches!(result, Err(ParseError::EmptyFileName { .. })));
+        let result = FileLineRefs::parse("  :10").unwrap();
+        assert_eq!(result.file, "");
     }
 
     #[test]
@@ -388,3 +908,73 @@ mod tests {
         assert_eq!(result.refs, vec![LineRef::AddRange(nz(10), nz(10))]);
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod proptests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn arb_line() -> impl Strategy<Value = NonZeroU32> {
+        (1..10_000u32).prop_map(|n| NonZeroU32::new(n).unwrap())
+    }
+
+    /// Generate a reference to changed lines, the forms `!` may exclude
+    fn arb_line_form() -> impl Strategy<Value = LineRef> {
+        prop_oneof![
+            arb_line().prop_map(LineRef::Add),
+            arb_line().prop_map(LineRef::Delete),
+            (arb_line(), arb_line()).prop_map(|(a, b)| LineRef::AddRange(a.min(b), a.max(b))),
+            (arb_line(), arb_line()).prop_map(|(a, b)| LineRef::DeleteRange(a.min(b), a.max(b))),
+            arb_line().prop_map(LineRef::AddRangeFrom),
+            arb_line().prop_map(LineRef::DeleteRangeFrom),
+            arb_line().prop_map(LineRef::DeleteAligned),
+        ]
+    }
+
+    /// Generate any reference other than an exclusion
+    fn arb_selecting() -> impl Strategy<Value = LineRef> {
+        prop_oneof![
+            4 => arb_line_form(),
+            1 => Just(LineRef::Rename),
+            1 => Just(LineRef::All),
+            1 => Just(LineRef::FinalNewline),
+        ]
+    }
+
+    /// Generate a parseable selection: at least one selecting reference,
+    /// followed by any mix of references and exclusions
+    fn arb_file_line_refs() -> impl Strategy<Value = FileLineRefs> {
+        (
+            "[a-zA-Z0-9_./:-]{0,12}",
+            arb_selecting(),
+            prop::collection::vec(
+                prop_oneof![
+                    arb_selecting(),
+                    arb_line_form().prop_map(|r| LineRef::Exclude(Box::new(r))),
+                ],
+                0..6,
+            ),
+        )
+            .prop_map(|(file, first, rest)| FileLineRefs {
+                file,
+                refs: std::iter::once(first).chain(rest).collect(),
+            })
+    }
+
+    proptest! {
+        /// Every selection survives display → parse
+        #[test]
+        fn file_line_refs_roundtrip(refs in arb_file_line_refs()) {
+            let rendered = refs.to_string();
+            prop_assert_eq!(FileLineRefs::parse(&rendered).unwrap(), refs, "{}", rendered);
+        }
+
+        /// A single reference's display parses back to it
+        #[test]
+        fn line_ref_roundtrip(line_ref in arb_selecting()) {
+            let rendered = line_ref.to_string();
+            prop_assert_eq!(parse_line_refs(&rendered).unwrap(), vec![line_ref]);
+        }
+    }
+}
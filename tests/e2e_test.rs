@@ -1,3 +1,4 @@
+#![cfg(feature = "integration")]
 #![allow(clippy::unwrap_used)]
 #![allow(clippy::expect_used)]
 #![allow(missing_docs)]
@@ -86,6 +87,18 @@ impl Fixture {
         String::from_utf8(output.stdout).unwrap()
     }
 
+    /// Run a git command in the repo and return its stdout
+    fn git(&self, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(self.dir.path())
+            .args(args)
+            .output()
+            .expect("Failed to run git");
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
     /// Helper to create a file with N numbered lines
     fn numbered_lines(n: usize) -> String {
         (1..=n)
@@ -911,10 +924,8 @@ mod multi_hunk {
         // Insert lines at 5, 15, 25, 35, 45, 55, 65, 75, 85, 95
         let mut lines: Vec<String> = (1..=94).map(|i| format!("line {}", i)).collect();
         let insertions = [4, 14, 24, 34, 44, 54, 64, 74, 84];
-        let mut offset = 0;
         for (idx, pos) in insertions.iter().enumerate() {
-            lines.insert(pos + offset, format!("     change_{}();", idx + 1));
-            offset += 1;
+            lines.insert(pos + idx, format!("     change_{}();", idx + 1));
         }
         lines.push("     change_10();".to_string());
         let modified = lines.join("\n") + "\n";
@@ -1306,6 +1317,26 @@ mod no_newline {
         insta::assert_snapshot!("no_newline__bridge_only__staged", f.git_diff_cached());
     }
 
+    /// 6.9: Final Newline Selector (Only the Newline, Not Other Changes)
+    #[test]
+    fn final_newline_selector() {
+        let f = Fixture::new();
+        f.write_file("config.nix", "line 1\nline 2\nno newline");
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        f.write_file(
+            "config.nix",
+            "line 1\nLINE 2\nno newline, edited\nnew line\n",
+        );
+
+        f.stager.stage("config.nix:eol").unwrap();
+        assert_eq!(
+            f.git(&["show", ":config.nix"]),
+            "line 1\nline 2\nno newline\n"
+        );
+    }
+
     /// 6.4: Delete No-Newline Line
     #[test]
     fn delete() {
@@ -1459,4 +1490,2494 @@ mod behavior {
             f.git_diff_cached()
         );
     }
+
+    /// File-less selection resolves to the only changed file
+    #[test]
+    fn implicit_single_file() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(5);
+        f.write_file("config.nix", &initial);
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        f.write_file("config.nix", &(initial + "extra 1\nextra 2\n"));
+
+        f.stager.stage(":7").unwrap();
+        insta::assert_snapshot!(
+            "behavior__implicit_single_file__staged",
+            f.git_diff_cached()
+        );
+    }
+
+    /// File-less selection is rejected when several files have changes
+    #[test]
+    fn implicit_file_ambiguous() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(5);
+        f.write_file("a.nix", &initial);
+        f.write_file("b.nix", &initial);
+        f.stage_file("a.nix");
+        f.stage_file("b.nix");
+        f.commit("initial");
+
+        f.write_file("a.nix", &(initial.clone() + "extra\n"));
+        f.write_file("b.nix", &(initial + "extra\n"));
+
+        let result = f.stager.stage(":6");
+        assert!(matches!(
+            result,
+            Err(git_lines::GitLinesError::AmbiguousFile { ref files }) if files == &["a.nix", "b.nix"]
+        ));
+        assert_eq!(f.git_diff_cached(), "");
+    }
+
+    /// Counting a mixed selection reports both sides without staging anything
+    #[test]
+    fn count_mixed_selection() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(10);
+        f.write_file("config.nix", &initial);
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        let mut lines: Vec<String> = (1..=10).map(|i| format!("line {}", i)).collect();
+        lines[3] = "changed 4".to_string();
+        lines[4] = "changed 5".to_string();
+        lines.insert(5, "inserted".to_string());
+        f.write_file("config.nix", &(lines.join("\n") + "\n"));
+
+        let count = f.stager.count("config.nix:-4,-5,4,6").unwrap();
+        assert_eq!(count.additions, 2);
+        assert_eq!(count.deletions, 2);
+        assert_eq!(f.git_diff_cached(), "");
+    }
+
+    /// Staging everything under a path prefix respects path boundaries
+    #[test]
+    fn stage_all_under_prefix() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(3);
+        f.write_file("packages/foo/a.txt", &initial);
+        f.write_file("packages/foobar/b.txt", &initial);
+        f.stage_file("packages/foo/a.txt");
+        f.stage_file("packages/foobar/b.txt");
+        f.commit("initial");
+
+        f.write_file("packages/foo/a.txt", &(initial.clone() + "foo change\n"));
+        f.write_file("packages/foobar/b.txt", &(initial + "foobar change\n"));
+
+        f.stager.stage_all(Some("packages/foo/")).unwrap();
+        insta::assert_snapshot!(
+            "behavior__stage_all_under_prefix__staged",
+            f.git_diff_cached()
+        );
+    }
+
+    /// Plan lists each hunk with a token that stages it
+    #[test]
+    fn plan_two_hunks() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(10);
+        f.write_file("config.nix", &initial);
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        let mut lines: Vec<String> = (1..=10).map(|i| format!("line {}", i)).collect();
+        lines[1] = "    old_setting = false;".to_string();
+        lines.insert(8, "    # SECOND".to_string());
+        f.write_file("config.nix", &(lines.join("\n") + "\n"));
+
+        let plan = f.stager.plan(["config.nix"]).unwrap();
+        insta::assert_snapshot!("behavior__plan_two_hunks__plan", plan);
+
+        // The second token stages exactly the second hunk
+        let token = plan.lines().nth(1).unwrap().split('\t').nth(1).unwrap();
+        f.stager.stage(token).unwrap();
+        insta::assert_snapshot!("behavior__plan_two_hunks__staged", f.git_diff_cached());
+    }
+
+    /// A detected copy is staged as a copy of its source plus the edits
+    #[test]
+    fn stage_detected_copy() {
+        let f = Fixture::new();
+        f.git(&["config", "diff.renames", "copies"]);
+        let initial = Fixture::numbered_lines(20);
+        f.write_file("a.txt", &initial);
+        f.stage_file("a.txt");
+        f.commit("initial");
+
+        // Copy detection only considers sources modified in the same diff
+        f.write_file("a.txt", &(initial.clone() + "line 21\n"));
+        f.write_file("b.txt", &initial.replace("line 5\n", "line five\n"));
+        f.git(&["add", "-N", "b.txt"]);
+
+        f.stager.stage_all(Some("b.txt")).unwrap();
+        insta::assert_snapshot!(
+            "behavior__stage_detected_copy__index",
+            f.git(&["show", ":b.txt"])
+        );
+        assert_eq!(f.git(&["show", ":a.txt"]), initial);
+    }
+
+    /// The repository path given at construction is readable back
+    #[test]
+    fn repo_path_accessor() {
+        let f = Fixture::new();
+        assert_eq!(f.stager.repo_path(), f.dir.path());
+    }
+
+    /// Aligned deletion picks the old line paired with a new line in a replacement
+    #[test]
+    fn aligned_deletion_in_replacement() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(10);
+        f.write_file("config.nix", &initial);
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        // Old lines 4-6 become new lines 5-7 after an insertion above them
+        let mut lines: Vec<String> = (1..=10).map(|i| format!("line {}", i)).collect();
+        lines[3] = "changed 4".to_string();
+        lines[4] = "changed 5".to_string();
+        lines[5] = "changed 6".to_string();
+        lines.insert(1, "inserted".to_string());
+        f.write_file("config.nix", &(lines.join("\n") + "\n"));
+
+        f.stager.stage("config.nix:~-6,6").unwrap();
+        insta::assert_snapshot!(
+            "behavior__aligned_deletion_in_replacement__staged",
+            f.git_diff_cached()
+        );
+    }
+
+    /// Two standalone files can be diffed and partially extracted without a repo
+    #[test]
+    fn diff_no_index_outside_repo() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("old.txt"), "a\nb\nc\n").unwrap();
+        fs::write(dir.path().join("new.txt"), "a\nB\nc\nd\n").unwrap();
+        let stager = GitLines::new(dir.path());
+
+        let diff = stager.diff_no_index("old.txt", "new.txt", None).unwrap();
+        insta::assert_snapshot!("behavior__diff_no_index__numbered", diff.to_string());
+
+        let selected = stager
+            .diff_no_index("old.txt", "new.txt", Some("-2,2"))
+            .unwrap();
+        insta::assert_snapshot!("behavior__diff_no_index__patch", selected.to_patch());
+    }
+
+    /// Sorted staging reports ignore git's configured file order
+    #[test]
+    fn stage_all_sorted_files() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(3);
+        f.write_file("a.txt", &initial);
+        f.write_file("b.txt", &initial);
+        f.write_file("order", "b.txt\na.txt\n");
+        f.stage_file("a.txt");
+        f.stage_file("b.txt");
+        f.commit("initial");
+        f.git(&["config", "diff.orderFile", "order"]);
+
+        f.write_file("a.txt", &(initial.clone() + "a change\n"));
+        f.write_file("b.txt", &(initial + "b change\n"));
+
+        let staged = GitLines::new(f.dir.path())
+            .sort_files(true)
+            .stage_all(None)
+            .unwrap();
+        let paths: Vec<&str> = staged.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["a.txt", "b.txt"]);
+    }
+
+    /// Only files with allowlisted extensions can be staged
+    #[test]
+    fn allowed_extensions() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(3);
+        for name in ["main.rs", "Cargo.lock", ".env"] {
+            f.write_file(name, &initial);
+            f.stage_file(name);
+        }
+        f.commit("initial");
+        for name in ["main.rs", "Cargo.lock", ".env"] {
+            f.write_file(name, &(initial.clone() + "change\n"));
+        }
+
+        let stager = GitLines::new(f.dir.path()).allowed_extensions(["rs", "toml"]);
+        for file_ref in ["Cargo.lock:4", ".env:4"] {
+            let err = stager.stage(file_ref).unwrap_err();
+            assert!(
+                matches!(err, git_lines::GitLinesError::DisallowedFile { .. }),
+                "{file_ref}: {err}"
+            );
+        }
+        assert!(matches!(
+            stager.stage_all(None),
+            Err(git_lines::GitLinesError::DisallowedFile { .. })
+        ));
+        assert_eq!(f.git_diff_cached(), "");
+
+        stager.stage("main.rs:4").unwrap();
+        insta::assert_snapshot!("behavior__allowed_extensions__staged", f.git_diff_cached());
+
+        // An empty extension admits files without one
+        GitLines::new(f.dir.path())
+            .allowed_extensions([""])
+            .stage(".env:4")
+            .unwrap();
+    }
+
+    /// The staged view accumulates across separate stage calls
+    #[test]
+    fn staged_diff_after_two_stages() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(10);
+        f.write_file("config.nix", &initial);
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        let mut lines: Vec<String> = (1..=10).map(|i| format!("line {}", i)).collect();
+        lines.insert(2, "first".to_string());
+        lines.insert(8, "second".to_string());
+        f.write_file("config.nix", &(lines.join("\n") + "\n"));
+
+        f.stager.stage("config.nix:3").unwrap();
+        f.stager.stage("config.nix:9").unwrap();
+        insta::assert_snapshot!(
+            "behavior__staged_diff_after_two_stages__index",
+            f.stager.staged_diff().unwrap()
+        );
+    }
+
+    /// Tab-indented recipe lines are shown verbatim, or with visible tabs
+    #[test]
+    fn diff_makefile_recipe_tabs() {
+        let f = Fixture::new();
+        f.write_file("Makefile", "all:\n\techo old\n");
+        f.stage_file("Makefile");
+        f.commit("initial");
+
+        f.write_file("Makefile", "all:\n\techo new\n\t\techo nested\n");
+
+        insta::assert_snapshot!(
+            "behavior__diff_makefile_recipe_tabs__shown",
+            GitLines::new(f.dir.path())
+                .show_tabs(true)
+                .diff(&[] as &[&str])
+                .unwrap()
+        );
+        assert_eq!(
+            f.stager.diff(["Makefile"]).unwrap(),
+            "Makefile:\n  -2: \techo old\n  +2: \techo new\n  +3: \t\techo nested\n\n"
+        );
+    }
+
+    /// Some deletions of a file removed from the worktree stage as a partial removal
+    #[test]
+    fn partial_worktree_deletion() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(10));
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        fs::remove_file(f.dir.path().join("config.nix")).unwrap();
+
+        f.stager.stage("config.nix:-3..-5").unwrap();
+        insta::assert_snapshot!(
+            "behavior__partial_worktree_deletion__staged",
+            f.git_diff_cached()
+        );
+    }
+
+    /// Opening a directory that is not a repository fails immediately
+    #[test]
+    fn open_non_repository() {
+        let dir = TempDir::new().unwrap();
+        let err = GitLines::open(dir.path()).err().unwrap();
+        assert!(matches!(
+            err,
+            git_lines::GitLinesError::NotARepository { .. }
+        ));
+
+        let f = Fixture::new();
+        assert!(GitLines::open(f.dir.path()).is_ok());
+    }
+
+    /// An indentation limit keeps only top-level lines of a mixed hunk
+    #[test]
+    fn stage_unindented_only() {
+        let f = Fixture::new();
+        f.write_file("lib.rs", "// header\n");
+        f.stage_file("lib.rs");
+        f.commit("initial");
+
+        f.write_file(
+            "lib.rs",
+            "// header\nfn a() {\n    one();\n\ttwo();\n}\nconst B: u8 = 1;\n",
+        );
+
+        GitLines::new(f.dir.path())
+            .max_indent(0)
+            .stage("lib.rs:2..6")
+            .unwrap();
+        insta::assert_snapshot!(
+            "behavior__stage_unindented_only__staged",
+            f.git_diff_cached()
+        );
+    }
+
+    /// Holding back one addition stages the rest of the hunk
+    #[test]
+    fn stage_except_one_addition() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(5);
+        f.write_file("config.nix", &initial);
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        let mut lines: Vec<String> = (1..=5).map(|i| format!("line {}", i)).collect();
+        lines[2] = "changed 3".to_string();
+        lines.insert(3, "debug print".to_string());
+        lines.insert(4, "keep me".to_string());
+        f.write_file("config.nix", &(lines.join("\n") + "\n"));
+
+        f.stager.stage_except("config.nix:4").unwrap();
+        insta::assert_snapshot!(
+            "behavior__stage_except_one_addition__staged",
+            f.git_diff_cached()
+        );
+    }
+
+    /// Re-running an already applied stage is a no-op under idempotent mode
+    #[test]
+    fn restage_idempotent() {
+        let f = Fixture::new();
+        let initial = Fixture::numbered_lines(10);
+        f.write_file("config.nix", &initial);
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        // Unstaged insertion above the staged lines shifts worktree numbering
+        let mut lines: Vec<String> = (1..=10).map(|i| format!("line {}", i)).collect();
+        lines.remove(4);
+        lines.insert(2, "first".to_string());
+        lines.insert(8, "second".to_string());
+        f.write_file("config.nix", &(lines.join("\n") + "\n"));
+
+        f.stager.stage("config.nix:-5,9").unwrap();
+        let staged = f.git_diff_cached();
+        assert!(matches!(
+            f.stager.stage("config.nix:-5,9"),
+            Err(git_lines::GitLinesError::NoMatchingLines { .. })
+        ));
+
+        let stager = GitLines::new(f.dir.path()).idempotent(true);
+        let again = stager.stage("config.nix:-5,9").unwrap();
+        assert!(again.files.is_empty());
+        assert_eq!(f.git_diff_cached(), staged);
+
+        // Lines that were never staged still fail
+        assert!(matches!(
+            stager.stage("config.nix:10"),
+            Err(git_lines::GitLinesError::NoMatchingLines { .. })
+        ));
+    }
+
+    /// Each hunk becomes its own commit, named from the template
+    #[test]
+    fn commit_per_hunk_two_hunks() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(10));
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        let modified = Fixture::numbered_lines(10)
+            .replace("line 2\n", "line 2\n  enable = true;\n")
+            .replace("line 8\n", "");
+        f.write_file("config.nix", &modified);
+
+        let messages = f
+            .stager
+            .commit_per_hunk("config.nix", "update {first_line}")
+            .unwrap();
+        assert_eq!(messages, ["update enable = true;", "update line 8"]);
+
+        let log = f.git(&["log", "--format=%s"]);
+        assert_eq!(log, "update line 8\nupdate enable = true;\ninitial\n");
+        assert_eq!(f.git(&["status", "--porcelain"]), "");
+        assert_eq!(
+            f.git(&["show", "--format=", "--numstat", "HEAD~1"]),
+            "1\t0\tconfig.nix\n"
+        );
+
+        assert!(matches!(
+            f.stager
+                .commit_per_hunk("config.nix", "update {first_line}"),
+            Err(git_lines::GitLinesError::NoChanges { .. })
+        ));
+    }
+
+    /// A text file replaced by binary content is reported, not staged by line
+    #[test]
+    fn text_replaced_by_binary() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(3));
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        fs::write(
+            f.dir.path().join("config.nix"),
+            b"\x7fELF\x00\x01\x02\xff\n",
+        )
+        .unwrap();
+
+        let result = f.stager.stage("config.nix:1");
+        assert!(matches!(
+            result,
+            Err(git_lines::GitLinesError::BinaryFile { ref file }) if file == "config.nix"
+        ));
+        assert_eq!(
+            f.stager.diff(["config.nix"]).unwrap(),
+            "config.nix: binary file, cannot stage by line\n\n"
+        );
+        assert_eq!(f.git_diff_cached(), "");
+    }
+
+    /// Lines of a stash stage into the index without touching the working tree
+    #[test]
+    fn stage_line_from_stash() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(5));
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        let stashed = Fixture::numbered_lines(5)
+            .replace("line 2\n", "line two\n")
+            .replace("line 4\n", "line four\n");
+        f.write_file("config.nix", &stashed);
+        f.git(&["stash", "--quiet"]);
+
+        f.stager
+            .stage_from_stash("stash@{0}", "config.nix:-4,4")
+            .unwrap();
+        assert_eq!(
+            f.git(&["diff", "--cached", "-U0", "--", "config.nix"])
+                .lines()
+                .filter(|l| l.starts_with(['-', '+'])
+                    && !l.starts_with("---")
+                    && !l.starts_with("+++"))
+                .collect::<Vec<_>>(),
+            ["-line 4", "+line four"]
+        );
+        // The worktree is untouched
+        assert_eq!(
+            fs::read_to_string(f.dir.path().join("config.nix")).unwrap(),
+            Fixture::numbered_lines(5)
+        );
+
+        // Once the index has moved on, the stash's other line no longer applies
+        f.write_file(
+            "config.nix",
+            &Fixture::numbered_lines(5).replace("line 2\n", "line 2b\n"),
+        );
+        f.stage_file("config.nix");
+        assert!(matches!(
+            f.stager.stage_from_stash("stash@{0}", "config.nix:-2,2"),
+            Err(git_lines::GitLinesError::StashConflict { ref stash, .. }) if stash == "stash@{0}"
+        ));
+    }
+
+    /// A three-way stash staging follows lines that have moved since the stash
+    #[test]
+    fn stage_from_stash_three_way_after_lines_moved() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(5));
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        f.write_file(
+            "config.nix",
+            &Fixture::numbered_lines(5).replace("line 3\n", "line 3\nline 3b\n"),
+        );
+        f.git(&["stash", "--quiet"]);
+
+        // Everything the stashed addition sat between moves down two lines
+        let moved = format!("header 1\nheader 2\n{}", Fixture::numbered_lines(5));
+        f.write_file("config.nix", &moved);
+        f.stage_file("config.nix");
+        f.commit("add header");
+
+        // A plain apply would insert at line 4 regardless; the merge follows line 3
+        let stager = GitLines::new(f.dir.path()).three_way(true);
+        stager
+            .stage_from_stash("stash@{0}", "config.nix:4")
+            .unwrap();
+        assert_eq!(
+            f.git(&["show", ":config.nix"]),
+            moved.replace("line 3\n", "line 3\nline 3b\n")
+        );
+    }
+
+    /// A conflicting three-way stash staging leaves the index as it was
+    #[test]
+    fn three_way_conflict_leaves_index_unchanged() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(5));
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        f.write_file(
+            "config.nix",
+            &Fixture::numbered_lines(5).replace("line 3\n", "line three\n"),
+        );
+        f.git(&["stash", "--quiet"]);
+
+        f.write_file(
+            "config.nix",
+            &Fixture::numbered_lines(5).replace("line 3\n", "line THREE\n"),
+        );
+        f.stage_file("config.nix");
+        let index_before = f.git(&["show", ":config.nix"]);
+
+        let stager = GitLines::new(f.dir.path()).three_way(true);
+        assert!(matches!(
+            stager.stage_from_stash("stash@{0}", "config.nix:-3,3"),
+            Err(git_lines::GitLinesError::MergeConflict { ref files }) if files == &["config.nix"]
+        ));
+        assert_eq!(f.git(&["ls-files", "--unmerged"]), "");
+        assert_eq!(f.git(&["show", ":config.nix"]), index_before);
+    }
+
+    /// A commit message template read from a file keeps its body
+    #[test]
+    fn commit_message_from_file() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(3));
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        f.write_file("config.nix", &Fixture::numbered_lines(4));
+        f.write_file(
+            "message.txt",
+            "feat: {first_line}\n\nFirst paragraph of the body.\n\nSecond paragraph,\nover two lines.\n",
+        );
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args([
+                "commit",
+                "--per-hunk",
+                "config.nix",
+                "--message-file",
+                "message.txt",
+            ])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "Committed: feat: line 4\n"
+        );
+
+        assert_eq!(f.git(&["log", "-1", "--format=%s"]), "feat: line 4\n");
+        assert_eq!(
+            f.git(&["log", "-1", "--format=%b"]),
+            "First paragraph of the body.\n\nSecond paragraph,\nover two lines.\n\n"
+        );
+    }
+
+    /// Changed line counts come from numstat, with errors for unchanged and untracked files
+    #[test]
+    fn changed_line_count_from_numstat() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(5));
+        f.write_file("other.nix", &Fixture::numbered_lines(2));
+        f.stage_file("config.nix");
+        f.stage_file("other.nix");
+        f.commit("initial");
+
+        f.write_file(
+            "config.nix",
+            &Fixture::numbered_lines(5).replace("line 2\n", "line two\nextra a\nextra b\n"),
+        );
+        f.write_file("untracked.nix", "new\n");
+
+        assert_eq!(
+            f.stager.changed_line_count("config.nix").unwrap(),
+            git_lines::LineCount {
+                additions: 3,
+                deletions: 1
+            }
+        );
+        assert!(matches!(
+            f.stager.changed_line_count("other.nix"),
+            Err(git_lines::GitLinesError::NoChanges { .. })
+        ));
+        assert!(matches!(
+            f.stager.changed_line_count("untracked.nix"),
+            Err(git_lines::GitLinesError::FileNotTracked { ref file }) if file == "untracked.nix"
+        ));
+    }
+
+    /// Skip-worktree and assume-unchanged files get an error saying so
+    #[test]
+    fn skip_worktree_file_gets_tailored_error() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(3));
+        f.write_file("local.nix", &Fixture::numbered_lines(3));
+        f.write_file("plain.nix", &Fixture::numbered_lines(3));
+        f.stage_file("config.nix");
+        f.stage_file("local.nix");
+        f.stage_file("plain.nix");
+        f.commit("initial");
+
+        f.git(&["update-index", "--skip-worktree", "config.nix"]);
+        f.git(&["update-index", "--assume-unchanged", "local.nix"]);
+        f.write_file("config.nix", &Fixture::numbered_lines(4));
+        f.write_file("local.nix", &Fixture::numbered_lines(4));
+
+        assert!(matches!(
+            f.stager.stage("config.nix:4"),
+            Err(git_lines::GitLinesError::FileSkipWorktree { ref file }) if file == "config.nix"
+        ));
+        assert!(matches!(
+            f.stager.stage("local.nix:4"),
+            Err(git_lines::GitLinesError::FileSkipWorktree { ref file }) if file == "local.nix"
+        ));
+        assert!(matches!(
+            f.stager.stage("plain.nix:4"),
+            Err(git_lines::GitLinesError::NoChanges { .. })
+        ));
+        assert_eq!(
+            f.stager.hidden_files().unwrap(),
+            ["config.nix", "local.nix"]
+        );
+    }
+
+    /// A plain patch carries context and no git headers, and applies with patch -p1
+    #[test]
+    fn plain_patch_applies_with_patch_p1() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(20));
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        let changed = Fixture::numbered_lines(20)
+            .replace("line 2\n", "line two\n")
+            .replace("line 15\n", "line 15\nline 15b\n");
+        f.write_file("config.nix", &changed);
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["diff", "config.nix", "--format", "plain-patch"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let patch = String::from_utf8(output.stdout).unwrap();
+        assert!(!patch.contains("diff --git"));
+        assert!(patch.contains("@@ -1,5 +1,5 @@\n line 1\n-line 2\n+line two\n"));
+
+        f.git(&["checkout", "--", "config.nix"]);
+        let mut child = Command::new("patch")
+            .arg("-p1")
+            .current_dir(f.dir.path())
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        {
+            use std::io::Write;
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(patch.as_bytes())
+                .unwrap();
+        }
+        assert!(child.wait().unwrap().success());
+        assert_eq!(
+            fs::read_to_string(f.dir.path().join("config.nix")).unwrap(),
+            changed
+        );
+    }
+
+    /// Lines staged into a sibling with the same base leave the source alone
+    #[test]
+    fn stage_as_remaps_selection_to_sibling() {
+        let f = Fixture::new();
+        f.write_file("template.txt", &Fixture::numbered_lines(3));
+        f.write_file("instance.txt", &Fixture::numbered_lines(3));
+        f.write_file("other.txt", &Fixture::numbered_lines(2));
+        f.stage_file("template.txt");
+        f.stage_file("instance.txt");
+        f.stage_file("other.txt");
+        f.commit("initial");
+
+        let generated = Fixture::numbered_lines(3).replace("line 2\n", "line two\nline 2b\n");
+        f.write_file("template.txt", &generated);
+
+        let staged = f
+            .stager
+            .stage_as("template.txt:-2,2", "instance.txt")
+            .unwrap();
+        assert_eq!(staged.files[0].path, "instance.txt");
+        assert_eq!(
+            f.git(&["show", ":instance.txt"]),
+            Fixture::numbered_lines(3).replace("line 2\n", "line two\n")
+        );
+        // The source keeps its index entry and worktree content
+        assert_eq!(
+            f.git(&["diff", "--cached", "--name-only"]),
+            "instance.txt\n"
+        );
+        assert_eq!(
+            fs::read_to_string(f.dir.path().join("template.txt")).unwrap(),
+            generated
+        );
+
+        // A target whose content differs from the diff's base is refused
+        assert!(matches!(
+            f.stager.stage_as("template.txt:3", "other.txt"),
+            Err(git_lines::GitLinesError::RemapBaseMismatch { ref target, .. }) if target == "other.txt"
+        ));
+        assert!(matches!(
+            f.stager.stage_as("template.txt:3", "missing.txt"),
+            Err(git_lines::GitLinesError::RemapBaseMismatch { .. })
+        ));
+        assert_eq!(
+            f.git(&["diff", "--cached", "--name-only"]),
+            "instance.txt\n"
+        );
+    }
+
+    /// Each file's patch is written to its own file and applies on its own
+    #[test]
+    fn output_dir_writes_one_patch_per_file() {
+        let f = Fixture::new();
+        f.write_file("a.nix", &Fixture::numbered_lines(3));
+        f.write_file("sub/b.nix", &Fixture::numbered_lines(3));
+        f.stage_file("a.nix");
+        f.stage_file("sub/b.nix");
+        f.commit("initial");
+        f.write_file("a.nix", &Fixture::numbered_lines(4));
+        f.write_file(
+            "sub/b.nix",
+            &Fixture::numbered_lines(3).replace("line 1\n", ""),
+        );
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args([
+                "stage",
+                "-q",
+                "--output-dir",
+                "patches",
+                "a.nix:4",
+                "sub/b.nix:-1",
+            ])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let mut names: Vec<String> = fs::read_dir(f.dir.path().join("patches"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a.nix.patch", "sub_b.nix.patch"]);
+
+        // Each patch applies on its own to a fresh index
+        for (name, staged) in [
+            ("a.nix.patch", "a.nix\n"),
+            ("sub_b.nix.patch", "sub/b.nix\n"),
+        ] {
+            f.git(&["reset", "--quiet"]);
+            f.git(&[
+                "apply",
+                "--cached",
+                "--unidiff-zero",
+                &format!("patches/{}", name),
+            ]);
+            assert_eq!(f.git(&["diff", "--cached", "--name-only"]), staged);
+        }
+    }
+
+    /// Paths that flatten to the same patch name get their own numbered file
+    #[test]
+    fn output_dir_numbers_patches_whose_names_collide() {
+        let f = Fixture::new();
+        f.write_file("d/b.txt", "one\n");
+        f.write_file("d_b.txt", "one\n");
+        f.stage_file("d/b.txt");
+        f.stage_file("d_b.txt");
+        f.commit("initial");
+        f.write_file("d/b.txt", "one\ntwo\n");
+        f.write_file("d_b.txt", "one\nthree\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args([
+                "stage",
+                "-q",
+                "--output-dir",
+                "patches",
+                "d/b.txt:2",
+                "d_b.txt:2",
+            ])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let patches = f.dir.path().join("patches");
+        let first = fs::read_to_string(patches.join("d_b.txt.patch")).unwrap();
+        let second = fs::read_to_string(patches.join("d_b.txt.2.patch")).unwrap();
+        assert!(first.contains("+++ b/d/b.txt") && first.contains("+two"));
+        assert!(second.contains("+++ b/d_b.txt") && second.contains("+three"));
+        assert!(!first.contains("+three"));
+    }
+
+    /// Under --keep-going every good spec is staged and every failure reported
+    #[test]
+    fn keep_going_reports_every_failed_spec() {
+        let f = Fixture::new();
+        for name in ["a.nix", "b.nix", "c.nix"] {
+            f.write_file(name, &Fixture::numbered_lines(3));
+            f.stage_file(name);
+        }
+        f.commit("initial");
+        for name in ["a.nix", "b.nix", "c.nix"] {
+            f.write_file(name, &Fixture::numbered_lines(4));
+        }
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args([
+                "stage",
+                "-q",
+                "--keep-going",
+                "a.nix:9",
+                "b.nix:4",
+                "c.nix:x",
+            ])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("2 of 3 specs failed:"), "{}", stderr);
+        assert!(stderr.contains("Failed to stage 'a.nix:9'"), "{}", stderr);
+        assert!(stderr.contains("Failed to stage 'c.nix:x'"), "{}", stderr);
+        // The good spec between the failures was still staged
+        assert_eq!(f.git(&["diff", "--cached", "--name-only"]), "b.nix\n");
+    }
+
+    /// Staging more files than --max-files is refused before anything is staged
+    #[test]
+    fn max_files_refuses_before_staging() {
+        let f = Fixture::new();
+        for name in ["a.nix", "b.nix", "c.nix"] {
+            f.write_file(name, &Fixture::numbered_lines(3));
+            f.stage_file(name);
+        }
+        f.commit("initial");
+        for name in ["a.nix", "b.nix", "c.nix"] {
+            f.write_file(name, &Fixture::numbered_lines(4));
+        }
+
+        let run = |args: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .args(["stage", "-q", "--max-files", "2"])
+                .args(args)
+                .output()
+                .unwrap()
+        };
+
+        let output = run(&["a.nix:4", "b.nix:4", "c.nix:4"]);
+        assert!(!output.status.success());
+        assert!(
+            String::from_utf8_lossy(&output.stderr)
+                .contains("Refusing to stage 3 files (more than --max-files 2)")
+        );
+        let output = run(&["--all"]);
+        assert!(!output.status.success());
+        assert_eq!(f.git(&["diff", "--cached", "--name-only"]), "");
+
+        // Two specs for the same file count once
+        f.write_file("a.nix", &Fixture::numbered_lines(5));
+        let output = run(&["a.nix:4", "a.nix:5", "b.nix:4"]);
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// A spec listing a line twice is warned about or rejected on request
+    #[test]
+    fn duplicate_lines_warn_or_error() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(3));
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        f.write_file("config.nix", &Fixture::numbered_lines(6));
+
+        let run = |flag: &str| {
+            Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .args(["stage", "-q", flag, "config.nix:4,4..5"])
+                .output()
+                .unwrap()
+        };
+
+        let output = run("--error-duplicates");
+        assert!(!output.status.success());
+        assert!(
+            String::from_utf8_lossy(&output.stderr)
+                .contains("'config.nix:4,4..5' lists lines more than once: 4")
+        );
+        assert_eq!(f.git(&["diff", "--cached", "--name-only"]), "");
+
+        let output = run("--warn-duplicates");
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "warning: 'config.nix:4,4..5' lists lines more than once: 4\n"
+        );
+        assert_eq!(
+            f.git(&["diff", "--cached", "--numstat"]),
+            "2\t0\tconfig.nix\n"
+        );
+    }
+
+    /// Equivalent stagings give the same index tree hash, without writing the index
+    #[test]
+    fn index_tree_hash_equal_for_equivalent_plans() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(5));
+        f.stage_file("config.nix");
+        f.commit("initial");
+        let head_tree = f.git(&["rev-parse", "HEAD^{tree}"]);
+        assert_eq!(f.stager.index_tree_hash().unwrap(), head_tree.trim_end());
+
+        f.write_file(
+            "config.nix",
+            &Fixture::numbered_lines(5).replace("line 4\n", "four\nmore\n"),
+        );
+
+        f.stager.stage("config.nix:-4,4").unwrap();
+        let together = f.stager.index_tree_hash().unwrap();
+        let index_before = fs::read(f.dir.path().join(".git/index")).unwrap();
+        assert_eq!(f.stager.index_tree_hash().unwrap(), together);
+        assert_eq!(
+            fs::read(f.dir.path().join(".git/index")).unwrap(),
+            index_before
+        );
+
+        f.git(&["reset", "--quiet"]);
+        f.stager.stage("config.nix:4").unwrap();
+        f.stager.stage("config.nix:-4").unwrap();
+        let separately = f.stager.index_tree_hash().unwrap();
+
+        assert_eq!(separately, together);
+        assert_ne!(together, head_tree.trim_end());
+    }
+
+    /// A rename without edits is shown, planned and staged by its rename ref
+    #[test]
+    fn stage_pure_rename() {
+        let f = Fixture::new();
+        f.write_file("old.nix", &Fixture::numbered_lines(3));
+        f.stage_file("old.nix");
+        f.commit("initial");
+
+        fs::rename(f.dir.path().join("old.nix"), f.dir.path().join("new.nix")).unwrap();
+        f.git(&["add", "--intent-to-add", "new.nix"]);
+
+        assert_eq!(
+            f.stager.diff(&[] as &[&str]).unwrap(),
+            "old.nix → new.nix (rename)\n\n"
+        );
+        assert_eq!(
+            f.stager.plan(&[] as &[&str]).unwrap(),
+            "1\tnew.nix:rename\told.nix → new.nix\n"
+        );
+
+        let staged = f.stager.stage("new.nix:rename").unwrap();
+        assert_eq!(staged.files[0].renamed_from.as_deref(), Some("old.nix"));
+        assert_eq!(
+            f.git(&["diff", "--cached", "-M", "--name-status"]),
+            "R100\told.nix\tnew.nix\n"
+        );
+        assert_eq!(f.stager.diff(&[] as &[&str]).unwrap(), "");
+    }
+
+    /// A preview shows what specs would stage without touching the index
+    #[test]
+    fn preview_two_file_plan() {
+        let f = Fixture::new();
+        f.write_file("a.nix", &Fixture::numbered_lines(3));
+        f.write_file("b.nix", &Fixture::numbered_lines(3));
+        f.stage_file("a.nix");
+        f.stage_file("b.nix");
+        f.commit("initial");
+
+        f.write_file(
+            "a.nix",
+            &Fixture::numbered_lines(3).replace("line 2\n", "two\n"),
+        );
+        f.write_file("b.nix", &Fixture::numbered_lines(5));
+        let index_before = fs::read(f.dir.path().join(".git/index")).unwrap();
+
+        let preview = f.stager.preview(["a.nix:-2,2", "b.nix:5"]).unwrap();
+        assert_eq!(
+            preview.to_string(),
+            "a.nix:\n  -2: line 2\n  +2: two\n\nb.nix:\n  +4: line 5\n\n"
+        );
+
+        assert_eq!(f.git_diff_cached(), "");
+        assert_eq!(
+            fs::read(f.dir.path().join(".git/index")).unwrap(),
+            index_before
+        );
+        let leftovers: Vec<_> = fs::read_dir(f.dir.path().join(".git"))
+            .unwrap()
+            .filter_map(|e| e.unwrap().file_name().into_string().ok())
+            .filter(|name| name.starts_with("git-lines-"))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+
+    /// Escaping control characters changes the display, never what is staged
+    #[test]
+    fn escape_control_only_changes_display() {
+        let f = Fixture::new();
+        f.write_file("log.txt", "plain\n");
+        f.stage_file("log.txt");
+        f.commit("initial");
+
+        f.write_file("log.txt", "plain\n\x1b[31merror\x1b[0m\n");
+        let diff = |when: &str| {
+            let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .args(["--escape-control", when, "diff"])
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}", output);
+            String::from_utf8(output.stdout).unwrap()
+        };
+        assert_eq!(diff("always"), "log.txt:\n  +2: ^[[31merror^[[0m\n\n");
+        assert_eq!(diff("never"), "log.txt:\n  +2: \x1b[31merror\x1b[0m\n\n");
+        // Piped output defaults to the raw bytes
+        assert_eq!(diff("auto"), diff("never"));
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["--escape-control", "always", "stage", "log.txt:2"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "Staged:\nlog.txt:\n  +2: ^[[31merror^[[0m\n\n"
+        );
+        assert_eq!(
+            f.git(&["show", ":log.txt"]),
+            "plain\n\x1b[31merror\x1b[0m\n"
+        );
+    }
+
+    /// A deletion range is limited to the lines one commit introduced
+    #[test]
+    fn stage_introduced_by_one_commit() {
+        let f = Fixture::new();
+        f.write_file("list.txt", "keep\n");
+        f.stage_file("list.txt");
+        f.commit("initial");
+        f.write_file("list.txt", "keep\ngood 1\ngood 2\n");
+        f.stage_file("list.txt");
+        f.commit("good");
+        f.write_file("list.txt", "keep\ngood 1\nbad 1\ngood 2\nbad 2\n");
+        f.stage_file("list.txt");
+        f.commit("bad");
+        let bad = f.git(&["rev-parse", "--short", "HEAD"]);
+
+        f.write_file("list.txt", "keep\n");
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["stage", "--introduced-by", bad.trim(), "list.txt:-2..-5"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+
+        assert_eq!(f.git(&["show", ":list.txt"]), "keep\ngood 1\ngood 2\n");
+        assert_eq!(
+            f.stager.diff(["list.txt"]).unwrap(),
+            "list.txt:\n  -2: good 1\n  -3: good 2\n\n"
+        );
+
+        let err = GitLines::new(f.dir.path())
+            .introduced_by("no-such-rev")
+            .stage("list.txt:-2")
+            .unwrap_err();
+        assert!(err.to_string().contains("no-such-rev"), "{}", err);
+    }
+
+    /// The repository config overrides the user config, and a flag overrides both
+    #[test]
+    fn repo_config_max_files_overridden_by_flag() {
+        let f = Fixture::new();
+        f.write_file("a.nix", &Fixture::numbered_lines(2));
+        f.write_file("b.nix", &Fixture::numbered_lines(2));
+        f.stage_file("a.nix");
+        f.stage_file("b.nix");
+        f.commit("initial");
+        f.write_file(".git-lines.toml", "max_files = 1\n");
+        f.write_file("a.nix", &Fixture::numbered_lines(3));
+        f.write_file("b.nix", &Fixture::numbered_lines(3));
+
+        let user_config = TempDir::new().unwrap();
+        fs::create_dir(user_config.path().join("git-lines")).unwrap();
+        fs::write(
+            user_config.path().join("git-lines/config.toml"),
+            "max_files = 10\n",
+        )
+        .unwrap();
+        let stage = |extra: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .env("XDG_CONFIG_HOME", user_config.path())
+                .arg("-C")
+                .arg(f.dir.path())
+                .arg("stage")
+                .args(extra)
+                .args(["a.nix:3", "b.nix:3"])
+                .output()
+                .unwrap()
+        };
+
+        // The repo config wins over the user config
+        let output = stage(&[]);
+        assert!(!output.status.success());
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("more than --max-files 1"),
+            "{:?}",
+            output
+        );
+        assert_eq!(f.git_diff_cached(), "");
+
+        // The flag wins over both
+        let output = stage(&["--max-files", "2"]);
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(f.git(&["show", ":a.nix"]), Fixture::numbered_lines(3));
+        assert_eq!(f.git(&["show", ":b.nix"]), Fixture::numbered_lines(3));
+    }
+
+    /// A switch turned on in the config is turned off by its --no- flag
+    #[test]
+    fn repo_config_switch_turned_off_by_flag() {
+        let f = Fixture::new();
+        f.write_file("Makefile", "all:\n");
+        f.stage_file("Makefile");
+        f.commit("initial");
+        f.write_file(".git-lines.toml", "show_tabs = true\n");
+        f.write_file("Makefile", "all:\n\techo hi\n");
+
+        let diff = |extra: &[&str]| {
+            let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .arg("diff")
+                .args(extra)
+                .arg("Makefile")
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}", output);
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        assert!(diff(&[]).contains('→'), "{}", diff(&[]));
+        assert!(!diff(&["--no-show-tabs"]).contains('→'));
+        // The last of the pair wins, as for other flags
+        assert!(diff(&["--no-show-tabs", "--show-tabs"]).contains('→'));
+    }
+
+    /// The repository config is found through --git-dir and --work-tree
+    #[test]
+    fn repo_config_read_from_explicit_work_tree() {
+        let f = Fixture::new();
+        f.write_file("Makefile", "all:\n");
+        f.stage_file("Makefile");
+        f.commit("initial");
+        f.write_file(".git-lines.toml", "show_tabs = true\n");
+        f.write_file("Makefile", "all:\n\techo hi\n");
+        let elsewhere = TempDir::new().unwrap();
+        let git_dir = elsewhere.path().join("repo.git");
+        fs::rename(f.dir.path().join(".git"), &git_dir).unwrap();
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(elsewhere.path())
+            .arg("--git-dir")
+            .arg(&git_dir)
+            .arg("--work-tree")
+            .arg(f.dir.path())
+            .args(["diff", "Makefile"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "Makefile:\n  +2: →   echo hi\n\n"
+        );
+    }
+
+    /// A malformed config fails the commands that read it, not completions or man
+    #[test]
+    fn malformed_config_spares_completions_and_man() {
+        let f = Fixture::new();
+        let user_config = TempDir::new().unwrap();
+        fs::create_dir(user_config.path().join("git-lines")).unwrap();
+        fs::write(
+            user_config.path().join("git-lines/config.toml"),
+            "max_file = 3\n",
+        )
+        .unwrap();
+        let run = |args: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .env("XDG_CONFIG_HOME", user_config.path())
+                .arg("-C")
+                .arg(f.dir.path())
+                .args(args)
+                .output()
+                .unwrap()
+        };
+
+        assert!(run(&["completions", "bash"]).status.success());
+        assert!(run(&["man"]).status.success());
+        let output = run(&["diff"]);
+        assert!(!output.status.success());
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("unknown key `max_file`"),
+            "{:?}",
+            output
+        );
+    }
+
+    /// Verified staging stages clean selections, bridges, new files and renames
+    #[test]
+    fn verify_apply_stages_clean_selection() {
+        let f = Fixture::new();
+        f.write_file("a.nix", "one\ntwo\nthree");
+        f.write_file("b.nix", "keep\n");
+        f.stage_file("a.nix");
+        f.stage_file("b.nix");
+        f.commit("initial");
+
+        // Staging only the addition after a line without newline needs a bridge
+        f.write_file("a.nix", "one\nTWO\nthree\nfour\n");
+        f.write_file("new.nix", "fresh\n");
+        f.git(&["add", "--intent-to-add", "new.nix"]);
+        fs::rename(f.dir.path().join("b.nix"), f.dir.path().join("c.nix")).unwrap();
+        f.git(&["add", "--intent-to-add", "c.nix"]);
+
+        let stager = GitLines::new(f.dir.path()).verify_apply(true);
+        stager.stage("a.nix:4").unwrap();
+        stager.stage("a.nix:-2,2").unwrap();
+        stager.stage("new.nix:1").unwrap();
+        stager.stage("c.nix:rename").unwrap();
+
+        assert_eq!(f.git(&["show", ":a.nix"]), "one\nTWO\nthree\nfour\n");
+        assert_eq!(f.git(&["show", ":new.nix"]), "fresh\n");
+        assert_eq!(
+            f.git(&["diff", "--cached", "-M", "--name-status"]),
+            "M\ta.nix\nR100\tb.nix\tc.nix\nA\tnew.nix\n"
+        );
+    }
+
+    /// Grepped tokens stage exactly the matching changed lines
+    #[test]
+    fn diff_grep_tokens_stage_matching_lines() {
+        let f = Fixture::new();
+        f.write_file("a.nix", "one\ntwo\n");
+        f.write_file("b.nix", "x\n");
+        f.stage_file("a.nix");
+        f.stage_file("b.nix");
+        f.commit("initial");
+
+        f.write_file("a.nix", "one\n# TODO: first\ntwo\nthree\n# TODO: last\n");
+        f.write_file("b.nix", "y\n");
+        let diff = |args: &[&str]| {
+            let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .arg("diff")
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}", output);
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        assert_eq!(
+            diff(&["--grep", "TODO"]),
+            "a.nix:\n  +2: # TODO: first\n\n  +5: # TODO: last\n\n"
+        );
+        let tokens = diff(&["--grep", "# TODO", "--format", "tokens"]);
+        assert_eq!(tokens, "a.nix:2,5\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .arg("stage")
+            .args(tokens.lines())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(
+            f.git(&["show", ":a.nix"]),
+            "one\n# TODO: first\ntwo\n# TODO: last\n"
+        );
+        assert_eq!(f.git_diff_cached().matches("+# TODO").count(), 2);
+    }
+
+    /// A path differing only in case matches under --ignore-case
+    #[test]
+    fn stage_ignore_case_path() {
+        let f = Fixture::new();
+        f.write_file("flake.nix", &Fixture::numbered_lines(2));
+        f.stage_file("flake.nix");
+        f.commit("initial");
+        f.write_file("flake.nix", &Fixture::numbered_lines(3));
+
+        assert!(f.stager.stage("Flake.nix:3").is_err());
+        let staged = GitLines::new(f.dir.path())
+            .ignore_case(true)
+            .stage("Flake.nix:3")
+            .unwrap();
+        assert_eq!(staged.files[0].path, "flake.nix");
+        assert_eq!(f.git(&["show", ":flake.nix"]), Fixture::numbered_lines(3));
+    }
+
+    /// Hunks are listed under additions, deletions and replacements
+    #[test]
+    fn diff_group_by_kind_sections() {
+        let f = Fixture::new();
+        f.write_file("list.txt", "a\nb\nc\nd\ne\nf\ng\n");
+        f.stage_file("list.txt");
+        f.commit("init");
+        f.write_file("list.txt", "A\nb\nc\ne\nf\ng\nh\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["diff", "--group-by-kind"])
+            .output()
+            .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "list.txt:\n additions:\n  +7: h\n\n deletions:\n  -4: d\n\n replacements:\n  -1: a\n  +1: A\n\n"
+        );
+    }
+
+    /// JSON error reports name the error kind and keep the exit status
+    #[test]
+    fn error_format_json_reports_parse_failure() {
+        let f = Fixture::new();
+        f.write_file("list.txt", "a\n");
+        f.stage_file("list.txt");
+        f.commit("init");
+        f.write_file("list.txt", "a\nb\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["--error-format", "json", "stage", "list.txt:x"])
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(2));
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            "{\"error\":\"ParseError\",\"file\":\"list.txt\",\"detail\":\"Failed to stage 'list.txt:x': Invalid line number 'x'\"}\n"
+        );
+
+        // Text reports keep the usual failure status
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["stage", "list.txt:x"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["--error-format", "json", "stage", "list.txt:5"])
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            "{\"error\":\"NoMatchingLines\",\"file\":\"list.txt\",\"detail\":\"Failed to stage 'list.txt:5': No matching lines found for list.txt\"}\n"
+        );
+        assert_eq!(f.git_diff_cached(), "");
+    }
+
+    /// A changed symlink target stages as a symlink
+    #[cfg(unix)]
+    #[test]
+    fn stage_symlink_target_change() {
+        use std::os::unix::fs::symlink;
+
+        let f = Fixture::new();
+        let link = f.dir.path().join("link");
+        symlink("old-target", &link).unwrap();
+        f.stage_file("link");
+        f.commit("init");
+        fs::remove_file(&link).unwrap();
+        symlink("new-target", &link).unwrap();
+
+        let staged = f.stager.stage("link:-1,1").unwrap();
+        assert_eq!(staged.files[0].mode.as_deref(), Some("120000"));
+
+        assert!(f.git(&["ls-files", "-s", "link"]).starts_with("120000 "));
+        assert_eq!(f.git(&["cat-file", "-p", ":link"]), "new-target");
+        assert_eq!(f.git(&["diff", "--", "link"]), "");
+    }
+
+    /// Only modified files are shown and staged under --only-modified
+    #[test]
+    fn stage_all_only_modified_skips_new_file() {
+        let f = Fixture::new();
+        f.write_file("old.txt", "a\n");
+        f.stage_file("old.txt");
+        f.commit("init");
+        f.write_file("old.txt", "a\nb\n");
+        f.write_file("new.txt", "fresh\n");
+        f.git(&["add", "-N", "new.txt"]);
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["diff", "--only-modified"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "old.txt:\n  +2: b\n\n"
+        );
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["stage", "--all", "--only-modified", "--quiet"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let staged = f.git(&["diff", "--cached", "--name-only"]);
+        assert_eq!(staged.trim(), "old.txt");
+        assert!(f.stager.stage("new.txt:1").is_ok());
+    }
+
+    /// The hidden AST dump prints the parsed diff structure
+    #[test]
+    fn diff_dump_ast_shows_parsed_structure() {
+        let f = Fixture::new();
+        f.write_file("list.txt", "a\nb\n");
+        f.stage_file("list.txt");
+        f.commit("init");
+        f.write_file("list.txt", "a\nB\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["diff", "--dump-ast"])
+            .output()
+            .unwrap();
+
+        assert!(output.status.success());
+        let dump = String::from_utf8_lossy(&output.stdout);
+        for expected in [
+            "Diff {",
+            "FileDiff {",
+            "path: \"list.txt\"",
+            "Hunk {",
+            "old: ModifiedLines {",
+            "start: 2",
+            "\"B\"",
+            "renamed_from: None",
+            "binary: false",
+            "change: Modified",
+        ] {
+            assert!(dump.contains(expected), "missing {expected} in:\n{dump}");
+        }
+    }
+
+    /// Further lines stage onto a file whose index already differs from HEAD
+    #[test]
+    fn stage_more_lines_onto_partially_staged_file() {
+        let f = Fixture::new();
+        f.write_file("list.txt", "a\nb\nc\nd\ne\nf\ng\nh\n");
+        f.stage_file("list.txt");
+        f.commit("init");
+        // One contiguous hunk (2..3) and a lone deletion far below it
+        f.write_file("list.txt", "a\nA1\nA2\nb\nc\nd\ne\nf\nh\n");
+
+        f.stager.stage("list.txt:3").unwrap();
+        assert_eq!(
+            f.git(&["show", ":list.txt"]),
+            "a\nA2\nb\nc\nd\ne\nf\ng\nh\n"
+        );
+
+        // Line numbers stay the worktree's; the diff is now taken against the index
+        f.stager.stage("list.txt:2").unwrap();
+        assert_eq!(
+            f.git(&["show", ":list.txt"]),
+            "a\nA1\nA2\nb\nc\nd\ne\nf\ng\nh\n"
+        );
+
+        // The deletion was old line 7 in HEAD, and is line 9 of the updated index
+        f.stager.stage("list.txt:-9").unwrap();
+        assert_eq!(
+            f.git(&["show", ":list.txt"]),
+            "a\nA1\nA2\nb\nc\nd\ne\nf\nh\n"
+        );
+        assert_eq!(f.git(&["diff"]), "");
+    }
+
+    /// Unstaging a fully staged file leaves a clean index and the working tree alone
+    #[test]
+    fn unstage_fully_staged_file_leaves_clean_index() {
+        let f = Fixture::new();
+        f.write_file("config.nix", "one\ntwo\nthree\n");
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "one\nTWO\nthree\nfour\n");
+        f.stage_file("config.nix");
+        assert!(!f.git_diff_cached().is_empty());
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["unstage", "config.nix"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(String::from_utf8_lossy(&output.stdout).starts_with("Unstaged:"));
+
+        assert_eq!(f.git_diff_cached(), "");
+        assert_eq!(
+            fs::read_to_string(f.dir.path().join("config.nix")).unwrap(),
+            "one\nTWO\nthree\nfour\n"
+        );
+    }
+
+    /// A staging summary fits on one line
+    #[test]
+    fn stage_summary_prints_one_line() {
+        let f = Fixture::new();
+        f.write_file("flake.nix", "a\nb\nc\n");
+        f.stage_file("flake.nix");
+        f.commit("initial");
+        f.write_file("flake.nix", "a\nB\nB2\nc\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["stage", "--summary", "flake.nix:-2,2..3"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "staged 2 additions, 1 deletion in flake.nix (1 hunk)\n"
+        );
+    }
+
+    /// A hunk report lists split hunks only when there are some, and is quiet under -q
+    #[test]
+    fn stage_report_lists_hunks_unless_quiet() {
+        let f = Fixture::new();
+        f.write_file("config.nix", "a\nb\nc\n");
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "a\nb\nB2\nc\nd\n");
+
+        let run = |args: &[&str]| {
+            let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        };
+        assert_eq!(
+            run(&["stage", "--report", "config.nix:3"]),
+            "Staged:\nconfig.nix:\n  +3: B2\n\nHunks:\n  config.nix: 1 hunks\n"
+        );
+        assert_eq!(run(&["stage", "--report", "-q", "config.nix:5"]), "");
+        assert!(f.git(&["diff"]).is_empty());
+    }
+
+    /// A report stages several specs for one file from the same diff, so a
+    /// deletion below an insertion keeps its line number
+    #[test]
+    fn stage_report_merges_specs_for_one_file() {
+        let f = Fixture::new();
+        f.write_file("f.txt", "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n");
+        f.stage_file("f.txt");
+        f.commit("initial");
+        f.write_file("f.txt", "1\n2\nnew\n3\n4\n5\n6\n7\n8\n9\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["stage", "--report", "f.txt:3", "f.txt:-10"])
+            .output()
+            .unwrap();
+
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "Staged:\nf.txt:\n  +3: new\n\n  -10: 10\n\nHunks:\n  f.txt: 2 hunks\n"
+        );
+        assert!(f.git(&["diff"]).is_empty());
+    }
+
+    /// Profiling reports each phase's time on stderr
+    #[test]
+    fn profile_reports_phases_on_stderr() {
+        let f = Fixture::new();
+        f.write_file("config.nix", "one\ntwo\n");
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "one\nTWO\n");
+
+        let run = |args: &[&str]| {
+            let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            String::from_utf8(output.stderr).unwrap()
+        };
+
+        let diff = run(&["diff", "--profile"]);
+        for label in ["profile:", "git diff", "parse", "render", "total"] {
+            assert!(diff.contains(label), "{label} missing from:\n{diff}");
+        }
+        let stage = run(&["stage", "--profile", "config.nix:-2,2"]);
+        for label in ["git diff", "parse", "filter", "git apply", "total"] {
+            assert!(stage.contains(label), "{label} missing from:\n{stage}");
+        }
+    }
+
+    /// An atomic staging stages every spec or none
+    #[test]
+    fn stage_atomic_stages_nothing_when_one_spec_fails() {
+        let f = Fixture::new();
+        f.write_file("a.nix", "a1\na2\n");
+        f.write_file("b.nix", "b1\nb2\n");
+        f.stage_file("a.nix");
+        f.stage_file("b.nix");
+        f.commit("initial");
+        f.write_file("a.nix", "a1\nA2\n");
+        f.write_file("b.nix", "b1\nB2\n");
+
+        let stage = |specs: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .args(["stage", "--atomic"])
+                .args(specs)
+                .output()
+                .unwrap()
+        };
+
+        let output = stage(&["a.nix:-2,2", "b.nix:9"]);
+        assert!(!output.status.success());
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("nothing was staged"),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(f.git_diff_cached(), "");
+
+        let output = stage(&["a.nix:-2", "b.nix:-2,2", "a.nix:2"]);
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(f.git(&["show", ":a.nix"]), "a1\nA2\n");
+        assert_eq!(f.git(&["show", ":b.nix"]), "b1\nB2\n");
+    }
+
+    /// Unstage refs are numbered by the staged diff
+    #[test]
+    fn unstage_takes_staged_line_numbers() {
+        let f = Fixture::new();
+        f.write_file("config.nix", "one\ntwo\nthree\nfour\n");
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file(
+            "config.nix",
+            "zero\none\ntwo\nthree\nthree and a half\nfour\n",
+        );
+        f.stager.stage("config.nix:5").unwrap();
+        // Staged diff: +4 "three and a half"; worktree diff numbers it 5
+        f.stager.stage("config.nix:1").unwrap();
+
+        let unstaged = f.stager.unstage("config.nix:5").unwrap();
+        assert_eq!(unstaged.total_additions(), 1);
+        assert_eq!(
+            f.git(&["show", ":config.nix"]),
+            "zero\none\ntwo\nthree\nfour\n"
+        );
+
+        let err = f.stager.unstage("config.nix:5").unwrap_err();
+        assert!(
+            matches!(err, git_lines::GitLinesError::NoMatchingLines { .. }),
+            "{err:?}"
+        );
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["unstage", "config.nix:1"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(f.git_diff_cached(), "");
+    }
+
+    /// Staged line numbers let unstage split a staged hunk apart
+    #[test]
+    fn diff_cached_numbers_split_a_staged_hunk() {
+        let f = Fixture::new();
+        f.write_file("config.nix", "one\nfour\n");
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "one\ntwo\nthree\nfour\n");
+        f.stage_file("config.nix");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["diff", "--cached"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "config.nix:\n  +2: two\n  +3: three\n\n"
+        );
+
+        f.stager.unstage("config.nix:3").unwrap();
+        assert_eq!(f.git(&["show", ":config.nix"]), "one\ntwo\nfour\n");
+    }
+
+    /// A dry run prints the patch staging would apply, and fails as staging would
+    #[test]
+    fn stage_dry_run_prints_patch_without_staging() {
+        let f = Fixture::new();
+        f.write_file("config.nix", "one\ntwo\n");
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "one\nTWO\nthree\n");
+
+        let run = |spec: &str| {
+            Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .args(["stage", "--dry-run", spec])
+                .output()
+                .unwrap()
+        };
+
+        let output = run("config.nix:-2,2");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            f.stager.stage_preview("config.nix:-2,2").unwrap()
+        );
+        assert!(String::from_utf8_lossy(&output.stdout).contains("\n-two\n+TWO\n"));
+        assert_eq!(f.git_diff_cached(), "");
+
+        // Fails the way staging would
+        let output = run("config.nix:9");
+        assert!(!output.status.success());
+        let err = f.stager.stage("config.nix:9").unwrap_err();
+        assert!(String::from_utf8_lossy(&output.stderr).contains(&err.to_string()));
+    }
+
+    /// An open-ended range runs to the end of its hunk only
+    #[test]
+    fn open_range_stops_at_end_of_hunk() {
+        let f = Fixture::new();
+        f.write_file("a.txt", "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n");
+        f.stage_file("a.txt");
+        f.commit("initial");
+
+        f.write_file("a.txt", "1\nA\nB\nC\n2\n3\n4\n5\n6\n7\n8\nD\n9\n10\n");
+
+        // Lines 3 and 4 finish the first hunk; line 12 is a later hunk
+        f.stager.stage("a.txt:3..").unwrap();
+        assert_eq!(
+            f.git(&["show", ":a.txt"]),
+            "1\nB\nC\n2\n3\n4\n5\n6\n7\n8\n9\n10\n"
+        );
+    }
+
+    /// `!REF` leaves lines out of a block the other refs select
+    #[test]
+    fn exclusion_skips_lines() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(6));
+        f.stage_file("config.nix");
+        f.commit("initial");
+
+        f.write_file(
+            "config.nix",
+            "line 1\nline 2\nnew A\nnew B\nnew C\nline 5\nline 6\n",
+        );
+
+        f.stager.stage("config.nix:3..5,!4,-3..-4,!-4").unwrap();
+        insta::assert_snapshot!(
+            "behavior__exclusion_skips_lines__staged",
+            f.git_diff_cached()
+        );
+    }
+
+    /// Specs for one file are staged from one diff, so deletions after an
+    /// earlier spec's addition keep their old line numbers
+    #[test]
+    fn stage_batches_specs_for_the_same_file() {
+        let f = Fixture::new();
+        f.write_file("file.nix", &Fixture::numbered_lines(30));
+        f.stage_file("file.nix");
+        f.commit("initial");
+
+        let mut lines: Vec<String> = (1..=30).map(|i| format!("line {}", i)).collect();
+        lines.remove(24);
+        lines.insert(18, "new at 20".to_string());
+        lines.insert(9, "new at 10".to_string());
+        f.write_file("file.nix", &(lines.join("\n") + "\n"));
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["stage", "file.nix:10", "file.nix:20", "file.nix:-25"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            f.git(&["show", ":file.nix"]),
+            fs::read_to_string(f.dir.path().join("file.nix")).unwrap()
+        );
+    }
+
+    /// A `!REF` leaves lines out of its own spec only; another spec for the
+    /// same file can still select them
+    #[test]
+    fn batched_specs_keep_exclusions_to_their_spec() {
+        let f = Fixture::new();
+        f.write_file("a.txt", &Fixture::numbered_lines(1));
+        f.write_file("b.txt", &Fixture::numbered_lines(1));
+        f.stage_file("a.txt");
+        f.stage_file("b.txt");
+        f.commit("initial");
+        f.write_file("a.txt", &Fixture::numbered_lines(5));
+        f.write_file("b.txt", &Fixture::numbered_lines(2));
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["stage", "a.txt:2..4,!3", "a.txt:3"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(f.git(&["show", ":a.txt"]), Fixture::numbered_lines(4));
+
+        // A failed batch counts each of its specs
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args([
+                "stage",
+                "-q",
+                "--keep-going",
+                "a.txt:9",
+                "a.txt:8",
+                "b.txt:2",
+            ])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("2 of 3 specs failed:"), "{}", stderr);
+        assert_eq!(f.git(&["show", ":b.txt"]), Fixture::numbered_lines(2));
+    }
+
+    /// The JSON diff lists every numbered line with its kind
+    #[test]
+    fn diff_json_lists_numbered_lines() {
+        let f = Fixture::new();
+        f.write_file("config.nix", "line 1\nline 2\n");
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "line 1\nLINE 2");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["diff", "--json"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let hunk = &json["files"][0]["hunks"][0];
+        assert_eq!(json["files"][0]["path"], "config.nix");
+        assert_eq!(hunk["lines"][0]["kind"], "delete");
+        assert_eq!(hunk["lines"][1]["kind"], "add");
+        assert_eq!(hunk["lines"][1]["line"], 2);
+        assert_eq!(hunk["lines"][1]["content"], "LINE 2");
+        assert_eq!(hunk["missing_final_newline"]["new"], true);
+    }
+
+    /// Context lines are shown while staging still uses the zero-context diff
+    #[test]
+    fn diff_unified_shows_context_lines() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(5));
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "line 1\nline 2\nLINE 3\nline 4\nline 5\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["diff", "-U1", "--color", "never"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "config.nix:\n   2: line 2\n  -3: line 3\n  +3: LINE 3\n   4: line 4\n\n"
+        );
+
+        // Staging still works from the zero-context diff
+        f.stager.stage("config.nix:-3,3").unwrap();
+        assert_eq!(
+            f.git(&["show", ":config.nix"]),
+            "line 1\nline 2\nLINE 3\nline 4\nline 5\n"
+        );
+    }
+
+    /// A configured git binary and environment reach every git command
+    #[cfg(unix)]
+    #[test]
+    fn system_git_runs_configured_binary_with_env() {
+        use git_lines::SystemGit;
+        use std::os::unix::fs::PermissionsExt;
+
+        let f = Fixture::new();
+        f.write_file("config.nix", "line 1\n");
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "line 1\nline 2\n");
+
+        let bin = tempfile::TempDir::new().unwrap();
+        let wrapper = bin.path().join("git-wrapper");
+        let log = bin.path().join("calls.log");
+        fs::write(
+            &wrapper,
+            "#!/bin/sh\necho \"$WRAPPER_TAG $*\" >> \"$WRAPPER_LOG\"\nexec git \"$@\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&wrapper, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let git = SystemGit::default()
+            .git_binary(&wrapper)
+            .env("WRAPPER_LOG", &log)
+            .env("WRAPPER_TAG", "wrapped");
+        GitLines::new(f.dir.path())
+            .backend(git)
+            .stage("config.nix:2")
+            .unwrap();
+
+        assert_eq!(f.git(&["show", ":config.nix"]), "line 1\nline 2\n");
+        let calls = fs::read_to_string(&log).unwrap();
+        assert!(
+            calls
+                .lines()
+                .any(|call| call.starts_with("wrapped -C") && call.contains(" diff ")),
+            "{calls}"
+        );
+        assert!(
+            calls.lines().any(|call| call.contains(" apply --cached ")),
+            "{calls}"
+        );
+    }
+
+    /// Discarding reverts only the selected lines of the working tree
+    #[test]
+    fn discard_reverts_only_selected_lines() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(5));
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file(
+            "config.nix",
+            "line 1\ndebug 1\nline 2\nLINE 3\nline 4\ndebug 2\nline 5\nkeep me\n",
+        );
+
+        let bin = || {
+            let mut command = Command::new(env!("CARGO_BIN_EXE_git-lines"));
+            command.arg("-C").arg(f.dir.path());
+            command
+        };
+        // Without --force nothing happens
+        let output = bin().args(["discard", "config.nix:2"]).output().unwrap();
+        assert!(!output.status.success());
+
+        // Line numbers are the unstaged diff's, as for stage; restoring only the
+        // deletion of a replaced line leaves what staging only its addition would
+        let output = bin()
+            .args(["discard", "--force", "config.nix:2,6,-3"])
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            fs::read_to_string(f.dir.path().join("config.nix")).unwrap(),
+            "line 1\nline 2\nLINE 3\nline 3\nline 4\nline 5\nkeep me\n"
+        );
+        assert_eq!(f.git_diff_cached(), "");
+    }
+
+    /// Several specs for one file are discarded from one diff, so later
+    /// specs keep the line numbers shown before the first was discarded
+    #[test]
+    fn discard_specs_for_the_same_file_together() {
+        let f = Fixture::new();
+        f.write_file("a.txt", "l1\nl2\nl3\nl4\n");
+        f.stage_file("a.txt");
+        f.commit("initial");
+        f.write_file("a.txt", "l1\nX\nl2\nl3\nY\nZ\nl4\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["discard", "--force", "a.txt:2", "a.txt:5"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(
+            fs::read_to_string(f.dir.path().join("a.txt")).unwrap(),
+            "l1\nl2\nl3\nZ\nl4\n"
+        );
+    }
+
+    /// Refs matching no changed line are warned about and the rest staged,
+    /// or fail the staging under --strict
+    #[test]
+    fn unmatched_refs_warn_or_fail_when_strict() {
+        let f = Fixture::new();
+        f.write_file("config.nix", "line 1\nline 2\n");
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "line 1\nnew\nline 2\n");
+
+        let stage = |args: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .arg("stage")
+                .args(args)
+                .output()
+                .unwrap()
+        };
+
+        let strict = stage(&["--strict", "config.nix:2,999"]);
+        assert!(!strict.status.success());
+        let stderr = String::from_utf8_lossy(&strict.stderr);
+        assert!(
+            stderr.contains("No changed lines of config.nix match 999"),
+            "{stderr}"
+        );
+        assert_eq!(f.git_diff_cached(), "");
+
+        let lenient = stage(&["config.nix:2,999"]);
+        assert!(lenient.status.success());
+        let stderr = String::from_utf8_lossy(&lenient.stderr);
+        assert!(
+            stderr.contains("warning: 'config.nix:2,999' has refs matching no changed line: 999"),
+            "{stderr}"
+        );
+        assert_eq!(f.git(&["show", ":config.nix"]), "line 1\nnew\nline 2\n");
+    }
+
+    /// CRLF line endings survive staging, so `git apply` finds the old lines
+    #[test]
+    fn stage_keeps_crlf_line_endings() {
+        let f = Fixture::new();
+        f.write_file("win.txt", "one\r\ntwo\r\nthree\r\n");
+        f.stage_file("win.txt");
+        f.commit("initial");
+        f.write_file("win.txt", "one\r\nTWO\r\nthree\r\nfour\r\n");
+
+        f.stager.stage("win.txt:-2,2").unwrap();
+
+        assert_eq!(f.git(&["show", ":win.txt"]), "one\r\nTWO\r\nthree\r\n");
+    }
+
+    /// A line added to a renamed file stages together with the rename
+    #[test]
+    fn stage_line_of_renamed_file() {
+        let f = Fixture::new();
+        f.write_file("old.nix", &Fixture::numbered_lines(6));
+        f.stage_file("old.nix");
+        f.commit("initial");
+
+        fs::remove_file(f.dir.path().join("old.nix")).unwrap();
+        f.write_file(
+            "new.nix",
+            &(Fixture::numbered_lines(6).replace("line 2\n", "line 2\nnew A\n") + "new B\n"),
+        );
+        f.git(&["add", "--intent-to-add", "new.nix"]);
+
+        let staged = f.stager.stage("new.nix:3").unwrap();
+        assert_eq!(staged.files[0].renamed_from.as_deref(), Some("old.nix"));
+        let status = f.git(&["diff", "--cached", "-M", "--name-status"]);
+        assert!(
+            status.starts_with('R') && status.ends_with("\told.nix\tnew.nix\n"),
+            "{status}"
+        );
+        assert_eq!(
+            f.git(&["show", ":new.nix"]),
+            Fixture::numbered_lines(6).replace("line 2\n", "line 2\nnew A\n")
+        );
+    }
+
+    /// `file:*` stages every change, leaving the index as `git add` would
+    #[test]
+    fn star_stages_every_change() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(8));
+        f.stage_file("config.nix");
+        f.commit("initial");
+        let changed = Fixture::numbered_lines(8)
+            .replace("line 2\n", "")
+            .replace("line 5\n", "five\nmore\n")
+            + "line 9";
+        f.write_file("config.nix", &changed);
+
+        assert!(matches!(
+            f.stager.stage("config.nix:*,2"),
+            Err(git_lines::GitLinesError::ParseError(_))
+        ));
+        f.stager.stage("config.nix:*").unwrap();
+
+        assert_eq!(f.git(&["show", ":config.nix"]), changed);
+        assert_eq!(f.git(&["diff"]), "");
+    }
+
+    /// Far-apart deletions selected with an addition from the same hunk split
+    /// into one hunk per run of deletions, the addition joining the first
+    #[test]
+    fn mixed_selection_splits_gapped_deletions() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(60));
+        f.stage_file("config.nix");
+        f.commit("initial");
+        let new: String = (1..=60)
+            .filter(|i| !(10..=50).contains(i))
+            .map(|i| match i {
+                9 => "line 9\nnew 10\nnew 11\n".to_string(),
+                i => format!("line {}\n", i),
+            })
+            .collect();
+        f.write_file("config.nix", &new);
+
+        let staged = f.stager.stage("config.nix:-10,-50,10").unwrap();
+
+        assert_eq!(staged.files[0].hunks.len(), 2);
+        let expected: String = (1..=60)
+            .filter(|&i| i != 10 && i != 50)
+            .map(|i| match i {
+                9 => "line 9\nnew 10\n".to_string(),
+                i => format!("line {}\n", i),
+            })
+            .collect();
+        assert_eq!(f.git(&["show", ":config.nix"]), expected);
+    }
+
+    /// A diff section that cannot be parsed is warned about, or fails the
+    /// staging under --strict
+    #[cfg(unix)]
+    #[test]
+    fn unparsed_diff_section_warns_or_fails_when_strict() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let f = Fixture::new();
+        f.write_file("run.sh", "echo hi\n");
+        f.stage_file("run.sh");
+        f.commit("initial");
+        let path = f.dir.path().join("run.sh");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let warnings = f.stager.parse_warnings(["run.sh"]).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].header, "diff --git a/run.sh b/run.sh");
+
+        let stage = |args: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .arg("stage")
+                .args(args)
+                .output()
+                .unwrap()
+        };
+        let lenient = stage(&["run.sh:all"]);
+        assert!(!lenient.status.success());
+        let stderr = String::from_utf8_lossy(&lenient.stderr);
+        let expected = "warning: skipped 'diff --git a/run.sh b/run.sh': \
+                        no changed lines, e.g. only a mode change";
+        assert!(stderr.contains(expected), "{stderr}");
+
+        let strict = stage(&["--strict", "run.sh:all"]);
+        assert!(!strict.status.success());
+        let stderr = String::from_utf8_lossy(&strict.stderr);
+        assert!(
+            stderr.contains("Could not parse the diff of run.sh: no changed lines"),
+            "{stderr}"
+        );
+        assert!(!stderr.contains("warning:"), "{stderr}");
+    }
+
+    /// `--stdin` shows and stages a captured diff, even after the working
+    /// tree has moved on
+    #[test]
+    fn stdin_diff_is_shown_and_staged() {
+        use std::io::Write;
+        use std::process::Stdio;
+
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(3));
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "line 1\nline two\nline 3\nline 4\n");
+        let captured = f.git(&["diff", "-U0"]);
+        f.write_file("config.nix", "changed again\n");
+
+        let run = |args: &[&str]| {
+            let mut child = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+                .arg("-C")
+                .arg(f.dir.path())
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(captured.as_bytes())
+                .unwrap();
+            child.wait_with_output().unwrap()
+        };
+
+        let shown = run(&["diff", "--stdin", "--map"]);
+        assert!(shown.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&shown.stdout),
+            "config.nix: -2, +2, +4\n"
+        );
+
+        let staged = run(&["stage", "--stdin", "-q", "config.nix:-2,2"]);
+        assert!(
+            staged.status.success(),
+            "{}",
+            String::from_utf8_lossy(&staged.stderr)
+        );
+        assert_eq!(
+            f.git(&["show", ":config.nix"]),
+            "line 1\nline two\nline 3\n"
+        );
+    }
+
+    /// Specs for one file in a captured diff are applied together, since
+    /// the diff's line numbers do not follow the index
+    #[test]
+    fn stdin_specs_for_the_same_file_stage_together() {
+        use std::io::Write;
+        use std::process::Stdio;
+
+        let f = Fixture::new();
+        f.write_file("a.txt", "l1\nl2\nl3\nl4\n");
+        f.stage_file("a.txt");
+        f.commit("initial");
+        f.write_file("a.txt", "l1\nX\nl2\nl3\nY\nZ\nl4\n");
+        let captured = f.git(&["diff", "-U0"]);
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["stage", "--stdin", "-q", "a.txt:2", "a.txt:5"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(captured.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(f.git(&["show", ":a.txt"]), "l1\nX\nl2\nl3\nY\nl4\n");
+    }
+
+    /// A repository whose .git lives elsewhere is staged through an explicit
+    /// git dir and work tree, from options or GIT_DIR / GIT_WORK_TREE
+    #[test]
+    fn relocated_git_dir_stages_with_explicit_work_tree() {
+        let f = Fixture::new();
+        f.write_file("config.nix", &Fixture::numbered_lines(3));
+        f.stage_file("config.nix");
+        f.commit("initial");
+        f.write_file("config.nix", "line 1\nnew A\nline 2\nline 3\nnew B\n");
+
+        let elsewhere = TempDir::new().unwrap();
+        let git_dir = elsewhere.path().join("repo.git");
+        fs::rename(f.dir.path().join(".git"), &git_dir).unwrap();
+        let indexed = || {
+            let output = Command::new("git")
+                .arg("--git-dir")
+                .arg(&git_dir)
+                .args(["show", ":config.nix"])
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        let git = git_lines::SystemGit::default()
+            .git_dir(&git_dir)
+            .work_tree(f.dir.path());
+        GitLines::new(f.dir.path())
+            .backend(git)
+            .stage("config.nix:2")
+            .unwrap();
+        assert_eq!(indexed(), "line 1\nnew A\nline 2\nline 3\n");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_git-lines"))
+            .arg("-C")
+            .arg(f.dir.path())
+            .args(["stage", "-q", "config.nix:5"])
+            .env("GIT_DIR", &git_dir)
+            .env("GIT_WORK_TREE", f.dir.path())
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(indexed(), "line 1\nnew A\nline 2\nline 3\nnew B\n");
+    }
 }